@@ -0,0 +1,3125 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    info::lua::{ErrCode, COMPILE_SYNTAX, LUAI_MAXSHORTLEN, LUA_ENV, LUA_MUL_RET},
+    lex::lexer::{chunkid, SyntaxError},
+    obj::objdef::{ObjectTrait, TObj, FLT, INT, T_NUM_INT, UINT},
+    parse::{
+        ast::{
+            Attrib, BinOp, Block, Expr, Field, FuncBody, FuncName, LocalName, Return, Stat, UnOp,
+        },
+        parser::parse,
+    },
+    vm::{
+        arith::{flt_to_int, raw_arith, to_integer_ns, to_number_ns, ArithOp},
+        tm::TagMethod,
+    },
+};
+
+use super::{
+    opcode::{
+        create_abck, create_abx, create_ax, create_sj, fits_sbx, fits_sc, get_a, get_b, get_k,
+        get_op, get_sj, int2sc, set_a, set_b, set_bx, set_c, set_k, set_op, set_sj, Instruction,
+        OpCode, LFIELDS_PER_FLUSH, MAXARG_B, MAXARG_BX, MAXARG_C, MAXARG_SJ, NO_REG, OFFSET_SBX,
+        OFFSET_SJ,
+    },
+    proto::{
+        AbsLineInfo, Constant, LocVar, Proto, UpvalDesc, ABSLINEINFO, RDKCONST, RDKCTC, RDKTOCLOSE,
+        VDKREG,
+    },
+};
+
+/// brief: parse and compile a chunk of Lua source
+pub fn compile_source(src: &[u8], chunkname: &str) -> Result<Proto, SyntaxError> {
+    let block = parse(src, chunkname)?;
+    compile(&block, chunkname)
+}
+
+/// brief: compile the block of a main function into its prototype
+/// the main function is vararg and has `_ENV` as its only upvalue
+pub fn compile(block: &Block, chunkname: &str) -> Result<Proto, SyntaxError> {
+    let mut cg = CodeGen::new(chunkname);
+    cg.open_func(0);
+    cg.set_vararg(0);
+    cg.fs().f.upvalues.push(UpvalDesc {
+        name: Some(LUA_ENV.to_string()),
+        instack: true,
+        idx: 0,
+        kind: VDKREG,
+    });
+    cg.stat_list(&block.stats, &block.ret, false)?;
+    cg.close_func()
+}
+
+type CResult<T> = Result<T, SyntaxError>;
+
+// end of a jump list
+const NO_JUMP: isize = -1;
+// maximum number of registers of a function
+const MAXREGS: usize = 255;
+// maximum number of local variables of a function
+const MAXVARS: usize = 200;
+// maximum number of upvalues of a function
+const MAXUPVAL: usize = 255;
+// maximum index of a constant used as an RK operand
+const MAXINDEXRK: usize = MAXARG_B as usize;
+// maximum number of relative line entries between two absolute ones
+const MAXIWTHABS: usize = 128;
+// line differences that do not fit a relative entry
+const LIMLINEDIFF: isize = 0x80;
+
+/// brief: what an expression is while its code is being generated
+/// registers are held until the expression is discharged
+#[derive(Debug, Clone, PartialEq)]
+enum ExpKind {
+    // empty expression list, or no expression at all
+    Void,
+    Nil,
+    True,
+    False,
+    // constant in the table, holds its index
+    K(usize),
+    KFlt(FLT),
+    KInt(INT),
+    KStr(Vec<u8>),
+    // value in a fixed register
+    NonReloc(usize),
+    // local variable, `vidx` is relative to the first local of the function
+    Local { ridx: usize, vidx: usize },
+    Upval(usize),
+    // compile-time constant, holds its absolute index in the active variables
+    Const(usize),
+    // t[idx], both registers
+    Indexed { t: usize, idx: usize },
+    // upvalue t with a short string constant key
+    IndexUp { t: usize, idx: usize },
+    // register t with an integer key that fits in C
+    IndexI { t: usize, idx: usize },
+    // register t with a short string constant key
+    IndexStr { t: usize, idx: usize },
+    // a test or comparison, holds the pc of its jump
+    Jmp(usize),
+    // result may go to any register, holds the pc of the instruction to patch
+    Reloc(usize),
+    Call(usize),
+    Vararg(usize),
+}
+
+#[derive(Debug, Clone)]
+struct ExpDesc {
+    k: ExpKind,
+    // patch list of "exit when true"
+    t: isize,
+    // patch list of "exit when false"
+    f: isize,
+}
+
+impl ExpDesc {
+    fn new(k: ExpKind) -> Self {
+        Self {
+            k,
+            t: NO_JUMP,
+            f: NO_JUMP,
+        }
+    }
+
+    #[inline(always)]
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+
+    #[inline(always)]
+    fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+
+    #[inline(always)]
+    fn is_var(&self) -> bool {
+        matches!(
+            self.k,
+            ExpKind::Local { .. }
+                | ExpKind::Upval(_)
+                | ExpKind::Const(_)
+                | ExpKind::Indexed { .. }
+                | ExpKind::IndexUp { .. }
+                | ExpKind::IndexI { .. }
+                | ExpKind::IndexStr { .. }
+        )
+    }
+
+    #[inline(always)]
+    fn is_indexed(&self) -> bool {
+        matches!(
+            self.k,
+            ExpKind::Indexed { .. }
+                | ExpKind::IndexUp { .. }
+                | ExpKind::IndexI { .. }
+                | ExpKind::IndexStr { .. }
+        )
+    }
+
+    /// brief: register, constant index or pc, depending on the kind
+    fn info(&self) -> usize {
+        match self.k {
+            ExpKind::K(i)
+            | ExpKind::NonReloc(i)
+            | ExpKind::Upval(i)
+            | ExpKind::Const(i)
+            | ExpKind::Jmp(i)
+            | ExpKind::Reloc(i)
+            | ExpKind::Call(i)
+            | ExpKind::Vararg(i) => i,
+            _ => 0,
+        }
+    }
+
+    /// brief: the value of a numeric constant without jumps
+    fn numeral(&self) -> Option<TObj> {
+        if self.has_jumps() {
+            return None;
+        }
+        match self.k {
+            ExpKind::KInt(i) => Some(Some(i).new()),
+            ExpKind::KFlt(n) => Some(Some(n).new()),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    fn is_kint(&self) -> bool {
+        matches!(self.k, ExpKind::KInt(_)) && !self.has_jumps()
+    }
+
+    /// brief: integer constant that fits in an unsigned C argument
+    fn is_cint(&self) -> bool {
+        match self.k {
+            ExpKind::KInt(i) => !self.has_jumps() && i >= 0 && i <= MAXARG_C as INT,
+            _ => false,
+        }
+    }
+
+    /// brief: integer constant that fits in a signed C argument
+    fn is_scint(&self) -> bool {
+        match self.k {
+            ExpKind::KInt(i) => !self.has_jumps() && fits_sc(i),
+            _ => false,
+        }
+    }
+
+    /// brief: a number with an integral value that fits in a signed argument,
+    /// gives the coded argument and whether the number is a float
+    fn is_scnumber(&self) -> Option<(usize, bool)> {
+        let (i, isfloat) = match self.k {
+            ExpKind::KInt(i) => (i, false),
+            ExpKind::KFlt(n) => (flt_to_int(n)?, true),
+            _ => return None,
+        };
+        if !self.has_jumps() && fits_sc(i) {
+            return Some((int2sc(i) as usize, isfloat));
+        }
+        None
+    }
+}
+
+/// brief: key of the constant cache, floats by their bits so 1.0 and 1 differ
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum KKey {
+    Nil,
+    Bool(bool),
+    Int(INT),
+    Flt(UINT), // the bits of the float
+    Str(Vec<u8>),
+}
+
+/// brief: an active local variable
+#[derive(Debug, Clone)]
+struct VarDesc {
+    name: String,
+    kind: u8,
+    // register holding the variable
+    ridx: usize,
+    // index of the variable in the debug information
+    pidx: usize,
+    // value of a compile-time constant
+    k: Constant,
+}
+
+/// brief: a label or a pending goto, `pc` is a jump list for gotos
+#[derive(Debug, Clone)]
+struct LabelDesc {
+    name: String,
+    pc: isize,
+    line: usize,
+    // number of active variables at that position
+    nactvar: usize,
+    // whether a goto jumps out of the scope of an upvalue
+    close: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockCnt {
+    firstlabel: usize,
+    firstgoto: usize,
+    // active locals outside the block
+    nactvar: usize,
+    // some variable of the block is an upvalue
+    upval: bool,
+    isloop: bool,
+    // inside the scope of a to-be-closed variable
+    insidetbc: bool,
+}
+
+/// brief: state of a function being compiled
+struct FuncState {
+    f: Proto,
+    kcache: HashMap<KKey, usize>,
+    blocks: Vec<BlockCnt>,
+    // pc of the last jump target
+    lasttarget: usize,
+    // line of the last instruction with line information
+    previousline: usize,
+    // instructions since the last absolute line information
+    iwthabs: usize,
+    // first local of the function in the active variables
+    firstlocal: usize,
+    // first label of the function
+    firstlabel: usize,
+    nactvar: usize,
+    // first free register
+    freereg: usize,
+    // function must close upvalues when returning
+    needclose: bool,
+}
+
+impl FuncState {
+    #[inline(always)]
+    fn pc(&self) -> usize {
+        self.f.code.len()
+    }
+
+    fn save_lineinfo(&mut self, line: usize) {
+        let mut linedif = line as isize - self.previousline as isize;
+        let pc = self.pc() - 1;
+        let force = self.iwthabs >= MAXIWTHABS;
+        self.iwthabs += 1;
+        if linedif.abs() >= LIMLINEDIFF || force {
+            self.f.abslineinfo.push(AbsLineInfo { pc, line });
+            linedif = ABSLINEINFO as isize;
+            self.iwthabs = 1;
+        }
+        self.f.lineinfo.push(linedif as i8);
+        self.previousline = line;
+    }
+
+    fn remove_last_lineinfo(&mut self) {
+        match self.f.lineinfo.pop() {
+            Some(ABSLINEINFO) => {
+                self.f.abslineinfo.pop();
+                // force the next line information to be absolute
+                self.iwthabs = MAXIWTHABS + 1;
+            }
+            Some(diff) => {
+                self.previousline = (self.previousline as isize - diff as isize) as usize;
+                self.iwthabs -= 1;
+            }
+            None => {}
+        }
+    }
+}
+
+struct CodeGen {
+    // chunk name as given and as shown in messages
+    source: String,
+    chunkname: String,
+    funcs: Vec<FuncState>,
+    // active local variables of all the functions being compiled
+    actvar: Vec<VarDesc>,
+    // pending gotos and active labels
+    gt: Vec<LabelDesc>,
+    label: Vec<LabelDesc>,
+    // line given to the next instructions
+    line: usize,
+}
+
+struct ConsControl {
+    // last list item read
+    v: ExpDesc,
+    // register of the table
+    t: usize,
+    // record elements
+    nh: usize,
+    // array elements already stored
+    na: usize,
+    // array elements pending to be stored
+    tostore: usize,
+}
+
+#[inline(always)]
+fn arith_op(op: BinOp) -> ArithOp {
+    match op {
+        BinOp::Add => ArithOp::Add,
+        BinOp::Sub => ArithOp::Sub,
+        BinOp::Mul => ArithOp::Mul,
+        BinOp::Mod => ArithOp::Mod,
+        BinOp::Pow => ArithOp::Pow,
+        BinOp::Div => ArithOp::Div,
+        BinOp::IDiv => ArithOp::IDiv,
+        BinOp::BAnd => ArithOp::BAnd,
+        BinOp::BOr => ArithOp::BOr,
+        BinOp::BXor => ArithOp::BXor,
+        BinOp::Shl => ArithOp::Shl,
+        _ => ArithOp::Shr,
+    }
+}
+
+/// brief: operators are laid out in the same order as their opcodes
+#[inline(always)]
+fn binop2op(op: BinOp, baseop: BinOp, base: OpCode) -> OpCode {
+    OpCode::from_u8(op as u8 - baseop as u8 + base as u8).unwrap_or(base)
+}
+
+#[inline(always)]
+fn binop2tm(op: BinOp) -> TagMethod {
+    TagMethod::from_u8(op as u8 - BinOp::Add as u8 + TagMethod::Add as u8).unwrap_or(TagMethod::Add)
+}
+
+#[inline(always)]
+fn const2exp(k: &Constant) -> ExpKind {
+    match k {
+        Constant::Nil => ExpKind::Nil,
+        Constant::Bool(true) => ExpKind::True,
+        Constant::Bool(false) => ExpKind::False,
+        Constant::Int(i) => ExpKind::KInt(*i),
+        Constant::Flt(n) => ExpKind::KFlt(*n),
+        Constant::Str(s) => ExpKind::KStr(s.clone()),
+    }
+}
+
+/// brief: whether folding the operation is safe,
+/// avoiding conversion errors and divisions by zero
+fn valid_op(op: ArithOp, v1: &TObj, v2: &TObj) -> bool {
+    match op {
+        ArithOp::BAnd
+        | ArithOp::BOr
+        | ArithOp::BXor
+        | ArithOp::Shl
+        | ArithOp::Shr
+        | ArithOp::BNot => to_integer_ns(v1).is_some() && to_integer_ns(v2).is_some(),
+        ArithOp::Div | ArithOp::IDiv | ArithOp::Mod => to_number_ns(v2).is_some_and(|n| n != 0.0),
+        _ => true,
+    }
+}
+
+#[inline(always)]
+fn ceil_log2(x: usize) -> usize {
+    (usize::BITS - (x - 1).leading_zeros()) as usize
+}
+
+impl CodeGen {
+    fn new(chunkname: &str) -> Self {
+        Self {
+            source: chunkname.to_string(),
+            chunkname: chunkid(chunkname),
+            funcs: Vec::new(),
+            actvar: Vec::new(),
+            gt: Vec::new(),
+            label: Vec::new(),
+            line: 1,
+        }
+    }
+
+    fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            code: ErrCode(COMPILE_SYNTAX),
+            chunkname: self.chunkname.clone(),
+            line: self.line,
+            msg: msg.to_string(),
+        }
+    }
+
+    fn error_limit(&self, linedefined: usize, limit: usize, what: &str) -> SyntaxError {
+        let place = if linedefined == 0 {
+            "main function".to_string()
+        } else {
+            format!("function at line {}", linedefined)
+        };
+        self.error(&format!(
+            "too many {} (limit is {}) in {}",
+            what, limit, place
+        ))
+    }
+
+    #[inline(always)]
+    fn fs(&mut self) -> &mut FuncState {
+        let n = self.funcs.len() - 1;
+        &mut self.funcs[n]
+    }
+
+    #[inline(always)]
+    fn fs_ref(&self) -> &FuncState {
+        &self.funcs[self.funcs.len() - 1]
+    }
+
+    #[inline(always)]
+    fn pc(&self) -> usize {
+        self.fs_ref().pc()
+    }
+
+    #[inline(always)]
+    fn instr(&mut self, pc: usize) -> &mut Instruction {
+        &mut self.fs().f.code[pc]
+    }
+
+    /* ------ code emission ------ */
+
+    fn code(&mut self, i: Instruction) -> usize {
+        let line = self.line;
+        let fs = self.fs();
+        fs.f.code.push(i);
+        fs.save_lineinfo(line);
+        fs.pc() - 1
+    }
+
+    #[inline(always)]
+    fn code_abck(&mut self, op: OpCode, a: usize, b: usize, c: usize, k: bool) -> usize {
+        self.code(create_abck(op, a as u32, b as u32, c as u32, k))
+    }
+
+    #[inline(always)]
+    fn code_abc(&mut self, op: OpCode, a: usize, b: usize, c: usize) -> usize {
+        self.code_abck(op, a, b, c, false)
+    }
+
+    #[inline(always)]
+    fn code_abx(&mut self, op: OpCode, a: usize, bx: usize) -> usize {
+        self.code(create_abx(op, a as u32, bx as u32))
+    }
+
+    #[inline(always)]
+    fn code_asbx(&mut self, op: OpCode, a: usize, sbx: INT) -> usize {
+        self.code(create_abx(op, a as u32, (sbx + OFFSET_SBX as INT) as u32))
+    }
+
+    #[inline(always)]
+    fn code_extraarg(&mut self, a: usize) -> usize {
+        self.code(create_ax(OpCode::ExtraArg, a as u32))
+    }
+
+    /// brief: load constant k into a register, with an extra argument if it is too far
+    fn code_k(&mut self, reg: usize, k: usize) -> usize {
+        if k <= MAXARG_BX as usize {
+            return self.code_abx(OpCode::LoadK, reg, k);
+        }
+        let p = self.code_abx(OpCode::LoadKX, reg, 0);
+        self.code_extraarg(k);
+        p
+    }
+
+    /// brief: change the line of the last instruction
+    fn fix_line(&mut self, line: usize) {
+        let fs = self.fs();
+        fs.remove_last_lineinfo();
+        fs.save_lineinfo(line);
+    }
+
+    fn remove_last_instruction(&mut self) {
+        let fs = self.fs();
+        fs.remove_last_lineinfo();
+        fs.f.code.pop();
+    }
+
+    /// brief: the previous instruction, unless the current position is a jump target
+    fn previous_instruction(&self) -> Option<usize> {
+        let fs = self.fs_ref();
+        if fs.pc() > fs.lasttarget {
+            return Some(fs.pc() - 1);
+        }
+        None
+    }
+
+    /// brief: set n registers to nil, merging with a previous LOADNIL when possible
+    fn nil(&mut self, mut from: usize, n: usize) {
+        let mut l = from + n - 1;
+        if let Some(prev) = self.previous_instruction() {
+            let ins = self.fs_ref().f.code[prev];
+            if get_op(ins) == OpCode::LoadNil {
+                let pfrom = get_a(ins) as usize;
+                let pl = pfrom + get_b(ins) as usize;
+                if (pfrom <= from && from <= pl + 1) || (from <= pfrom && pfrom <= l + 1) {
+                    from = from.min(pfrom);
+                    l = l.max(pl);
+                    let ins = self.instr(prev);
+                    set_a(ins, from as u32);
+                    set_b(ins, (l - from) as u32);
+                    return;
+                }
+            }
+        }
+        self.code_abc(OpCode::LoadNil, from, n - 1, 0);
+    }
+
+    fn load_int(&mut self, reg: usize, i: INT) {
+        if fits_sbx(i) {
+            self.code_asbx(OpCode::LoadI, reg, i);
+        } else {
+            let k = self.int_k(i);
+            self.code_k(reg, k);
+        }
+    }
+
+    fn load_flt(&mut self, reg: usize, n: FLT) {
+        match flt_to_int(n) {
+            Some(i) if fits_sbx(i) => {
+                self.code_asbx(OpCode::LoadF, reg, i);
+            }
+            _ => {
+                let k = self.number_k(n);
+                self.code_k(reg, k);
+            }
+        }
+    }
+
+    fn ret(&mut self, first: usize, nret: isize) {
+        let op = match nret {
+            0 => OpCode::Return0,
+            1 => OpCode::Return1,
+            _ => OpCode::Return,
+        };
+        self.code_abc(op, first, (nret + 1) as usize, 0);
+    }
+
+    fn set_vararg(&mut self, nparams: usize) {
+        self.fs().f.is_vararg = true;
+        self.code_abc(OpCode::VarArgPrep, nparams, 0, 0);
+    }
+
+    /* ------ constants ------ */
+
+    fn add_k(&mut self, key: KKey, v: Constant) -> usize {
+        let fs = self.fs();
+        if let Some(&idx) = fs.kcache.get(&key) {
+            return idx;
+        }
+        fs.f.k.push(v);
+        let idx = fs.f.k.len() - 1;
+        fs.kcache.insert(key, idx);
+        idx
+    }
+
+    fn string_k(&mut self, s: &[u8]) -> usize {
+        self.add_k(KKey::Str(s.to_vec()), Constant::Str(s.to_vec()))
+    }
+
+    fn int_k(&mut self, i: INT) -> usize {
+        self.add_k(KKey::Int(i), Constant::Int(i))
+    }
+
+    fn number_k(&mut self, n: FLT) -> usize {
+        self.add_k(KKey::Flt(n.to_bits()), Constant::Flt(n))
+    }
+
+    fn bool_k(&mut self, b: bool) -> usize {
+        self.add_k(KKey::Bool(b), Constant::Bool(b))
+    }
+
+    fn nil_k(&mut self) -> usize {
+        self.add_k(KKey::Nil, Constant::Nil)
+    }
+
+    fn str2k(&mut self, e: &mut ExpDesc) {
+        if let ExpKind::KStr(s) = &e.k {
+            let s = s.clone();
+            e.k = ExpKind::K(self.string_k(&s));
+        }
+    }
+
+    /// brief: is the expression a short string constant usable as a key?
+    fn is_kstr(&self, e: &ExpDesc) -> bool {
+        match e.k {
+            ExpKind::K(idx) if !e.has_jumps() && idx <= MAXARG_B as usize => {
+                matches!(&self.fs_ref().f.k[idx], Constant::Str(s) if s.len() <= LUAI_MAXSHORTLEN)
+            }
+            _ => false,
+        }
+    }
+
+    /* ------ jumps ------ */
+
+    #[inline(always)]
+    fn jump(&mut self) -> isize {
+        self.code(create_sj(
+            OpCode::Jmp,
+            (NO_JUMP as i32 + OFFSET_SJ) as u32,
+            false,
+        )) as isize
+    }
+
+    fn cond_jump(&mut self, op: OpCode, a: usize, b: usize, c: usize, k: bool) -> isize {
+        self.code_abck(op, a, b, c, k);
+        self.jump()
+    }
+
+    /// brief: mark the current position as a jump target
+    fn get_label(&mut self) -> usize {
+        let fs = self.fs();
+        fs.lasttarget = fs.pc();
+        fs.lasttarget
+    }
+
+    fn get_jump(&self, pc: usize) -> isize {
+        let offset = get_sj(self.fs_ref().f.code[pc]);
+        if offset as isize == NO_JUMP {
+            return NO_JUMP;
+        }
+        pc as isize + 1 + offset as isize
+    }
+
+    fn fix_jump(&mut self, pc: usize, dest: usize) -> CResult<()> {
+        let offset = dest as isize - (pc as isize + 1);
+        if !(-(OFFSET_SJ as isize) <= offset && offset <= MAXARG_SJ as isize - OFFSET_SJ as isize) {
+            return Err(self.error("control structure too long"));
+        }
+        set_sj(self.instr(pc), offset as i32);
+        Ok(())
+    }
+
+    /// brief: append jump list l2 to the list in l1
+    fn concat_jumps(&mut self, l1: &mut isize, l2: isize) -> CResult<()> {
+        if l2 == NO_JUMP {
+            return Ok(());
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return Ok(());
+        }
+        let mut list = *l1 as usize;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next as usize;
+        }
+        self.fix_jump(list, l2 as usize)
+    }
+
+    /// brief: the instruction controlling a jump, a test or the jump itself
+    fn jump_control(&self, pc: usize) -> usize {
+        if pc >= 1 && get_op(self.fs_ref().f.code[pc - 1]).is_test() {
+            return pc - 1;
+        }
+        pc
+    }
+
+    /// brief: make a TESTSET store into reg, or turn it into a TEST
+    fn patch_test_reg(&mut self, node: usize, reg: usize) -> bool {
+        let i = self.jump_control(node);
+        let ins = self.fs_ref().f.code[i];
+        if get_op(ins) != OpCode::TestSet {
+            return false;
+        }
+        if reg != NO_REG as usize && reg != get_b(ins) as usize {
+            set_a(self.instr(i), reg as u32);
+        } else {
+            *self.instr(i) = create_abck(OpCode::Test, get_b(ins), 0, 0, get_k(ins));
+        }
+        true
+    }
+
+    fn remove_values(&mut self, mut list: isize) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list as usize, NO_REG as usize);
+            list = self.get_jump(list as usize);
+        }
+    }
+
+    /// brief: tests producing values jump to vtarget, the others to dtarget
+    fn patch_list_aux(
+        &mut self,
+        mut list: isize,
+        vtarget: usize,
+        reg: usize,
+        dtarget: isize,
+    ) -> CResult<()> {
+        while list != NO_JUMP {
+            let next = self.get_jump(list as usize);
+            if self.patch_test_reg(list as usize, reg) {
+                self.fix_jump(list as usize, vtarget)?;
+            } else {
+                self.fix_jump(list as usize, dtarget as usize)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+
+    fn patch_list(&mut self, list: isize, target: usize) -> CResult<()> {
+        self.patch_list_aux(list, target, NO_REG as usize, target as isize)
+    }
+
+    fn patch_to_here(&mut self, list: isize) -> CResult<()> {
+        let here = self.get_label();
+        self.patch_list(list, here)
+    }
+
+    fn jump_to(&mut self, target: usize) -> CResult<()> {
+        let j = self.jump();
+        self.patch_list(j, target)
+    }
+
+    /// brief: whether some jump in the list needs a value, i.e. is not a TESTSET
+    fn need_value(&self, mut list: isize) -> bool {
+        while list != NO_JUMP {
+            let i = self.fs_ref().f.code[self.jump_control(list as usize)];
+            if get_op(i) != OpCode::TestSet {
+                return true;
+            }
+            list = self.get_jump(list as usize);
+        }
+        false
+    }
+
+    /* ------ registers ------ */
+
+    fn check_stack(&mut self, n: usize) -> CResult<()> {
+        let newstack = self.fs_ref().freereg + n;
+        if newstack > self.fs_ref().f.maxstacksize as usize {
+            if newstack >= MAXREGS {
+                return Err(self.error("function or expression needs too many registers"));
+            }
+            self.fs().f.maxstacksize = newstack as u8;
+        }
+        Ok(())
+    }
+
+    fn reserve_regs(&mut self, n: usize) -> CResult<()> {
+        self.check_stack(n)?;
+        self.fs().freereg += n;
+        Ok(())
+    }
+
+    /// brief: free a register unless it belongs to a local variable
+    fn free_reg(&mut self, reg: usize) {
+        if reg >= self.nvarstack() {
+            self.fs().freereg -= 1;
+        }
+    }
+
+    fn free_regs(&mut self, r1: usize, r2: usize) {
+        if r1 > r2 {
+            self.free_reg(r1);
+            self.free_reg(r2);
+        } else {
+            self.free_reg(r2);
+            self.free_reg(r1);
+        }
+    }
+
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(r) = e.k {
+            self.free_reg(r);
+        }
+    }
+
+    fn free_exps(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        match (&e1.k, &e2.k) {
+            (ExpKind::NonReloc(r1), ExpKind::NonReloc(r2)) => self.free_regs(*r1, *r2),
+            (ExpKind::NonReloc(r1), _) => self.free_reg(*r1),
+            (_, ExpKind::NonReloc(r2)) => self.free_reg(*r2),
+            _ => {}
+        }
+    }
+
+    /* ------ expressions ------ */
+
+    /// brief: fix the number of results of an open call or vararg
+    fn set_returns(&mut self, e: &mut ExpDesc, nresults: isize) -> CResult<()> {
+        match e.k {
+            ExpKind::Call(pc) => set_c(self.instr(pc), (nresults + 1) as u32),
+            ExpKind::Vararg(pc) => {
+                let freereg = self.fs_ref().freereg;
+                let ins = self.instr(pc);
+                set_c(ins, (nresults + 1) as u32);
+                set_a(ins, freereg as u32);
+                self.reserve_regs(1)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn set_multret(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.set_returns(e, LUA_MUL_RET)
+    }
+
+    /// brief: adjust a multi-result expression to exactly one result
+    fn set_oneret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Call(pc) => {
+                e.k = ExpKind::NonReloc(get_a(self.fs_ref().f.code[pc]) as usize);
+            }
+            ExpKind::Vararg(pc) => {
+                set_c(self.instr(pc), 2);
+                e.k = ExpKind::Reloc(pc);
+            }
+            _ => {}
+        }
+    }
+
+    /// brief: turn a variable into a value
+    fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Const(idx) => e.k = const2exp(&self.actvar[idx].k),
+            ExpKind::Local { ridx, .. } => e.k = ExpKind::NonReloc(ridx),
+            ExpKind::Upval(idx) => e.k = ExpKind::Reloc(self.code_abc(OpCode::GetUpval, 0, idx, 0)),
+            ExpKind::IndexUp { t, idx } => {
+                e.k = ExpKind::Reloc(self.code_abc(OpCode::GetTabUp, 0, t, idx));
+            }
+            ExpKind::IndexI { t, idx } => {
+                self.free_reg(t);
+                e.k = ExpKind::Reloc(self.code_abc(OpCode::GetI, 0, t, idx));
+            }
+            ExpKind::IndexStr { t, idx } => {
+                self.free_reg(t);
+                e.k = ExpKind::Reloc(self.code_abc(OpCode::GetField, 0, t, idx));
+            }
+            ExpKind::Indexed { t, idx } => {
+                self.free_regs(t, idx);
+                e.k = ExpKind::Reloc(self.code_abc(OpCode::GetTable, 0, t, idx));
+            }
+            ExpKind::Call(_) | ExpKind::Vararg(_) => self.set_oneret(e),
+            _ => {}
+        }
+    }
+
+    fn discharge2reg(&mut self, e: &mut ExpDesc, reg: usize) {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil => self.nil(reg, 1),
+            ExpKind::False => {
+                self.code_abc(OpCode::LoadFalse, reg, 0, 0);
+            }
+            ExpKind::True => {
+                self.code_abc(OpCode::LoadTrue, reg, 0, 0);
+            }
+            ExpKind::KStr(_) => {
+                self.str2k(e);
+                self.code_k(reg, e.info());
+            }
+            ExpKind::K(idx) => {
+                self.code_k(reg, idx);
+            }
+            ExpKind::KFlt(n) => self.load_flt(reg, n),
+            ExpKind::KInt(i) => self.load_int(reg, i),
+            ExpKind::Reloc(pc) => set_a(self.instr(pc), reg as u32),
+            ExpKind::NonReloc(r) => {
+                if r != reg {
+                    self.code_abc(OpCode::Move, reg, r, 0);
+                }
+            }
+            // a jump, nothing to do yet
+            _ => return,
+        }
+        e.k = ExpKind::NonReloc(reg);
+    }
+
+    fn discharge2anyreg(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        if !matches!(e.k, ExpKind::NonReloc(_)) {
+            self.reserve_regs(1)?;
+            let reg = self.fs_ref().freereg - 1;
+            self.discharge2reg(e, reg);
+        }
+        Ok(())
+    }
+
+    fn code_loadbool(&mut self, a: usize, op: OpCode) -> isize {
+        // those instructions may be jump targets
+        self.get_label();
+        self.code_abc(op, a, 0, 0) as isize
+    }
+
+    /// brief: put the final value of an expression, jumps included, into reg
+    fn exp2reg(&mut self, e: &mut ExpDesc, reg: usize) -> CResult<()> {
+        self.discharge2reg(e, reg);
+        if let ExpKind::Jmp(pc) = e.k {
+            let mut t = e.t;
+            self.concat_jumps(&mut t, pc as isize)?;
+            e.t = t;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP;
+            let mut p_t = NO_JUMP;
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = if matches!(e.k, ExpKind::Jmp(_)) {
+                    NO_JUMP
+                } else {
+                    self.jump()
+                };
+                p_f = self.code_loadbool(reg, OpCode::LFalseSkip);
+                p_t = self.code_loadbool(reg, OpCode::LoadTrue);
+                // jump around these booleans if e is not a test
+                self.patch_to_here(fj)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f)?;
+            self.patch_list_aux(e.t, end, reg, p_t)?;
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+
+    fn exp2nextreg(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        let reg = self.fs_ref().freereg - 1;
+        self.exp2reg(e, reg)
+    }
+
+    fn exp2anyreg(&mut self, e: &mut ExpDesc) -> CResult<usize> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(r) = e.k {
+            if !e.has_jumps() {
+                return Ok(r);
+            }
+            // a local cannot take the values of the jumps
+            if r >= self.nvarstack() {
+                self.exp2reg(e, r)?;
+                return Ok(r);
+            }
+        }
+        self.exp2nextreg(e)?;
+        Ok(e.info())
+    }
+
+    /// brief: like `exp2anyreg`, but upvalues may stay where they are
+    fn exp2anyregup(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        if !matches!(e.k, ExpKind::Upval(_)) || e.has_jumps() {
+            self.exp2anyreg(e)?;
+        }
+        Ok(())
+    }
+
+    /// brief: a register or a constant
+    fn exp2val(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        if e.has_jumps() {
+            self.exp2anyreg(e)?;
+        } else {
+            self.discharge_vars(e);
+        }
+        Ok(())
+    }
+
+    /// brief: try to make the expression a constant usable as an RK operand
+    fn exp2k(&mut self, e: &mut ExpDesc) -> bool {
+        if e.has_jumps() {
+            return false;
+        }
+        let info = match &e.k {
+            ExpKind::True => self.bool_k(true),
+            ExpKind::False => self.bool_k(false),
+            ExpKind::Nil => self.nil_k(),
+            ExpKind::KInt(i) => self.int_k(*i),
+            ExpKind::KFlt(n) => self.number_k(*n),
+            ExpKind::KStr(s) => {
+                let s = s.clone();
+                self.string_k(&s)
+            }
+            ExpKind::K(idx) => *idx,
+            _ => return false,
+        };
+        if info <= MAXINDEXRK {
+            e.k = ExpKind::K(info);
+            return true;
+        }
+        false
+    }
+
+    fn exp2rk(&mut self, e: &mut ExpDesc) -> CResult<bool> {
+        if self.exp2k(e) {
+            return Ok(true);
+        }
+        self.exp2anyreg(e)?;
+        Ok(false)
+    }
+
+    fn code_abrk(&mut self, op: OpCode, a: usize, b: usize, ec: &mut ExpDesc) -> CResult<()> {
+        let k = self.exp2rk(ec)?;
+        self.code_abck(op, a, b, ec.info(), k);
+        Ok(())
+    }
+
+    /// brief: value of an expression known at compile time
+    fn exp2const(&self, e: &ExpDesc) -> Option<Constant> {
+        if e.has_jumps() {
+            return None;
+        }
+        match &e.k {
+            ExpKind::False => Some(Constant::Bool(false)),
+            ExpKind::True => Some(Constant::Bool(true)),
+            ExpKind::Nil => Some(Constant::Nil),
+            ExpKind::KStr(s) => Some(Constant::Str(s.clone())),
+            ExpKind::KInt(i) => Some(Constant::Int(*i)),
+            ExpKind::KFlt(n) => Some(Constant::Flt(*n)),
+            ExpKind::Const(idx) => Some(self.actvar[*idx].k.clone()),
+            _ => None,
+        }
+    }
+
+    fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> CResult<()> {
+        match var.k {
+            ExpKind::Local { ridx, .. } => {
+                self.free_exp(ex);
+                return self.exp2reg(ex, ridx);
+            }
+            ExpKind::Upval(idx) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abc(OpCode::SetUpval, e, idx, 0);
+            }
+            ExpKind::IndexUp { t, idx } => self.code_abrk(OpCode::SetTabUp, t, idx, ex)?,
+            ExpKind::IndexI { t, idx } => self.code_abrk(OpCode::SetI, t, idx, ex)?,
+            ExpKind::IndexStr { t, idx } => self.code_abrk(OpCode::SetField, t, idx, ex)?,
+            ExpKind::Indexed { t, idx } => self.code_abrk(OpCode::SetTable, t, idx, ex)?,
+            _ => return Err(self.error("syntax error")),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+
+    /// brief: `e:key`, the method and the object go to two consecutive registers
+    fn self_(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> CResult<()> {
+        self.exp2anyreg(e)?;
+        let ereg = e.info();
+        self.free_exp(e);
+        let base = self.fs_ref().freereg;
+        e.k = ExpKind::NonReloc(base);
+        self.reserve_regs(2)?;
+        self.code_abrk(OpCode::Self_, base, ereg, key)?;
+        self.free_exp(key);
+        Ok(())
+    }
+
+    /// brief: `t[k]`, t must already be a local, a register or an upvalue
+    fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> CResult<()> {
+        self.str2k(k);
+        if matches!(t.k, ExpKind::Upval(_)) && !self.is_kstr(k) {
+            self.exp2anyreg(t)?;
+        }
+        if let ExpKind::Upval(up) = t.k {
+            t.k = ExpKind::IndexUp {
+                t: up,
+                idx: k.info(),
+            };
+            return Ok(());
+        }
+        let treg = match t.k {
+            ExpKind::Local { ridx, .. } => ridx,
+            ExpKind::NonReloc(r) => r,
+            _ => return Err(self.error("syntax error")),
+        };
+        if self.is_kstr(k) {
+            t.k = ExpKind::IndexStr {
+                t: treg,
+                idx: k.info(),
+            };
+        } else if k.is_cint() {
+            let idx = match k.k {
+                ExpKind::KInt(i) => i as usize,
+                _ => 0,
+            };
+            t.k = ExpKind::IndexI { t: treg, idx };
+        } else {
+            let idx = self.exp2anyreg(k)?;
+            t.k = ExpKind::Indexed { t: treg, idx };
+        }
+        Ok(())
+    }
+
+    fn negate_condition(&mut self, pc: usize) {
+        let i = self.jump_control(pc);
+        let ins = self.instr(i);
+        let k = get_k(*ins);
+        set_k(ins, !k);
+    }
+
+    /// brief: jump if e is cond, a NOT right before is folded into the test
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> CResult<isize> {
+        if let ExpKind::Reloc(pc) = e.k {
+            let ie = self.fs_ref().f.code[pc];
+            if get_op(ie) == OpCode::Not {
+                self.remove_last_instruction();
+                return Ok(self.cond_jump(OpCode::Test, get_b(ie) as usize, 0, 0, !cond));
+            }
+        }
+        self.discharge2anyreg(e)?;
+        self.free_exp(e);
+        Ok(self.cond_jump(OpCode::TestSet, NO_REG as usize, e.info(), 0, cond))
+    }
+
+    /// brief: go through when e is true, jump out when false
+    fn go_if_true(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(pc);
+                pc as isize
+            }
+            ExpKind::K(_)
+            | ExpKind::KFlt(_)
+            | ExpKind::KInt(_)
+            | ExpKind::KStr(_)
+            | ExpKind::True => NO_JUMP,
+            _ => self.jump_on_cond(e, false)?,
+        };
+        let mut f = e.f;
+        self.concat_jumps(&mut f, pc)?;
+        e.f = f;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+
+    /// brief: go through when e is false, jump out when true
+    fn go_if_false(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Jmp(pc) => pc as isize,
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            _ => self.jump_on_cond(e, true)?,
+        };
+        let mut t = e.t;
+        self.concat_jumps(&mut t, pc)?;
+        e.t = t;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+
+    fn code_not(&mut self, e: &mut ExpDesc) -> CResult<()> {
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            ExpKind::K(_)
+            | ExpKind::KFlt(_)
+            | ExpKind::KInt(_)
+            | ExpKind::KStr(_)
+            | ExpKind::True => e.k = ExpKind::False,
+            ExpKind::Jmp(pc) => self.negate_condition(pc),
+            ExpKind::Reloc(_) | ExpKind::NonReloc(_) => {
+                self.discharge2anyreg(e)?;
+                self.free_exp(e);
+                e.k = ExpKind::Reloc(self.code_abc(OpCode::Not, 0, e.info(), 0));
+            }
+            _ => {}
+        }
+        // values are useless when negated
+        std::mem::swap(&mut e.t, &mut e.f);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+
+    /// brief: fold an operation over numeric constants into e1
+    fn const_folding(&mut self, op: ArithOp, e1: &mut ExpDesc, e2: &ExpDesc) -> bool {
+        let (v1, v2) = match (e1.numeral(), e2.numeral()) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => return false,
+        };
+        if !valid_op(op, &v1, &v2) {
+            return false;
+        }
+        let res = match raw_arith(op, &v1, &v2) {
+            Some(res) => res,
+            None => return false,
+        };
+        if res.val_idx.into_inner() == T_NUM_INT {
+            e1.k = ExpKind::KInt(Option::<INT>::into_inner(&res).unwrap_or(0));
+        } else {
+            // folds neither NaN nor 0.0, to avoid problems with -0.0
+            let n = to_number_ns(&res).unwrap_or(0.0);
+            if n.is_nan() || n == 0.0 {
+                return false;
+            }
+            e1.k = ExpKind::KFlt(n);
+        }
+        true
+    }
+
+    fn code_unexpval(&mut self, op: OpCode, e: &mut ExpDesc, line: usize) -> CResult<()> {
+        let r = self.exp2anyreg(e)?;
+        self.free_exp(e);
+        e.k = ExpKind::Reloc(self.code_abc(op, 0, r, 0));
+        self.fix_line(line);
+        Ok(())
+    }
+
+    /// brief: emit a binary operation and the MMBIN* that follows it
+    #[allow(clippy::too_many_arguments)]
+    fn finish_binexpval(
+        &mut self,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        op: OpCode,
+        v2: usize,
+        flip: bool,
+        line: usize,
+        mmop: OpCode,
+        event: TagMethod,
+    ) -> CResult<()> {
+        let v1 = self.exp2anyreg(e1)?;
+        let pc = self.code_abck(op, 0, v1, v2, false);
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Reloc(pc);
+        self.fix_line(line);
+        self.code_abck(mmop, v1, v2, event as usize, flip);
+        self.fix_line(line);
+        Ok(())
+    }
+
+    /// brief: both operands in registers
+    fn code_binexpval(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        line: usize,
+    ) -> CResult<()> {
+        let op = binop2op(opr, BinOp::Add, OpCode::Add);
+        let v2 = self.exp2anyreg(e2)?;
+        self.finish_binexpval(e1, e2, op, v2, false, line, OpCode::MmBin, binop2tm(opr))
+    }
+
+    /// brief: second operand is an immediate integer
+    #[allow(clippy::too_many_arguments)]
+    fn code_bini(
+        &mut self,
+        op: OpCode,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        flip: bool,
+        line: usize,
+        event: TagMethod,
+    ) -> CResult<()> {
+        let v2 = match e2.k {
+            ExpKind::KInt(i) => int2sc(i) as usize,
+            _ => 0,
+        };
+        self.finish_binexpval(e1, e2, op, v2, flip, line, OpCode::MmBinI, event)
+    }
+
+    /// brief: second operand is a constant
+    fn code_bink(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        flip: bool,
+        line: usize,
+    ) -> CResult<()> {
+        let op = binop2op(opr, BinOp::Add, OpCode::AddK);
+        let v2 = e2.info();
+        self.finish_binexpval(e1, e2, op, v2, flip, line, OpCode::MmBinK, binop2tm(opr))
+    }
+
+    /// brief: code `e1 op -i` for a small integer constant e2 = i,
+    /// the metamethod still gets the original operand
+    fn finish_binexpneg(
+        &mut self,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        op: OpCode,
+        line: usize,
+        event: TagMethod,
+    ) -> CResult<bool> {
+        let i2 = match e2.k {
+            ExpKind::KInt(i) if e2.is_kint() => i,
+            _ => return Ok(false),
+        };
+        if !(fits_sc(i2) && fits_sc(-i2)) {
+            return Ok(false);
+        }
+        self.finish_binexpval(
+            e1,
+            e2,
+            op,
+            int2sc(-i2) as usize,
+            false,
+            line,
+            OpCode::MmBinI,
+            event,
+        )?;
+        let pc = self.pc() - 1;
+        set_b(self.instr(pc), int2sc(i2));
+        Ok(true)
+    }
+
+    fn code_binnok(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        flip: bool,
+        line: usize,
+    ) -> CResult<()> {
+        if flip {
+            // back to the original order
+            std::mem::swap(e1, e2);
+        }
+        self.code_binexpval(opr, e1, e2, line)
+    }
+
+    fn code_arith(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        flip: bool,
+        line: usize,
+    ) -> CResult<()> {
+        if e2.numeral().is_some() && self.exp2k(e2) {
+            return self.code_bink(opr, e1, e2, flip, line);
+        }
+        self.code_binnok(opr, e1, e2, flip, line)
+    }
+
+    /// brief: a numeric constant may go second in `+` and `*`
+    fn code_commutative(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        line: usize,
+    ) -> CResult<()> {
+        let mut flip = false;
+        if e1.numeral().is_some() {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if opr == BinOp::Add && e2.is_scint() {
+            return self.code_bini(OpCode::AddI, e1, e2, flip, line, TagMethod::Add);
+        }
+        self.code_arith(opr, e1, e2, flip, line)
+    }
+
+    fn code_bitwise(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        line: usize,
+    ) -> CResult<()> {
+        let mut flip = false;
+        if matches!(e1.k, ExpKind::KInt(_)) {
+            std::mem::swap(e1, e2);
+            flip = true;
+        }
+        if matches!(e2.k, ExpKind::KInt(_)) && self.exp2k(e2) {
+            return self.code_bink(opr, e1, e2, flip, line);
+        }
+        self.code_binnok(opr, e1, e2, flip, line)
+    }
+
+    /// brief: `<` and `<=`, with an immediate operand when possible
+    fn code_order(&mut self, opr: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CResult<()> {
+        let (r1, r2, op, isfloat);
+        if let Some((im, fl)) = e2.is_scnumber() {
+            r1 = self.exp2anyreg(e1)?;
+            r2 = im;
+            op = binop2op(opr, BinOp::Lt, OpCode::LtI);
+            isfloat = fl;
+        } else if let Some((im, fl)) = e1.is_scnumber() {
+            // (A < B) becomes (B > A) and (A <= B) becomes (B >= A)
+            r1 = self.exp2anyreg(e2)?;
+            r2 = im;
+            op = binop2op(opr, BinOp::Lt, OpCode::GtI);
+            isfloat = fl;
+        } else {
+            r1 = self.exp2anyreg(e1)?;
+            r2 = self.exp2anyreg(e2)?;
+            op = binop2op(opr, BinOp::Lt, OpCode::Lt);
+            isfloat = false;
+        }
+        self.free_exps(e1, e2);
+        e1.k = ExpKind::Jmp(self.cond_jump(op, r1, r2, isfloat as usize, true) as usize);
+        Ok(())
+    }
+
+    fn code_eq(&mut self, opr: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) -> CResult<()> {
+        if !matches!(e1.k, ExpKind::NonReloc(_)) {
+            // e1 is a constant kept by `infix`
+            std::mem::swap(e1, e2);
+        }
+        let r1 = self.exp2anyreg(e1)?;
+        let (op, r2, isfloat);
+        if let Some((im, fl)) = e2.is_scnumber() {
+            op = OpCode::EqI;
+            r2 = im;
+            isfloat = fl;
+        } else if self.exp2rk(e2)? {
+            op = OpCode::EqK;
+            r2 = e2.info();
+            isfloat = false;
+        } else {
+            op = OpCode::Eq;
+            r2 = self.exp2anyreg(e2)?;
+            isfloat = false;
+        }
+        self.free_exps(e1, e2);
+        e1.k =
+            ExpKind::Jmp(self.cond_jump(op, r1, r2, isfloat as usize, opr == BinOp::Eq) as usize);
+        Ok(())
+    }
+
+    fn prefix(&mut self, op: UnOp, e: &mut ExpDesc, line: usize) -> CResult<()> {
+        self.discharge_vars(e);
+        match op {
+            UnOp::Neg | UnOp::BNot => {
+                // fold with a fake second operand
+                let ef = ExpDesc::new(ExpKind::KInt(0));
+                let aop = if op == UnOp::Neg {
+                    ArithOp::Unm
+                } else {
+                    ArithOp::BNot
+                };
+                if self.const_folding(aop, e, &ef) {
+                    return Ok(());
+                }
+                let opcode = if op == UnOp::Neg {
+                    OpCode::Unm
+                } else {
+                    OpCode::BNot
+                };
+                self.code_unexpval(opcode, e, line)
+            }
+            UnOp::Len => self.code_unexpval(OpCode::Len, e, line),
+            UnOp::Not => self.code_not(e),
+        }
+    }
+
+    /// brief: prepare the first operand before the second one is read
+    fn infix(&mut self, op: BinOp, v: &mut ExpDesc) -> CResult<()> {
+        self.discharge_vars(v);
+        match op {
+            BinOp::And => self.go_if_true(v)?,
+            BinOp::Or => self.go_if_false(v)?,
+            // operands must be on consecutive registers
+            BinOp::Concat => self.exp2nextreg(v)?,
+            BinOp::Add
+            | BinOp::Sub
+            | BinOp::Mul
+            | BinOp::Div
+            | BinOp::IDiv
+            | BinOp::Mod
+            | BinOp::Pow
+            | BinOp::BAnd
+            | BinOp::BOr
+            | BinOp::BXor
+            | BinOp::Shl
+            | BinOp::Shr => {
+                // a numeral may be folded or used as an immediate operand
+                if v.numeral().is_none() {
+                    self.exp2anyreg(v)?;
+                }
+            }
+            BinOp::Eq | BinOp::Ne => {
+                if v.numeral().is_none() {
+                    self.exp2rk(v)?;
+                }
+            }
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                if v.is_scnumber().is_none() {
+                    self.exp2anyreg(v)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn code_concat(&mut self, e1: &mut ExpDesc, e2: &mut ExpDesc, line: usize) {
+        if let Some(prev) = self.previous_instruction() {
+            let ie2 = self.fs_ref().f.code[prev];
+            if get_op(ie2) == OpCode::Concat {
+                // e2 is a concatenation, extend it with e1
+                let n = get_b(ie2);
+                self.free_exp(e2);
+                let ins = self.instr(prev);
+                set_a(ins, e1.info() as u32);
+                set_b(ins, n + 1);
+                return;
+            }
+        }
+        self.code_abc(OpCode::Concat, e1.info(), 2, 0);
+        self.free_exp(e2);
+        self.fix_line(line);
+    }
+
+    fn posfix(
+        &mut self,
+        opr: BinOp,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        line: usize,
+    ) -> CResult<()> {
+        self.discharge_vars(e2);
+        if (opr as u8) <= BinOp::Shr as u8 && self.const_folding(arith_op(opr), e1, e2) {
+            return Ok(());
+        }
+        match opr {
+            BinOp::And => {
+                let mut f = e2.f;
+                self.concat_jumps(&mut f, e1.f)?;
+                e2.f = f;
+                *e1 = e2.clone();
+            }
+            BinOp::Or => {
+                let mut t = e2.t;
+                self.concat_jumps(&mut t, e1.t)?;
+                e2.t = t;
+                *e1 = e2.clone();
+            }
+            BinOp::Concat => {
+                self.exp2nextreg(e2)?;
+                self.code_concat(e1, e2, line);
+            }
+            BinOp::Add | BinOp::Mul => self.code_commutative(opr, e1, e2, line)?,
+            BinOp::Sub => {
+                if !self.finish_binexpneg(e1, e2, OpCode::AddI, line, TagMethod::Sub)? {
+                    self.code_arith(opr, e1, e2, false, line)?;
+                }
+            }
+            BinOp::Div | BinOp::IDiv | BinOp::Mod | BinOp::Pow => {
+                self.code_arith(opr, e1, e2, false, line)?
+            }
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor => self.code_bitwise(opr, e1, e2, line)?,
+            BinOp::Shl => {
+                if e1.is_scint() {
+                    // I << r2
+                    std::mem::swap(e1, e2);
+                    self.code_bini(OpCode::ShlI, e1, e2, true, line, TagMethod::Shl)?;
+                } else if !self.finish_binexpneg(e1, e2, OpCode::ShrI, line, TagMethod::Shl)? {
+                    self.code_binexpval(opr, e1, e2, line)?;
+                }
+            }
+            BinOp::Shr => {
+                if e2.is_scint() {
+                    self.code_bini(OpCode::ShrI, e1, e2, false, line, TagMethod::Shr)?;
+                } else {
+                    self.code_binexpval(opr, e1, e2, line)?;
+                }
+            }
+            BinOp::Eq | BinOp::Ne => self.code_eq(opr, e1, e2)?,
+            BinOp::Gt | BinOp::Ge => {
+                // (a > b) is (b < a), (a >= b) is (b <= a)
+                std::mem::swap(e1, e2);
+                let opr = if opr == BinOp::Gt {
+                    BinOp::Lt
+                } else {
+                    BinOp::Le
+                };
+                self.code_order(opr, e1, e2)?;
+            }
+            BinOp::Lt | BinOp::Le => self.code_order(opr, e1, e2)?,
+        }
+        Ok(())
+    }
+
+    fn set_table_size(&mut self, pc: usize, ra: usize, asize: usize, hsize: usize) {
+        let rb = if hsize != 0 { ceil_log2(hsize) + 1 } else { 0 };
+        let extra = asize / (MAXARG_C as usize + 1);
+        let rc = asize % (MAXARG_C as usize + 1);
+        let code = &mut self.fs().f.code;
+        code[pc] = create_abck(OpCode::NewTable, ra as u32, rb as u32, rc as u32, extra > 0);
+        code[pc + 1] = create_ax(OpCode::ExtraArg, extra as u32);
+    }
+
+    /// brief: store `tostore` list items after the first `nelems` ones of the table at base
+    fn set_list(&mut self, base: usize, nelems: usize, tostore: isize) {
+        let tostore = if tostore == LUA_MUL_RET {
+            0
+        } else {
+            tostore as usize
+        };
+        if nelems <= MAXARG_C as usize {
+            self.code_abc(OpCode::SetList, base, tostore, nelems);
+        } else {
+            let extra = nelems / (MAXARG_C as usize + 1);
+            let nelems = nelems % (MAXARG_C as usize + 1);
+            self.code_abck(OpCode::SetList, base, tostore, nelems, true);
+            self.code_extraarg(extra);
+        }
+        // free the registers of the list values
+        self.fs().freereg = base + 1;
+    }
+
+    /// brief: final pass over the code of a function
+    fn finish(&mut self) -> CResult<()> {
+        let (needclose, is_vararg, numparams) = {
+            let fs = self.fs_ref();
+            (fs.needclose, fs.f.is_vararg, fs.f.numparams)
+        };
+        for i in 0..self.pc() {
+            let ins = self.instr(i);
+            match get_op(*ins) {
+                OpCode::Return0 | OpCode::Return1 | OpCode::Return | OpCode::TailCall => {
+                    if matches!(get_op(*ins), OpCode::Return0 | OpCode::Return1) {
+                        if !(needclose || is_vararg) {
+                            continue;
+                        }
+                        // needs the extra work of a full RETURN
+                        set_op(ins, OpCode::Return);
+                    }
+                    if needclose {
+                        set_k(ins, true);
+                    }
+                    if is_vararg {
+                        set_c(ins, numparams as u32 + 1);
+                    }
+                }
+                OpCode::Jmp => {
+                    let target = self.final_target(i);
+                    self.fix_jump(i, target)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// brief: follow a chain of jumps to its final destination
+    fn final_target(&self, mut i: usize) -> usize {
+        let code = &self.fs_ref().f.code;
+        // bounded to avoid infinite loops
+        for _ in 0..100 {
+            let ins = code[i];
+            if get_op(ins) != OpCode::Jmp {
+                break;
+            }
+            i = (i as isize + get_sj(ins) as isize + 1) as usize;
+        }
+        i
+    }
+
+    /* ------ variables and scopes ------ */
+
+    /// brief: number of registers taken by the first nvar active locals
+    fn reglevel(&self, nvar: usize) -> usize {
+        let fs = self.fs_ref();
+        for i in (0..nvar).rev() {
+            let vd = &self.actvar[fs.firstlocal + i];
+            if vd.kind != RDKCTC {
+                return vd.ridx + 1;
+            }
+        }
+        0
+    }
+
+    #[inline(always)]
+    fn nvarstack(&self) -> usize {
+        self.reglevel(self.fs_ref().nactvar)
+    }
+
+    fn new_localvar(&mut self, name: &str) -> CResult<usize> {
+        let (firstlocal, linedefined) = (self.fs_ref().firstlocal, self.fs_ref().f.linedefined);
+        if self.actvar.len() + 1 - firstlocal > MAXVARS {
+            return Err(self.error_limit(linedefined, MAXVARS, "local variables"));
+        }
+        self.actvar.push(VarDesc {
+            name: name.to_string(),
+            kind: VDKREG,
+            ridx: 0,
+            pidx: 0,
+            k: Constant::Nil,
+        });
+        Ok(self.actvar.len() - 1 - firstlocal)
+    }
+
+    /// brief: bring the last nvars declared variables into scope
+    fn adjust_localvars(&mut self, nvars: usize) {
+        let reglevel = self.nvarstack();
+        for i in 0..nvars {
+            let fs = self.fs();
+            let vidx = fs.nactvar;
+            fs.nactvar += 1;
+            let pc = fs.pc();
+            let idx = fs.firstlocal + vidx;
+            let name = self.actvar[idx].name.clone();
+            let locvars = &mut self.fs().f.locvars;
+            locvars.push(LocVar {
+                name,
+                startpc: pc,
+                endpc: 0,
+            });
+            let pidx = locvars.len() - 1;
+            let var = &mut self.actvar[idx];
+            var.ridx = reglevel + i;
+            var.pidx = pidx;
+        }
+    }
+
+    /// brief: close the scope of the variables above tolevel
+    fn remove_vars(&mut self, tolevel: usize) {
+        let pc = self.pc();
+        while self.fs_ref().nactvar > tolevel {
+            let fs = self.fs();
+            fs.nactvar -= 1;
+            let idx = fs.firstlocal + fs.nactvar;
+            let var = &self.actvar[idx];
+            // constants have no debug information
+            if var.kind != RDKCTC {
+                let pidx = var.pidx;
+                self.fs().f.locvars[pidx].endpc = pc;
+            }
+        }
+    }
+
+    fn search_upvalue(&self, level: usize, name: &str) -> Option<usize> {
+        self.funcs[level]
+            .f
+            .upvalues
+            .iter()
+            .position(|up| up.name.as_deref() == Some(name))
+    }
+
+    fn new_upvalue(&mut self, level: usize, name: &str, v: &ExpDesc) -> CResult<usize> {
+        let fs = &self.funcs[level];
+        if fs.f.upvalues.len() + 1 > MAXUPVAL {
+            return Err(self.error_limit(fs.f.linedefined, MAXUPVAL, "upvalues"));
+        }
+        let prev = &self.funcs[level - 1];
+        let up = match v.k {
+            ExpKind::Local { ridx, vidx } => UpvalDesc {
+                name: Some(name.to_string()),
+                instack: true,
+                idx: ridx as u8,
+                kind: self.actvar[prev.firstlocal + vidx].kind,
+            },
+            _ => UpvalDesc {
+                name: Some(name.to_string()),
+                instack: false,
+                idx: v.info() as u8,
+                kind: prev.f.upvalues[v.info()].kind,
+            },
+        };
+        let upvalues = &mut self.funcs[level].f.upvalues;
+        upvalues.push(up);
+        Ok(upvalues.len() - 1)
+    }
+
+    fn search_var(&self, level: usize, name: &str) -> Option<ExpDesc> {
+        let fs = &self.funcs[level];
+        for i in (0..fs.nactvar).rev() {
+            let vd = &self.actvar[fs.firstlocal + i];
+            if vd.name == name {
+                if vd.kind == RDKCTC {
+                    return Some(ExpDesc::new(ExpKind::Const(fs.firstlocal + i)));
+                }
+                return Some(ExpDesc::new(ExpKind::Local {
+                    ridx: vd.ridx,
+                    vidx: i,
+                }));
+            }
+        }
+        None
+    }
+
+    /// brief: a local of an enclosing function is used as an upvalue,
+    /// its block must close it
+    fn mark_upval(&mut self, level: usize, vidx: usize) {
+        let fs = &mut self.funcs[level];
+        if let Some(bl) = fs.blocks.iter_mut().rev().find(|bl| bl.nactvar <= vidx) {
+            bl.upval = true;
+        }
+        fs.needclose = true;
+    }
+
+    fn mark_to_be_closed(&mut self) {
+        let fs = self.fs();
+        if let Some(bl) = fs.blocks.last_mut() {
+            bl.upval = true;
+            bl.insidetbc = true;
+        }
+        fs.needclose = true;
+    }
+
+    /// brief: find a variable from the function at level outwards,
+    /// a `Void` result means it is a global
+    fn single_var_aux(&mut self, level: usize, name: &str, base: bool) -> CResult<ExpDesc> {
+        if let Some(var) = self.search_var(level, name) {
+            if let ExpKind::Local { vidx, .. } = var.k {
+                if !base {
+                    self.mark_upval(level, vidx);
+                }
+            }
+            return Ok(var);
+        }
+        let idx = match self.search_upvalue(level, name) {
+            Some(idx) => idx,
+            None => {
+                if level == 0 {
+                    return Ok(ExpDesc::new(ExpKind::Void));
+                }
+                let var = self.single_var_aux(level - 1, name, false)?;
+                match var.k {
+                    ExpKind::Local { .. } | ExpKind::Upval(_) => {
+                        self.new_upvalue(level, name, &var)?
+                    }
+                    // globals and constants need nothing at this level
+                    _ => return Ok(var),
+                }
+            }
+        };
+        Ok(ExpDesc::new(ExpKind::Upval(idx)))
+    }
+
+    /// brief: a global `name` is `_ENV.name`
+    fn single_var(&mut self, name: &str) -> CResult<ExpDesc> {
+        let level = self.funcs.len() - 1;
+        let mut var = self.single_var_aux(level, name, true)?;
+        if var.k == ExpKind::Void {
+            var = self.single_var_aux(level, LUA_ENV, true)?;
+            self.exp2anyregup(&mut var)?;
+            let mut key = ExpDesc::new(ExpKind::KStr(name.as_bytes().to_vec()));
+            self.indexed(&mut var, &mut key)?;
+        }
+        Ok(var)
+    }
+
+    fn check_readonly(&self, e: &ExpDesc) -> CResult<()> {
+        let fs = self.fs_ref();
+        let varname = match e.k {
+            ExpKind::Const(idx) => Some(self.actvar[idx].name.clone()),
+            ExpKind::Local { vidx, .. } => {
+                let vd = &self.actvar[fs.firstlocal + vidx];
+                if vd.kind != VDKREG {
+                    Some(vd.name.clone())
+                } else {
+                    None
+                }
+            }
+            ExpKind::Upval(idx) => {
+                let up = &fs.f.upvalues[idx];
+                if up.kind != VDKREG {
+                    up.name.clone()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(name) = varname {
+            return Err(self.error(&format!("attempt to assign to const variable '{}'", name)));
+        }
+        Ok(())
+    }
+
+    /// brief: adjust the values of an expression list to nvars
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, e: &mut ExpDesc) -> CResult<()> {
+        let needed = nvars as isize - nexps as isize;
+        if e.has_multret() {
+            // the last expression provides the difference
+            self.set_returns(e, (needed + 1).max(0))?;
+        } else {
+            if e.k != ExpKind::Void {
+                self.exp2nextreg(e)?;
+            }
+            if needed > 0 {
+                let freereg = self.fs_ref().freereg;
+                self.nil(freereg, needed as usize);
+            }
+        }
+        if needed > 0 {
+            self.reserve_regs(needed as usize)?;
+        } else {
+            // remove the extra values
+            self.fs().freereg -= (-needed) as usize;
+        }
+        Ok(())
+    }
+
+    fn enter_block(&mut self, isloop: bool) {
+        let firstlabel = self.label.len();
+        let firstgoto = self.gt.len();
+        let fs = self.fs();
+        let bl = BlockCnt {
+            firstlabel,
+            firstgoto,
+            nactvar: fs.nactvar,
+            upval: false,
+            isloop,
+            insidetbc: fs.blocks.last().is_some_and(|bl| bl.insidetbc),
+        };
+        fs.blocks.push(bl);
+    }
+
+    fn leave_block(&mut self) -> CResult<()> {
+        let bl = match self.fs_ref().blocks.last() {
+            Some(bl) => *bl,
+            None => return Ok(()),
+        };
+        // level outside the block
+        let stklevel = self.reglevel(bl.nactvar);
+        let nactvar = self.fs_ref().nactvar;
+        let keep = self.actvar.len() - (nactvar - bl.nactvar);
+        self.remove_vars(bl.nactvar);
+        let mut hasclose = false;
+        if bl.isloop {
+            // fix pending breaks
+            hasclose = self.create_label("break", 0, false)?;
+        }
+        let nested = self.fs_ref().blocks.len() > 1;
+        if !hasclose && nested && bl.upval {
+            self.code_abc(OpCode::Close, stklevel, 0, 0);
+        }
+        self.fs().freereg = stklevel;
+        self.label.truncate(bl.firstlabel);
+        self.fs().blocks.pop();
+        if nested {
+            self.move_gotos_out(&bl);
+        } else if bl.firstgoto < self.gt.len() {
+            return Err(self.undef_goto(&self.gt[bl.firstgoto]));
+        }
+        // the variables stay until here for the scope checks of the gotos
+        self.actvar.truncate(keep);
+        Ok(())
+    }
+
+    fn undef_goto(&self, gt: &LabelDesc) -> SyntaxError {
+        if gt.name == "break" {
+            return self.error(&format!("break outside a loop at line {}", gt.line));
+        }
+        self.error(&format!(
+            "no visible label '{}' for <goto> at line {}",
+            gt.name, gt.line
+        ))
+    }
+
+    /// brief: pending gotos of a closed block now belong to the enclosing one
+    fn move_gotos_out(&mut self, bl: &BlockCnt) {
+        let outer = self.reglevel(bl.nactvar);
+        for i in bl.firstgoto..self.gt.len() {
+            // leaving a variable scope?
+            if self.reglevel(self.gt[i].nactvar) > outer {
+                self.gt[i].close |= bl.upval;
+            }
+            self.gt[i].nactvar = bl.nactvar;
+        }
+    }
+
+    fn find_label(&self, name: &str) -> Option<&LabelDesc> {
+        self.label[self.fs_ref().firstlabel..]
+            .iter()
+            .find(|lb| lb.name == name)
+    }
+
+    fn new_goto_entry(&mut self, name: &str, line: usize, pc: isize) {
+        let nactvar = self.fs_ref().nactvar;
+        self.gt.push(LabelDesc {
+            name: name.to_string(),
+            pc,
+            line,
+            nactvar,
+            close: false,
+        });
+    }
+
+    fn solve_goto(&mut self, g: usize, label: usize) -> CResult<()> {
+        let gt = self.gt[g].clone();
+        let lb_nactvar = self.label[label].nactvar;
+        if gt.nactvar < lb_nactvar {
+            let varname = &self.actvar[self.fs_ref().firstlocal + gt.nactvar].name;
+            return Err(self.error(&format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                gt.name, gt.line, varname
+            )));
+        }
+        let target = self.label[label].pc as usize;
+        self.patch_list(gt.pc, target)?;
+        self.gt.remove(g);
+        Ok(())
+    }
+
+    /// brief: solve the pending gotos of the current block to a new label,
+    /// tells whether some of them need to close upvalues
+    fn solve_gotos(&mut self, label: usize) -> CResult<bool> {
+        let mut i = self.fs_ref().blocks.last().map_or(0, |bl| bl.firstgoto);
+        let mut needsclose = false;
+        while i < self.gt.len() {
+            if self.gt[i].name == self.label[label].name {
+                needsclose |= self.gt[i].close;
+                self.solve_goto(i, label)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(needsclose)
+    }
+
+    /// brief: `last` tells the label ends its block, so locals are already gone
+    fn create_label(&mut self, name: &str, line: usize, last: bool) -> CResult<bool> {
+        let pc = self.get_label();
+        let fs = self.fs_ref();
+        let nactvar = if last {
+            fs.blocks.last().map_or(fs.nactvar, |bl| bl.nactvar)
+        } else {
+            fs.nactvar
+        };
+        self.label.push(LabelDesc {
+            name: name.to_string(),
+            pc: pc as isize,
+            line,
+            nactvar,
+            close: false,
+        });
+        let l = self.label.len() - 1;
+        if self.solve_gotos(l)? {
+            let level = self.nvarstack();
+            self.code_abc(OpCode::Close, level, 0, 0);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn open_func(&mut self, linedefined: usize) {
+        let f = Proto {
+            source: Some(self.source.clone()),
+            linedefined,
+            // registers 0/1 are always valid
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        self.funcs.push(FuncState {
+            f,
+            kcache: HashMap::new(),
+            blocks: Vec::new(),
+            lasttarget: 0,
+            previousline: linedefined,
+            iwthabs: 0,
+            firstlocal: self.actvar.len(),
+            firstlabel: self.label.len(),
+            nactvar: 0,
+            freereg: 0,
+            needclose: false,
+        });
+        self.enter_block(false);
+    }
+
+    fn close_func(&mut self) -> CResult<Proto> {
+        let first = self.nvarstack();
+        self.ret(first, 0);
+        self.leave_block()?;
+        self.finish()?;
+        let fs = self.funcs.pop();
+        Ok(fs.map(|fs| fs.f).unwrap_or_default())
+    }
+
+    /* ------ statements ------ */
+
+    fn block(&mut self, block: &Block) -> CResult<()> {
+        self.enter_block(false);
+        self.stat_list(&block.stats, &block.ret, false)?;
+        self.leave_block()
+    }
+
+    /// brief: `in_repeat` tells the list is followed by `until`,
+    /// so a label at its end still sees the locals of the block
+    fn stat_list(&mut self, stats: &[Stat], ret: &Option<Return>, in_repeat: bool) -> CResult<()> {
+        for (i, stat) in stats.iter().enumerate() {
+            let last = !in_repeat
+                && ret.is_none()
+                && stats[i + 1..]
+                    .iter()
+                    .all(|s| matches!(s, Stat::Empty | Stat::Label { .. }));
+            self.statement(stat, last)?;
+        }
+        if let Some(ret) = ret {
+            self.ret_stat(ret)?;
+            let level = self.nvarstack();
+            self.fs().freereg = level;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stat: &Stat, last: bool) -> CResult<()> {
+        match stat {
+            Stat::Empty => {}
+            Stat::Assign {
+                targets,
+                exprs,
+                line,
+            } => {
+                self.line = *line;
+                self.assign_stat(targets, exprs)?;
+            }
+            Stat::Call(call) => {
+                let e = self.expr(call)?;
+                match e.k {
+                    // a call statement uses no results
+                    ExpKind::Call(pc) => set_c(self.instr(pc), 1),
+                    _ => return Err(self.error("syntax error")),
+                }
+            }
+            Stat::Local { names, exprs, line } => {
+                self.line = *line;
+                self.local_stat(names, exprs)?;
+            }
+            Stat::LocalFunction { name, body } => {
+                self.line = body.line;
+                self.local_func(name, body)?;
+            }
+            Stat::Function { name, body } => {
+                self.line = body.line;
+                self.func_stat(name, body)?;
+            }
+            Stat::Do(block) => self.block(block)?,
+            Stat::While { cond, body, line } => {
+                self.line = *line;
+                self.while_stat(cond, body)?;
+            }
+            Stat::Repeat { body, cond, line } => {
+                self.line = *line;
+                self.repeat_stat(body, cond)?;
+            }
+            Stat::If {
+                branches,
+                else_block,
+                line,
+            } => {
+                self.line = *line;
+                self.if_stat(branches, else_block)?;
+            }
+            Stat::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+                line,
+            } => {
+                self.line = *line;
+                self.for_num(var, start, limit, step, body, *line)?;
+            }
+            Stat::GenericFor {
+                names,
+                exprs,
+                body,
+                line,
+            } => {
+                self.line = *line;
+                self.for_list(names, exprs, body, *line)?;
+            }
+            Stat::Break { line } => {
+                self.line = *line;
+                let pc = self.jump();
+                self.new_goto_entry("break", *line, pc);
+            }
+            Stat::Goto { label, line } => {
+                self.line = *line;
+                self.goto_stat(label, *line)?;
+            }
+            Stat::Label { name, line } => {
+                self.line = *line;
+                self.label_stat(name, *line, last)?;
+            }
+        }
+        // free registers
+        let level = self.nvarstack();
+        self.fs().freereg = level;
+        Ok(())
+    }
+
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], v: &ExpDesc) -> CResult<()> {
+        // position to save a copy of the variable
+        let extra = self.fs_ref().freereg;
+        let mut conflict = false;
+        for lh in lhs.iter_mut() {
+            match (&mut lh.k, &v.k) {
+                // the table is the upvalue being assigned now
+                (ExpKind::IndexUp { t, idx }, ExpKind::Upval(up)) if *t == *up => {
+                    conflict = true;
+                    lh.k = ExpKind::IndexStr {
+                        t: extra,
+                        idx: *idx,
+                    };
+                }
+                (ExpKind::Indexed { t, idx }, ExpKind::Local { ridx, .. }) => {
+                    if *t == *ridx {
+                        conflict = true;
+                        *t = extra;
+                    }
+                    if *idx == *ridx {
+                        conflict = true;
+                        *idx = extra;
+                    }
+                }
+                (
+                    ExpKind::IndexI { t, .. } | ExpKind::IndexStr { t, .. },
+                    ExpKind::Local { ridx, .. },
+                ) if *t == *ridx => {
+                    conflict = true;
+                    *t = extra;
+                }
+                _ => {}
+            }
+        }
+        if conflict {
+            match v.k {
+                ExpKind::Local { ridx, .. } => self.code_abc(OpCode::Move, extra, ridx, 0),
+                _ => self.code_abc(OpCode::GetUpval, extra, v.info(), 0),
+            };
+            self.reserve_regs(1)?;
+        }
+        Ok(())
+    }
+
+    fn assign_stat(&mut self, targets: &[Expr], exprs: &[Expr]) -> CResult<()> {
+        let mut lhs: Vec<ExpDesc> = Vec::with_capacity(targets.len());
+        for target in targets {
+            let v = self.expr(target)?;
+            if !lhs.is_empty() && !v.is_indexed() {
+                self.check_conflict(&mut lhs, &v)?;
+            }
+            if !v.is_var() {
+                return Err(self.error("syntax error"));
+            }
+            self.check_readonly(&v)?;
+            lhs.push(v);
+        }
+        let nvars = lhs.len();
+        let mut e = self.explist(exprs)?;
+        if exprs.len() != nvars {
+            self.adjust_assign(nvars, exprs.len(), &mut e)?;
+        } else if let Some(last) = lhs.pop() {
+            // close the last expression and store it directly
+            self.set_oneret(&mut e);
+            self.store_var(&last, &mut e)?;
+        }
+        // the other values sit on the top of the stack
+        while let Some(var) = lhs.pop() {
+            let mut e = ExpDesc::new(ExpKind::NonReloc(self.fs_ref().freereg - 1));
+            self.store_var(&var, &mut e)?;
+        }
+        Ok(())
+    }
+
+    fn local_stat(&mut self, names: &[LocalName], exprs: &[Expr]) -> CResult<()> {
+        let mut toclose: Option<usize> = None;
+        let mut vidx = 0;
+        for (nvars, lname) in names.iter().enumerate() {
+            vidx = self.new_localvar(&lname.name)?;
+            let kind = match lname.attrib {
+                Some(Attrib::Const) => RDKCONST,
+                Some(Attrib::Close) => RDKTOCLOSE,
+                None => VDKREG,
+            };
+            let firstlocal = self.fs_ref().firstlocal;
+            self.actvar[firstlocal + vidx].kind = kind;
+            if kind == RDKTOCLOSE {
+                if toclose.is_some() {
+                    return Err(self.error("multiple to-be-closed variables in local list"));
+                }
+                toclose = Some(self.fs_ref().nactvar + nvars);
+            }
+        }
+        let nvars = names.len();
+        let mut e = if exprs.is_empty() {
+            ExpDesc::new(ExpKind::Void)
+        } else {
+            self.explist(exprs)?
+        };
+        let var = self.fs_ref().firstlocal + vidx;
+        let constant = if nvars == exprs.len() && self.actvar[var].kind == RDKCONST {
+            self.exp2const(&e)
+        } else {
+            None
+        };
+        if let Some(k) = constant {
+            // the last variable is a compile-time constant and takes no register
+            self.actvar[var].kind = RDKCTC;
+            self.actvar[var].k = k;
+            self.adjust_localvars(nvars - 1);
+            self.fs().nactvar += 1;
+        } else {
+            self.adjust_assign(nvars, exprs.len(), &mut e)?;
+            self.adjust_localvars(nvars);
+        }
+        if let Some(level) = toclose {
+            self.mark_to_be_closed();
+            let reg = self.reglevel(level);
+            self.code_abc(OpCode::Tbc, reg, 0, 0);
+        }
+        Ok(())
+    }
+
+    fn local_func(&mut self, name: &str, body: &FuncBody) -> CResult<()> {
+        let fvar = self.fs_ref().nactvar;
+        self.new_localvar(name)?;
+        self.adjust_localvars(1);
+        // the function is created in the register of the variable
+        self.body(body)?;
+        // debug information only sees the variable after this point
+        let pidx = self.actvar[self.fs_ref().firstlocal + fvar].pidx;
+        let pc = self.pc();
+        self.fs().f.locvars[pidx].startpc = pc;
+        Ok(())
+    }
+
+    fn field_sel(&mut self, v: &mut ExpDesc, name: &str) -> CResult<()> {
+        self.exp2anyregup(v)?;
+        let mut key = ExpDesc::new(ExpKind::KStr(name.as_bytes().to_vec()));
+        self.indexed(v, &mut key)
+    }
+
+    fn func_stat(&mut self, name: &FuncName, body: &FuncBody) -> CResult<()> {
+        let mut v = match name.path.first() {
+            Some(first) => self.single_var(first)?,
+            None => return Err(self.error("<name> expected")),
+        };
+        for key in name.path[1..].iter().chain(name.method.iter()) {
+            self.field_sel(&mut v, key)?;
+        }
+        let mut b = self.body(body)?;
+        self.check_readonly(&v)?;
+        self.store_var(&v, &mut b)?;
+        // the definition happens in the first line
+        self.fix_line(body.line);
+        Ok(())
+    }
+
+    fn ret_stat(&mut self, ret: &Return) -> CResult<()> {
+        self.line = ret.line;
+        let mut first = self.nvarstack();
+        let nret: isize;
+        if ret.exprs.is_empty() {
+            nret = 0;
+        } else {
+            let mut e = self.explist(&ret.exprs)?;
+            if e.has_multret() {
+                self.set_multret(&mut e)?;
+                let insidetbc = self.fs_ref().blocks.last().is_some_and(|bl| bl.insidetbc);
+                if let ExpKind::Call(pc) = e.k {
+                    if ret.exprs.len() == 1 && !insidetbc {
+                        set_op(self.instr(pc), OpCode::TailCall);
+                    }
+                }
+                nret = LUA_MUL_RET;
+            } else if ret.exprs.len() == 1 {
+                // can use the original slot
+                first = self.exp2anyreg(&mut e)?;
+                nret = 1;
+            } else {
+                self.exp2nextreg(&mut e)?;
+                first = self.nvarstack();
+                nret = ret.exprs.len() as isize;
+            }
+        }
+        self.ret(first, nret);
+        Ok(())
+    }
+
+    /// brief: code a condition, gives the jumps taken when it is false
+    fn cond(&mut self, cond: &Expr) -> CResult<isize> {
+        let mut v = self.expr(cond)?;
+        if v.k == ExpKind::Nil {
+            // all falses are equal here
+            v.k = ExpKind::False;
+        }
+        self.go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+
+    fn while_stat(&mut self, cond: &Expr, body: &Block) -> CResult<()> {
+        let whileinit = self.get_label();
+        let condexit = self.cond(cond)?;
+        self.enter_block(true);
+        self.block(body)?;
+        self.jump_to(whileinit)?;
+        self.leave_block()?;
+        // false conditions finish the loop
+        self.patch_to_here(condexit)
+    }
+
+    fn repeat_stat(&mut self, body: &Block, cond: &Expr) -> CResult<()> {
+        let repeat_init = self.get_label();
+        // loop block and scope block
+        self.enter_block(true);
+        self.enter_block(false);
+        self.stat_list(&body.stats, &body.ret, true)?;
+        // the condition is inside the scope block
+        let mut condexit = self.cond(cond)?;
+        let bl2 = self.fs_ref().blocks.last().copied();
+        self.leave_block()?;
+        if let Some(bl2) = bl2.filter(|bl| bl.upval) {
+            // the repetition must close the upvalues, the normal exit jumps over it
+            let exit = self.jump();
+            self.patch_to_here(condexit)?;
+            let level = self.reglevel(bl2.nactvar);
+            self.code_abc(OpCode::Close, level, 0, 0);
+            condexit = self.jump();
+            self.patch_to_here(exit)?;
+        }
+        self.patch_list(condexit, repeat_init)?;
+        self.leave_block()
+    }
+
+    fn exp1(&mut self, e: &Expr) -> CResult<()> {
+        let mut e = self.expr(e)?;
+        self.exp2nextreg(&mut e)
+    }
+
+    /// brief: for loop jumps use an unsigned Bx, backward ones are negated
+    fn fix_for_jump(&mut self, pc: usize, dest: usize, back: bool) -> CResult<()> {
+        let mut offset = dest as isize - (pc as isize + 1);
+        if back {
+            offset = -offset;
+        }
+        if offset > MAXARG_BX as isize {
+            return Err(self.error("control structure too long"));
+        }
+        set_bx(self.instr(pc), offset as u32);
+        Ok(())
+    }
+
+    fn for_body(
+        &mut self,
+        base: usize,
+        line: usize,
+        nvars: usize,
+        isgen: bool,
+        body: &Block,
+    ) -> CResult<()> {
+        let (forprep, forloop) = if isgen {
+            (OpCode::TForPrep, OpCode::TForLoop)
+        } else {
+            (OpCode::ForPrep, OpCode::ForLoop)
+        };
+        let prep = self.code_abx(forprep, base, 0);
+        // scope of the declared variables
+        self.enter_block(false);
+        self.adjust_localvars(nvars);
+        self.reserve_regs(nvars)?;
+        self.block(body)?;
+        self.leave_block()?;
+        let here = self.get_label();
+        self.fix_for_jump(prep, here, false)?;
+        if isgen {
+            self.code_abc(OpCode::TForCall, base, 0, nvars);
+            self.fix_line(line);
+        }
+        let endfor = self.code_abx(forloop, base, 0);
+        self.fix_for_jump(endfor, prep + 1, true)?;
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn for_num(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        limit: &Expr,
+        step: &Option<Expr>,
+        body: &Block,
+        line: usize,
+    ) -> CResult<()> {
+        // scope of the loop and control variables, breaks jump to its end
+        self.enter_block(true);
+        let base = self.fs_ref().freereg;
+        self.new_localvar("(for state)")?;
+        self.new_localvar("(for state)")?;
+        self.new_localvar("(for state)")?;
+        self.new_localvar(var)?;
+        self.exp1(start)?;
+        self.exp1(limit)?;
+        match step {
+            Some(step) => self.exp1(step)?,
+            None => {
+                let reg = self.fs_ref().freereg;
+                self.load_int(reg, 1);
+                self.reserve_regs(1)?;
+            }
+        }
+        self.adjust_localvars(3);
+        self.for_body(base, line, 1, false, body)?;
+        self.leave_block()
+    }
+
+    fn for_list(
+        &mut self,
+        names: &[String],
+        exprs: &[Expr],
+        body: &Block,
+        line: usize,
+    ) -> CResult<()> {
+        self.enter_block(true);
+        let base = self.fs_ref().freereg;
+        // generator, state, control and closing value
+        for _ in 0..4 {
+            self.new_localvar("(for state)")?;
+        }
+        for name in names {
+            self.new_localvar(name)?;
+        }
+        let mut e = self.explist(exprs)?;
+        self.adjust_assign(4, exprs.len(), &mut e)?;
+        self.adjust_localvars(4);
+        // the last control variable must be closed
+        self.mark_to_be_closed();
+        // extra space to call the generator
+        self.check_stack(3)?;
+        self.for_body(base, line, names.len(), true, body)?;
+        self.leave_block()
+    }
+
+    fn test_then_block(
+        &mut self,
+        cond: &Expr,
+        block: &Block,
+        more: bool,
+        escapelist: &mut isize,
+    ) -> CResult<()> {
+        let mut v = self.expr(cond)?;
+        let jf;
+        let mut stats = &block.stats[..];
+        if let Some(Stat::Break { line }) = stats.first() {
+            // `if x then break`, jump out when the condition is true
+            self.go_if_false(&mut v)?;
+            self.enter_block(false);
+            self.new_goto_entry("break", *line, v.t);
+            let skip = stats[1..]
+                .iter()
+                .take_while(|s| matches!(s, Stat::Empty))
+                .count();
+            stats = &stats[1 + skip..];
+            if stats.is_empty() && block.ret.is_none() {
+                // the jump is the entire block
+                return self.leave_block();
+            }
+            jf = self.jump();
+        } else {
+            self.go_if_true(&mut v)?;
+            self.enter_block(false);
+            jf = v.f;
+        }
+        self.stat_list(stats, &block.ret, false)?;
+        self.leave_block()?;
+        if more {
+            // must jump over the following parts
+            let j = self.jump();
+            self.concat_jumps(escapelist, j)?;
+        }
+        self.patch_to_here(jf)
+    }
+
+    fn if_stat(&mut self, branches: &[(Expr, Block)], else_block: &Option<Block>) -> CResult<()> {
+        // exit list of the finished parts
+        let mut escapelist = NO_JUMP;
+        for (i, (cond, block)) in branches.iter().enumerate() {
+            let more = i + 1 < branches.len() || else_block.is_some();
+            self.test_then_block(cond, block, more, &mut escapelist)?;
+        }
+        if let Some(block) = else_block {
+            self.block(block)?;
+        }
+        self.patch_to_here(escapelist)
+    }
+
+    fn goto_stat(&mut self, name: &str, line: usize) -> CResult<()> {
+        match self.find_label(name).map(|lb| (lb.nactvar, lb.pc)) {
+            // forward jump, solved when the label is declared
+            None => {
+                let pc = self.jump();
+                self.new_goto_entry(name, line, pc);
+            }
+            // backward jump, solved here
+            Some((nactvar, pc)) => {
+                let lblevel = self.reglevel(nactvar);
+                if self.nvarstack() > lblevel {
+                    // leaving the scope of a variable
+                    self.code_abc(OpCode::Close, lblevel, 0, 0);
+                }
+                let j = self.jump();
+                self.patch_list(j, pc as usize)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn label_stat(&mut self, name: &str, line: usize, last: bool) -> CResult<()> {
+        if let Some(lb) = self.find_label(name) {
+            return Err(self.error(&format!(
+                "label '{}' already defined on line {}",
+                name, lb.line
+            )));
+        }
+        self.create_label(name, line, last)?;
+        Ok(())
+    }
+
+    /* ------ expression statements ------ */
+
+    fn body(&mut self, body: &FuncBody) -> CResult<ExpDesc> {
+        self.open_func(body.line);
+        for param in body.params.iter() {
+            self.new_localvar(param)?;
+        }
+        self.adjust_localvars(body.params.len());
+        let nparams = self.fs_ref().nactvar;
+        self.fs().f.numparams = nparams as u8;
+        if body.is_vararg {
+            self.set_vararg(nparams);
+        }
+        self.reserve_regs(nparams)?;
+        self.stat_list(&body.block.stats, &body.block.ret, false)?;
+        self.fs().f.lastlinedefined = body.lastline;
+        self.line = body.lastline;
+        let f = self.close_func()?;
+        let fs = self.fs();
+        fs.f.p.push(Rc::new(f));
+        let np = fs.f.p.len();
+        let pc = self.code_abx(OpCode::Closure, 0, np - 1);
+        let mut e = ExpDesc::new(ExpKind::Reloc(pc));
+        // fix it at the last register
+        self.exp2nextreg(&mut e)?;
+        Ok(e)
+    }
+
+    /// brief: code an expression list, all values but the last go to the stack
+    fn explist(&mut self, exprs: &[Expr]) -> CResult<ExpDesc> {
+        let mut v = ExpDesc::new(ExpKind::Void);
+        for (i, e) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.exp2nextreg(&mut v)?;
+            }
+            v = self.expr(e)?;
+        }
+        Ok(v)
+    }
+
+    fn func_args(&mut self, f: &mut ExpDesc, args: &[Expr], line: usize) -> CResult<()> {
+        let base = f.info();
+        let mut a = self.explist(args)?;
+        if a.has_multret() {
+            self.set_multret(&mut a)?;
+        }
+        let nparams = if a.has_multret() {
+            // open call
+            LUA_MUL_RET
+        } else {
+            if a.k != ExpKind::Void {
+                // close the last argument
+                self.exp2nextreg(&mut a)?;
+            }
+            (self.fs_ref().freereg - (base + 1)) as isize
+        };
+        let pc = self.code_abc(OpCode::Call, base, (nparams + 1) as usize, 2);
+        f.k = ExpKind::Call(pc);
+        self.fix_line(line);
+        // the call removes the function and the arguments and leaves one result
+        self.fs().freereg = base + 1;
+        Ok(())
+    }
+
+    fn constructor(&mut self, fields: &[Field], line: usize) -> CResult<ExpDesc> {
+        self.line = line;
+        let pc = self.code_abc(OpCode::NewTable, 0, 0, 0);
+        // space for the extra argument
+        self.code(0);
+        let treg = self.fs_ref().freereg;
+        let t = ExpDesc::new(ExpKind::NonReloc(treg));
+        self.reserve_regs(1)?;
+        let mut cc = ConsControl {
+            v: ExpDesc::new(ExpKind::Void),
+            t: treg,
+            nh: 0,
+            na: 0,
+            tostore: 0,
+        };
+        for field in fields {
+            self.close_list_field(&mut cc)?;
+            match field {
+                Field::Positional(e) => {
+                    cc.v = self.expr(e)?;
+                    cc.tostore += 1;
+                }
+                Field::Named(name, val) => {
+                    let mut key = ExpDesc::new(ExpKind::KStr(name.as_bytes().to_vec()));
+                    self.rec_field(&t, &mut key, val, &mut cc)?;
+                }
+                Field::Keyed(key, val) => {
+                    let mut key = self.expr(key)?;
+                    self.exp2val(&mut key)?;
+                    self.rec_field(&t, &mut key, val, &mut cc)?;
+                }
+            }
+        }
+        self.last_list_field(&mut cc)?;
+        self.set_table_size(pc, treg, cc.na, cc.nh);
+        Ok(t)
+    }
+
+    fn rec_field(
+        &mut self,
+        t: &ExpDesc,
+        key: &mut ExpDesc,
+        val: &Expr,
+        cc: &mut ConsControl,
+    ) -> CResult<()> {
+        let reg = self.fs_ref().freereg;
+        cc.nh += 1;
+        let mut tab = t.clone();
+        self.indexed(&mut tab, key)?;
+        let mut val = self.expr(val)?;
+        self.store_var(&tab, &mut val)?;
+        // free registers
+        self.fs().freereg = reg;
+        Ok(())
+    }
+
+    fn close_list_field(&mut self, cc: &mut ConsControl) -> CResult<()> {
+        if cc.v.k == ExpKind::Void {
+            // there is no list item
+            return Ok(());
+        }
+        self.exp2nextreg(&mut cc.v)?;
+        cc.v = ExpDesc::new(ExpKind::Void);
+        if cc.tostore == LFIELDS_PER_FLUSH as usize {
+            self.set_list(cc.t, cc.na, cc.tostore as isize);
+            cc.na += cc.tostore;
+            cc.tostore = 0;
+        }
+        Ok(())
+    }
+
+    fn last_list_field(&mut self, cc: &mut ConsControl) -> CResult<()> {
+        if cc.tostore == 0 {
+            return Ok(());
+        }
+        if cc.v.has_multret() {
+            self.set_multret(&mut cc.v)?;
+            self.set_list(cc.t, cc.na, LUA_MUL_RET);
+            // the last item has an unknown number of elements
+            cc.na += cc.tostore - 1;
+        } else {
+            if cc.v.k != ExpKind::Void {
+                self.exp2nextreg(&mut cc.v)?;
+            }
+            self.set_list(cc.t, cc.na, cc.tostore as isize);
+            cc.na += cc.tostore;
+        }
+        Ok(())
+    }
+
+    /// brief: code an expression, leaving it undischarged as the parser of
+    /// the reference implementation would
+    fn expr(&mut self, expr: &Expr) -> CResult<ExpDesc> {
+        let e = match expr {
+            Expr::Nil => ExpDesc::new(ExpKind::Nil),
+            Expr::True => ExpDesc::new(ExpKind::True),
+            Expr::False => ExpDesc::new(ExpKind::False),
+            Expr::Int(i) => ExpDesc::new(ExpKind::KInt(*i)),
+            Expr::Flt(n) => ExpDesc::new(ExpKind::KFlt(*n)),
+            Expr::Str(s) => ExpDesc::new(ExpKind::KStr(s.clone())),
+            Expr::Vararg => {
+                if !self.fs_ref().f.is_vararg {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                ExpDesc::new(ExpKind::Vararg(self.code_abc(OpCode::VarArg, 0, 0, 1)))
+            }
+            Expr::Function(body) => self.body(body)?,
+            Expr::Table { fields, line } => self.constructor(fields, *line)?,
+            Expr::Name(name) => self.single_var(name)?,
+            Expr::Index { obj, key, line } => {
+                let mut v = self.expr(obj)?;
+                self.exp2anyregup(&mut v)?;
+                let mut k = self.expr(key)?;
+                self.exp2val(&mut k)?;
+                self.line = *line;
+                self.indexed(&mut v, &mut k)?;
+                v
+            }
+            Expr::Call { func, args, line } => {
+                self.line = *line;
+                let mut v = self.expr(func)?;
+                self.exp2nextreg(&mut v)?;
+                self.func_args(&mut v, args, *line)?;
+                v
+            }
+            Expr::Method {
+                obj,
+                name,
+                args,
+                line,
+            } => {
+                self.line = *line;
+                let mut v = self.expr(obj)?;
+                let mut key = ExpDesc::new(ExpKind::KStr(name.as_bytes().to_vec()));
+                self.self_(&mut v, &mut key)?;
+                self.func_args(&mut v, args, *line)?;
+                v
+            }
+            Expr::Paren(inner) => {
+                let mut v = self.expr(inner)?;
+                self.discharge_vars(&mut v);
+                v
+            }
+            Expr::BinOp { .. } => self.binop_chain(expr)?,
+            Expr::UnOp { op, expr, line } => {
+                let mut v = self.expr(expr)?;
+                self.line = *line;
+                self.prefix(*op, &mut v, *line)?;
+                v
+            }
+        };
+        Ok(e)
+    }
+
+    /// brief: left operands are walked without recursion,
+    /// long chains like `a + b + c + ...` would nest deeply otherwise
+    fn binop_chain(&mut self, expr: &Expr) -> CResult<ExpDesc> {
+        let mut spine = Vec::new();
+        let mut left = expr;
+        while let Expr::BinOp { op, lhs, rhs, line } = left {
+            spine.push((*op, rhs, *line));
+            left = lhs;
+        }
+        let mut v = self.expr(left)?;
+        for (op, rhs, line) in spine.into_iter().rev() {
+            self.infix(op, &mut v)?;
+            let mut v2 = self.expr(rhs)?;
+            self.line = line;
+            self.posfix(op, &mut v, &mut v2, line)?;
+        }
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::opcode::{get_ax, get_bx, get_c, get_sb, get_sbx, get_sc};
+
+    /// brief: an instruction in the form `luac -l` lists it
+    fn show(i: Instruction) -> String {
+        let op = get_op(i);
+        let name = format!("{:?}", op).to_uppercase().replace('_', "");
+        let (a, b, c, k) = (get_a(i), get_b(i), get_c(i), get_k(i) as u32);
+        let args = match op {
+            OpCode::LoadI | OpCode::LoadF => format!("{} {}", a, get_sbx(i)),
+            OpCode::LoadK | OpCode::Closure => format!("{} {}", a, get_bx(i)),
+            OpCode::ForLoop | OpCode::ForPrep | OpCode::TForPrep | OpCode::TForLoop => {
+                format!("{} {}", a, get_bx(i))
+            }
+            OpCode::Jmp => format!("{}", get_sj(i)),
+            OpCode::ExtraArg => format!("{}", get_ax(i)),
+            OpCode::AddI | OpCode::ShrI | OpCode::ShlI => format!("{} {} {}", a, b, get_sc(i)),
+            OpCode::EqI | OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => {
+                format!("{} {} {}", a, get_sb(i), k)
+            }
+            OpCode::MmBinI => format!("{} {} {} {}", a, get_sb(i), c, k),
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::EqK => format!("{} {} {}", a, b, k),
+            OpCode::Test => format!("{} {}", a, k),
+            OpCode::TestSet => format!("{} {} {}", a, b, k),
+            OpCode::LoadFalse | OpCode::LFalseSkip | OpCode::LoadTrue | OpCode::Return1 => {
+                format!("{}", a)
+            }
+            OpCode::VarArgPrep | OpCode::Close | OpCode::Tbc => format!("{}", a),
+            OpCode::Return0 => String::new(),
+            OpCode::Move | OpCode::LoadNil | OpCode::GetUpval | OpCode::SetUpval => {
+                format!("{} {}", a, b)
+            }
+            OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len | OpCode::Concat => {
+                format!("{} {}", a, b)
+            }
+            OpCode::VarArg | OpCode::TForCall => format!("{} {}", a, c),
+            _ => format!("{} {} {}{}", a, b, c, if k != 0 { "k" } else { "" }),
+        };
+        format!("{} {}", name, args).trim_end().to_string()
+    }
+
+    fn code(src: &str) -> Vec<String> {
+        let p = compile_source(src.as_bytes(), "=test").unwrap();
+        p.code.iter().map(|&i| show(i)).collect()
+    }
+
+    fn constants(src: &str) -> Vec<Constant> {
+        compile_source(src.as_bytes(), "=test").unwrap().k
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(
+            code("local x = 2 * 3 + 1"),
+            ["VARARGPREP 0", "LOADI 0 7", "RETURN 1 1 1"]
+        );
+        assert_eq!(code("local x = -(2 ^ 2)")[1], "LOADF 0 -4");
+        assert_eq!(code("local x = 3 / 1")[1], "LOADF 0 3");
+        assert_eq!(code("local x = 7 // 2 | 8 ~ 1")[1], "LOADI 0 11");
+        assert_eq!(code("local x = ~0 >> 1 << 1")[1], "LOADI 0 -2");
+        assert_eq!(code("local x = 2 ^ 0.5")[1], "LOADK 0 0");
+        assert_eq!(
+            constants("local x = 2 ^ 0.5"),
+            [Constant::Flt(2f64.sqrt() as FLT)]
+        );
+        // integers wrap around
+        let over = format!("local x = {} + 1", INT::MAX);
+        assert_eq!(constants(&over), [Constant::Int(INT::MIN)]);
+        assert_eq!(code("local x = (1 << 31) << 33")[1], "LOADI 0 0");
+    }
+
+    #[test]
+    fn operations_not_folded() {
+        // a division by zero is left for run time
+        assert_eq!(
+            code("local x = 7 // 0"),
+            [
+                "VARARGPREP 0",
+                "LOADI 0 7",
+                "IDIVK 0 0 0",
+                "MMBINK 0 0 12",
+                "RETURN 1 1 1"
+            ]
+        );
+        assert_eq!(code("local x = 1 % 0")[2], "MODK 0 0 0");
+        assert_eq!(code("local x = 0 / 0")[2], "DIVK 0 0 0");
+        // so are -0.0, which a constant could not tell from 0.0, and NaN
+        assert_eq!(
+            code("local x = 0.0 * -1"),
+            [
+                "VARARGPREP 0",
+                "LOADI 0 -1",
+                "MULK 0 0 0",
+                "MMBINK 0 0 8k",
+                "RETURN 1 1 1"
+            ]
+        );
+        // strings are not numerals, even when they convert
+        assert_eq!(code("local x = '1' + 1")[2], "ADDI 0 0 1");
+        assert_eq!(
+            code("local x = 'a' .. 'b'"),
+            [
+                "VARARGPREP 0",
+                "LOADK 0 0",
+                "LOADK 1 1",
+                "CONCAT 0 2",
+                "RETURN 1 1 1"
+            ]
+        );
+        // floats that are not integers keep their bitwise error for run time
+        assert_eq!(
+            code("local x = 1.5 | 0")[1..4],
+            ["LOADK 0 1", "BORK 0 0 0", "MMBINK 0 0 14"]
+        );
+    }
+
+    #[test]
+    fn jumps_of_conditions() {
+        assert_eq!(
+            code("local a, b if a then b = 1 else b = 2 end"),
+            [
+                "VARARGPREP 0",
+                "LOADNIL 0 1",
+                "TEST 0 0",
+                "JMP 2",
+                "LOADI 1 1",
+                "JMP 1",
+                "LOADI 1 2",
+                "RETURN 2 1 1"
+            ]
+        );
+        assert_eq!(
+            code("local a, b if a and b then a = 1 end")[2..6],
+            ["TEST 0 0", "JMP 3", "TEST 1 0", "JMP 1"]
+        );
+        assert_eq!(
+            code("local a, b if a or b then a = 1 end")[2..6],
+            ["TEST 0 1", "JMP 2", "TEST 1 0", "JMP 1"]
+        );
+        assert_eq!(
+            code("local a, b if not (a or b) then a = 1 end")[2..6],
+            ["TEST 0 1", "JMP 3", "TEST 1 1", "JMP 1"]
+        );
+    }
+
+    #[test]
+    fn jumps_of_values() {
+        // the TESTSET stores the value of b, the false list of a skips to c
+        assert_eq!(
+            code("local a, b, c local x = a and b or c")[2..7],
+            ["TEST 0 0", "JMP 2", "TESTSET 3 1 1", "JMP 1", "MOVE 3 2"]
+        );
+        assert_eq!(
+            code("local a, b local x = a == b")[2..6],
+            ["EQ 0 1 1", "JMP 1", "LFALSESKIP 2", "LOADTRUE 2"]
+        );
+        assert_eq!(
+            code("local a local x = a > 1")[2..6],
+            ["GTI 0 1 1", "JMP 1", "LFALSESKIP 1", "LOADTRUE 1"]
+        );
+        assert_eq!(code("local a local x = not a")[2], "NOT 1 0");
+    }
+
+    #[test]
+    fn jumps_of_loops() {
+        assert_eq!(
+            code("local a while a do a = nil end")[2..6],
+            ["TEST 0 0", "JMP 2", "LOADNIL 0 0", "JMP -4"]
+        );
+        assert_eq!(
+            code("local a repeat a = a + 1 until a > 10")[2..6],
+            ["ADDI 0 0 1", "MMBINI 0 1 6 0", "GTI 0 10 0", "JMP -4"]
+        );
+        assert_eq!(
+            code("for i = 1, 3 do end"),
+            [
+                "VARARGPREP 0",
+                "LOADI 0 1",
+                "LOADI 1 3",
+                "LOADI 2 1",
+                "FORPREP 0 0",
+                "FORLOOP 0 1",
+                "RETURN 0 1 1"
+            ]
+        );
+        // break and goto are patched once the end of the loop, or the label, is known
+        assert_eq!(
+            code("local a while true do if a then break end a = 1 end")[2..7],
+            ["TEST 0 1", "JMP 2", "LOADI 0 1", "JMP -4", "RETURN 1 1 1"]
+        );
+        // a jump to a jump goes to the final target at once
+        assert_eq!(
+            code("local a ::top:: a = 1 if a then goto top end goto out a = 2 ::out::")[2..9],
+            [
+                "LOADI 0 1",
+                "TEST 0 0",
+                "JMP 3",
+                "JMP -4",
+                "JMP 1",
+                "LOADI 0 2",
+                "RETURN 1 1 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_errors() {
+        let error = |src: &str| {
+            compile_source(src.as_bytes(), "=test")
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error("goto nowhere"),
+            "test:1: no visible label 'nowhere' for <goto> at line 1"
+        );
+        assert_eq!(error("break"), "test:1: break outside a loop at line 1");
+        assert_eq!(
+            error("::a:: ::a::"),
+            "test:1: label 'a' already defined on line 1"
+        );
+        assert_eq!(
+            error("goto f local x ::f:: print(x)"),
+            "test:1: <goto f> at line 1 jumps into the scope of local 'x'"
+        );
+    }
+}
@@ -0,0 +1,282 @@
+use std::mem::size_of;
+
+use crate::{
+    info::lua::LUAI_MAXSHORTLEN,
+    obj::objdef::{FLT, INT},
+};
+
+use super::{
+    opcode::Instruction,
+    proto::{Constant, Proto},
+    undump::{
+        LUAC_DATA, LUAC_FORMAT, LUAC_INT, LUAC_NUM, LUAC_VERSION, LUAC_VFALSE, LUAC_VLNGSTR,
+        LUAC_VNIL, LUAC_VNUMFLT, LUAC_VNUMINT, LUAC_VSHRSTR, LUAC_VTRUE, LUA_SIGNATURE,
+    },
+};
+
+struct DumpState {
+    buf: Vec<u8>,
+    strip: bool,
+}
+
+impl DumpState {
+    fn dump_byte(&mut self, b: u8) {
+        self.buf.push(b);
+    }
+
+    /// brief: an unsigned integer of 7-bit groups, most significant first,
+    /// the last group has its high bit set
+    fn dump_size(&mut self, mut x: usize) {
+        let mut groups = Vec::new();
+        loop {
+            groups.push((x & 0x7f) as u8);
+            x >>= 7;
+            if x == 0 {
+                break;
+            }
+        }
+        groups[0] |= 0x80;
+        self.buf.extend(groups.iter().rev());
+    }
+
+    fn dump_int(&mut self, x: usize) {
+        self.dump_size(x);
+    }
+
+    fn dump_integer(&mut self, x: INT) {
+        self.buf.extend_from_slice(&x.to_ne_bytes());
+    }
+
+    fn dump_number(&mut self, x: FLT) {
+        self.buf.extend_from_slice(&x.to_ne_bytes());
+    }
+
+    /// brief: a string, `None` for a missing one
+    fn dump_string(&mut self, s: Option<&[u8]>) {
+        match s {
+            None => self.dump_size(0),
+            Some(s) => {
+                // the size counts one more byte than the string has
+                self.dump_size(s.len() + 1);
+                self.buf.extend_from_slice(s);
+            }
+        }
+    }
+
+    fn dump_code(&mut self, f: &Proto) {
+        self.dump_int(f.code.len());
+        for i in f.code.iter() {
+            self.buf.extend_from_slice(&i.to_ne_bytes());
+        }
+    }
+
+    fn dump_constants(&mut self, f: &Proto) {
+        self.dump_int(f.k.len());
+        for k in f.k.iter() {
+            match k {
+                Constant::Nil => self.dump_byte(LUAC_VNIL),
+                Constant::Bool(false) => self.dump_byte(LUAC_VFALSE),
+                Constant::Bool(true) => self.dump_byte(LUAC_VTRUE),
+                Constant::Int(i) => {
+                    self.dump_byte(LUAC_VNUMINT);
+                    self.dump_integer(*i);
+                }
+                Constant::Flt(n) => {
+                    self.dump_byte(LUAC_VNUMFLT);
+                    self.dump_number(*n);
+                }
+                Constant::Str(s) => {
+                    if s.len() <= LUAI_MAXSHORTLEN {
+                        self.dump_byte(LUAC_VSHRSTR);
+                    } else {
+                        self.dump_byte(LUAC_VLNGSTR);
+                    }
+                    self.dump_string(Some(s));
+                }
+            }
+        }
+    }
+
+    fn dump_upvalues(&mut self, f: &Proto) {
+        self.dump_int(f.upvalues.len());
+        for uv in f.upvalues.iter() {
+            self.dump_byte(uv.instack as u8);
+            self.dump_byte(uv.idx);
+            self.dump_byte(uv.kind);
+        }
+    }
+
+    fn dump_protos(&mut self, f: &Proto) {
+        self.dump_int(f.p.len());
+        for p in f.p.iter() {
+            self.dump_function(p, f.source.as_deref());
+        }
+    }
+
+    fn dump_debug(&mut self, f: &Proto) {
+        if self.strip {
+            // no line information, local variables nor upvalue names
+            for _ in 0..4 {
+                self.dump_int(0);
+            }
+            return;
+        }
+        self.dump_int(f.lineinfo.len());
+        self.buf.extend(f.lineinfo.iter().map(|&l| l as u8));
+        self.dump_int(f.abslineinfo.len());
+        for abs in f.abslineinfo.iter() {
+            self.dump_int(abs.pc);
+            self.dump_int(abs.line);
+        }
+        self.dump_int(f.locvars.len());
+        for var in f.locvars.iter() {
+            self.dump_string(Some(var.name.as_bytes()));
+            self.dump_int(var.startpc);
+            self.dump_int(var.endpc);
+        }
+        self.dump_int(f.upvalues.len());
+        for uv in f.upvalues.iter() {
+            self.dump_string(uv.name.as_ref().map(|n| n.as_bytes()));
+        }
+    }
+
+    fn dump_function(&mut self, f: &Proto, psource: Option<&str>) {
+        // a function shares the source of its parent, the loader gives it back
+        if self.strip || f.source.as_deref() == psource {
+            self.dump_string(None);
+        } else {
+            self.dump_string(f.source.as_ref().map(|s| s.as_bytes()));
+        }
+        self.dump_int(f.linedefined);
+        self.dump_int(f.lastlinedefined);
+        self.dump_byte(f.numparams);
+        self.dump_byte(f.is_vararg as u8);
+        self.dump_byte(f.maxstacksize);
+        self.dump_code(f);
+        self.dump_constants(f);
+        self.dump_upvalues(f);
+        self.dump_protos(f);
+        self.dump_debug(f);
+    }
+
+    fn dump_header(&mut self) {
+        self.buf.extend_from_slice(LUA_SIGNATURE);
+        self.dump_byte(LUAC_VERSION);
+        self.dump_byte(LUAC_FORMAT);
+        self.buf.extend_from_slice(LUAC_DATA);
+        self.dump_byte(size_of::<Instruction>() as u8);
+        self.dump_byte(size_of::<INT>() as u8);
+        self.dump_byte(size_of::<FLT>() as u8);
+        self.dump_integer(LUAC_INT);
+        self.dump_number(LUAC_NUM);
+    }
+}
+
+/// brief: serialize a main function and the functions nested in it into a binary chunk,
+/// `strip` leaves out the debug information
+pub fn dump(f: &Proto, strip: bool) -> Vec<u8> {
+    let mut d = DumpState {
+        buf: Vec::new(),
+        strip,
+    };
+    d.dump_header();
+    d.dump_byte(f.upvalues.len() as u8);
+    d.dump_function(f, None);
+    d.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::compile::{codegen::compile_source, undump::undump};
+    use crate::info::lua::COMPILE_BAD_BINARY;
+
+    const SOURCE: &str = r#"
+local t <const> = 10
+local x <close> = nil
+local s, long = "short", "a string too long to be kept as a short one by the dumper"
+local n, f, neg = 1 << 40, 2.5, -7
+local function outer(a, ...)
+    local b = a + t + select('#', ...)
+    return function(c)
+        for i = 1, c do b = b + i * f end
+        return b, long, true, false, nil
+    end
+end
+goto done
+::done::
+return outer, s, n, neg
+"#;
+
+    fn compile(src: &str) -> Proto {
+        compile_source(src.as_bytes(), "@round.lua").unwrap()
+    }
+
+    /// brief: f without the debug information that `strip` leaves out
+    fn stripped(f: &Proto) -> Proto {
+        let mut s = f.clone();
+        s.source = None;
+        s.lineinfo.clear();
+        s.abslineinfo.clear();
+        s.locvars.clear();
+        for uv in s.upvalues.iter_mut() {
+            uv.name = None;
+        }
+        s.p = f.p.iter().map(|p| Rc::new(stripped(p))).collect();
+        s
+    }
+
+    #[test]
+    fn round_trip() {
+        let f = compile(SOURCE);
+        assert_eq!(f.p.len(), 1);
+        assert!(f.k.len() >= 3);
+        let chunk = dump(&f, false);
+        assert!(chunk.starts_with(LUA_SIGNATURE));
+        let g = undump(&chunk, "=round").unwrap();
+        assert_eq!(g, f);
+        // dumping it again gives the same chunk
+        assert_eq!(dump(&g, false), chunk);
+    }
+
+    #[test]
+    fn round_trip_stripped() {
+        let f = compile(SOURCE);
+        let chunk = dump(&f, true);
+        assert!(chunk.len() < dump(&f, false).len());
+        let g = undump(&chunk, "=round").unwrap();
+        assert_eq!(g, stripped(&f));
+        assert_eq!(g.getline(0), None);
+        assert_eq!(dump(&g, true), chunk);
+    }
+
+    #[test]
+    fn round_trip_constants() {
+        let f = compile("return 0, -2.5, 1e30, 0.1, 'a\\0b', ''");
+        let g = undump(&dump(&f, false), "=k").unwrap();
+        assert_eq!(g.k, f.k);
+        assert!(g.k.contains(&Constant::Flt(-2.5)));
+    }
+
+    #[test]
+    fn bad_chunks() {
+        let chunk = dump(&compile(SOURCE), false);
+        for len in [0, 4, chunk.len() / 2, chunk.len() - 1] {
+            let e = undump(&chunk[..len], "=cut").unwrap_err();
+            assert_eq!(e.code.0, COMPILE_BAD_BINARY);
+            assert_eq!(e.chunkname, "cut");
+        }
+        let e = undump(&chunk[..chunk.len() / 2], "=cut").unwrap_err();
+        assert_eq!(e.msg, "bad binary format (truncated chunk)");
+        let mut bad = chunk.clone();
+        bad[4] = 0x53;
+        let e = undump(&bad, "=v").unwrap_err();
+        assert_eq!(e.msg, "bad binary format (version mismatch)");
+        let mut bad = chunk.clone();
+        bad[5] = 1;
+        let e = undump(&bad, "=v").unwrap_err();
+        assert_eq!(e.msg, "bad binary format (format mismatch)");
+    }
+}
@@ -0,0 +1,5 @@
+pub mod codegen;
+pub mod dump;
+pub mod opcode;
+pub mod proto;
+pub mod undump;
@@ -0,0 +1,419 @@
+use crate::obj::objdef::INT;
+
+/// brief: a 32-bit instruction in the Lua 5.4 layout
+///
+/// ```text
+/// iABC   C(8) | B(8) |k|  A(8)  | Op(7)
+/// iABx       Bx(17)    |  A(8)  | Op(7)
+/// iAsBx     sBx(17)    |  A(8)  | Op(7)
+/// iAx            Ax(25)         | Op(7)
+/// isJ            sJ(25)         | Op(7)
+/// ```
+pub type Instruction = u32;
+
+pub const SIZE_OP: u32 = 7;
+pub const SIZE_A: u32 = 8;
+pub const SIZE_B: u32 = 8;
+pub const SIZE_C: u32 = 8;
+pub const SIZE_BX: u32 = SIZE_C + SIZE_B + 1;
+pub const SIZE_AX: u32 = SIZE_BX + SIZE_A;
+pub const SIZE_SJ: u32 = SIZE_BX + SIZE_A;
+
+pub const POS_OP: u32 = 0;
+pub const POS_A: u32 = POS_OP + SIZE_OP;
+pub const POS_K: u32 = POS_A + SIZE_A;
+pub const POS_B: u32 = POS_K + 1;
+pub const POS_C: u32 = POS_B + SIZE_B;
+pub const POS_BX: u32 = POS_K;
+pub const POS_AX: u32 = POS_A;
+pub const POS_SJ: u32 = POS_A;
+
+pub const MAXARG_A: u32 = (1 << SIZE_A) - 1;
+pub const MAXARG_B: u32 = (1 << SIZE_B) - 1;
+pub const MAXARG_C: u32 = (1 << SIZE_C) - 1;
+pub const MAXARG_BX: u32 = (1 << SIZE_BX) - 1;
+pub const MAXARG_AX: u32 = (1 << SIZE_AX) - 1;
+pub const MAXARG_SJ: u32 = (1 << SIZE_SJ) - 1;
+
+pub const OFFSET_SBX: i32 = (MAXARG_BX >> 1) as i32;
+pub const OFFSET_SJ: i32 = (MAXARG_SJ >> 1) as i32;
+pub const OFFSET_SC: i32 = (MAXARG_C >> 1) as i32;
+
+/// invalid register that fits in 8 bits
+pub const NO_REG: u32 = MAXARG_A;
+
+/// number of list items to accumulate before a SETLIST instruction
+pub const LFIELDS_PER_FLUSH: u32 = 50;
+
+/// brief: the opcodes of Lua 5.4, in the order of the reference implementation
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpCode {
+    Move,       // A B      R[A] := R[B]
+    LoadI,      // A sBx    R[A] := sBx
+    LoadF,      // A sBx    R[A] := (float)sBx
+    LoadK,      // A Bx     R[A] := K[Bx]
+    LoadKX,     // A        R[A] := K[extra arg]
+    LoadFalse,  // A        R[A] := false
+    LFalseSkip, // A        R[A] := false; pc++
+    LoadTrue,   // A        R[A] := true
+    LoadNil,    // A B      R[A], R[A+1], ..., R[A+B] := nil
+    GetUpval,   // A B      R[A] := UpValue[B]
+    SetUpval,   // A B      UpValue[B] := R[A]
+    GetTabUp,   // A B C    R[A] := UpValue[B][K[C]:shortstring]
+    GetTable,   // A B C    R[A] := R[B][R[C]]
+    GetI,       // A B C    R[A] := R[B][C]
+    GetField,   // A B C    R[A] := R[B][K[C]:shortstring]
+    SetTabUp,   // A B C    UpValue[A][K[B]:shortstring] := RK(C)
+    SetTable,   // A B C    R[A][R[B]] := RK(C)
+    SetI,       // A B C    R[A][B] := RK(C)
+    SetField,   // A B C    R[A][K[B]:shortstring] := RK(C)
+    NewTable,   // A B C k  R[A] := {}
+    Self_,      // A B C    R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+    AddI,       // A B sC   R[A] := R[B] + sC
+    AddK,       // A B C    R[A] := R[B] + K[C]:number
+    SubK,       // A B C    R[A] := R[B] - K[C]:number
+    MulK,       // A B C    R[A] := R[B] * K[C]:number
+    ModK,       // A B C    R[A] := R[B] % K[C]:number
+    PowK,       // A B C    R[A] := R[B] ^ K[C]:number
+    DivK,       // A B C    R[A] := R[B] / K[C]:number
+    IDivK,      // A B C    R[A] := R[B] // K[C]:number
+    BAndK,      // A B C    R[A] := R[B] & K[C]:integer
+    BOrK,       // A B C    R[A] := R[B] | K[C]:integer
+    BXorK,      // A B C    R[A] := R[B] ~ K[C]:integer
+    ShrI,       // A B sC   R[A] := R[B] >> sC
+    ShlI,       // A B sC   R[A] := sC << R[B]
+    Add,        // A B C    R[A] := R[B] + R[C]
+    Sub,        // A B C    R[A] := R[B] - R[C]
+    Mul,        // A B C    R[A] := R[B] * R[C]
+    Mod,        // A B C    R[A] := R[B] % R[C]
+    Pow,        // A B C    R[A] := R[B] ^ R[C]
+    Div,        // A B C    R[A] := R[B] / R[C]
+    IDiv,       // A B C    R[A] := R[B] // R[C]
+    BAnd,       // A B C    R[A] := R[B] & R[C]
+    BOr,        // A B C    R[A] := R[B] | R[C]
+    BXor,       // A B C    R[A] := R[B] ~ R[C]
+    Shl,        // A B C    R[A] := R[B] << R[C]
+    Shr,        // A B C    R[A] := R[B] >> R[C]
+    MmBin,      // A B C    call C metamethod over R[A] and R[B]
+    MmBinI,     // A sB C k call C metamethod over R[A] and sB
+    MmBinK,     // A B C k  call C metamethod over R[A] and K[B]
+    Unm,        // A B      R[A] := -R[B]
+    BNot,       // A B      R[A] := ~R[B]
+    Not,        // A B      R[A] := not R[B]
+    Len,        // A B      R[A] := #R[B] (length operator)
+    Concat,     // A B      R[A] := R[A].. ... ..R[A + B - 1]
+    Close,      // A        close all upvalues >= R[A]
+    Tbc,        // A        mark variable A "to be closed"
+    Jmp,        // sJ       pc += sJ
+    Eq,         // A B k    if ((R[A] == R[B]) ~= k) then pc++
+    Lt,         // A B k    if ((R[A] <  R[B]) ~= k) then pc++
+    Le,         // A B k    if ((R[A] <= R[B]) ~= k) then pc++
+    EqK,        // A B k    if ((R[A] == K[B]) ~= k) then pc++
+    EqI,        // A sB k   if ((R[A] == sB) ~= k) then pc++
+    LtI,        // A sB k   if ((R[A] < sB) ~= k) then pc++
+    LeI,        // A sB k   if ((R[A] <= sB) ~= k) then pc++
+    GtI,        // A sB k   if ((R[A] > sB) ~= k) then pc++
+    GeI,        // A sB k   if ((R[A] >= sB) ~= k) then pc++
+    Test,       // A k      if (not R[A] == k) then pc++
+    TestSet,    // A B k    if (not R[B] == k) then pc++ else R[A] := R[B]
+    Call,       // A B C    R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    TailCall,   // A B C k  return R[A](R[A+1], ... ,R[A+B-1])
+    Return,     // A B C k  return R[A], ... ,R[A+B-2]
+    Return0,    //          return
+    Return1,    // A        return R[A]
+    ForLoop,    // A Bx     update counters; if loop continues then pc-=Bx;
+    ForPrep,    // A Bx     <check values and prepare counters>; if not to run then pc+=Bx+1;
+    TForPrep,   // A Bx     create upvalue for R[A + 3]; pc+=Bx
+    TForCall,   // A C      R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+    TForLoop,   // A Bx     if R[A+2] ~= nil then { R[A]=R[A+2]; pc -= Bx }
+    SetList,    // A B C k  R[A][C+i] := R[A+i], 1 <= i <= B
+    Closure,    // A Bx     R[A] := closure(KPROTO[Bx])
+    VarArg,     // A C      R[A], R[A+1], ..., R[A+C-2] = vararg
+    VarArgPrep, // A        (adjust vararg parameters)
+    ExtraArg,   // Ax       extra (larger) argument for previous opcode
+}
+
+pub const NUM_OPCODES: usize = OpCode::ExtraArg as usize + 1;
+
+const OPCODES: [OpCode; NUM_OPCODES] = [
+    OpCode::Move,
+    OpCode::LoadI,
+    OpCode::LoadF,
+    OpCode::LoadK,
+    OpCode::LoadKX,
+    OpCode::LoadFalse,
+    OpCode::LFalseSkip,
+    OpCode::LoadTrue,
+    OpCode::LoadNil,
+    OpCode::GetUpval,
+    OpCode::SetUpval,
+    OpCode::GetTabUp,
+    OpCode::GetTable,
+    OpCode::GetI,
+    OpCode::GetField,
+    OpCode::SetTabUp,
+    OpCode::SetTable,
+    OpCode::SetI,
+    OpCode::SetField,
+    OpCode::NewTable,
+    OpCode::Self_,
+    OpCode::AddI,
+    OpCode::AddK,
+    OpCode::SubK,
+    OpCode::MulK,
+    OpCode::ModK,
+    OpCode::PowK,
+    OpCode::DivK,
+    OpCode::IDivK,
+    OpCode::BAndK,
+    OpCode::BOrK,
+    OpCode::BXorK,
+    OpCode::ShrI,
+    OpCode::ShlI,
+    OpCode::Add,
+    OpCode::Sub,
+    OpCode::Mul,
+    OpCode::Mod,
+    OpCode::Pow,
+    OpCode::Div,
+    OpCode::IDiv,
+    OpCode::BAnd,
+    OpCode::BOr,
+    OpCode::BXor,
+    OpCode::Shl,
+    OpCode::Shr,
+    OpCode::MmBin,
+    OpCode::MmBinI,
+    OpCode::MmBinK,
+    OpCode::Unm,
+    OpCode::BNot,
+    OpCode::Not,
+    OpCode::Len,
+    OpCode::Concat,
+    OpCode::Close,
+    OpCode::Tbc,
+    OpCode::Jmp,
+    OpCode::Eq,
+    OpCode::Lt,
+    OpCode::Le,
+    OpCode::EqK,
+    OpCode::EqI,
+    OpCode::LtI,
+    OpCode::LeI,
+    OpCode::GtI,
+    OpCode::GeI,
+    OpCode::Test,
+    OpCode::TestSet,
+    OpCode::Call,
+    OpCode::TailCall,
+    OpCode::Return,
+    OpCode::Return0,
+    OpCode::Return1,
+    OpCode::ForLoop,
+    OpCode::ForPrep,
+    OpCode::TForPrep,
+    OpCode::TForCall,
+    OpCode::TForLoop,
+    OpCode::SetList,
+    OpCode::Closure,
+    OpCode::VarArg,
+    OpCode::VarArgPrep,
+    OpCode::ExtraArg,
+];
+
+impl OpCode {
+    #[inline(always)]
+    pub fn from_u8(op: u8) -> Option<OpCode> {
+        OPCODES.get(op as usize).copied()
+    }
+
+    /// brief: is this a test, always followed by a jump?
+    pub fn is_test(self) -> bool {
+        matches!(
+            self,
+            OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+                | OpCode::EqK
+                | OpCode::EqI
+                | OpCode::LtI
+                | OpCode::LeI
+                | OpCode::GtI
+                | OpCode::GeI
+                | OpCode::Test
+                | OpCode::TestSet
+        )
+    }
+
+    /// brief: does this instruction set its register A?
+    pub fn sets_a(self) -> bool {
+        if self.is_test() {
+            return self == OpCode::TestSet;
+        }
+        !matches!(
+            self,
+            OpCode::SetUpval
+                | OpCode::SetTabUp
+                | OpCode::SetTable
+                | OpCode::SetI
+                | OpCode::SetField
+                | OpCode::MmBin
+                | OpCode::MmBinI
+                | OpCode::MmBinK
+                | OpCode::Close
+                | OpCode::Tbc
+                | OpCode::Jmp
+                | OpCode::Return
+                | OpCode::Return0
+                | OpCode::Return1
+                | OpCode::TForPrep
+                | OpCode::TForCall
+                | OpCode::SetList
+                | OpCode::ExtraArg
+        )
+    }
+}
+
+#[inline(always)]
+fn mask1(n: u32, p: u32) -> u32 {
+    (!((!0u32) << n)) << p
+}
+
+#[inline(always)]
+fn getarg(i: Instruction, pos: u32, size: u32) -> u32 {
+    (i >> pos) & mask1(size, 0)
+}
+
+#[inline(always)]
+fn setarg(i: &mut Instruction, v: u32, pos: u32, size: u32) {
+    *i = (*i & !mask1(size, pos)) | ((v << pos) & mask1(size, pos));
+}
+
+/// brief: the opcode of an instruction; undumped code is checked beforehand
+#[inline(always)]
+pub fn get_op(i: Instruction) -> OpCode {
+    OPCODES[getarg(i, POS_OP, SIZE_OP) as usize % NUM_OPCODES]
+}
+
+#[inline(always)]
+pub fn set_op(i: &mut Instruction, op: OpCode) {
+    setarg(i, op as u32, POS_OP, SIZE_OP);
+}
+
+#[inline(always)]
+pub fn get_a(i: Instruction) -> u32 {
+    getarg(i, POS_A, SIZE_A)
+}
+
+#[inline(always)]
+pub fn set_a(i: &mut Instruction, v: u32) {
+    setarg(i, v, POS_A, SIZE_A);
+}
+
+#[inline(always)]
+pub fn get_b(i: Instruction) -> u32 {
+    getarg(i, POS_B, SIZE_B)
+}
+
+#[inline(always)]
+pub fn get_sb(i: Instruction) -> i32 {
+    get_b(i) as i32 - OFFSET_SC
+}
+
+#[inline(always)]
+pub fn set_b(i: &mut Instruction, v: u32) {
+    setarg(i, v, POS_B, SIZE_B);
+}
+
+#[inline(always)]
+pub fn get_c(i: Instruction) -> u32 {
+    getarg(i, POS_C, SIZE_C)
+}
+
+#[inline(always)]
+pub fn get_sc(i: Instruction) -> i32 {
+    get_c(i) as i32 - OFFSET_SC
+}
+
+#[inline(always)]
+pub fn set_c(i: &mut Instruction, v: u32) {
+    setarg(i, v, POS_C, SIZE_C);
+}
+
+#[inline(always)]
+pub fn get_k(i: Instruction) -> bool {
+    getarg(i, POS_K, 1) != 0
+}
+
+#[inline(always)]
+pub fn set_k(i: &mut Instruction, v: bool) {
+    setarg(i, v as u32, POS_K, 1);
+}
+
+#[inline(always)]
+pub fn get_bx(i: Instruction) -> u32 {
+    getarg(i, POS_BX, SIZE_BX)
+}
+
+#[inline(always)]
+pub fn set_bx(i: &mut Instruction, v: u32) {
+    setarg(i, v, POS_BX, SIZE_BX);
+}
+
+#[inline(always)]
+pub fn get_sbx(i: Instruction) -> i32 {
+    get_bx(i) as i32 - OFFSET_SBX
+}
+
+#[inline(always)]
+pub fn get_ax(i: Instruction) -> u32 {
+    getarg(i, POS_AX, SIZE_AX)
+}
+
+#[inline(always)]
+pub fn get_sj(i: Instruction) -> i32 {
+    getarg(i, POS_SJ, SIZE_SJ) as i32 - OFFSET_SJ
+}
+
+#[inline(always)]
+pub fn set_sj(i: &mut Instruction, v: i32) {
+    setarg(i, (v + OFFSET_SJ) as u32, POS_SJ, SIZE_SJ);
+}
+
+#[inline(always)]
+pub fn create_abck(op: OpCode, a: u32, b: u32, c: u32, k: bool) -> Instruction {
+    ((op as u32) << POS_OP) | (a << POS_A) | (b << POS_B) | (c << POS_C) | ((k as u32) << POS_K)
+}
+
+#[inline(always)]
+pub fn create_abx(op: OpCode, a: u32, bx: u32) -> Instruction {
+    ((op as u32) << POS_OP) | (a << POS_A) | (bx << POS_BX)
+}
+
+#[inline(always)]
+pub fn create_ax(op: OpCode, ax: u32) -> Instruction {
+    ((op as u32) << POS_OP) | (ax << POS_AX)
+}
+
+#[inline(always)]
+pub fn create_sj(op: OpCode, j: u32, k: bool) -> Instruction {
+    ((op as u32) << POS_OP) | (j << POS_SJ) | ((k as u32) << POS_K)
+}
+
+/// brief: does the integer fit as a signed sBx argument?
+#[inline(always)]
+pub fn fits_sbx(i: INT) -> bool {
+    -(OFFSET_SBX as INT) <= i && i <= (MAXARG_BX as INT - OFFSET_SBX as INT)
+}
+
+/// brief: does the integer fit as a signed sC (or sB) argument?
+#[inline(always)]
+pub fn fits_sc(i: INT) -> bool {
+    -(OFFSET_SC as INT) <= i && i <= (MAXARG_C as INT - OFFSET_SC as INT)
+}
+
+#[inline(always)]
+pub fn int2sc(i: INT) -> u32 {
+    (i + OFFSET_SC as INT) as u32
+}
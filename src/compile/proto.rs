@@ -0,0 +1,109 @@
+use std::rc::Rc;
+
+use crate::obj::objdef::{FLT, INT};
+
+use super::opcode::Instruction;
+
+/// brief: a value of the constant table of a function
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constant {
+    Nil,
+    Bool(bool),
+    Int(INT),
+    Flt(FLT),
+    Str(Vec<u8>),
+}
+
+// kinds of variables, shared by the compiler and the upvalue descriptors
+pub const VDKREG: u8 = 0; // regular
+pub const RDKCONST: u8 = 1; // constant
+pub const RDKTOCLOSE: u8 = 2; // to-be-closed
+pub const RDKCTC: u8 = 3; // compile-time constant
+
+/// brief: where a closure finds an upvalue when it is created,
+/// a register of the enclosing function or one of its upvalues
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpvalDesc {
+    pub name: Option<String>,
+    pub instack: bool,
+    pub idx: u8,
+    pub kind: u8,
+}
+
+/// brief: debug information of a local variable, alive in `[startpc, endpc)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocVar {
+    pub name: String,
+    pub startpc: usize,
+    pub endpc: usize,
+}
+
+/// brief: an absolute line for the instruction at `pc`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbsLineInfo {
+    pub pc: usize,
+    pub line: usize,
+}
+
+/// marks a `lineinfo` entry whose line lives in `abslineinfo`
+pub const ABSLINEINFO: i8 = -0x80;
+
+/// brief: a compiled function
+/// `lineinfo` keeps, per instruction, the line difference to the previous one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Proto {
+    pub source: Option<String>,
+    pub linedefined: usize,
+    pub lastlinedefined: usize,
+    pub numparams: u8,
+    pub is_vararg: bool,
+    pub maxstacksize: u8,
+    pub code: Vec<Instruction>,
+    pub k: Vec<Constant>,
+    pub p: Vec<Rc<Proto>>,
+    pub upvalues: Vec<UpvalDesc>,
+    pub lineinfo: Vec<i8>,
+    pub abslineinfo: Vec<AbsLineInfo>,
+    pub locvars: Vec<LocVar>,
+}
+
+impl Proto {
+    /// brief: the source line of the instruction at `pc`, if it is known
+    pub fn getline(&self, pc: usize) -> Option<usize> {
+        if self.lineinfo.len() <= pc {
+            return None;
+        }
+        // start from the closest absolute line before pc
+        let (mut basepc, mut line) = match self.abslineinfo.iter().rev().find(|a| a.pc <= pc) {
+            Some(abs) => (abs.pc as isize, abs.line as isize),
+            None => (-1, self.linedefined as isize),
+        };
+        while basepc < pc as isize {
+            basepc += 1;
+            let diff = self.lineinfo[basepc as usize];
+            if diff != ABSLINEINFO {
+                line += diff as isize;
+            }
+        }
+        Some(line as usize)
+    }
+
+    /// brief: the name of the n-th (from 1) local variable active at `pc`
+    pub fn get_local_name(&self, mut n: usize, pc: usize) -> Option<&str> {
+        if n == 0 {
+            return None;
+        }
+        for var in self.locvars.iter() {
+            if var.startpc > pc {
+                break;
+            }
+            if pc < var.endpc {
+                n -= 1;
+                if n == 0 {
+                    return Some(&var.name);
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,643 @@
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::{
+    info::lua::{ErrCode, COMPILE_BAD_BINARY, LUAI_MAXCCALLS},
+    lex::lexer::SyntaxError,
+    obj::objdef::{FLT, INT},
+};
+
+use super::{
+    opcode::{
+        get_a, get_ax, get_b, get_bx, get_c, get_k, get_sj, Instruction, OpCode, POS_OP, SIZE_OP,
+    },
+    proto::{AbsLineInfo, Constant, LocVar, Proto, UpvalDesc},
+};
+
+// header of a binary chunk, the layout of the reference `luac 5.4`
+pub const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+pub const LUAC_VERSION: u8 = 0x54;
+pub const LUAC_FORMAT: u8 = 0; // the official format
+pub const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1a\n"; // catches text-mode conversions
+pub const LUAC_INT: INT = 0x5678; // catches integer endianness
+pub const LUAC_NUM: FLT = 370.5; // catches float format
+
+// tags of the constants, the variant tags of the reference
+pub const LUAC_VNIL: u8 = 0x00;
+pub const LUAC_VFALSE: u8 = 0x01;
+pub const LUAC_VTRUE: u8 = 0x11;
+pub const LUAC_VNUMINT: u8 = 0x03;
+pub const LUAC_VNUMFLT: u8 = 0x13;
+pub const LUAC_VSHRSTR: u8 = 0x04;
+pub const LUAC_VLNGSTR: u8 = 0x14;
+
+type UResult<T> = Result<T, SyntaxError>;
+
+struct LoadState<'a> {
+    chunk: &'a [u8],
+    pos: usize,
+    name: String,
+    depth: usize,    // nesting of the function being loaded
+    int_size: usize, // bytes of an integer in the chunk, 4 or 8
+    num_size: usize, // bytes of a float in the chunk, 4 or 8
+}
+
+/// brief: the first N bytes of a block, of at least N bytes
+fn ne_bytes<const N: usize>(block: &[u8]) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(&block[..N]);
+    bytes
+}
+
+impl<'a> LoadState<'a> {
+    fn error(&self, why: &str) -> SyntaxError {
+        SyntaxError {
+            code: ErrCode(COMPILE_BAD_BINARY),
+            chunkname: self.name.clone(),
+            line: 0,
+            msg: format!("bad binary format ({})", why),
+        }
+    }
+
+    fn load_block(&mut self, n: usize) -> UResult<&'a [u8]> {
+        if self.chunk.len() - self.pos < n {
+            return Err(self.error("truncated chunk"));
+        }
+        let block = &self.chunk[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(block)
+    }
+
+    fn load_byte(&mut self) -> UResult<u8> {
+        Ok(self.load_block(1)?[0])
+    }
+
+    /// brief: an unsigned integer of 7-bit groups, most significant first,
+    /// the last group has its high bit set
+    fn load_unsigned(&mut self, limit: usize) -> UResult<usize> {
+        let limit = limit >> 7;
+        let mut x: usize = 0;
+        loop {
+            let b = self.load_byte()?;
+            if x >= limit {
+                return Err(self.error("integer overflow"));
+            }
+            x = (x << 7) | (b & 0x7f) as usize;
+            if b & 0x80 != 0 {
+                return Ok(x);
+            }
+        }
+    }
+
+    fn load_size(&mut self) -> UResult<usize> {
+        self.load_unsigned(usize::MAX)
+    }
+
+    fn load_int(&mut self) -> UResult<usize> {
+        self.load_unsigned(i32::MAX as usize)
+    }
+
+    /// brief: an integer of the width of the chunk, one that does not fit `INT` is an error
+    fn load_integer(&mut self) -> UResult<INT> {
+        let block = self.load_block(self.int_size)?;
+        let x = if self.int_size == 4 {
+            i32::from_ne_bytes(ne_bytes(block)) as i64
+        } else {
+            i64::from_ne_bytes(ne_bytes(block))
+        };
+        let i = x as INT;
+        if i as i64 != x {
+            return Err(self.error("integer overflow"));
+        }
+        Ok(i)
+    }
+
+    /// brief: a float of the width of the chunk, rounded to `FLT`
+    fn load_number(&mut self) -> UResult<FLT> {
+        let block = self.load_block(self.num_size)?;
+        if self.num_size == 4 {
+            return Ok(f32::from_ne_bytes(ne_bytes(block)) as FLT);
+        }
+        Ok(f64::from_ne_bytes(ne_bytes(block)) as FLT)
+    }
+
+    fn load_bool(&mut self) -> UResult<bool> {
+        match self.load_byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.error("bad boolean")),
+        }
+    }
+
+    /// brief: a string, `None` for the empty slot of a missing one
+    fn load_string_n(&mut self) -> UResult<Option<Vec<u8>>> {
+        // the size counts one more byte than the string has
+        let size = self.load_size()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.load_block(size - 1)?.to_vec()))
+    }
+
+    /// brief: a string used as a name, kept as text
+    fn load_name(&mut self) -> UResult<Option<String>> {
+        Ok(self
+            .load_string_n()?
+            .map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    fn load_code(&mut self, f: &mut Proto) -> UResult<()> {
+        let n = self.load_int()?;
+        let block = self.load_block(n.saturating_mul(size_of::<Instruction>()))?;
+        f.code = block
+            .chunks_exact(size_of::<Instruction>())
+            .map(|c| Instruction::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+        Ok(())
+    }
+
+    fn load_constants(&mut self, f: &mut Proto) -> UResult<()> {
+        let n = self.load_int()?;
+        for _ in 0..n {
+            let k = match self.load_byte()? {
+                LUAC_VNIL => Constant::Nil,
+                LUAC_VFALSE => Constant::Bool(false),
+                LUAC_VTRUE => Constant::Bool(true),
+                LUAC_VNUMINT => Constant::Int(self.load_integer()?),
+                LUAC_VNUMFLT => Constant::Flt(self.load_number()?),
+                LUAC_VSHRSTR | LUAC_VLNGSTR => match self.load_string_n()? {
+                    Some(s) => Constant::Str(s),
+                    None => return Err(self.error("bad format for constant string")),
+                },
+                _ => return Err(self.error("bad constant tag")),
+            };
+            f.k.push(k);
+        }
+        Ok(())
+    }
+
+    fn load_upvalues(&mut self, f: &mut Proto) -> UResult<()> {
+        let n = self.load_int()?;
+        for _ in 0..n {
+            let instack = self.load_bool()?;
+            let idx = self.load_byte()?;
+            let kind = self.load_byte()?;
+            f.upvalues.push(UpvalDesc {
+                name: None,
+                instack,
+                idx,
+                kind,
+            });
+        }
+        Ok(())
+    }
+
+    fn load_protos(&mut self, f: &mut Proto) -> UResult<()> {
+        let n = self.load_int()?;
+        if n > 0 && self.depth >= LUAI_MAXCCALLS {
+            return Err(self.error("functions nested too deep"));
+        }
+        self.depth += 1;
+        for _ in 0..n {
+            let p = self.load_function(f.source.clone())?;
+            f.p.push(Rc::new(p));
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn load_debug(&mut self, f: &mut Proto) -> UResult<()> {
+        let n = self.load_int()?;
+        f.lineinfo = self.load_block(n)?.iter().map(|&b| b as i8).collect();
+        let n = self.load_int()?;
+        for _ in 0..n {
+            let pc = self.load_int()?;
+            let line = self.load_int()?;
+            f.abslineinfo.push(AbsLineInfo { pc, line });
+        }
+        let n = self.load_int()?;
+        for _ in 0..n {
+            let name = self.load_name()?.unwrap_or_default();
+            let startpc = self.load_int()?;
+            let endpc = self.load_int()?;
+            f.locvars.push(LocVar {
+                name,
+                startpc,
+                endpc,
+            });
+        }
+        // upvalue names may be stripped, or only some of them kept
+        let n = self.load_int()?;
+        if n > f.upvalues.len() {
+            return Err(self.error("bad upvalue names"));
+        }
+        for i in 0..n {
+            f.upvalues[i].name = self.load_name()?;
+        }
+        Ok(())
+    }
+
+    fn load_function(&mut self, psource: Option<String>) -> UResult<Proto> {
+        let mut f = Proto {
+            // a stripped function has no source, it shares the one of its parent
+            source: self.load_name()?.or(psource),
+            linedefined: self.load_int()?,
+            lastlinedefined: self.load_int()?,
+            numparams: self.load_byte()?,
+            is_vararg: self.load_bool()?,
+            maxstacksize: self.load_byte()?,
+            ..Default::default()
+        };
+        self.load_code(&mut f)?;
+        self.load_constants(&mut f)?;
+        self.load_upvalues(&mut f)?;
+        self.load_protos(&mut f)?;
+        self.load_debug(&mut f)?;
+        if !f.lineinfo.is_empty() && f.lineinfo.len() != f.code.len() {
+            return Err(self.error("bad line information"));
+        }
+        self.check_code(&f)?;
+        Ok(f)
+    }
+
+    /// brief: check that the code of a loaded function only refers to what it has:
+    /// registers of its frame, its constants, upvalues and nested functions,
+    /// and that its jumps and the instructions that read the next one stay in the code
+    fn check_code(&self, f: &Proto) -> UResult<()> {
+        let size = f.maxstacksize as usize;
+        if f.numparams as usize > size {
+            return Err(self.error("bad number of parameters"));
+        }
+        let ncode = f.code.len();
+        // get_op wraps a bad opcode around, this gives None for it
+        let opcode =
+            |pc: usize| OpCode::from_u8((f.code[pc] >> POS_OP & ((1 << SIZE_OP) - 1)) as u8);
+        match ncode.checked_sub(1).map(opcode) {
+            None => return Err(self.error("empty code")),
+            Some(Some(OpCode::Return | OpCode::Return0 | OpCode::Return1)) => {}
+            Some(_) => return Err(self.error("missing final return")),
+        }
+        let reg = |r: u32| -> UResult<()> {
+            if r as usize >= size {
+                return Err(self.error("register out of range"));
+            }
+            Ok(())
+        };
+        let kst = |k: u32| -> UResult<()> {
+            if k as usize >= f.k.len() {
+                return Err(self.error("constant index out of range"));
+            }
+            Ok(())
+        };
+        let rk = |c: u32, k: bool| if k { kst(c) } else { reg(c) };
+        let upval = |u: u32| -> UResult<()> {
+            if u as usize >= f.upvalues.len() {
+                return Err(self.error("upvalue index out of range"));
+            }
+            Ok(())
+        };
+        // the instruction executed next, at pc + 1 + offset
+        let target = |pc: usize, offset: isize| -> UResult<()> {
+            match (pc as isize + 1).checked_add(offset) {
+                Some(t) if t >= 0 && (t as usize) < ncode => Ok(()),
+                _ => Err(self.error("jump out of range")),
+            }
+        };
+        let next_is = |pc: usize, op: OpCode| -> UResult<()> {
+            if pc + 1 >= ncode || opcode(pc + 1) != Some(op) {
+                return Err(self.error("bad instruction sequence"));
+            }
+            Ok(())
+        };
+        for (pc, &i) in f.code.iter().enumerate() {
+            let op = match opcode(pc) {
+                Some(op) => op,
+                None => return Err(self.error("bad opcode")),
+            };
+            let (a, b, c) = (get_a(i), get_b(i), get_c(i));
+            // register A, but where A is an upvalue, a level or not an operand
+            match op {
+                OpCode::SetTabUp | OpCode::Jmp | OpCode::ExtraArg => {}
+                OpCode::Return | OpCode::Return0 | OpCode::Close => {
+                    if a as usize > size {
+                        return Err(self.error("register out of range"));
+                    }
+                }
+                _ => reg(a)?,
+            }
+            match op {
+                OpCode::Move => reg(b)?,
+                OpCode::LoadK => kst(get_bx(i))?,
+                OpCode::LoadKX => {
+                    next_is(pc, OpCode::ExtraArg)?;
+                    kst(get_ax(f.code[pc + 1]))?;
+                }
+                OpCode::LFalseSkip => target(pc, 1)?,
+                OpCode::LoadNil => reg(a + b)?,
+                OpCode::GetUpval | OpCode::SetUpval => upval(b)?,
+                OpCode::GetTabUp => {
+                    upval(b)?;
+                    kst(c)?;
+                }
+                OpCode::GetTable => {
+                    reg(b)?;
+                    reg(c)?;
+                }
+                OpCode::GetI => reg(b)?,
+                OpCode::GetField => {
+                    reg(b)?;
+                    kst(c)?;
+                }
+                OpCode::SetTabUp | OpCode::SetTable | OpCode::SetI | OpCode::SetField => {
+                    match op {
+                        OpCode::SetTabUp => {
+                            upval(a)?;
+                            kst(b)?;
+                        }
+                        OpCode::SetTable => reg(b)?,
+                        OpCode::SetField => kst(b)?,
+                        _ => {}
+                    }
+                    rk(c, get_k(i))?;
+                }
+                OpCode::NewTable | OpCode::SetList if get_k(i) => next_is(pc, OpCode::ExtraArg)?,
+                OpCode::Self_ => {
+                    reg(a + 1)?;
+                    reg(b)?;
+                    rk(c, get_k(i))?;
+                }
+                OpCode::AddI | OpCode::ShrI | OpCode::ShlI => reg(b)?,
+                OpCode::AddK
+                | OpCode::SubK
+                | OpCode::MulK
+                | OpCode::ModK
+                | OpCode::PowK
+                | OpCode::DivK
+                | OpCode::IDivK
+                | OpCode::BAndK
+                | OpCode::BOrK
+                | OpCode::BXorK => {
+                    reg(b)?;
+                    kst(c)?;
+                }
+                OpCode::Add
+                | OpCode::Sub
+                | OpCode::Mul
+                | OpCode::Mod
+                | OpCode::Pow
+                | OpCode::Div
+                | OpCode::IDiv
+                | OpCode::BAnd
+                | OpCode::BOr
+                | OpCode::BXor
+                | OpCode::Shl
+                | OpCode::Shr => {
+                    reg(b)?;
+                    reg(c)?;
+                }
+                // the result goes where the arithmetic instruction before says
+                OpCode::MmBin | OpCode::MmBinI | OpCode::MmBinK => {
+                    if pc == 0 {
+                        return Err(self.error("bad instruction sequence"));
+                    }
+                    match op {
+                        OpCode::MmBin => reg(b)?,
+                        OpCode::MmBinK => kst(b)?,
+                        _ => {}
+                    }
+                }
+                OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => reg(b)?,
+                OpCode::Concat => {
+                    if b == 0 {
+                        return Err(self.error("bad instruction sequence"));
+                    }
+                    reg(a + b - 1)?;
+                }
+                OpCode::Jmp => target(pc, get_sj(i) as isize)?,
+                OpCode::ForLoop | OpCode::TForLoop => target(pc, -(get_bx(i) as isize))?,
+                OpCode::ForPrep => target(pc, get_bx(i) as isize + 1)?,
+                OpCode::TForPrep => target(pc, get_bx(i) as isize)?,
+                OpCode::Closure if get_bx(i) as usize >= f.p.len() => {
+                    return Err(self.error("function index out of range"));
+                }
+                _ => {}
+            }
+            if op.is_test() {
+                if matches!(op, OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::TestSet) {
+                    reg(b)?;
+                }
+                if op == OpCode::EqK {
+                    kst(b)?;
+                }
+                // the jump taken when the test passes
+                next_is(pc, OpCode::Jmp)?;
+            }
+        }
+        // the upvalues a nested function captures from this one
+        for np in f.p.iter() {
+            for uv in np.upvalues.iter() {
+                let n = if uv.instack { size } else { f.upvalues.len() };
+                if uv.idx as usize >= n {
+                    return Err(self.error("bad upvalue of a nested function"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_literal(&mut self, s: &[u8], why: &str) -> UResult<()> {
+        if self.load_block(s.len())? != s {
+            return Err(self.error(why));
+        }
+        Ok(())
+    }
+
+    fn check_size(&mut self, size: usize, tname: &str) -> UResult<()> {
+        if self.load_byte()? as usize != size {
+            return Err(self.error(&format!("{} size mismatch", tname)));
+        }
+        Ok(())
+    }
+
+    /// brief: the size of a number type, 4 or 8 bytes whatever the width of this build
+    fn load_num_size(&mut self, tname: &str) -> UResult<usize> {
+        match self.load_byte()? {
+            4 => Ok(4),
+            8 => Ok(8),
+            _ => Err(self.error(&format!("{} size mismatch", tname))),
+        }
+    }
+
+    fn check_header(&mut self) -> UResult<()> {
+        self.check_literal(LUA_SIGNATURE, "not a binary chunk")?;
+        if self.load_byte()? != LUAC_VERSION {
+            return Err(self.error("version mismatch"));
+        }
+        if self.load_byte()? != LUAC_FORMAT {
+            return Err(self.error("format mismatch"));
+        }
+        self.check_literal(LUAC_DATA, "corrupted chunk")?;
+        self.check_size(size_of::<Instruction>(), "Instruction")?;
+        self.int_size = self.load_num_size("lua_Integer")?;
+        self.num_size = self.load_num_size("lua_Number")?;
+        if self.load_integer()? != LUAC_INT {
+            return Err(self.error("integer format mismatch"));
+        }
+        if self.load_number()? != LUAC_NUM {
+            return Err(self.error("float format mismatch"));
+        }
+        Ok(())
+    }
+}
+
+/// brief: whether a chunk is precompiled rather than source text
+#[inline(always)]
+pub fn is_binary(chunk: &[u8]) -> bool {
+    chunk.first() == LUA_SIGNATURE.first()
+}
+
+/// brief: load a precompiled chunk into the prototype of its main function
+/// instructions must be of 4 bytes, integers and floats of 4 or 8 whatever `INT` and `FLT` are
+pub fn undump(chunk: &[u8], chunkname: &str) -> Result<Proto, SyntaxError> {
+    let name = if let Some(name) = chunkname
+        .strip_prefix('@')
+        .or_else(|| chunkname.strip_prefix('='))
+    {
+        name.to_string()
+    } else if is_binary(chunkname.as_bytes()) {
+        "binary string".to_string()
+    } else {
+        chunkname.to_string()
+    };
+    let mut s = LoadState {
+        chunk,
+        pos: 0,
+        name,
+        depth: 0,
+        int_size: size_of::<INT>(),
+        num_size: size_of::<FLT>(),
+    };
+    s.check_header()?;
+    let nupvalues = s.load_byte()? as usize;
+    let f = s.load_function(None)?;
+    if f.upvalues.len() != nupvalues {
+        return Err(s.error("bad number of upvalues"));
+    }
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile::codegen::compile_source;
+    use crate::compile::dump::dump;
+    use crate::compile::opcode::{get_op, set_b, set_bx, set_op, set_sj};
+
+    const SOURCE: &str = "local a, b = 1, 'k' local function f() return a end
+                          if b then a = f() end return a, b";
+
+    /// brief: the message of loading f after breaking it with corrupt
+    fn load_corrupted(corrupt: impl Fn(&mut Proto)) -> String {
+        let mut f = compile_source(SOURCE.as_bytes(), "=t").unwrap();
+        // without line information, the code can change size
+        f.lineinfo.clear();
+        f.abslineinfo.clear();
+        corrupt(&mut f);
+        undump(&dump(&f, true), "=t").unwrap_err().msg
+    }
+
+    /// brief: the index of the first instruction of op
+    fn find(f: &Proto, op: OpCode) -> usize {
+        f.code.iter().position(|&i| get_op(i) == op).unwrap()
+    }
+
+    #[test]
+    fn valid_code() {
+        let f = compile_source(SOURCE.as_bytes(), "=t").unwrap();
+        assert_eq!(undump(&dump(&f, false), "=t").unwrap(), f);
+    }
+
+    #[test]
+    fn bad_code() {
+        assert_eq!(
+            load_corrupted(|f| f.code.clear()),
+            "bad binary format (empty code)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let last = f.code.len() - 1;
+                set_op(&mut f.code[last], OpCode::LoadNil);
+            }),
+            "bad binary format (missing final return)"
+        );
+        assert_eq!(
+            load_corrupted(|f| f.code[0] |= 0x7f),
+            "bad binary format (bad opcode)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let pc = find(f, OpCode::Test);
+                set_op(&mut f.code[pc + 1], OpCode::Move);
+            }),
+            "bad binary format (bad instruction sequence)"
+        );
+    }
+
+    #[test]
+    fn operands_out_of_range() {
+        assert_eq!(
+            load_corrupted(|f| {
+                let pc = find(f, OpCode::LoadK);
+                set_bx(&mut f.code[pc], 100);
+            }),
+            "bad binary format (constant index out of range)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let pc = find(f, OpCode::Closure);
+                set_bx(&mut f.code[pc], 1);
+            }),
+            "bad binary format (function index out of range)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let pc = find(f, OpCode::Move);
+                set_b(&mut f.code[pc], 200);
+            }),
+            "bad binary format (register out of range)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let pc = find(f, OpCode::Jmp);
+                set_sj(&mut f.code[pc], -100);
+            }),
+            "bad binary format (jump out of range)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                let g = Rc::get_mut(&mut f.p[0]).unwrap();
+                let pc = find(g, OpCode::GetUpval);
+                set_b(&mut g.code[pc], 1);
+            }),
+            "bad binary format (upvalue index out of range)"
+        );
+    }
+
+    #[test]
+    fn nested_upvalues_out_of_range() {
+        // the upvalue of f is a register of the main function
+        assert_eq!(
+            load_corrupted(|f| {
+                Rc::get_mut(&mut f.p[0]).unwrap().upvalues[0].idx = 100;
+            }),
+            "bad binary format (bad upvalue of a nested function)"
+        );
+        assert_eq!(
+            load_corrupted(|f| {
+                Rc::get_mut(&mut f.p[0]).unwrap().upvalues[0].instack = false;
+                Rc::get_mut(&mut f.p[0]).unwrap().upvalues[0].idx = 1;
+            }),
+            "bad binary format (bad upvalue of a nested function)"
+        );
+    }
+}
@@ -1,15 +1,17 @@
-/* ------ macro for debugging-------- */
+/* ------ macro for debugging, prints only with the debug feature -------- */
 #[macro_export]
 macro_rules! DEBUG {
     ()=>(
-        {
+        if cfg!(feature = "debug") {
             let (file, line) = (file!(), line!());
             println!("{:30}", format!("[{}:{}]", file, line));
         }
     );
     ($($arg:tt)*) => ({
-        let (file, line) = (file!(), line!());
-        print!("{:30}", format!("[{}:{}]", file, line));
-        println!("{:40}", format_args!($($arg)*));
+        if cfg!(feature = "debug") {
+            let (file, line) = (file!(), line!());
+            print!("{:30}", format!("[{}:{}]", file, line));
+            println!("{:40}", format_args!($($arg)*));
+        }
     });
 }
@@ -11,6 +11,47 @@ impl Default for ErrCode {
     }
 }
 
+impl ErrCode {
+    /// brief: the message of an error raised by the VM itself, the one an error object gets
+    pub fn msg(&self) -> &'static str {
+        match self.0 {
+            INVOKE_RET_MISMATCH => "wrong number of results",
+            INVOKE_STACK_OVERFLOW => "stack overflow",
+            INVOKE_FRAME_OVERFLOW => "stack overflow (too many nested calls)",
+            INVOKE_RESUME_DEAD => "cannot resume dead coroutine",
+            INVOKE_RESUME_ACTIVE => "cannot resume non-suspended coroutine",
+            INVOKE_YIELD_OUTSIDE => "attempt to yield from outside a coroutine",
+            INVOKE_YIELD_BOUNDARY => "attempt to yield across a Rust-call boundary",
+            INVOKE_RESUME_OVERFLOW => "C stack overflow",
+            INVOKE_RCLOSURE_ACTIVE => "attempt to call a Rust closure that is running",
+            INVOKE_RCALL_OVERFLOW => "C stack overflow",
+            MEMORY_ALLOC_FAIL | MEMORY_REALLOC_FAIL => "not enough memory",
+            MEMORY_TYPE_MISMATCH => "value of a wrong type",
+            MEMORY_BORROW_FAIL => "userdata already borrowed",
+            COMPILE_LEXICAL | COMPILE_SYNTAX => "syntax error",
+            COMPILE_BAD_BINARY => "bad binary format",
+            RUNTIME_ARITH => "attempt to perform arithmetic on a non-number value",
+            RUNTIME_NO_INTEGER => "number has no integer representation",
+            RUNTIME_DIV_BY_ZERO => "attempt to perform 'n//0' or 'n%0'",
+            RUNTIME_COMPARE => "attempt to compare two incomparable values",
+            RUNTIME_CONCAT => "attempt to concatenate a non-string value",
+            RUNTIME_INDEX => "attempt to index a non-table value",
+            RUNTIME_LENGTH => "attempt to get length of a non-table value",
+            RUNTIME_FOR => "'for' initial value, limit and step must be numbers",
+            RUNTIME_FOR_ZERO => "'for' step is zero",
+            RUNTIME_CLOSE => "variable got a non-closable value",
+            RUNTIME_UNSUPPORTED => "value of an unsupported type",
+            RUNTIME_NIL_INDEX => "index is nil",
+            RUNTIME_NAN_INDEX => "index is NaN",
+            RUNTIME_CALL => "attempt to call a non-function value",
+            RUNTIME_TM_LOOP => "'__index' chain too long; possible loop",
+            RUNTIME_TOSTRING => "'__tostring' must return a string",
+            STATE_ERR_ERR => "error in error handling",
+            _ => "internal error",
+        }
+    }
+}
+
 // basic error
 pub const FINE: Err = 0;
 pub const ERR_INVOKE: Err = 1;
@@ -18,6 +59,7 @@ pub const ERR_MEMORY: Err = 2;
 pub const ERR_CONCURENCY: Err = 3;
 pub const ERR_COMPILE: Err = 4;
 pub const ERR_OPTIMIZE: Err = 5;
+pub const ERR_RUNTIME: Err = 6;
 
 pub const BASIC_ERROR_BITS: usize = 4;
 
@@ -25,6 +67,13 @@ pub const BASIC_ERROR_BITS: usize = 4;
 pub const INVOKE_RET_MISMATCH: Err = 1 << BASIC_ERROR_BITS | ERR_INVOKE;
 pub const INVOKE_STACK_OVERFLOW: Err = 2 << BASIC_ERROR_BITS | ERR_INVOKE;
 pub const INVOKE_FRAME_OVERFLOW: Err = 3 << BASIC_ERROR_BITS | ERR_INVOKE;
+pub const INVOKE_RESUME_DEAD: Err = 4 << BASIC_ERROR_BITS | ERR_INVOKE; // cannot resume dead coroutine
+pub const INVOKE_RESUME_ACTIVE: Err = 5 << BASIC_ERROR_BITS | ERR_INVOKE; // cannot resume non-suspended coroutine
+pub const INVOKE_YIELD_OUTSIDE: Err = 6 << BASIC_ERROR_BITS | ERR_INVOKE; // attempt to yield from outside a coroutine
+pub const INVOKE_YIELD_BOUNDARY: Err = 7 << BASIC_ERROR_BITS | ERR_INVOKE; // attempt to yield across a Rust call boundary
+pub const INVOKE_RESUME_OVERFLOW: Err = 8 << BASIC_ERROR_BITS | ERR_INVOKE; // too many nested resumes
+pub const INVOKE_RCLOSURE_ACTIVE: Err = 9 << BASIC_ERROR_BITS | ERR_INVOKE; // Rust closure called from inside itself
+pub const INVOKE_RCALL_OVERFLOW: Err = 10 << BASIC_ERROR_BITS | ERR_INVOKE; // too many calls nested in the Rust stack
 
 // memory access error
 pub const MEMORY_ALLOC_FAIL: Err = 1 << BASIC_ERROR_BITS | ERR_MEMORY;
@@ -33,6 +82,30 @@ pub const MEMORY_UNREACHABLE: Err = 3 << BASIC_ERROR_BITS | ERR_MEMORY;
 pub const MEMORY_MODIFY_FAIL: Err = 6 << BASIC_ERROR_BITS | ERR_MEMORY;
 pub const MEMORY_TYPE_MISMATCH: Err = 7 << BASIC_ERROR_BITS | ERR_MEMORY;
 pub const MEMORY_DROP_FAIL: Err = 8 << BASIC_ERROR_BITS | ERR_MEMORY;
+pub const MEMORY_BORROW_FAIL: Err = 9 << BASIC_ERROR_BITS | ERR_MEMORY; // userdata borrowed in a conflicting way
+
+// compilation error
+pub const COMPILE_LEXICAL: Err = 1 << BASIC_ERROR_BITS | ERR_COMPILE;
+pub const COMPILE_SYNTAX: Err = 2 << BASIC_ERROR_BITS | ERR_COMPILE;
+pub const COMPILE_BAD_BINARY: Err = 3 << BASIC_ERROR_BITS | ERR_COMPILE; // malformed precompiled chunk
+
+// runtime error
+pub const RUNTIME_ARITH: Err = 1 << BASIC_ERROR_BITS | ERR_RUNTIME; // operand is not a number
+pub const RUNTIME_NO_INTEGER: Err = 2 << BASIC_ERROR_BITS | ERR_RUNTIME; // number has no integer representation
+pub const RUNTIME_DIV_BY_ZERO: Err = 3 << BASIC_ERROR_BITS | ERR_RUNTIME; // integer 'n//0' or 'n%0'
+pub const RUNTIME_COMPARE: Err = 4 << BASIC_ERROR_BITS | ERR_RUNTIME;
+pub const RUNTIME_CONCAT: Err = 5 << BASIC_ERROR_BITS | ERR_RUNTIME;
+pub const RUNTIME_INDEX: Err = 6 << BASIC_ERROR_BITS | ERR_RUNTIME;
+pub const RUNTIME_LENGTH: Err = 7 << BASIC_ERROR_BITS | ERR_RUNTIME;
+pub const RUNTIME_FOR: Err = 8 << BASIC_ERROR_BITS | ERR_RUNTIME; // bad 'for' initial value, limit or step
+pub const RUNTIME_CLOSE: Err = 9 << BASIC_ERROR_BITS | ERR_RUNTIME; // variable got a non-closable value
+pub const RUNTIME_UNSUPPORTED: Err = 10 << BASIC_ERROR_BITS | ERR_RUNTIME; // value of a type the VM lacks
+pub const RUNTIME_NIL_INDEX: Err = 11 << BASIC_ERROR_BITS | ERR_RUNTIME; // table key is nil
+pub const RUNTIME_NAN_INDEX: Err = 12 << BASIC_ERROR_BITS | ERR_RUNTIME; // table key is NaN
+pub const RUNTIME_CALL: Err = 13 << BASIC_ERROR_BITS | ERR_RUNTIME; // value is not callable
+pub const RUNTIME_TM_LOOP: Err = 14 << BASIC_ERROR_BITS | ERR_RUNTIME; // '__index' or '__newindex' chain too long
+pub const RUNTIME_TOSTRING: Err = 15 << BASIC_ERROR_BITS | ERR_RUNTIME; // '__tostring' must return a string
+pub const RUNTIME_FOR_ZERO: Err = 16 << BASIC_ERROR_BITS | ERR_RUNTIME; // 'for' step is zero
 
 pub const NULL_POINTER: Err = 1;
 pub const NONE_OBJECT: Err = 2;
@@ -43,6 +116,8 @@ pub const STATE_OK: Err = 0 << 4;
 pub const STATE_ERR_ERR: Err = 1 << 4;
 pub const LUA_ERR_MEM: Err = 2 << 4; // failed allocating memory
 pub const STATE_ERR_RUN: Err = 3 << 4;
+pub const STATE_YIELD: Err = 4 << 4; // a coroutine suspended by a yield
+pub const STATE_ERR_SYNTAX: Err = 5 << 4; // a chunk that does not load
 // R[7-4]
 
 pub const CALL_OK: Err = 0 << 8;
@@ -54,10 +129,24 @@ pub const CALL_MISMATCHED: Err = 3 << 8;
 pub const LUA_MIN_STACK: u32 = 20; // for callinfo structure
 pub const LUA_STACK_SIZE: u32 = 2 * LUA_MIN_STACK; // initial stack size
 pub const LUA_EXTRA_STACK: u32 = 5;
-pub const LUA_MAX_STACK: u32 = 15000;
+pub const LUA_MAX_STACK: u32 = 1000000; // slots of a stack, it grows on demand up to it
 pub const LUA_ERROR_STACK: u32 = 200;
 
 pub const LUA_MUL_RET: isize = -1;
-pub const LUA_MAX_CALLS: usize = 200;
+pub const LUA_MAX_CALLS: usize = 200; // calls nested in the Rust stack: Rust functions, metamethods
+pub const LUA_MAX_LCALLS: usize = 200000; // frames of a thread, Lua calls do not use the Rust stack
+pub const LUAI_MAXCCALLS: usize = 200; // nesting limit of syntactical structures and of resumes
 pub const LUA_CI_LEN: usize = 20; // need not pop out
 pub const LUA_EXTRASPACE: usize = size_of::<*mut ()>();
+pub const LUA_IDSIZE: usize = 60; // size of the chunk name shown in messages
+pub const LUAI_MAXSHORTLEN: usize = 40; // longest string kept as a short one
+pub const LUA_ENV: &str = "_ENV"; // name of the environment upvalue
+pub const LUA_REGISTRY_INDEX: usize = LUA_MAX_STACK as usize + 1000; // the step of the registry, steps past it reach upvalues
+pub const LUA_RIDX_GLOBALS: usize = 2; // the table of globals in the registry, 1 is kept for the main thread
+pub const LUA_RIDX_LAST: usize = LUA_RIDX_GLOBALS;
+
+/// brief: the step that reaches upvalue i (from 1) of the running Rust closure
+#[inline(always)]
+pub const fn lua_upvalue_index(i: usize) -> usize {
+    LUA_REGISTRY_INDEX + i
+}
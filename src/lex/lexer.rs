@@ -0,0 +1,757 @@
+use core::fmt;
+
+use crate::{
+    info::lua::{ErrCode, COMPILE_LEXICAL, LUA_IDSIZE},
+    lex::token::{Lexeme, Token},
+    obj::objdef::INT,
+    vm::convert::{hex_value, is_space, str2flt, str2int},
+};
+
+/// brief: an error found while reading or compiling a chunk,
+/// displayed as `chunkname:line: message`, or `chunkname: message` when no line applies
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    pub code: ErrCode,
+    pub chunkname: String,
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}: {}", self.chunkname, self.msg);
+        }
+        write!(f, "{}:{}: {}", self.chunkname, self.line, self.msg)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// brief: turn a chunk name into the form shown in messages
+/// `=name` is used as is, `@file` is a file name, anything else is source text
+pub fn chunkid(source: &str) -> String {
+    const RETS: &str = "...";
+    const PRE: &str = "[string \"";
+    const POS: &str = "\"]";
+
+    if let Some(name) = source.strip_prefix('=') {
+        return name.chars().take(LUA_IDSIZE - 1).collect();
+    }
+    if let Some(name) = source.strip_prefix('@') {
+        let len = name.chars().count();
+        if len < LUA_IDSIZE {
+            return name.to_string();
+        }
+        // keep the tail of the file name
+        let tail: String = name.chars().skip(len - (LUA_IDSIZE - 1 - RETS.len())).collect();
+        return format!("{}{}", RETS, tail);
+    }
+    // string: keep the first line only
+    let first = source.split(['\n', '\r']).next().unwrap_or("");
+    let room = LUA_IDSIZE - 1 - PRE.len() - POS.len() - RETS.len();
+    let nchars = first.chars().count();
+    if nchars == source.chars().count() && nchars <= room + RETS.len() {
+        format!("{}{}{}", PRE, first, POS)
+    } else {
+        let head: String = first.chars().take(room).collect();
+        format!("{}{}{}{}", PRE, head, RETS, POS)
+    }
+}
+
+#[inline(always)]
+fn is_alpha(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+#[inline(always)]
+fn is_alnum(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// brief: convert a numeral as read by the lexer into an integer or a float token
+/// decimal integers that overflow are read as floats, hexadecimal ones wrap around
+fn str2number(buff: &[u8]) -> Option<Token> {
+    if let Some(i) = str2int(buff, false) {
+        return Some(Token::Int(i));
+    }
+    str2flt(buff).map(Token::Flt)
+}
+
+/// brief: encode a code point with the extended (up to 6 bytes) UTF-8 scheme
+fn utf8_encode(mut x: u32, out: &mut Vec<u8>) {
+    if x < 0x80 {
+        out.push(x as u8);
+        return;
+    }
+    let mut buff = [0u8; 8];
+    let mut n = 1;
+    // maximum that fits in first byte
+    let mut mfb: u32 = 0x3f;
+    loop {
+        buff[8 - n] = 0x80 | (x & 0x3f) as u8;
+        n += 1;
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    buff[8 - n] = ((!mfb << 1) | x) as u8;
+    out.extend_from_slice(&buff[8 - n..]);
+}
+
+/// brief: split Lua 5.4 source text into tokens
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: usize,
+    column: usize,
+    // start of the token being read, for error messages
+    tk_start: usize,
+    chunkname: String,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a [u8], chunkname: &str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            line: 1,
+            column: 1,
+            tk_start: 0,
+            chunkname: chunkid(chunkname),
+        }
+    }
+
+    /// brief: the name used in error messages
+    pub fn chunkname(&self) -> &str {
+        &self.chunkname
+    }
+
+    /// brief: current line number
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    #[inline(always)]
+    fn current(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    #[inline(always)]
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.src.get(self.pos + offset).copied()
+    }
+
+    #[inline(always)]
+    fn advance(&mut self) {
+        self.pos += 1;
+        self.column += 1;
+    }
+
+    #[inline(always)]
+    fn is_newline(&self) -> bool {
+        matches!(self.current(), Some(b'\n') | Some(b'\r'))
+    }
+
+    /// brief: skip `\n`, `\r`, `\n\r` or `\r\n`
+    fn inc_line(&mut self) -> Result<(), SyntaxError> {
+        let old = self.current();
+        self.advance();
+        if self.is_newline() && self.current() != old {
+            self.advance();
+        }
+        self.line += 1;
+        self.column = 1;
+        if self.line >= INT::MAX as usize {
+            return Err(self.error("chunk has too many lines", None));
+        }
+        Ok(())
+    }
+
+    /// brief: build an error at the current line, optionally quoting the token
+    pub fn error(&self, msg: &str, near: Option<String>) -> SyntaxError {
+        let msg = match near {
+            Some(near) => format!("{} near {}", msg, near),
+            None => msg.to_string(),
+        };
+        SyntaxError {
+            code: ErrCode(COMPILE_LEXICAL),
+            chunkname: self.chunkname.clone(),
+            line: self.line,
+            msg,
+        }
+    }
+
+    /// brief: an error quoting the raw text of the token being read
+    fn token_error(&self, msg: &str) -> SyntaxError {
+        let end = self.pos.min(self.src.len());
+        let text = String::from_utf8_lossy(&self.src[self.tk_start..end]);
+        self.error(msg, Some(format!("'{}'", text)))
+    }
+
+    /// brief: read all tokens, ending with `Token::Eos`
+    pub fn tokenize(&mut self) -> Result<Vec<Lexeme>, SyntaxError> {
+        let mut tokens = Vec::new();
+        loop {
+            let lexeme = self.next_token()?;
+            let end = lexeme.token == Token::Eos;
+            tokens.push(lexeme);
+            if end {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Lexeme, SyntaxError> {
+        self.skip_blank()?;
+        let (line, column) = (self.line, self.column);
+        self.tk_start = self.pos;
+        let token = self.read_token()?;
+        Ok(Lexeme {
+            token,
+            line,
+            column,
+        })
+    }
+
+    /// brief: skip white space and comments
+    fn skip_blank(&mut self) -> Result<(), SyntaxError> {
+        loop {
+            match self.current() {
+                Some(b'\n') | Some(b'\r') => self.inc_line()?,
+                Some(c) if is_space(c) => self.advance(),
+                Some(b'-') if self.peek(1) == Some(b'-') => {
+                    self.tk_start = self.pos;
+                    self.advance();
+                    self.advance();
+                    if self.current() == Some(b'[') {
+                        let sep = self.skip_sep();
+                        if sep >= 2 {
+                            self.read_long_string(sep, false)?;
+                            continue;
+                        }
+                    }
+                    // short comment
+                    while !self.is_newline() && self.current().is_some() {
+                        self.advance();
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_token(&mut self) -> Result<Token, SyntaxError> {
+        let c = match self.current() {
+            Some(c) => c,
+            None => return Ok(Token::Eos),
+        };
+        let tk = match c {
+            b'[' => {
+                let sep = self.skip_sep();
+                if sep >= 2 {
+                    return Ok(Token::Str(self.read_long_string(sep, true)?));
+                } else if sep == 0 {
+                    return Err(self.token_error("invalid long string delimiter"));
+                }
+                return Ok(Token::LBracket);
+            }
+            b'=' => self.pick(b'=', Token::Eq, Token::Assign),
+            b'<' => {
+                self.advance();
+                match self.current() {
+                    Some(b'=') => {
+                        self.advance();
+                        return Ok(Token::Le);
+                    }
+                    Some(b'<') => {
+                        self.advance();
+                        return Ok(Token::Shl);
+                    }
+                    _ => return Ok(Token::Lt),
+                }
+            }
+            b'>' => {
+                self.advance();
+                match self.current() {
+                    Some(b'=') => {
+                        self.advance();
+                        return Ok(Token::Ge);
+                    }
+                    Some(b'>') => {
+                        self.advance();
+                        return Ok(Token::Shr);
+                    }
+                    _ => return Ok(Token::Gt),
+                }
+            }
+            b'/' => self.pick(b'/', Token::IDiv, Token::Div),
+            b'~' => self.pick(b'=', Token::Ne, Token::Tilde),
+            b':' => self.pick(b':', Token::DbColon, Token::Colon),
+            b'"' | b'\'' => return Ok(Token::Str(self.read_string(c)?)),
+            b'.' => {
+                if self.peek(1) == Some(b'.') {
+                    self.advance();
+                    self.advance();
+                    if self.current() == Some(b'.') {
+                        self.advance();
+                        return Ok(Token::Dots);
+                    }
+                    return Ok(Token::Concat);
+                } else if matches!(self.peek(1), Some(d) if d.is_ascii_digit()) {
+                    return self.read_numeral();
+                }
+                self.advance();
+                return Ok(Token::Dot);
+            }
+            b'0'..=b'9' => return self.read_numeral(),
+            _ if is_alpha(c) => {
+                while matches!(self.current(), Some(c) if is_alnum(c)) {
+                    self.advance();
+                }
+                // identifiers are ascii only
+                let name = String::from_utf8_lossy(&self.src[self.tk_start..self.pos]);
+                return Ok(Token::keyword(&name).unwrap_or(Token::Name(name.into_owned())));
+            }
+            b'-' => Token::Sub,
+            b'+' => Token::Add,
+            b'*' => Token::Mul,
+            b'%' => Token::Mod,
+            b'^' => Token::Pow,
+            b'#' => Token::Len,
+            b'&' => Token::BAnd,
+            b'|' => Token::BOr,
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            b'{' => Token::LBrace,
+            b'}' => Token::RBrace,
+            b']' => Token::RBracket,
+            b';' => Token::Semi,
+            b',' => Token::Comma,
+            _ => {
+                self.advance();
+                return Err(self.token_error("unexpected symbol"));
+            }
+        };
+        self.advance();
+        Ok(tk)
+    }
+
+    /// brief: read a two-char token if the next char is `second`, otherwise the single one
+    fn pick(&mut self, second: u8, double: Token, single: Token) -> Token {
+        if self.peek(1) == Some(second) {
+            self.advance();
+            double
+        } else {
+            single
+        }
+    }
+
+    /// brief: read a sequence `[=*[` or `]=*]`, leaving the last bracket unread
+    /// returns its number of '=' + 2 if well formed, 1 for a single bracket
+    /// and 0 for an unfinished `[==`
+    fn skip_sep(&mut self) -> usize {
+        let s = self.current();
+        let mut count = 0;
+        self.advance();
+        while self.current() == Some(b'=') {
+            self.advance();
+            count += 1;
+        }
+        if self.current() == s {
+            count + 2
+        } else if count == 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn read_long_string(&mut self, sep: usize, is_string: bool) -> Result<Vec<u8>, SyntaxError> {
+        let start_line = self.line;
+        let mut buff = Vec::new();
+        // skip the second '['
+        self.advance();
+        // string starts with a newline? skip it
+        if self.is_newline() {
+            self.inc_line()?;
+        }
+        loop {
+            match self.current() {
+                None => {
+                    let what = if is_string { "string" } else { "comment" };
+                    let msg = format!("unfinished long {} (starting at line {})", what, start_line);
+                    return Err(self.error(&msg, Some(Token::Eos.to_string())));
+                }
+                Some(b']') => {
+                    let start = self.pos;
+                    if self.skip_sep() == sep {
+                        // skip the second ']'
+                        self.advance();
+                        return Ok(buff);
+                    }
+                    // not the closing bracket, what was read is part of the string
+                    buff.extend_from_slice(&self.src[start..self.pos]);
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    buff.push(b'\n');
+                    self.inc_line()?;
+                }
+                Some(c) => {
+                    buff.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn read_string(&mut self, del: u8) -> Result<Vec<u8>, SyntaxError> {
+        let mut buff = Vec::new();
+        // skip the delimiter
+        self.advance();
+        loop {
+            let c = match self.current() {
+                None => return Err(self.error("unfinished string", Some(Token::Eos.to_string()))),
+                Some(b'\n') | Some(b'\r') => return Err(self.token_error("unfinished string")),
+                Some(c) if c == del => {
+                    self.advance();
+                    return Ok(buff);
+                }
+                Some(c) => c,
+            };
+            if c != b'\\' {
+                buff.push(c);
+                self.advance();
+                continue;
+            }
+            // escape sequences
+            self.advance();
+            let esc = match self.current() {
+                // will raise an error next loop
+                None => continue,
+                Some(e) => e,
+            };
+            let value = match esc {
+                b'a' => 0x07,
+                b'b' => 0x08,
+                b'f' => 0x0c,
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'v' => 0x0b,
+                b'\\' | b'"' | b'\'' => esc,
+                b'\n' | b'\r' => {
+                    self.inc_line()?;
+                    buff.push(b'\n');
+                    continue;
+                }
+                b'x' => self.read_hex_esc()?,
+                b'u' => {
+                    let code = self.read_utf8_esc()?;
+                    utf8_encode(code, &mut buff);
+                    continue;
+                }
+                b'z' => {
+                    // zap the following span of spaces
+                    self.advance();
+                    while matches!(self.current(), Some(c) if is_space(c)) {
+                        if self.is_newline() {
+                            self.inc_line()?;
+                        } else {
+                            self.advance();
+                        }
+                    }
+                    continue;
+                }
+                b'0'..=b'9' => {
+                    buff.push(self.read_dec_esc()?);
+                    continue;
+                }
+                _ => {
+                    self.advance();
+                    return Err(self.token_error("invalid escape sequence"));
+                }
+            };
+            buff.push(value);
+            self.advance();
+        }
+    }
+
+    fn read_hex_digit(&mut self) -> Result<u32, SyntaxError> {
+        self.advance();
+        match self.current() {
+            Some(c) if c.is_ascii_hexdigit() => Ok(hex_value(c)),
+            _ => {
+                if self.current().is_some() {
+                    self.advance();
+                }
+                Err(self.token_error("hexadecimal digit expected"))
+            }
+        }
+    }
+
+    /// brief: `\xXX`, leaves the last digit unread
+    fn read_hex_esc(&mut self) -> Result<u8, SyntaxError> {
+        let high = self.read_hex_digit()?;
+        let low = self.read_hex_digit()?;
+        Ok(((high << 4) + low) as u8)
+    }
+
+    /// brief: `\u{XXX}`, consumes the closing brace
+    fn read_utf8_esc(&mut self) -> Result<u32, SyntaxError> {
+        self.advance();
+        if self.current() != Some(b'{') {
+            if self.current().is_some() {
+                self.advance();
+            }
+            return Err(self.token_error("missing '{' in \\u{xxxx}"));
+        }
+        // must have at least one digit
+        let mut r = self.read_hex_digit()?;
+        self.advance();
+        while let Some(c) = self.current().filter(|c| c.is_ascii_hexdigit()) {
+            r = (r << 4) + hex_value(c);
+            if r > 0x7FFF_FFFF {
+                self.advance();
+                return Err(self.token_error("UTF-8 value too large"));
+            }
+            self.advance();
+        }
+        if self.current() != Some(b'}') {
+            if self.current().is_some() {
+                self.advance();
+            }
+            return Err(self.token_error("missing '}' in \\u{xxxx}"));
+        }
+        self.advance();
+        Ok(r)
+    }
+
+    /// brief: `\ddd`, consumes up to three digits
+    fn read_dec_esc(&mut self) -> Result<u8, SyntaxError> {
+        let mut r: u32 = 0;
+        let mut i = 0;
+        while i < 3 {
+            match self.current() {
+                Some(c) if c.is_ascii_digit() => {
+                    r = 10 * r + (c - b'0') as u32;
+                    self.advance();
+                }
+                _ => break,
+            }
+            i += 1;
+        }
+        if r > u8::MAX as u32 {
+            return Err(self.token_error("decimal escape too large"));
+        }
+        Ok(r as u8)
+    }
+
+    /// brief: read a numeral the same greedy way as the reference lexer,
+    /// then convert it; anything that does not convert is malformed
+    fn read_numeral(&mut self) -> Result<Token, SyntaxError> {
+        let mut expo = [b'E', b'e'];
+        let first = self.current();
+        self.advance();
+        if first == Some(b'0') && matches!(self.current(), Some(b'x') | Some(b'X')) {
+            self.advance();
+            expo = [b'P', b'p'];
+        }
+        loop {
+            match self.current() {
+                Some(c) if expo.contains(&c) => {
+                    self.advance();
+                    if matches!(self.current(), Some(b'+') | Some(b'-')) {
+                        self.advance();
+                    }
+                }
+                Some(c) if c.is_ascii_hexdigit() || c == b'.' => self.advance(),
+                _ => break,
+            }
+        }
+        // is the numeral touching a letter? force an error
+        if matches!(self.current(), Some(c) if is_alpha(c)) {
+            self.advance();
+        }
+        match str2number(&self.src[self.tk_start..self.pos]) {
+            Some(tk) => Ok(tk),
+            None => Err(self.token_error("malformed number")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obj::objdef::{FLT, UINT};
+    use core::mem::size_of;
+
+    fn tokens(src: &str) -> Vec<Token> {
+        let lexemes = Lexer::new(src.as_bytes(), "=test").tokenize().unwrap();
+        lexemes.into_iter().map(|l| l.token).collect()
+    }
+
+    /// brief: the single token of src, which must be nothing else
+    fn token(src: &str) -> Token {
+        let mut tks = tokens(src);
+        assert_eq!(tks.pop(), Some(Token::Eos));
+        assert_eq!(tks.len(), 1, "{:?}", tks);
+        tks.pop().unwrap()
+    }
+
+    fn error(src: &str) -> String {
+        Lexer::new(src.as_bytes(), "=test")
+            .tokenize()
+            .unwrap_err()
+            .to_string()
+    }
+
+    fn string(src: &str) -> Vec<u8> {
+        match token(src) {
+            Token::Str(s) => s,
+            tk => panic!("not a string: {:?}", tk),
+        }
+    }
+
+    #[test]
+    fn long_strings() {
+        assert_eq!(string("[[abc]]"), b"abc");
+        assert_eq!(string("[==[a]]b]=]c]==]"), b"a]]b]=]c");
+        // a first newline is skipped, the others become '\n'
+        assert_eq!(string("[[\nx\r\ny\n\rz]]"), b"x\ny\nz");
+        assert_eq!(string("[=[\\n]=]"), b"\\n");
+        assert_eq!(
+            error("x = [==[abc]=]"),
+            "test:1: unfinished long string (starting at line 1) near <eof>"
+        );
+        assert_eq!(
+            error("[=x"),
+            "test:1: invalid long string delimiter near '[='"
+        );
+    }
+
+    #[test]
+    fn comments_and_lines() {
+        let src = "--[==[ a\n]] ]==] x --[ short\n-- y\ny";
+        let lexemes = Lexer::new(src.as_bytes(), "=test").tokenize().unwrap();
+        let found: Vec<_> = lexemes.iter().map(|l| (l.token.clone(), l.line)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (Token::Name("x".to_string()), 2),
+                (Token::Name("y".to_string()), 4),
+                (Token::Eos, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(string(r#""a\tb\n\\\"\'""#), b"a\tb\n\\\"'");
+        assert_eq!(string(r"'\a\b\f\r\v'"), b"\x07\x08\x0c\r\x0b");
+        assert_eq!(string(r"'\x41\x7a\65\0\255'"), b"Az\x41\x00\xff");
+        assert_eq!(
+            string(r"'\u{48}\u{E9}\u{20AC}'"),
+            "H\u{e9}\u{20ac}".as_bytes()
+        );
+        assert_eq!(string(r"'\u{7FFFFFFF}'"), b"\xfd\xbf\xbf\xbf\xbf\xbf");
+        // \z skips the spaces and newlines that follow
+        assert_eq!(string("'a\\z  \n\t  b'"), b"ab");
+        // an escaped newline is a newline
+        assert_eq!(string("'a\\\nb'"), b"a\nb");
+        assert_eq!(
+            error(r"'\q'"),
+            r"test:1: invalid escape sequence near ''\q'"
+        );
+        assert_eq!(
+            error(r"'\256'"),
+            r"test:1: decimal escape too large near ''\256'"
+        );
+        assert_eq!(
+            error(r"'\xg0'"),
+            r"test:1: hexadecimal digit expected near ''\xg'"
+        );
+        assert_eq!(
+            error(r"'\u{80000000}'"),
+            r"test:1: UTF-8 value too large near ''\u{80000000'"
+        );
+        assert_eq!(error("'abc\nd'"), "test:1: unfinished string near ''abc'");
+        assert_eq!(error("'abc"), "test:1: unfinished string near <eof>");
+    }
+
+    #[test]
+    fn numerals() {
+        assert_eq!(token("3"), Token::Int(3));
+        assert_eq!(token("345"), Token::Int(345));
+        assert_eq!(token("0xff"), Token::Int(255));
+        assert_eq!(token("0xBEBADA"), Token::Int(0xBEBADA));
+        assert_eq!(token("3.0"), Token::Flt(3.0));
+        assert_eq!(token("2.5625"), Token::Flt(2.5625));
+        assert_eq!(token("256.25e-2"), Token::Flt(2.5625));
+        assert_eq!(token("0.25625E1"), Token::Flt(2.5625));
+        assert_eq!(token("34e1"), Token::Flt(340.0));
+        assert_eq!(token(".5"), Token::Flt(0.5));
+        assert_eq!(token("5."), Token::Flt(5.0));
+        assert_eq!(token("0x0.1E"), Token::Flt(0.1171875));
+        assert_eq!(token("0xA23p-4"), Token::Flt(162.1875));
+        assert_eq!(token("0x.8p1"), Token::Flt(1.0));
+        assert_eq!(
+            token("0X1.921FB54442D18P+1"),
+            Token::Flt(core::f64::consts::PI as FLT)
+        );
+        // a decimal integer that overflows is a float
+        let over = (INT::MAX as UINT + 1).to_string();
+        assert_eq!(token(&over), Token::Flt(-(INT::MIN as FLT)));
+        // a hexadecimal one wraps around
+        let ones = format!("0x{}", "f".repeat(2 * size_of::<INT>()));
+        assert_eq!(token(&ones), Token::Int(-1));
+        let wrap = format!("0x1{}", "0".repeat(2 * size_of::<INT>()));
+        assert_eq!(token(&wrap), Token::Int(0));
+        // a sign is not part of the numeral
+        assert_eq!(tokens("-1"), vec![Token::Sub, Token::Int(1), Token::Eos]);
+        assert_eq!(
+            tokens("1 ..2"),
+            vec![Token::Int(1), Token::Concat, Token::Int(2), Token::Eos]
+        );
+        assert_eq!(error("3..2"), "test:1: malformed number near '3..2'");
+        assert_eq!(error("0xg"), "test:1: malformed number near '0xg'");
+        assert_eq!(error("12a"), "test:1: malformed number near '12a'");
+        assert_eq!(error("1e"), "test:1: malformed number near '1e'");
+        assert_eq!(error("0x"), "test:1: malformed number near '0x'");
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            tokens("a//b ~= c >> 1 << 2 :: ... ~x"),
+            vec![
+                Token::Name("a".to_string()),
+                Token::IDiv,
+                Token::Name("b".to_string()),
+                Token::Ne,
+                Token::Name("c".to_string()),
+                Token::Shr,
+                Token::Int(1),
+                Token::Shl,
+                Token::Int(2),
+                Token::DbColon,
+                Token::Dots,
+                Token::Tilde,
+                Token::Name("x".to_string()),
+                Token::Eos
+            ]
+        );
+        assert_eq!(token("goto"), Token::Goto);
+        assert_eq!(token("_end"), Token::Name("_end".to_string()));
+    }
+
+    #[test]
+    fn chunk_ids() {
+        assert_eq!(chunkid("=stdin"), "stdin");
+        assert_eq!(chunkid("@main.lua"), "main.lua");
+        assert_eq!(chunkid("return 1"), "[string \"return 1\"]");
+        assert_eq!(chunkid("x = 1\ny = 2"), "[string \"x = 1...\"]");
+    }
+}
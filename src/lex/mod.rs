@@ -0,0 +1,2 @@
+pub mod lexer;
+pub mod token;
@@ -0,0 +1,191 @@
+use core::fmt;
+
+use crate::obj::objdef::{FLT, INT};
+
+/// brief: a single Lua 5.4 token
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // reserved words
+    And,
+    Break,
+    Do,
+    Else,
+    Elseif,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+
+    // operators and punctuation
+    Add,      // +
+    Sub,      // -
+    Mul,      // *
+    Div,      // /
+    IDiv,     // //
+    Mod,      // %
+    Pow,      // ^
+    Len,      // #
+    BAnd,     // &
+    Tilde,    // ~ (bnot and bxor)
+    BOr,      // |
+    Shl,      // <<
+    Shr,      // >>
+    Concat,   // ..
+    Dots,     // ...
+    Eq,       // ==
+    Ne,       // ~=
+    Le,       // <=
+    Ge,       // >=
+    Lt,       // <
+    Gt,       // >
+    Assign,   // =
+    LParen,   // (
+    RParen,   // )
+    LBrace,   // {
+    RBrace,   // }
+    LBracket, // [
+    RBracket, // ]
+    DbColon,  // ::
+    Semi,     // ;
+    Colon,    // :
+    Comma,    // ,
+    Dot,      // .
+
+    // literals
+    Int(INT),
+    Flt(FLT),
+    Str(Vec<u8>),
+    Name(String),
+
+    Eos,
+}
+
+impl Token {
+    /// brief: map an identifier to its reserved word, if it is one
+    pub fn keyword(name: &str) -> Option<Token> {
+        let tk = match name {
+            "and" => Token::And,
+            "break" => Token::Break,
+            "do" => Token::Do,
+            "else" => Token::Else,
+            "elseif" => Token::Elseif,
+            "end" => Token::End,
+            "false" => Token::False,
+            "for" => Token::For,
+            "function" => Token::Function,
+            "goto" => Token::Goto,
+            "if" => Token::If,
+            "in" => Token::In,
+            "local" => Token::Local,
+            "nil" => Token::Nil,
+            "not" => Token::Not,
+            "or" => Token::Or,
+            "repeat" => Token::Repeat,
+            "return" => Token::Return,
+            "then" => Token::Then,
+            "true" => Token::True,
+            "until" => Token::Until,
+            "while" => Token::While,
+            _ => return None,
+        };
+        Some(tk)
+    }
+
+    fn text(&self) -> &'static str {
+        match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+",
+            Token::Sub => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::IDiv => "//",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BAnd => "&",
+            Token::Tilde => "~",
+            Token::BOr => "|",
+            Token::Shl => "<<",
+            Token::Shr => ">>",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Le => "<=",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Gt => ">",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::DbColon => "::",
+            Token::Semi => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Int(_) | Token::Flt(_) | Token::Str(_) | Token::Name(_) => "",
+            Token::Eos => "<eof>",
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    /// the form used by error messages: quoted text, except for `<eof>`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Int(i) => write!(f, "'{}'", i),
+            Token::Flt(n) => write!(f, "'{}'", n),
+            Token::Str(s) => write!(f, "'{}'", String::from_utf8_lossy(s)),
+            Token::Name(n) => write!(f, "'{}'", n),
+            Token::Eos => write!(f, "{}", self.text()),
+            _ => write!(f, "'{}'", self.text()),
+        }
+    }
+}
+
+/// brief: a token together with the position of its first character
+/// note that both line and column start from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub line: usize,
+    pub column: usize,
+}
@@ -0,0 +1,9 @@
+
+pub mod compile;
+pub mod info;
+pub mod lex;
+pub mod lualib;
+pub mod method;
+pub mod obj;
+pub mod parse;
+pub mod vm;
@@ -0,0 +1,220 @@
+use crate::info::lua::{ErrCode, FINE, LUA_MUL_RET, STATE_OK, STATE_YIELD};
+use crate::obj::objdef::{type_name, ObjectTrait, TObj, FFUNC, KCTX, T_NUMBER};
+use crate::obj::statedef::LuaState;
+use crate::obj::string::LuaString;
+use crate::vm::arith::to_integer_ns;
+use crate::vm::convert::{str2int_base, str2number, to_integer};
+
+const BASE_FUNCS: [(&[u8], FFUNC); 5] = [
+    (b"error", lua_error),
+    (b"pcall", lua_pcall),
+    (b"tonumber", lua_tonumber),
+    (b"tostring", lua_tostring),
+    (b"xpcall", lua_xpcall),
+];
+
+/// brief: the slot of the first argument of the running Rust function
+fn arg_base(l: &LuaState) -> usize {
+    l.get_stack_top() - l.arg_count()
+}
+
+/// brief: raise `bad argument #arg to 'fname' (msg)`, at the caller's position
+fn arg_error(l: &mut LuaState, arg: usize, fname: &str, msg: &str) -> usize {
+    let text = format!(
+        "{}bad argument #{} to '{}' ({})",
+        l.where_(1).unwrap_or_default(),
+        arg,
+        fname,
+        msg
+    );
+    match l.push_string(text.as_bytes()) {
+        Ok(_) => l.error(),
+        Err(e) => l.raise(e),
+    }
+}
+
+/// brief: error(msg [, level]), a string message gets the position of the function
+/// level calls up, 1 by default, 0 for none
+fn lua_error(l: &mut LuaState) -> usize {
+    let base = arg_base(l);
+    let level = if l.get_stack_top() > base + 1 {
+        let v = match l.get_stkelem_fromtop(l.get_stack_top() - base - 2) {
+            Ok(v) => v,
+            Err(e) => return l.raise(e),
+        };
+        match to_integer_ns(&v) {
+            Some(level) => level,
+            None => return arg_error(l, 2, "error", "number expected"),
+        }
+    } else {
+        1
+    };
+    if l.get_stack_top() == base {
+        if let Err(e) = l.push_nil() {
+            return l.raise(e);
+        }
+    }
+    l.move_top_to(base + 1);
+    let msg = match l.get_stkelem_fromtop(0) {
+        Ok(v) => Option::<*mut LuaString>::into_inner(&v),
+        Err(e) => return l.raise(e),
+    };
+    if let (Some(ts), true) = (msg, level > 0) {
+        let pos = match l.where_(level as usize) {
+            Ok(pos) => pos,
+            Err(e) => return l.raise(e),
+        };
+        let mut text = pos.into_bytes();
+        text.extend_from_slice(unsafe { (*ts).as_bytes() });
+        l.move_top(1, false);
+        if let Err(e) = l.push_string(&text) {
+            return l.raise(e);
+        }
+    }
+    l.error()
+}
+
+/// brief: tonumber(v [, base]), the number v converts to, or nil;
+/// with a base, v must be a string holding an integer in that base
+fn lua_tonumber(l: &mut LuaState) -> usize {
+    let base = arg_base(l);
+    let nargs = l.get_stack_top() - base;
+    if nargs == 0 {
+        return arg_error(l, 1, "tonumber", "value expected");
+    }
+    let v = match l.get_stkelem_fromtop(nargs - 1) {
+        Ok(v) => v,
+        Err(e) => return l.raise(e),
+    };
+    let res = if nargs < 2 || l.get_stkelem_fromtop(nargs - 2).map_or(true, |b| b.is_nil()) {
+        if v.val_idx.basic_type() == T_NUMBER {
+            Some(v)
+        } else {
+            Option::<*mut LuaString>::into_inner(&v)
+                .and_then(|ts| str2number(unsafe { (*ts).as_bytes() }))
+        }
+    } else {
+        let nb = match l.get_stkelem_fromtop(nargs - 2).map(|b| to_integer(&b)) {
+            Ok(Some(nb)) => nb,
+            Ok(None) => return arg_error(l, 2, "tonumber", "number expected"),
+            Err(e) => return l.raise(e),
+        };
+        let ts = match Option::<*mut LuaString>::into_inner(&v) {
+            Some(ts) => ts,
+            None => {
+                let msg = format!("string expected, got {}", type_name(v.val_idx.basic_type()));
+                return arg_error(l, 1, "tonumber", &msg);
+            }
+        };
+        if !(2..=36).contains(&nb) {
+            return arg_error(l, 2, "tonumber", "base out of range");
+        }
+        str2int_base(unsafe { (*ts).as_bytes() }, nb as u32).map(|i| Some(i).new())
+    };
+    let res = match res {
+        Some(n) => l.push_obj(n),
+        None => l.push_nil(),
+    };
+    match res {
+        Ok(_) => 1,
+        Err(e) => l.raise(e),
+    }
+}
+
+/// brief: tostring(v), the text of v, through its `__tostring` if it has one
+fn lua_tostring(l: &mut LuaState) -> usize {
+    let base = arg_base(l);
+    let nargs = l.get_stack_top() - base;
+    if nargs == 0 {
+        return arg_error(l, 1, "tostring", "value expected");
+    }
+    match l.tostring(nargs - 1) {
+        Ok(_) => 1,
+        Err(e) => l.raise(e),
+    }
+}
+
+/// brief: continuation of `pcall` and `xpcall`, extra is the number of slots
+/// below the results that are not returned
+fn finish_pcall(l: &mut LuaState, status: ErrCode, extra: KCTX) -> usize {
+    if status.0 != STATE_OK && status.0 != STATE_YIELD {
+        // false and the error object, which is on top
+        let err = match l.get_stkelem_fromtop(0) {
+            Ok(err) => err,
+            Err(e) => return l.raise(e),
+        };
+        let res = l.push_bool(false).and_then(|_| l.push_obj(err));
+        return match res {
+            Ok(_) => 2,
+            Err(e) => l.raise(e),
+        };
+    }
+    l.arg_count() - extra as usize
+}
+
+/// brief: make room for one value at slot, moving up the values from it
+fn insert_at(l: &mut LuaState, slot: usize, v: TObj) -> Result<ErrCode, ErrCode> {
+    l.stack_check(1)?;
+    let stk = l.get_stack_mut_ref()?;
+    for j in (slot..l.stack_top_index).rev() {
+        stk.set_elem(j + 1, stk.get_elem(j)?)?;
+    }
+    stk.set_elem(slot, v)?;
+    l.move_top(1, true);
+    Ok(ErrCode(FINE))
+}
+
+/// brief: pcall(f, ...), true and the results of f or false and the error object
+fn lua_pcall(l: &mut LuaState) -> usize {
+    let base = arg_base(l);
+    let nargs = l.get_stack_top() - base;
+    if nargs == 0 {
+        return arg_error(l, 1, "pcall", "value expected");
+    }
+    // true goes below the function, as first result
+    if let Err(e) = insert_at(l, base, Some(true).new()) {
+        return l.raise(e);
+    }
+    match l.pcall_k(nargs - 1, LUA_MUL_RET, None, 0, Some(finish_pcall)) {
+        Ok(status) => finish_pcall(l, status, 0),
+        Err(_) if l.get_status().0 == STATE_YIELD => 0,
+        Err(e) => l.raise(e),
+    }
+}
+
+/// brief: xpcall(f, msgh, ...), pcall with msgh as message handler
+fn lua_xpcall(l: &mut LuaState) -> usize {
+    let base = arg_base(l);
+    let nargs = l.get_stack_top() - base;
+    if nargs < 2 {
+        return arg_error(l, 2, "xpcall", "value expected");
+    }
+    // f, msgh, true, f, args...
+    let f = match l.get_stkelem_fromtop(nargs - 1) {
+        Ok(f) => f,
+        Err(e) => return l.raise(e),
+    };
+    let res = insert_at(l, base + 2, Some(true).new()).and_then(|_| insert_at(l, base + 3, f));
+    if let Err(e) = res {
+        return l.raise(e);
+    }
+    let msgh = l.get_stack_top() - base - 2;
+    match l.pcall_k(nargs - 2, LUA_MUL_RET, Some(msgh), 2, Some(finish_pcall)) {
+        Ok(status) => finish_pcall(l, status, 2),
+        Err(_) if l.get_status().0 == STATE_YIELD => 0,
+        Err(e) => l.raise(e),
+    }
+}
+
+impl LuaState {
+    /// brief: set the functions of the base library as global variables
+    pub fn open_base(&mut self) -> Result<ErrCode, ErrCode> {
+        self.push_global_table()?;
+        for (name, f) in BASE_FUNCS.iter() {
+            self.push_rfunc(*f)?;
+            self.set_field(1, name)?;
+        }
+        self.move_top(1, false);
+        Ok(ErrCode(FINE))
+    }
+}
@@ -0,0 +1,506 @@
+use core::mem::size_of;
+use core::ptr::{null_mut, NonNull};
+use std::rc::Rc;
+
+use crate::compile::proto::Proto;
+use crate::info::lua::{
+    ErrCode, FINE, INVOKE_RCLOSURE_ACTIVE, LUA_REGISTRY_INDEX, MEMORY_TYPE_MISMATCH,
+    MEMORY_UNREACHABLE, RUNTIME_CLOSE,
+};
+use crate::vm::tm::TagMethod;
+
+use super::gc::GcHeader;
+use super::objdef::{ObjectTrait, TObj, T_CCL, T_LCL, T_UPVAL};
+use super::statedef::{LuaState, Stack};
+
+/// brief: the cell of a variable captured by closures;
+/// while the variable is in scope the upvalue is open and refers to its stack slot,
+/// when the scope ends the upvalue is closed and the value moves into it
+#[repr(C)]
+pub struct UpVal {
+    header: GcHeader,
+    v: TObj,                               // the value, once closed
+    open: Option<(NonNull<Stack>, usize)>, // the stack and slot of the variable, while open
+    pub(crate) next: *mut UpVal,           // list of open upvalues of a thread, by decreasing level
+}
+
+impl UpVal {
+    /// brief: a closed upvalue holding v
+    pub fn new(v: TObj) -> *mut UpVal {
+        Box::leak(Box::new(UpVal {
+            header: GcHeader::new(T_UPVAL),
+            v,
+            open: None,
+            next: null_mut(),
+        }))
+    }
+
+    fn new_open(stack: NonNull<Stack>, level: usize) -> *mut UpVal {
+        Box::leak(Box::new(UpVal {
+            header: GcHeader::new(T_UPVAL),
+            v: TObj::default(),
+            open: Some((stack, level)),
+            next: null_mut(),
+        }))
+    }
+
+    /// brief: the stack slot of an open upvalue
+    #[inline(always)]
+    pub fn level(&self) -> Option<usize> {
+        self.open.map(|(_, level)| level)
+    }
+
+    #[inline(always)]
+    pub fn get(&self) -> TObj {
+        match self.open {
+            Some((stk, level)) => unsafe { stk.as_ref() }.get_elem(level).unwrap_or_default(),
+            None => self.v,
+        }
+    }
+
+    #[inline(always)]
+    pub fn set(&mut self, v: TObj) {
+        match self.open {
+            Some((stk, level)) => {
+                let _ = unsafe { stk.as_ref() }.set_elem(level, v);
+            }
+            None => self.v = v,
+        }
+    }
+
+    /// brief: move the value of the variable into the upvalue
+    pub(crate) fn close(&mut self) {
+        self.v = self.get();
+        self.open = None;
+    }
+}
+
+/// brief: a Lua function, a prototype and the upvalues it captured
+#[repr(C)]
+pub struct LClosure {
+    header: GcHeader,
+    pub p: Rc<Proto>,
+    pub upvals: Vec<*mut UpVal>,
+    pub(crate) k: Vec<TObj>, // the constants of the prototype as values, made once
+}
+
+impl LClosure {
+    pub fn new(p: Rc<Proto>, upvals: Vec<*mut UpVal>, k: Vec<TObj>) -> *mut LClosure {
+        Box::leak(Box::new(LClosure {
+            header: GcHeader::new(T_LCL),
+            p,
+            upvals,
+            k,
+        }))
+    }
+
+    /// brief: the bytes the closure accounts for
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<LClosure>()
+            + self.upvals.len() * size_of::<*mut UpVal>()
+            + self.k.len() * size_of::<TObj>()
+    }
+}
+
+/// brief: the callable of a Rust closure, it returns the number of results it pushed;
+/// it is not reentrant: calling the closure again before it returns raises an error
+pub type RFn = Box<dyn FnMut(&mut LuaState) -> usize>;
+
+/// brief: a Rust function, the state it captured and the Lua values it keeps as upvalues
+#[repr(C)]
+pub struct RClosure {
+    header: GcHeader,
+    f: Option<RFn>, // taken out while the closure runs
+    pub upvals: Vec<TObj>,
+}
+
+impl RClosure {
+    pub fn new(f: RFn, upvals: Vec<TObj>) -> *mut RClosure {
+        Box::leak(Box::new(RClosure {
+            header: GcHeader::new(T_CCL),
+            f: Some(f),
+            upvals,
+        }))
+    }
+
+    /// brief: the bytes the closure accounts for
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<RClosure>() + self.upvals.len() * size_of::<TObj>()
+    }
+}
+
+impl LuaState {
+    /// brief: push a Rust closure, the n values on top, which are popped, become its upvalues;
+    /// inside the call they are reached with `lua_upvalue_index`
+    pub fn push_rclosure(&mut self, f: RFn, n: usize) -> Result<ErrCode, ErrCode> {
+        if n > self.stack_top_index {
+            return Err(ErrCode(MEMORY_UNREACHABLE));
+        }
+        let mut upvals = Vec::with_capacity(n);
+        for j in (0..n).rev() {
+            upvals.push(self.get_stkelem_fromtop(j)?);
+        }
+        self.move_top(n, false);
+        let rcl = self.global_mut()?.link(RClosure::new(f, upvals));
+        self.push_obj(Some(rcl).new())?;
+        self.check_gc()
+    }
+
+    /// brief: run a Rust closure and give the number of results it pushed;
+    /// its callable is taken out of it meanwhile, a call from inside itself raises an error
+    pub(crate) fn call_rclosure(&mut self, rcl: *mut RClosure) -> Result<usize, ErrCode> {
+        let mut f = unsafe { (&mut *rcl).f.take() }.ok_or(ErrCode(INVOKE_RCLOSURE_ACTIVE))?;
+        let n = f(self);
+        unsafe { (*rcl).f = Some(f) };
+        Ok(n)
+    }
+
+    /// brief: the running Rust closure, the function of the innermost frame
+    fn running_rclosure(&self) -> Result<*mut RClosure, ErrCode> {
+        let frame = self.get_frame(self.ncalls.wrapping_sub(1))?;
+        let f = self.get_stack_mut_ref()?.get_elem(frame.stack_func_index)?;
+        Option::<*mut RClosure>::into_inner(&f).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))
+    }
+
+    /// brief: the registry, or an upvalue, of a pseudo-index step
+    pub(crate) fn pseudo_get(&self, step: usize) -> Result<TObj, ErrCode> {
+        if step == LUA_REGISTRY_INDEX {
+            return Ok(Some(self.global()?.registry).new());
+        }
+        let rcl = unsafe { &*self.running_rclosure()? };
+        rcl.upvals
+            .get((step - LUA_REGISTRY_INDEX).wrapping_sub(1))
+            .copied()
+            .ok_or(ErrCode(MEMORY_UNREACHABLE))
+    }
+
+    /// brief: pop the value on top into the slot `step` below it,
+    /// or into an upvalue of the running Rust closure for a pseudo-index
+    pub fn replace_fromtop(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let v = self.get_stkelem_fromtop(0)?;
+        if step > LUA_REGISTRY_INDEX {
+            let rcl = self.running_rclosure()?;
+            let uv = unsafe { &mut *rcl }
+                .upvals
+                .get_mut((step - LUA_REGISTRY_INDEX).wrapping_sub(1))
+                .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+            *uv = v;
+            self.global_mut()?.barrier(rcl as *mut GcHeader, &v);
+        } else if step == LUA_REGISTRY_INDEX {
+            return Err(ErrCode(MEMORY_TYPE_MISMATCH));
+        } else {
+            let index = self.stack_top_index - 1 - step;
+            self.get_stack_mut_ref()?.set_elem(index, v)?;
+        }
+        self.move_top(1, false);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: the open upvalue of a stack slot, created if no closure captured it yet,
+    /// so that sibling closures share it
+    pub(crate) fn find_upval(&mut self, level: usize) -> Result<*mut UpVal, ErrCode> {
+        let stack = self.stack.ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+        let mut prev: *mut UpVal = null_mut();
+        let mut p = self.openupval;
+        while !p.is_null() {
+            let uv = unsafe { &*p };
+            match uv.level() {
+                Some(l) if l > level => {}
+                Some(l) if l == level => return Ok(p),
+                _ => break,
+            }
+            prev = p;
+            p = uv.next;
+        }
+        // not found, link a new one before the first upvalue of a lower level
+        let uv = self.global_mut()?.link(UpVal::new_open(stack, level));
+        unsafe { (*uv).next = p };
+        if prev.is_null() {
+            self.openupval = uv;
+        } else {
+            unsafe { (*prev).next = uv };
+        }
+        Ok(uv)
+    }
+
+    /// brief: close the open upvalues of the stack slots at or above level
+    pub(crate) fn close_upvals(&mut self, level: usize) -> Result<ErrCode, ErrCode> {
+        while !self.openupval.is_null() {
+            let p = self.openupval;
+            let uv = unsafe { &mut *p };
+            match uv.level() {
+                Some(l) if l >= level => {}
+                _ => break,
+            }
+            self.openupval = uv.next;
+            uv.next = null_mut();
+            uv.close();
+            self.global_mut()?
+                .close_barrier(p as *mut GcHeader, &uv.get());
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: mark the variable at level as to-be-closed, it must be false, nil
+    /// or have a `__close` metamethod
+    pub(crate) fn new_tbc(&mut self, level: usize) -> Result<ErrCode, ErrCode> {
+        let obj = self.get_stack_mut_ref()?.get_elem(level)?;
+        if obj.is_false() {
+            return Ok(ErrCode(FINE));
+        }
+        if self.get_tm_by_obj(&obj, TagMethod::Close)?.is_nil() {
+            return Err(ErrCode(RUNTIME_CLOSE));
+        }
+        self.tbclist.push(level);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: call the `__close` metamethods of the to-be-closed variables at or above level,
+    /// the last one declared first; err is the error object that made them go out of scope,
+    /// nil on a normal exit
+    pub(crate) fn close_tbc(&mut self, level: usize, err: TObj) -> Result<ErrCode, ErrCode> {
+        while let Some(&tbc) = self.tbclist.last() {
+            if tbc < level {
+                break;
+            }
+            self.tbclist.pop();
+            let obj = self.get_stack_mut_ref()?.get_elem(tbc)?;
+            let tm = self.get_tm_by_obj(&obj, TagMethod::Close)?;
+            if tm.is_nil() {
+                return Err(ErrCode(RUNTIME_CLOSE));
+            }
+            self.call_tm(tm, &[obj, err], 0)?;
+        }
+        Ok(ErrCode(FINE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::info::lua::lua_upvalue_index;
+    use crate::obj::objdef::INT;
+    use crate::vm::machine::tests::{eval, new_state, run};
+
+    #[test]
+    fn sibling_closures_share_upvalues() {
+        let src = "local function counter()
+                       local n = 0
+                       return function() n = n + 1 return n end, function() return n end
+                   end
+                   local inc, get = counter()
+                   inc() inc()
+                   local inc2 = counter()
+                   inc2()
+                   return get(), inc2()";
+        assert_eq!(eval(src), ["2", "2"]);
+        // an upvalue of an upvalue is the same variable
+        let src = "local x = 1
+                   local function f() return function() x = x + 1 return x end end
+                   local g = f() g()
+                   return x, g()";
+        assert_eq!(eval(src), ["2", "3"]);
+    }
+
+    #[test]
+    fn a_variable_per_iteration() {
+        let src = "local fs = {}
+                   for i = 1, 3 do local j = i * 10 fs[i] = function() return i + j end end
+                   return fs[1](), fs[2](), fs[3]()";
+        assert_eq!(eval(src), ["11", "22", "33"]);
+        // closed by break as well
+        let src = "local fs, i = {}, 0
+                   while true do
+                       i = i + 1
+                       local x = i
+                       fs[i] = function() x = x + 1 return x end
+                       if i == 2 then break end
+                   end
+                   return fs[1](), fs[1](), fs[2]()";
+        assert_eq!(eval(src), ["2", "3", "3"]);
+    }
+
+    #[test]
+    fn open_upvalues_by_level() {
+        let l = new_state();
+        let base = l.get_stack_top();
+        for j in 0..3 {
+            l.push_integer(j).unwrap();
+        }
+        let b = l.find_upval(base + 1).unwrap();
+        let a = l.find_upval(base).unwrap();
+        let c = l.find_upval(base + 2).unwrap();
+        assert_eq!(l.find_upval(base + 1).unwrap(), b);
+        // the list goes down the stack
+        let mut levels = Vec::new();
+        let mut p = l.openupval;
+        while !p.is_null() {
+            levels.push(unsafe { (*p).level() }.unwrap() - base);
+            p = unsafe { (*p).next };
+        }
+        assert_eq!(levels, [2, 1, 0]);
+        unsafe { (*b).set(Some(10 as INT).new()) };
+        l.close_upvals(base + 1).unwrap();
+        let (a, b, c) = unsafe { (&mut *a, &mut *b, &mut *c) };
+        assert_eq!((a.level(), b.level(), c.level()), (Some(base), None, None));
+        // a closed upvalue keeps the value and no longer sees the slot
+        l.get_stack_mut_ref()
+            .unwrap()
+            .set_elem(base + 1, Some(0 as INT).new())
+            .unwrap();
+        assert_eq!(Option::<INT>::into_inner(&b.get()), Some(10));
+        assert_eq!(Option::<INT>::into_inner(&c.get()), Some(2));
+        l.close_upvals(base).unwrap();
+        assert!(l.openupval.is_null());
+        l.move_top_to(base);
+    }
+
+    #[test]
+    fn closed_upvalues_survive_collection() {
+        let l = new_state();
+        let src = b"local n = 41 return function() n = n + 1 return n end";
+        l.load(src, "=test").unwrap();
+        l.call(0, 1).unwrap();
+        assert!(l.openupval.is_null());
+        l.gc_collect().unwrap();
+        for n in [42, 43] {
+            let f = l.get_stkelem_fromtop(0).unwrap();
+            l.push_obj(f).unwrap();
+            l.call(0, 1).unwrap();
+            assert_eq!(l.get_integer_fromtop(0).unwrap(), n);
+            l.move_top(1, false);
+            l.gc_collect().unwrap();
+        }
+        l.move_top(1, false);
+    }
+
+    /// brief: make a Rust closure, with the n values on top as upvalues, the global name
+    fn set_global_rclosure(l: &mut LuaState, name: &[u8], f: RFn, n: usize) {
+        l.push_rclosure(f, n).unwrap();
+        l.push_global_table().unwrap();
+        let f = l.get_stkelem_fromtop(1).unwrap();
+        l.push_obj(f).unwrap();
+        l.set_field(1, name).unwrap();
+        l.move_top(2, false);
+    }
+
+    #[test]
+    fn captured_state() {
+        let l = new_state();
+        let mut n: INT = 0;
+        let f: RFn = Box::new(move |l| {
+            n += 1;
+            l.push_integer(n).unwrap();
+            1
+        });
+        set_global_rclosure(l, b"count", f, 0);
+        assert_eq!(run(l, "count() count() return count()").unwrap(), ["3"]);
+        // two closures share what they captured
+        let shared = Rc::new(Cell::new(0));
+        let s = shared.clone();
+        set_global_rclosure(
+            l,
+            b"add",
+            Box::new(move |_| {
+                s.set(s.get() + 1);
+                0
+            }),
+            0,
+        );
+        let s = shared.clone();
+        let f: RFn = Box::new(move |l| {
+            l.push_integer(s.get()).unwrap();
+            1
+        });
+        set_global_rclosure(l, b"get", f, 0);
+        assert_eq!(run(l, "add() add() return get()").unwrap(), ["2"]);
+        assert_eq!(shared.get(), 2);
+    }
+
+    #[test]
+    fn upvalues_of_a_rust_closure() {
+        let l = new_state();
+        l.push_integer(5).unwrap();
+        l.push_string(b"kept").unwrap();
+        let f: RFn = Box::new(|l| {
+            let v = l.get_integer_fromtop(lua_upvalue_index(1)).unwrap() + 1;
+            l.push_integer(v).unwrap();
+            l.replace_fromtop(lua_upvalue_index(1)).unwrap();
+            l.push_integer(v).unwrap();
+            let kept = l.get_stkelem_fromtop(lua_upvalue_index(2)).unwrap();
+            l.push_obj(kept).unwrap();
+            2
+        });
+        set_global_rclosure(l, b"f", f, 2);
+        l.gc_collect().unwrap();
+        assert_eq!(run(l, "f() f() return f()").unwrap(), ["8", "kept"]);
+    }
+
+    /// brief: a value that counts its drops
+    struct Tracked(Rc<Cell<usize>>);
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn captured_state_dropped_with_the_closure() {
+        let l = new_state();
+        let drops = Rc::new(Cell::new(0));
+        let t = Tracked(drops.clone());
+        l.push_rclosure(
+            Box::new(move |_| {
+                let _ = &t;
+                0
+            }),
+            0,
+        )
+        .unwrap();
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 0);
+        l.move_top(1, false);
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 1);
+        // and when the state is closed
+        let t = Tracked(drops.clone());
+        l.push_rclosure(
+            Box::new(move |_| {
+                let _ = &t;
+                0
+            }),
+            0,
+        )
+        .unwrap();
+        l.close().unwrap();
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn not_reentrant() {
+        let l = new_state();
+        // with an argument it calls itself
+        let f: RFn = Box::new(|l| {
+            if l.arg_count() == 0 {
+                l.push_integer(1).unwrap();
+                return 1;
+            }
+            l.push_global_table().unwrap();
+            l.get_field(0, b"f").unwrap();
+            match l.call(0, 0) {
+                Ok(_) => 0,
+                Err(e) => l.raise(e),
+            }
+        });
+        set_global_rclosure(l, b"f", f, 0);
+        let e = run(l, "f(true)").unwrap_err();
+        assert!(e.contains("Rust closure that is running"), "{}", e);
+        // it can be called again once it returned
+        assert_eq!(run(l, "return f()").unwrap(), ["1"]);
+    }
+}
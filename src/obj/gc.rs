@@ -0,0 +1,1951 @@
+use core::mem::{size_of, take};
+use core::ptr::null_mut;
+
+use crate::info::lua::{ErrCode, FINE};
+
+use super::closure::{LClosure, RClosure, UpVal};
+use super::objdef::{
+    DataType, Dt, ObjectTrait, TObj, T_CCL, T_DEADKEY, T_LCL, T_LNG_STR, T_SHR_STR, T_TABLE,
+    T_THREAD, T_UPVAL, T_USER_DATA,
+};
+use super::statedef::{GlobalState, LuaState};
+use super::string::LuaString;
+use super::table::{LuaTable, Node};
+use super::userdata::LuaUserData;
+use crate::vm::tm::TagMethod;
+
+// bits of `marked`; an object is white, gray (no color bit) or black
+const WHITE0BIT: u8 = 3;
+const WHITE1BIT: u8 = 4;
+const BLACKBIT: u8 = 5;
+const FINALIZEDBIT: u8 = 6; // the object has a finalizer, so it is kept in `finobj`
+const WHITEBITS: u8 = (1 << WHITE0BIT) | (1 << WHITE1BIT);
+const AGEBITS: u8 = 0x07; // the age, in generational mode
+const MASKGCBITS: u8 = WHITEBITS | (1 << BLACKBIT) | AGEBITS;
+
+const GCSWEEPMAX: usize = 100; // objects swept in a single step
+const WORK2MEM: isize = size_of::<TObj>() as isize; // a unit of work is worth this many bytes
+const PAUSEADJ: usize = 100;
+const LUAI_GCPAUSE: usize = 200; // wait for the memory in use to double
+const LUAI_GCMUL: usize = 100; // work a unit per unit of allocation
+const LUAI_GCSTEPSIZE: u32 = 13; // log2 of a step, 8 KB
+const LUAI_GENMINORMUL: usize = 20; // a minor collection after the memory grows by 20%
+const LUAI_GENMAJORMUL: usize = 100; // a major collection after the memory doubles
+const GCFINMAX: usize = 10; // finalizers called in a single step
+const GCFINALIZECOST: usize = 50; // the work of calling a finalizer
+
+// reasons for the collector not to run
+pub(crate) const GCSTPUSR: u8 = 1; // stopped by the user
+pub(crate) const GCSTPGC: u8 = 2; // stopped while running a finalizer
+pub(crate) const GCSTPCLS: u8 = 4; // stopped while closing the state
+
+// ages of the objects in generational mode
+const G_NEW: u8 = 0; // created in the current cycle
+const G_SURVIVAL: u8 = 1; // created in the previous cycle
+const G_OLD0: u8 = 2; // made old by a forward barrier in this cycle
+const G_OLD1: u8 = 3; // first full cycle as old
+const G_OLD: u8 = 4; // really old, not visited by minor collections
+const G_TOUCHED1: u8 = 5; // old object touched in this cycle
+const G_TOUCHED2: u8 = 6; // old object touched in the previous cycle
+
+/// brief: the header every collectable object starts with
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct GcHeader {
+    pub(crate) next: *mut GcHeader, // list of all the objects
+    pub(crate) tt: Dt,
+    pub(crate) marked: u8,
+}
+
+impl GcHeader {
+    pub fn new(tt: Dt) -> Self {
+        Self {
+            next: null_mut(),
+            tt,
+            marked: 0,
+        }
+    }
+}
+
+/// brief: an object that starts with a `GcHeader`, so it can be linked to the collector
+/// # Safety
+/// the type must be `repr(C)` with the header as its first field
+pub(crate) unsafe trait Collectable {}
+
+unsafe impl Collectable for LuaString {}
+unsafe impl Collectable for LuaTable {}
+unsafe impl Collectable for LClosure {}
+unsafe impl Collectable for RClosure {}
+unsafe impl Collectable for UpVal {}
+unsafe impl Collectable for LuaState {}
+unsafe impl Collectable for LuaUserData {}
+
+/// brief: the modes of the collector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcKind {
+    Incremental,
+    Generational,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GcState {
+    Propagate,
+    EnterAtomic,
+    Atomic,
+    SwpAllGc,
+    SwpFinObj,
+    SwpToBeFnz,
+    SwpEnd,
+    CallFin,
+    Pause,
+}
+
+/// brief: the state of the collector
+#[derive(Debug)]
+pub struct Collector {
+    pub(crate) allgc: *mut GcHeader, // every collectable object
+    finobj: *mut GcHeader,           // objects with finalizers
+    tobefnz: *mut GcHeader,          // objects unreachable, to be finalized
+    sweepgc: *mut *mut GcHeader,     // the link where sweeping resumes
+    gray: Vec<*mut GcHeader>,        // objects marked, their references not yet
+    grayagain: Vec<*mut GcHeader>,   // objects to traverse again in the atomic phase
+    weak: Vec<*mut GcHeader>,        // tables with weak values to clear
+    ephemeron: Vec<*mut GcHeader>,   // tables with weak keys, with white keys to white values
+    allweak: Vec<*mut GcHeader>,     // tables with weak entries to clear
+    pub(crate) threads: Vec<*mut LuaState>, // every thread but the main one
+    borrowed: Vec<*mut LuaUserData>, // userdata borrowed from Rust, kept alive meanwhile
+    currentwhite: u8,
+    pub(crate) state: GcState,
+    pub(crate) gcstp: u8,      // why the collector must not run, 0 when it may
+    totalbytes: isize,         // bytes in use minus the debt
+    pub(crate) debt: isize,    // bytes allocated and not yet paid for with work
+    estimate: usize,           // an estimate of the bytes in use by live objects
+    pub(crate) pause: usize,   // percentage of the live memory to wait for before a cycle
+    pub(crate) stepmul: usize, // speed of the collection relative to allocation
+    pub(crate) stepsize: u32,  // log2 of the bytes allocated between steps
+    pub(crate) kind: GcKind,
+    // generational mode: allgc is split by age, the youngest objects first
+    survival: *mut GcHeader,  // first object of the survivals
+    old1: *mut GcHeader,      // first object of the old1 ones
+    reallyold: *mut GcHeader, // first object of the really old ones
+    firstold1: *mut GcHeader, // first old1 object among the younger ones, if any
+    finobjsur: *mut GcHeader, // the same splits for the objects with finalizers
+    finobjold1: *mut GcHeader,
+    finobjrold: *mut GcHeader,
+    lastatomic: usize, // objects marked by the last major collection if it was bad, else 0
+    pub(crate) genminormul: usize, // percentage of growth that triggers a minor collection
+    pub(crate) genmajormul: usize, // percentage of growth that triggers a major collection
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self {
+            allgc: null_mut(),
+            finobj: null_mut(),
+            tobefnz: null_mut(),
+            sweepgc: null_mut(),
+            gray: Vec::new(),
+            grayagain: Vec::new(),
+            weak: Vec::new(),
+            ephemeron: Vec::new(),
+            allweak: Vec::new(),
+            threads: Vec::new(),
+            borrowed: Vec::new(),
+            currentwhite: 1 << WHITE0BIT,
+            state: GcState::Pause,
+            gcstp: 0,
+            totalbytes: 0,
+            debt: 0,
+            estimate: 0,
+            pause: LUAI_GCPAUSE,
+            stepmul: LUAI_GCMUL,
+            stepsize: LUAI_GCSTEPSIZE,
+            kind: GcKind::Incremental,
+            survival: null_mut(),
+            old1: null_mut(),
+            reallyold: null_mut(),
+            firstold1: null_mut(),
+            finobjsur: null_mut(),
+            finobjold1: null_mut(),
+            finobjrold: null_mut(),
+            lastatomic: 0,
+            genminormul: LUAI_GENMINORMUL,
+            genmajormul: LUAI_GENMAJORMUL,
+        }
+    }
+}
+
+#[inline(always)]
+fn is_white(o: *mut GcHeader) -> bool {
+    unsafe { (*o).marked & WHITEBITS != 0 }
+}
+
+#[inline(always)]
+fn is_black(o: *mut GcHeader) -> bool {
+    unsafe { (*o).marked & (1 << BLACKBIT) != 0 }
+}
+
+#[inline(always)]
+fn set_gray(o: *mut GcHeader) {
+    unsafe { (*o).marked &= !(WHITEBITS | (1 << BLACKBIT)) };
+}
+
+#[inline(always)]
+fn set_black(o: *mut GcHeader) {
+    unsafe { (*o).marked = ((*o).marked & !WHITEBITS) | (1 << BLACKBIT) };
+}
+
+#[inline(always)]
+fn get_age(o: *mut GcHeader) -> u8 {
+    unsafe { (*o).marked & AGEBITS }
+}
+
+#[inline(always)]
+fn set_age(o: *mut GcHeader, age: u8) {
+    unsafe { (*o).marked = ((*o).marked & !AGEBITS) | age };
+}
+
+#[inline(always)]
+fn is_old(o: *mut GcHeader) -> bool {
+    get_age(o) > G_SURVIVAL
+}
+
+/// brief: the collectable object a value refers to
+pub(crate) fn gc_value(v: &TObj) -> Option<*mut GcHeader> {
+    if v.val_idx.into_inner() == T_DEADKEY {
+        return None;
+    }
+    match v.val {
+        DataType::LClosure(Some(p)) => Some(p as *mut GcHeader),
+        DataType::RClosure(Some(p)) => Some(p as *mut GcHeader),
+        DataType::String(Some(p)) => Some(p as *mut GcHeader),
+        DataType::Table(Some(p)) => Some(p as *mut GcHeader),
+        DataType::FullUserData(Some(p)) => Some(p as *mut GcHeader),
+        DataType::Thread(Some(p)) => Some(p as *mut GcHeader),
+        _ => None,
+    }
+}
+
+/// brief: the value of a collectable object, the reverse of `gc_value`
+fn gc_object_value(o: *mut GcHeader) -> TObj {
+    match unsafe { (*o).tt } {
+        T_TABLE => Some(o as *mut LuaTable).new(),
+        T_LCL => Some(o as *mut LClosure).new(),
+        T_CCL => Some(o as *mut RClosure).new(),
+        T_SHR_STR | T_LNG_STR => Some(o as *mut LuaString).new(),
+        T_THREAD => Some(o as *mut LuaState).new(),
+        T_USER_DATA => Some(o as *mut LuaUserData).new(),
+        _ => TObj::default(),
+    }
+}
+
+/// brief: the object is in `finobj` or `tobefnz`
+#[inline(always)]
+fn to_finalize(o: *mut GcHeader) -> bool {
+    unsafe { (*o).marked & (1 << FINALIZEDBIT) != 0 }
+}
+
+#[inline(always)]
+fn val_is_white(v: &TObj) -> bool {
+    gc_value(v).is_some_and(is_white)
+}
+
+/// brief: the key of an empty node may be collected, it must not be followed anymore
+#[inline(always)]
+fn clear_key(n: &mut Node) {
+    if gc_value(&n.key).is_some() {
+        n.key.set_dead_key();
+    }
+}
+
+/// brief: the bytes an object accounts for
+fn obj_size(o: *mut GcHeader) -> usize {
+    unsafe {
+        match (*o).tt {
+            T_SHR_STR | T_LNG_STR => (*(o as *mut LuaString)).mem_size(),
+            T_TABLE => (*(o as *mut LuaTable)).mem_size(),
+            T_LCL => (*(o as *mut LClosure)).mem_size(),
+            T_CCL => (*(o as *mut RClosure)).mem_size(),
+            T_UPVAL => size_of::<UpVal>(),
+            T_THREAD => (*(o as *mut LuaState)).mem_size(),
+            T_USER_DATA => (*(o as *mut LuaUserData)).mem_size(),
+            _ => 0,
+        }
+    }
+}
+
+impl GlobalState {
+    /// brief: hand a new object over to the collector
+    pub(crate) fn link<T: Collectable>(&mut self, o: *mut T) -> *mut T {
+        let h = o as *mut GcHeader;
+        unsafe {
+            (*h).marked = self.gc.currentwhite;
+            (*h).next = self.gc.allgc;
+        }
+        self.gc.allgc = h;
+        self.gc.debt += obj_size(h) as isize;
+        if unsafe { (*h).tt } == T_THREAD {
+            self.gc.threads.push(o as *mut LuaState);
+        }
+        o
+    }
+
+    /// brief: count the growth, or the shrinking, of an object
+    #[inline(always)]
+    pub(crate) fn account(&mut self, delta: isize) {
+        self.gc.debt += delta;
+    }
+
+    #[inline(always)]
+    fn other_white(&self) -> u8 {
+        self.gc.currentwhite ^ WHITEBITS
+    }
+
+    /// brief: an object left white by the last atomic phase and not yet swept
+    #[inline(always)]
+    pub(crate) fn is_dead(&self, o: *mut GcHeader) -> bool {
+        unsafe { (*o).marked & self.other_white() != 0 }
+    }
+
+    /// brief: bring back a dead object that is found again, such as an interned string
+    #[inline(always)]
+    pub(crate) fn resurrect(&self, o: *mut GcHeader) {
+        unsafe { (*o).marked ^= WHITEBITS };
+    }
+
+    /// brief: whether black objects exist, so that the invariant must hold
+    #[inline(always)]
+    fn keep_invariant(&self) -> bool {
+        self.gc.state <= GcState::Atomic
+    }
+
+    #[inline(always)]
+    fn is_sweep_phase(&self) -> bool {
+        GcState::SwpAllGc <= self.gc.state && self.gc.state <= GcState::SwpEnd
+    }
+
+    #[inline(always)]
+    fn make_white(&self, o: *mut GcHeader) {
+        unsafe { (*o).marked = ((*o).marked & !MASKGCBITS) | self.gc.currentwhite };
+    }
+
+    pub(crate) fn total_bytes(&self) -> usize {
+        (self.gc.totalbytes + self.gc.debt) as usize
+    }
+
+    fn set_debt(&mut self, debt: isize) {
+        let tb = self.total_bytes() as isize;
+        self.gc.totalbytes = tb - debt;
+        self.gc.debt = debt;
+    }
+
+    /// brief: wait until the memory in use grows by `pause` percent of the estimate
+    fn set_pause(&mut self) {
+        let estimate = self.gc.estimate / PAUSEADJ;
+        let threshold = if self.gc.pause < isize::MAX as usize / estimate.max(1) {
+            (estimate * self.gc.pause) as isize
+        } else {
+            isize::MAX
+        };
+        let debt = (self.total_bytes() as isize).saturating_sub(threshold);
+        self.set_debt(debt.min(0));
+    }
+
+    fn mark_object(&mut self, o: *mut GcHeader) {
+        if is_white(o) {
+            self.really_mark(o);
+        }
+    }
+
+    fn really_mark(&mut self, o: *mut GcHeader) {
+        match unsafe { (*o).tt } {
+            T_SHR_STR | T_LNG_STR => set_black(o),
+            T_UPVAL => {
+                let uv = unsafe { &*(o as *mut UpVal) };
+                // an open upvalue stays gray, its value is on a stack
+                if uv.level().is_some() {
+                    set_gray(o);
+                } else {
+                    set_black(o);
+                    self.mark_value(&uv.get());
+                }
+            }
+            T_USER_DATA if unsafe { (*(o as *mut LuaUserData)).nuvalue() } == 0 => {
+                // nothing to traverse but the metatable
+                let mt = unsafe { (*(o as *mut LuaUserData)).metatable };
+                if !mt.is_null() {
+                    self.mark_object(mt as *mut GcHeader);
+                }
+                set_black(o);
+            }
+            _ => {
+                set_gray(o);
+                self.gc.gray.push(o);
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn mark_value(&mut self, v: &TObj) {
+        if let Some(o) = gc_value(v) {
+            self.mark_object(o);
+        }
+    }
+
+    /// brief: whether a table has weak keys and weak values, by the `__mode` of its metatable
+    fn weak_mode(&self, t: &LuaTable) -> (bool, bool) {
+        if t.metatable.is_null() {
+            return (false, false);
+        }
+        let ename = self.tmname[TagMethod::Mode as usize];
+        let mode = unsafe { (*t.metatable).get_tm(TagMethod::Mode, ename) };
+        match Option::<*mut LuaString>::into_inner(&mode) {
+            Some(ts) if unsafe { (*ts).is_short() } => {
+                let m = unsafe { (*ts).as_bytes() };
+                (m.contains(&b'k'), m.contains(&b'v'))
+            }
+            _ => (false, false),
+        }
+    }
+
+    /// brief: whether a weak entry with this key or value must go; strings are values,
+    /// they are never removed from weak tables and are marked instead
+    fn is_cleared(&mut self, v: &TObj) -> bool {
+        match gc_value(v) {
+            None => false,
+            Some(o) if matches!(unsafe { (*o).tt }, T_SHR_STR | T_LNG_STR) => {
+                self.mark_object(o);
+                false
+            }
+            Some(o) => is_white(o),
+        }
+    }
+
+    #[inline(always)]
+    fn link_gray(list: &mut Vec<*mut GcHeader>, o: *mut GcHeader) {
+        set_gray(o);
+        list.push(o);
+    }
+
+    fn traverse_table(&mut self, o: *mut GcHeader) -> usize {
+        let t = unsafe { &mut *(o as *mut LuaTable) };
+        if !t.metatable.is_null() {
+            self.mark_object(t.metatable as *mut GcHeader);
+        }
+        match self.weak_mode(t) {
+            (false, false) => self.traverse_strong_table(o),
+            (false, true) => self.traverse_weak_value(o),
+            (true, false) => {
+                self.traverse_ephemeron(o, false);
+            }
+            // nothing to traverse now
+            (true, true) => Self::link_gray(&mut self.gc.allweak, o),
+        }
+        1 + t.array.len() + 2 * t.node.len()
+    }
+
+    fn traverse_strong_table(&mut self, o: *mut GcHeader) {
+        let t = unsafe { &mut *(o as *mut LuaTable) };
+        for v in t.array.iter() {
+            self.mark_value(v);
+        }
+        for n in t.node.iter_mut() {
+            if n.val.is_nil() {
+                clear_key(n);
+            } else {
+                self.mark_value(&n.key);
+                self.mark_value(&n.val);
+            }
+        }
+        self.gen_link(o);
+    }
+
+    /// brief: mark the keys of a table with weak values,
+    /// it is visited again in the atomic phase to clear the values
+    fn traverse_weak_value(&mut self, o: *mut GcHeader) {
+        let t = unsafe { &mut *(o as *mut LuaTable) };
+        // the array part may have white values, not worth checking now
+        let mut hasclears = !t.array.is_empty();
+        for n in t.node.iter_mut() {
+            if n.val.is_nil() {
+                clear_key(n);
+            } else {
+                self.mark_value(&n.key);
+                if !hasclears && self.is_cleared(&n.val) {
+                    hasclears = true;
+                }
+            }
+        }
+        if self.gc.state == GcState::Atomic && hasclears {
+            Self::link_gray(&mut self.gc.weak, o);
+        } else {
+            Self::link_gray(&mut self.gc.grayagain, o);
+        }
+    }
+
+    /// brief: mark the values of the entries whose keys are marked, an ephemeron table
+    /// keeps an entry only as long as its key is reachable from outside the entry;
+    /// `inv` traverses the nodes backwards; whether anything was marked
+    fn traverse_ephemeron(&mut self, o: *mut GcHeader, inv: bool) -> bool {
+        let t = unsafe { &mut *(o as *mut LuaTable) };
+        let mut marked = false;
+        let mut hasclears = false; // some key is white
+        let mut hasww = false; // some white key maps to a white value
+        for v in t.array.iter() {
+            if let Some(v) = gc_value(v).filter(|&v| is_white(v)) {
+                marked = true;
+                self.really_mark(v);
+            }
+        }
+        let nsize = t.node.len();
+        for j in 0..nsize {
+            let n = &mut t.node[if inv { nsize - 1 - j } else { j }];
+            if n.val.is_nil() {
+                clear_key(n);
+            } else if self.is_cleared(&n.key) {
+                hasclears = true;
+                if val_is_white(&n.val) {
+                    hasww = true;
+                }
+            } else if let Some(v) = gc_value(&n.val).filter(|&v| is_white(v)) {
+                marked = true;
+                self.really_mark(v);
+            }
+        }
+        if self.gc.state == GcState::Propagate {
+            Self::link_gray(&mut self.gc.grayagain, o);
+        } else if hasww {
+            Self::link_gray(&mut self.gc.ephemeron, o);
+        } else if hasclears {
+            Self::link_gray(&mut self.gc.allweak, o);
+        } else {
+            self.gen_link(o);
+        }
+        marked
+    }
+
+    /// brief: traverse the ephemeron tables until no value gets marked,
+    /// a marked value may make the key of another entry reachable
+    fn converge_ephemerons(&mut self) {
+        let mut dir = false;
+        loop {
+            let list = take(&mut self.gc.ephemeron);
+            let mut changed = false;
+            for &o in list.iter() {
+                set_black(o);
+                if self.traverse_ephemeron(o, dir) {
+                    self.propagate_all();
+                    changed = true;
+                }
+            }
+            // alternate the direction, chains of entries converge faster
+            dir = !dir;
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// brief: remove the entries with unmarked keys
+    fn clear_by_keys(&mut self, list: &[*mut GcHeader]) {
+        for &o in list.iter() {
+            let t = unsafe { &mut *(o as *mut LuaTable) };
+            for n in t.node.iter_mut() {
+                if !n.val.is_nil() && self.is_cleared(&n.key) {
+                    n.val = TObj::default();
+                }
+                if n.val.is_nil() {
+                    clear_key(n);
+                }
+            }
+        }
+    }
+
+    /// brief: remove the entries with unmarked values
+    fn clear_by_values(&mut self, list: &[*mut GcHeader]) {
+        for &o in list.iter() {
+            let t = unsafe { &mut *(o as *mut LuaTable) };
+            for v in t.array.iter_mut() {
+                if self.is_cleared(v) {
+                    *v = TObj::default();
+                }
+            }
+            for n in t.node.iter_mut() {
+                if self.is_cleared(&n.val) {
+                    n.val = TObj::default();
+                }
+                if n.val.is_nil() {
+                    clear_key(n);
+                }
+            }
+        }
+    }
+
+    fn traverse_lclosure(&mut self, cl: *mut LClosure) -> usize {
+        let cl = unsafe { &*cl };
+        for &uv in cl.upvals.iter() {
+            self.mark_object(uv as *mut GcHeader);
+        }
+        for v in cl.k.iter() {
+            self.mark_value(v);
+        }
+        1 + cl.upvals.len() + cl.k.len()
+    }
+
+    fn traverse_rclosure(&mut self, cl: *mut RClosure) -> usize {
+        let cl = unsafe { &*cl };
+        for v in cl.upvals.iter() {
+            self.mark_value(v);
+        }
+        1 + cl.upvals.len()
+    }
+
+    fn traverse_udata(&mut self, o: *mut GcHeader) -> usize {
+        let u = unsafe { &*(o as *mut LuaUserData) };
+        if !u.metatable.is_null() {
+            self.mark_object(u.metatable as *mut GcHeader);
+        }
+        for v in u.uv.iter() {
+            self.mark_value(v);
+        }
+        self.gen_link(o);
+        1 + u.uv.len()
+    }
+
+    /// brief: mark the live part of a stack and its open upvalues;
+    /// in the atomic phase the dead part is cleared too
+    fn traverse_thread(&mut self, th: *mut LuaState, atomic: bool) -> usize {
+        let th = unsafe { &mut *th };
+        let stk = match th.get_stack_mut_ref() {
+            Ok(stk) => stk,
+            Err(_) => return 1,
+        };
+        for j in 0..th.stack_top_index {
+            if let Ok(v) = stk.get_elem(j) {
+                self.mark_value(&v);
+            }
+        }
+        self.mark_value(&th.errobj);
+        let mut uv = th.openupval;
+        while !uv.is_null() {
+            self.mark_object(uv as *mut GcHeader);
+            uv = unsafe { (*uv).next };
+        }
+        if atomic {
+            for j in th.stack_top_index..th.stack_size {
+                let _ = stk.set_elem(j, TObj::default());
+            }
+        }
+        1 + th.stack_size
+    }
+
+    /// brief: traverse a gray object, turning it black
+    fn propagate_mark(&mut self) -> usize {
+        let o = match self.gc.gray.pop() {
+            Some(o) => o,
+            None => return 0,
+        };
+        set_black(o);
+        match unsafe { (*o).tt } {
+            T_TABLE => self.traverse_table(o),
+            T_LCL => self.traverse_lclosure(o as *mut LClosure),
+            T_CCL => self.traverse_rclosure(o as *mut RClosure),
+            T_USER_DATA => self.traverse_udata(o),
+            T_THREAD => {
+                // a stack changes without barriers, so a thread stays gray
+                // and is traversed again in the atomic phase
+                let atomic = self.gc.state == GcState::Atomic;
+                if !atomic || is_old(o) {
+                    Self::link_gray(&mut self.gc.grayagain, o);
+                }
+                self.traverse_thread(o as *mut LuaState, atomic)
+            }
+            _ => 0,
+        }
+    }
+
+    /// brief: the values of the open upvalues of an unmarked thread are marked,
+    /// the upvalues may outlive it and get closed when it dies
+    fn remark_upvals(&mut self) -> usize {
+        let mut work = 0;
+        for j in 0..self.gc.threads.len() {
+            let th = self.gc.threads[j];
+            if !is_white(th as *mut GcHeader) {
+                continue;
+            }
+            let mut uv = unsafe { (*th).openupval };
+            while !uv.is_null() {
+                work += 1;
+                self.mark_value(&unsafe { (*uv).get() });
+                uv = unsafe { (*uv).next };
+            }
+        }
+        work
+    }
+
+    /// brief: close the open upvalues of the threads that are garbage,
+    /// before their stacks are freed; their values were marked by `remark_upvals`
+    fn close_dead_upvals(&mut self) {
+        for j in 0..self.gc.threads.len() {
+            let th = unsafe { &mut *self.gc.threads[j] };
+            if !is_white(th as *mut LuaState as *mut GcHeader) {
+                continue;
+            }
+            while !th.openupval.is_null() {
+                let p = th.openupval;
+                let uv = unsafe { &mut *p };
+                th.openupval = uv.next;
+                uv.next = null_mut();
+                uv.close();
+                let o = p as *mut GcHeader;
+                if is_white(o) {
+                    continue;
+                }
+                set_black(o);
+                if let Some(v) = gc_value(&uv.get()) {
+                    if is_old(o) && !is_old(v) {
+                        // v must not be collected by a minor collection
+                        set_age(v, G_OLD0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// brief: after its traversal, a touched object is kept gray for the next cycle
+    /// or becomes old again
+    fn gen_link(&mut self, o: *mut GcHeader) {
+        match get_age(o) {
+            G_TOUCHED1 => Self::link_gray(&mut self.gc.grayagain, o),
+            G_TOUCHED2 => set_age(o, G_OLD),
+            _ => {}
+        }
+    }
+
+    fn propagate_all(&mut self) -> usize {
+        let mut work = 0;
+        while !self.gc.gray.is_empty() {
+            work += self.propagate_mark();
+        }
+        work
+    }
+
+    /// brief: keep a userdata alive while Rust borrows its value, whether reachable or not
+    pub(crate) fn pin(&mut self, u: *mut LuaUserData) {
+        // it may still be there from a borrow that ended since the last marking
+        if !self.gc.borrowed.contains(&u) {
+            self.gc.borrowed.push(u);
+        }
+    }
+
+    /// brief: whether Rust still borrows the value of some userdata
+    pub(crate) fn any_borrowed(&self) -> bool {
+        self.gc.borrowed.iter().any(|&u| unsafe { (*u).is_borrowed() })
+    }
+
+    /// brief: mark the registry, the metatables of the basic types, the event names,
+    /// the userdata borrowed from Rust and the main thread
+    fn mark_roots(&mut self, atomic: bool) -> usize {
+        if !self.registry.is_null() {
+            self.mark_object(self.registry as *mut GcHeader);
+        }
+        // the borrows that ended let their userdata go
+        self.gc.borrowed.retain(|&u| unsafe { (*u).is_borrowed() });
+        for j in 0..self.gc.borrowed.len() {
+            self.mark_object(self.gc.borrowed[j] as *mut GcHeader);
+        }
+        for j in 0..self.mt.len() {
+            if !self.mt[j].is_null() {
+                self.mark_object(self.mt[j] as *mut GcHeader);
+            }
+        }
+        for j in 0..self.tmname.len() {
+            if !self.tmname[j].is_null() {
+                self.mark_object(self.tmname[j] as *mut GcHeader);
+            }
+        }
+        match self.mainthread {
+            Some(th) => self.traverse_thread(th.as_ptr(), atomic),
+            None => 0,
+        }
+    }
+
+    fn clear_gray_lists(&mut self) {
+        self.gc.gray.clear();
+        self.gc.grayagain.clear();
+        self.gc.weak.clear();
+        self.gc.ephemeron.clear();
+        self.gc.allweak.clear();
+    }
+
+    fn restart_collection(&mut self) {
+        self.clear_gray_lists();
+        self.mark_roots(false);
+        self.mark_being_fnz();
+    }
+
+    /// brief: o, with a metatable now, goes to `finobj` if the metatable has `__gc`
+    pub(crate) fn check_finalizer(&mut self, o: *mut GcHeader, mt: *mut LuaTable) {
+        if to_finalize(o) || mt.is_null() || self.gc.gcstp & GCSTPCLS != 0 {
+            return;
+        }
+        let ename = self.tmname[TagMethod::Gc as usize];
+        if unsafe { (*mt).get_tm(TagMethod::Gc, ename) }.is_nil() {
+            return;
+        }
+        if self.is_sweep_phase() {
+            // sweep o now, and so it must not be where the sweep resumes
+            self.make_white(o);
+            if self.gc.sweepgc == unsafe { &mut (*o).next as *mut *mut GcHeader } {
+                self.gc.sweepgc = self.sweep_to_live(self.gc.sweepgc);
+            }
+        } else {
+            self.correct_pointers(o);
+        }
+        unsafe {
+            let mut p: *mut *mut GcHeader = &mut self.gc.allgc;
+            while *p != o {
+                p = &mut (**p).next;
+            }
+            *p = (*o).next;
+            (*o).next = self.gc.finobj;
+            self.gc.finobj = o;
+            (*o).marked |= 1 << FINALIZEDBIT;
+        }
+    }
+
+    /// brief: o leaves `allgc`, the generational splits must not point to it
+    fn correct_pointers(&mut self, o: *mut GcHeader) {
+        let next = unsafe { (*o).next };
+        for p in [
+            &mut self.gc.survival,
+            &mut self.gc.old1,
+            &mut self.gc.reallyold,
+            &mut self.gc.firstold1,
+        ] {
+            if *p == o {
+                *p = next;
+            }
+        }
+    }
+
+    /// brief: move the unreachable objects of `finobj`, or all of them, to the end of `tobefnz`;
+    /// old objects are not looked at, they are not collected in a minor collection
+    fn separate_to_be_fnz(&mut self, all: bool) {
+        unsafe {
+            let mut lastnext: *mut *mut GcHeader = &mut self.gc.tobefnz;
+            while !(*lastnext).is_null() {
+                lastnext = &mut (**lastnext).next;
+            }
+            let mut p: *mut *mut GcHeader = &mut self.gc.finobj;
+            while *p != self.gc.finobjold1 {
+                let curr = *p;
+                if !(is_white(curr) || all) {
+                    p = &mut (*curr).next;
+                    continue;
+                }
+                if curr == self.gc.finobjsur {
+                    self.gc.finobjsur = (*curr).next;
+                }
+                *p = (*curr).next;
+                (*curr).next = *lastnext;
+                *lastnext = curr;
+                lastnext = &mut (*curr).next;
+            }
+        }
+    }
+
+    /// brief: objects to be finalized are kept alive, with all they refer to
+    fn mark_being_fnz(&mut self) -> usize {
+        let mut count = 0;
+        let mut o = self.gc.tobefnz;
+        while !o.is_null() {
+            count += 1;
+            self.mark_object(o);
+            o = unsafe { (*o).next };
+        }
+        count
+    }
+
+    /// brief: take the first object of `tobefnz` back to `allgc`, as a normal object
+    fn next_to_finalize(&mut self) -> *mut GcHeader {
+        let o = self.gc.tobefnz;
+        unsafe {
+            self.gc.tobefnz = (*o).next;
+            (*o).next = self.gc.allgc;
+            self.gc.allgc = o;
+            (*o).marked &= !(1 << FINALIZEDBIT);
+        }
+        if self.is_sweep_phase() {
+            self.make_white(o);
+        } else if get_age(o) == G_OLD1 {
+            self.gc.firstold1 = o;
+        }
+        o
+    }
+
+    /// brief: finish the marking in one go, the mutator may have changed anything meanwhile
+    fn atomic(&mut self) -> usize {
+        self.gc.state = GcState::Atomic;
+        let grayagain = take(&mut self.gc.grayagain);
+        let mut work = self.mark_roots(true);
+        work += self.propagate_all();
+        work += self.remark_upvals();
+        work += self.propagate_all();
+        self.gc.gray.extend(grayagain);
+        work += self.propagate_all();
+        self.converge_ephemerons();
+        // all the strongly reachable objects are marked,
+        // the values left white in weak tables go before any finalizer may see them
+        let weak = take(&mut self.gc.weak);
+        self.clear_by_values(&weak);
+        self.gc.weak = weak;
+        let allweak = take(&mut self.gc.allweak);
+        self.clear_by_values(&allweak);
+        self.gc.allweak = allweak;
+        let origweak = self.gc.weak.len();
+        let origall = self.gc.allweak.len();
+        // the unreachable objects with finalizers are resurrected, with all they refer to
+        self.separate_to_be_fnz(false);
+        work += self.mark_being_fnz();
+        work += self.propagate_all();
+        self.converge_ephemerons();
+        // then the entries whose keys are dead go
+        let ephemeron = take(&mut self.gc.ephemeron);
+        self.clear_by_keys(&ephemeron);
+        self.gc.ephemeron = ephemeron;
+        let allweak = take(&mut self.gc.allweak);
+        self.clear_by_keys(&allweak);
+        // and the values of the weak tables found by the resurrection
+        let weak = take(&mut self.gc.weak);
+        self.clear_by_values(&weak[origweak..]);
+        self.gc.weak = weak;
+        self.clear_by_values(&allweak[origall..]);
+        self.gc.allweak = allweak;
+        self.close_dead_upvals();
+        // what is still white is garbage, from now on it is the other white
+        self.gc.currentwhite = self.other_white();
+        work
+    }
+
+    fn enter_sweep(&mut self) {
+        self.gc.state = GcState::SwpAllGc;
+        self.gc.sweepgc = &mut self.gc.allgc;
+    }
+
+    fn free_obj(&mut self, o: *mut GcHeader) {
+        self.gc.debt -= obj_size(o) as isize;
+        unsafe {
+            match (*o).tt {
+                T_SHR_STR => {
+                    self.strt.remove(o as *mut LuaString);
+                    drop(Box::from_raw(o as *mut LuaString));
+                }
+                T_LNG_STR => drop(Box::from_raw(o as *mut LuaString)),
+                T_TABLE => drop(Box::from_raw(o as *mut LuaTable)),
+                T_LCL => drop(Box::from_raw(o as *mut LClosure)),
+                T_CCL => drop(Box::from_raw(o as *mut RClosure)),
+                T_UPVAL => drop(Box::from_raw(o as *mut UpVal)),
+                T_USER_DATA => drop(Box::from_raw(o as *mut LuaUserData)),
+                T_THREAD => {
+                    let th = o as *mut LuaState;
+                    self.gc.threads.retain(|&t| t != th);
+                    drop(Box::from_raw(th));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// brief: free the dead objects among the next `count` of a list and whiten the live ones;
+    /// the link to resume from, or null at the end of the list
+    fn sweep_list(&mut self, mut p: *mut *mut GcHeader, count: usize) -> *mut *mut GcHeader {
+        let ow = self.other_white();
+        let white = self.gc.currentwhite;
+        let mut j = 0;
+        unsafe {
+            while !(*p).is_null() && j < count {
+                let curr = *p;
+                let marked = (*curr).marked;
+                if marked & ow != 0 {
+                    *p = (*curr).next;
+                    self.free_obj(curr);
+                } else {
+                    (*curr).marked = (marked & !MASKGCBITS) | white;
+                    p = &mut (*curr).next;
+                }
+                j += 1;
+            }
+            if (*p).is_null() {
+                return null_mut();
+            }
+        }
+        p
+    }
+
+    /// brief: sweep on until a live object, whose next link is given
+    fn sweep_to_live(&mut self, p: *mut *mut GcHeader) -> *mut *mut GcHeader {
+        loop {
+            let next = self.sweep_list(p, 1);
+            if next != p {
+                return next;
+            }
+        }
+    }
+
+    fn sweep_step(&mut self, next: GcState, nextlist: *mut *mut GcHeader) -> usize {
+        if self.gc.sweepgc.is_null() {
+            self.gc.state = next;
+            self.gc.sweepgc = nextlist;
+            return 0;
+        }
+        let olddebt = self.gc.debt;
+        self.gc.sweepgc = self.sweep_list(self.gc.sweepgc, GCSWEEPMAX);
+        let freed = (olddebt - self.gc.debt) as usize;
+        self.gc.estimate = self.gc.estimate.saturating_sub(freed);
+        GCSWEEPMAX
+    }
+
+    /// brief: advance the collection by a step, the work done in units;
+    /// finalizers run in `l`
+    fn single_step(&mut self, l: *mut LuaState) -> usize {
+        match self.gc.state {
+            GcState::Pause => {
+                self.restart_collection();
+                self.gc.state = GcState::Propagate;
+                1
+            }
+            GcState::Propagate => {
+                if self.gc.gray.is_empty() {
+                    self.gc.state = GcState::EnterAtomic;
+                    return 0;
+                }
+                self.propagate_mark()
+            }
+            GcState::EnterAtomic | GcState::Atomic => {
+                let work = self.atomic();
+                self.enter_sweep();
+                self.gc.estimate = self.total_bytes();
+                work
+            }
+            GcState::SwpAllGc => {
+                let finobj: *mut *mut GcHeader = &mut self.gc.finobj;
+                self.sweep_step(GcState::SwpFinObj, finobj)
+            }
+            GcState::SwpFinObj => {
+                let tobefnz: *mut *mut GcHeader = &mut self.gc.tobefnz;
+                self.sweep_step(GcState::SwpToBeFnz, tobefnz)
+            }
+            GcState::SwpToBeFnz => self.sweep_step(GcState::SwpEnd, null_mut()),
+            GcState::SwpEnd => {
+                self.strt.check_size();
+                self.gc.state = GcState::CallFin;
+                0
+            }
+            GcState::CallFin => {
+                if self.gc.tobefnz.is_null() {
+                    self.gc.state = GcState::Pause;
+                    return 0;
+                }
+                unsafe { (*l).run_a_few_finalizers(GCFINMAX) * GCFINALIZECOST }
+            }
+        }
+    }
+
+    fn run_until(&mut self, l: *mut LuaState, state: GcState) {
+        while self.gc.state != state {
+            self.single_step(l);
+        }
+    }
+
+    /// brief: do work in proportion to the debt, a step of `stepsize` bytes at least
+    fn inc_step(&mut self, l: *mut LuaState) {
+        let stepmul = (self.gc.stepmul | 1) as isize;
+        let mut debt = (self.gc.debt / WORK2MEM) * stepmul;
+        let stepsize = ((1isize << self.gc.stepsize) / WORK2MEM) * stepmul;
+        loop {
+            debt -= self.single_step(l) as isize;
+            if debt <= -stepsize || self.gc.state == GcState::Pause {
+                break;
+            }
+        }
+        if self.gc.state == GcState::Pause {
+            self.set_pause();
+        } else {
+            self.set_debt((debt / stepmul) * WORK2MEM);
+        }
+    }
+
+    /// brief: free the dead objects of a list up to limit, the survivors grow older;
+    /// the link to the first object not swept
+    fn sweep_gen(
+        &mut self,
+        mut p: *mut *mut GcHeader,
+        limit: *mut GcHeader,
+        firstold1: &mut *mut GcHeader,
+    ) -> *mut *mut GcHeader {
+        const NEXTAGE: [u8; 7] = [
+            G_SURVIVAL, // from G_NEW
+            G_OLD1,     // from G_SURVIVAL
+            G_OLD1,     // from G_OLD0
+            G_OLD,      // from G_OLD1
+            G_OLD,      // from G_OLD
+            G_TOUCHED1, // from G_TOUCHED1
+            G_TOUCHED2, // from G_TOUCHED2
+        ];
+        let white = self.gc.currentwhite;
+        unsafe {
+            while *p != limit {
+                let curr = *p;
+                if is_white(curr) {
+                    *p = (*curr).next;
+                    self.free_obj(curr);
+                    continue;
+                }
+                if get_age(curr) == G_NEW {
+                    // new objects go back to white
+                    (*curr).marked = ((*curr).marked & !MASKGCBITS) | G_SURVIVAL | white;
+                } else {
+                    // the others are old, and so keep their color
+                    set_age(curr, NEXTAGE[get_age(curr) as usize]);
+                    if get_age(curr) == G_OLD1 && firstold1.is_null() {
+                        *firstold1 = curr;
+                    }
+                }
+                p = &mut (*curr).next;
+            }
+        }
+        p
+    }
+
+    /// brief: free the dead objects of a list and make the others old
+    fn sweep_to_old(&mut self, mut p: *mut *mut GcHeader) {
+        unsafe {
+            while !(*p).is_null() {
+                let curr = *p;
+                if is_white(curr) {
+                    *p = (*curr).next;
+                    self.free_obj(curr);
+                    continue;
+                }
+                set_age(curr, G_OLD);
+                if (*curr).tt == T_THREAD {
+                    // threads are always gray, traversed again in every cycle
+                    Self::link_gray(&mut self.gc.grayagain, curr);
+                } else if (*curr).tt == T_UPVAL && (*(curr as *mut UpVal)).level().is_some() {
+                    // open upvalues are always gray
+                    set_gray(curr);
+                } else {
+                    set_black(curr);
+                }
+                p = &mut (*curr).next;
+            }
+        }
+    }
+
+    /// brief: whiten a list and forget the ages, for the incremental mode
+    fn white_list(&mut self, mut p: *mut GcHeader) {
+        let white = self.gc.currentwhite;
+        while !p.is_null() {
+            unsafe {
+                (*p).marked = ((*p).marked & !MASKGCBITS) | white;
+                p = (*p).next;
+            }
+        }
+    }
+
+    /// brief: keep the objects touched in this cycle for the next one, drop the others
+    fn correct_gray_list(&mut self) {
+        let mut list = take(&mut self.gc.grayagain);
+        // weak tables are traversed again in every cycle
+        list.append(&mut self.gc.weak);
+        list.append(&mut self.gc.allweak);
+        list.append(&mut self.gc.ephemeron);
+        list.retain(|&o| {
+            if is_white(o) {
+                return false;
+            }
+            if unsafe { (*o).tt } == T_THREAD {
+                return true;
+            }
+            if get_age(o) == G_TOUCHED1 {
+                // black, for the next barrier
+                set_black(o);
+                set_age(o, G_TOUCHED2);
+                return true;
+            }
+            if get_age(o) == G_TOUCHED2 {
+                set_age(o, G_OLD);
+            }
+            set_black(o);
+            false
+        });
+        self.gc.grayagain = list;
+    }
+
+    /// brief: traverse again the black old1 objects from `from` up to `to`,
+    /// they may refer to young ones
+    fn mark_old(&mut self, from: *mut GcHeader, to: *mut GcHeader) {
+        let mut p = from;
+        while p != to {
+            if get_age(p) == G_OLD1 {
+                set_age(p, G_OLD);
+                if is_black(p) {
+                    self.really_mark(p);
+                }
+            }
+            p = unsafe { (*p).next };
+        }
+    }
+
+    fn finish_gen_cycle(&mut self, l: *mut LuaState) {
+        self.correct_gray_list();
+        self.strt.check_size();
+        // skip the restart, the marks of the old objects are kept
+        self.gc.state = GcState::Propagate;
+        unsafe { (*l).call_all_pending_finalizers() };
+    }
+
+    /// brief: a minor collection, only the young objects are traversed and swept
+    fn young_collection(&mut self, l: *mut LuaState) {
+        if !self.gc.firstold1.is_null() {
+            self.mark_old(self.gc.firstold1, self.gc.reallyold);
+            self.gc.firstold1 = null_mut();
+        }
+        self.mark_old(self.gc.finobj, self.gc.finobjrold);
+        self.mark_old(self.gc.tobefnz, null_mut());
+        self.atomic();
+        self.gc.state = GcState::SwpAllGc;
+        let mut firstold1 = null_mut();
+        let allgc: *mut *mut GcHeader = &mut self.gc.allgc;
+        let psurvival = self.sweep_gen(allgc, self.gc.survival, &mut firstold1);
+        self.sweep_gen(psurvival, self.gc.old1, &mut firstold1);
+        self.gc.firstold1 = firstold1;
+        self.gc.reallyold = self.gc.old1;
+        // the survivals that survived again are old now
+        self.gc.old1 = unsafe { *psurvival };
+        // and all the new ones are survivals
+        self.gc.survival = self.gc.allgc;
+        // the same for the objects with finalizers, without the old1 shortcut
+        let mut dummy = null_mut();
+        let finobj: *mut *mut GcHeader = &mut self.gc.finobj;
+        let psurvival = self.sweep_gen(finobj, self.gc.finobjsur, &mut dummy);
+        self.sweep_gen(psurvival, self.gc.finobjold1, &mut dummy);
+        self.gc.finobjrold = self.gc.finobjold1;
+        self.gc.finobjold1 = unsafe { *psurvival };
+        self.gc.finobjsur = self.gc.finobj;
+        let tobefnz: *mut *mut GcHeader = &mut self.gc.tobefnz;
+        self.sweep_gen(tobefnz, null_mut(), &mut dummy);
+        self.finish_gen_cycle(l);
+    }
+
+    /// brief: after a whole marking, every live object becomes old
+    fn atomic_to_gen(&mut self, l: *mut LuaState) {
+        self.clear_gray_lists();
+        self.gc.state = GcState::SwpAllGc;
+        let allgc: *mut *mut GcHeader = &mut self.gc.allgc;
+        self.sweep_to_old(allgc);
+        self.gc.reallyold = self.gc.allgc;
+        self.gc.old1 = self.gc.allgc;
+        self.gc.survival = self.gc.allgc;
+        self.gc.firstold1 = null_mut();
+        let finobj: *mut *mut GcHeader = &mut self.gc.finobj;
+        self.sweep_to_old(finobj);
+        self.gc.finobjrold = self.gc.finobj;
+        self.gc.finobjold1 = self.gc.finobj;
+        self.gc.finobjsur = self.gc.finobj;
+        let tobefnz: *mut *mut GcHeader = &mut self.gc.tobefnz;
+        self.sweep_to_old(tobefnz);
+        self.gc.kind = GcKind::Generational;
+        self.gc.lastatomic = 0;
+        self.gc.estimate = self.total_bytes();
+        self.finish_gen_cycle(l);
+    }
+
+    /// brief: wait for a growth of `genminormul` percent before the next minor collection
+    fn set_minor_debt(&mut self) {
+        let debt = (self.total_bytes() / 100) * self.gc.genminormul;
+        self.set_debt(-(debt as isize));
+    }
+
+    /// brief: a whole collection that leaves the survivors old;
+    /// the work of its marking
+    fn enter_gen(&mut self, l: *mut LuaState) -> usize {
+        self.run_until(l, GcState::Pause);
+        self.run_until(l, GcState::Propagate);
+        let work = self.atomic();
+        self.atomic_to_gen(l);
+        self.set_minor_debt();
+        work
+    }
+
+    fn enter_inc(&mut self) {
+        self.white_list(self.gc.allgc);
+        self.gc.reallyold = null_mut();
+        self.gc.old1 = null_mut();
+        self.gc.survival = null_mut();
+        self.gc.firstold1 = null_mut();
+        self.white_list(self.gc.finobj);
+        self.white_list(self.gc.tobefnz);
+        self.gc.finobjrold = null_mut();
+        self.gc.finobjold1 = null_mut();
+        self.gc.finobjsur = null_mut();
+        self.clear_gray_lists();
+        self.gc.state = GcState::Pause;
+        self.gc.kind = GcKind::Incremental;
+        self.gc.lastatomic = 0;
+    }
+
+    pub(crate) fn change_mode(&mut self, l: *mut LuaState, kind: GcKind) {
+        if kind != self.gc.kind {
+            match kind {
+                GcKind::Generational => {
+                    self.enter_gen(l);
+                }
+                GcKind::Incremental => self.enter_inc(),
+            }
+        }
+        self.gc.lastatomic = 0;
+    }
+
+    /// brief: a major collection in generational mode
+    fn full_gen(&mut self, l: *mut LuaState) -> usize {
+        self.enter_inc();
+        self.enter_gen(l)
+    }
+
+    /// brief: after a bad major collection, collect in full until one frees enough,
+    /// staying in incremental mode meanwhile
+    fn step_gen_full(&mut self, l: *mut LuaState) {
+        let lastatomic = self.gc.lastatomic;
+        if self.gc.kind == GcKind::Generational {
+            self.enter_inc();
+        }
+        self.run_until(l, GcState::Propagate);
+        let newatomic = self.atomic();
+        if newatomic < lastatomic + (lastatomic >> 3) {
+            // a good collection, back to generational mode
+            self.atomic_to_gen(l);
+            self.set_minor_debt();
+        } else {
+            // another bad one, finish it in incremental mode
+            self.gc.estimate = self.total_bytes();
+            self.enter_sweep();
+            self.run_until(l, GcState::Pause);
+            self.set_pause();
+            self.gc.lastatomic = newatomic;
+        }
+    }
+
+    /// brief: a minor collection, or a major one when the memory grew past
+    /// `genmajormul` percent since the last major collection
+    fn gen_step(&mut self, l: *mut LuaState) {
+        if self.gc.lastatomic != 0 {
+            self.step_gen_full(l);
+            return;
+        }
+        let majorbase = self.gc.estimate;
+        let majorinc = (majorbase / 100) * self.gc.genmajormul;
+        if self.gc.debt > 0 && self.total_bytes() > majorbase + majorinc {
+            let work = self.full_gen(l);
+            if self.total_bytes() >= majorbase + majorinc / 2 {
+                // it freed less than half of the growth: wait longer for the next one
+                self.gc.lastatomic = work;
+                self.set_pause();
+            }
+        } else {
+            self.young_collection(l);
+            self.set_minor_debt();
+            // the base is the memory after the last major collection
+            self.gc.estimate = majorbase;
+        }
+    }
+
+    fn step(&mut self, l: *mut LuaState) {
+        if self.gc.gcstp != 0 {
+            // do not come back too soon
+            self.set_debt(-2000);
+        } else if self.gc.kind == GcKind::Generational || self.gc.lastatomic != 0 {
+            self.gen_step(l);
+        } else {
+            self.inc_step(l);
+        }
+    }
+
+    fn full_gc(&mut self, l: *mut LuaState) {
+        if self.gc.kind == GcKind::Generational {
+            self.full_gen(l);
+            return;
+        }
+        if self.keep_invariant() {
+            // sweep everything back to white
+            self.enter_sweep();
+        }
+        // finish a pending sweep, then run a whole cycle
+        self.run_until(l, GcState::Pause);
+        self.single_step(l);
+        self.run_until(l, GcState::Pause);
+        self.set_pause();
+    }
+
+    /// brief: o, black, now refers to v: mark v, or in the sweep phase whiten o
+    pub(crate) fn barrier(&mut self, o: *mut GcHeader, v: &TObj) {
+        if let Some(v) = gc_value(v) {
+            if is_black(o) && is_white(v) {
+                if self.keep_invariant() {
+                    self.mark_object(v);
+                    if is_old(o) {
+                        // v must not be collected by a minor collection
+                        set_age(v, G_OLD0);
+                    }
+                } else {
+                    unsafe { (*o).marked = ((*o).marked & !MASKGCBITS) | self.gc.currentwhite };
+                }
+            }
+        }
+    }
+
+    /// brief: a closed upvalue, gray while its value was on a stack, turns black
+    /// and its value must be marked
+    pub(crate) fn close_barrier(&mut self, uv: *mut GcHeader, v: &TObj) {
+        if !is_white(uv) {
+            set_black(uv);
+            self.barrier(uv, v);
+        }
+    }
+
+    /// brief: o, black, now refers to v: traverse o again in the atomic phase
+    pub(crate) fn barrier_back(&mut self, o: *mut GcHeader, v: &TObj) {
+        if let Some(v) = gc_value(v) {
+            if is_black(o) && is_white(v) {
+                // a touched2 object is still in grayagain
+                if get_age(o) != G_TOUCHED2 {
+                    self.gc.grayagain.push(o);
+                }
+                set_gray(o);
+                if is_old(o) {
+                    set_age(o, G_TOUCHED1);
+                }
+            }
+        }
+    }
+}
+
+impl LuaState {
+    /// brief: a step of collection, when enough was allocated since the last one
+    #[inline(always)]
+    pub(crate) fn check_gc(&mut self) -> Result<ErrCode, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        if g.gc.debt > 0 {
+            g.step(l);
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: call the finalizer of the next object to be finalized;
+    /// an error in it is reported as a warning
+    fn call_gc_tm(&mut self) -> Result<ErrCode, ErrCode> {
+        let g = self.global_mut()?;
+        let v = gc_object_value(g.next_to_finalize());
+        let tm = self.get_tm_by_obj(&v, TagMethod::Gc)?;
+        if tm.is_nil() {
+            return Ok(ErrCode(FINE));
+        }
+        let g = self.global_mut()?;
+        let oldgcstp = g.gc.gcstp;
+        // no collection while a finalizer runs
+        g.gc.gcstp |= GCSTPGC;
+        let top = self.stack_top_index;
+        let ncalls = self.ncalls;
+        let tbc = self.tbclist.len();
+        let status = self.call_tm(tm, &[v], 0);
+        self.global_mut()?.gc.gcstp = oldgcstp;
+        if let Err(e) = status {
+            let err = self.error_object(e)?;
+            // drop what the failed call left behind
+            while self.ncalls > ncalls {
+                self.pop_frame()?;
+            }
+            self.close_upvals(top)?;
+            self.tbclist.truncate(tbc);
+            self.stack_top_index = top;
+            self.warn_error("__gc", &err);
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: call at most n pending finalizers, the number called is given
+    pub(crate) fn run_a_few_finalizers(&mut self, n: usize) -> usize {
+        let mut j = 0;
+        while j < n && self.global_mut().is_ok_and(|g| !g.gc.tobefnz.is_null()) {
+            if self.call_gc_tm().is_err() {
+                break;
+            }
+            j += 1;
+        }
+        j
+    }
+
+    pub(crate) fn call_all_pending_finalizers(&mut self) {
+        while self.run_a_few_finalizers(GCFINMAX) > 0 {}
+    }
+
+    /// brief: when the state closes, every object with a finalizer is finalized,
+    /// reachable or not, then all the objects are freed
+    pub(crate) fn free_all_objects(&mut self) -> Result<ErrCode, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        // no new finalizers from now on
+        g.gc.gcstp = GCSTPCLS;
+        g.change_mode(l, GcKind::Incremental);
+        g.separate_to_be_fnz(true);
+        self.call_all_pending_finalizers();
+        let g = self.global_mut()?;
+        let mut o = take(&mut g.gc.allgc);
+        while !o.is_null() {
+            let next = unsafe { (*o).next };
+            g.free_obj(o);
+            o = next;
+        }
+        g.gc.sweepgc = null_mut();
+        g.gc.borrowed.clear();
+        g.clear_gray_lists();
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: a full collection cycle, nothing is done inside a finalizer
+    pub fn gc_collect(&mut self) -> Result<ErrCode, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        if g.gc.gcstp & GCSTPGC == 0 {
+            g.full_gc(l);
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: a basic step of collection for 0, otherwise the work due to `kb` kilobytes
+    /// of allocation; true when the step ended a cycle
+    pub fn gc_step(&mut self, kb: usize) -> Result<bool, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        if g.gc.gcstp & GCSTPGC != 0 {
+            return Ok(false);
+        }
+        let oldgcstp = g.gc.gcstp;
+        g.gc.gcstp = 0;
+        if kb == 0 {
+            g.set_debt(0);
+        } else {
+            g.set_debt(g.gc.debt + (kb as isize) * 1024);
+        }
+        g.step(l);
+        g.gc.gcstp = oldgcstp;
+        Ok(g.gc.state == GcState::Pause)
+    }
+
+    /// brief: the bytes in use by collectable objects
+    pub fn gc_count(&self) -> Result<usize, ErrCode> {
+        Ok(self.global()?.total_bytes())
+    }
+
+    pub fn gc_stop(&mut self) -> Result<ErrCode, ErrCode> {
+        self.global_mut()?.gc.gcstp = GCSTPUSR;
+        Ok(ErrCode(FINE))
+    }
+
+    pub fn gc_restart(&mut self) -> Result<ErrCode, ErrCode> {
+        let g = self.global_mut()?;
+        g.set_debt(0);
+        g.gc.gcstp = 0;
+        Ok(ErrCode(FINE))
+    }
+
+    pub fn gc_is_running(&self) -> Result<bool, ErrCode> {
+        Ok(self.global()?.gc.gcstp == 0)
+    }
+
+    /// brief: set the pause, in percent, the previous one is returned
+    pub fn gc_set_pause(&mut self, pause: usize) -> Result<usize, ErrCode> {
+        let g = self.global_mut()?;
+        Ok(core::mem::replace(&mut g.gc.pause, pause))
+    }
+
+    /// brief: set the step multiplier, in percent, the previous one is returned
+    pub fn gc_set_stepmul(&mut self, stepmul: usize) -> Result<usize, ErrCode> {
+        let g = self.global_mut()?;
+        Ok(core::mem::replace(&mut g.gc.stepmul, stepmul))
+    }
+
+    /// brief: switch to generational mode, a minor collection after the memory grows
+    /// by `minormul` percent and a major one after it grows by `majormul` percent;
+    /// 0 keeps a parameter, the previous mode is returned, nothing is done inside a finalizer
+    pub fn gc_generational(&mut self, minormul: usize, majormul: usize) -> Result<GcKind, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        let old = g.gc.kind;
+        if g.gc.gcstp & GCSTPGC != 0 {
+            return Ok(old);
+        }
+        if minormul != 0 {
+            g.gc.genminormul = minormul;
+        }
+        if majormul != 0 {
+            g.gc.genmajormul = majormul;
+        }
+        g.change_mode(l, GcKind::Generational);
+        Ok(old)
+    }
+
+    /// brief: switch to incremental mode with a pause, a step multiplier and
+    /// the log2 of a step size; 0 keeps a parameter, the previous mode is returned,
+    /// nothing is done inside a finalizer
+    pub fn gc_incremental(
+        &mut self,
+        pause: usize,
+        stepmul: usize,
+        stepsize: u32,
+    ) -> Result<GcKind, ErrCode> {
+        let l: *mut LuaState = self;
+        let g = self.global_mut()?;
+        let old = g.gc.kind;
+        if g.gc.gcstp & GCSTPGC != 0 {
+            return Ok(old);
+        }
+        if pause != 0 {
+            g.gc.pause = pause;
+        }
+        if stepmul != 0 {
+            g.gc.stepmul = stepmul;
+        }
+        if stepsize != 0 {
+            g.gc.stepsize = stepsize;
+        }
+        g.change_mode(l, GcKind::Incremental);
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::info::lua::MEMORY_UNREACHABLE;
+    use crate::vm::machine::tests::{lua_setmetatable, new_state, run, set_global_fn};
+
+    /// brief: a Rust value that counts its drops
+    struct Tracked {
+        n: i64,
+        drops: Rc<Cell<usize>>,
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    /// brief: push a metatable whose `__gc` records the numbers of the finalized values
+    fn push_gc_metatable(l: &mut LuaState, order: &Rc<RefCell<Vec<i64>>>) {
+        let order = order.clone();
+        l.new_table().unwrap();
+        l.push_rclosure(
+            Box::new(move |l: &mut LuaState| {
+                let n = l.borrow_userdata::<Tracked>(0).unwrap().n;
+                order.borrow_mut().push(n);
+                0
+            }),
+            0,
+        )
+        .unwrap();
+        l.set_field(1, b"__gc").unwrap();
+    }
+
+    #[test]
+    fn close_runs_pending_finalizers() {
+        let l = new_state();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let drops = Rc::new(Cell::new(0));
+        push_gc_metatable(l, &order);
+        for n in 1..=4 {
+            l.push_userdata(
+                Tracked {
+                    n,
+                    drops: drops.clone(),
+                },
+                0,
+            )
+            .unwrap();
+            let mt = l.get_stkelem_fromtop(1).unwrap();
+            l.push_obj(mt).unwrap();
+            l.set_metatable(1).unwrap();
+            // below the metatable, which stays on top
+            let mt = l.get_stkelem_fromtop(1).unwrap();
+            l.push_obj(mt).unwrap();
+        }
+        // the last one only is unreachable: finalized, then freed by the next cycle
+        l.move_top(2, false);
+        l.gc_collect().unwrap();
+        assert_eq!(*order.borrow(), [4]);
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 1);
+        l.close().unwrap();
+        // the others in the reverse order of their marking, each value dropped once
+        assert_eq!(*order.borrow(), [4, 3, 2, 1]);
+        assert_eq!(drops.get(), 4);
+        // the state is gone
+        assert_eq!(l.push_global_table().unwrap_err().0, MEMORY_UNREACHABLE);
+        assert_eq!(l.gc_collect().unwrap_err().0, MEMORY_UNREACHABLE);
+        assert_eq!(l.close().unwrap_err().0, MEMORY_UNREACHABLE);
+        assert_eq!(drops.get(), 4);
+    }
+
+    /// brief: step(), a basic step of collection from Lua
+    fn lua_step(l: &mut LuaState) -> usize {
+        match l.gc_step(0) {
+            Ok(_) => 0,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    #[test]
+    fn mutation_between_steps() {
+        let l = new_state();
+        set_global_fn(l, b"step", lua_step);
+        // new objects go into tables and upvalues already traversed
+        let src = "local keep, up = {}, {}
+                   local function f() return up end
+                   for i = 1, 3000 do
+                       keep[i % 100 + 1] = {i, tostring(i)}
+                       up = {i}
+                       step()
+                   end
+                   local s = 0
+                   for i = 1, 100 do s = s + keep[i][1] end
+                   return s, f()[1], keep[1][2]";
+        assert_eq!(run(l, src).unwrap(), ["295050", "3000", "3000"]);
+    }
+
+    #[test]
+    fn collection_under_stress() {
+        let l = new_state();
+        // a cycle every few kilobytes
+        l.gc_incremental(100, 400, 8).unwrap();
+        let src = "local list
+                   for i = 1, 5000 do
+                       local garbage = {i, {}, tostring(i) .. 'x'}
+                       if i % 10 == 0 then list = {v = i, next = list, g = garbage} end
+                   end
+                   local n, s = 0, 0
+                   while list do n = n + 1 s = s + list.g[1] list = list.next end
+                   return n, s";
+        assert_eq!(run(l, src).unwrap(), ["500", "1252500"]);
+    }
+
+    #[test]
+    fn memory_is_reclaimed() {
+        let l = new_state();
+        l.gc_collect().unwrap();
+        let base = l.gc_count().unwrap();
+        l.gc_stop().unwrap();
+        assert!(!l.gc_is_running().unwrap());
+        run(l, "local t for i = 1, 5000 do t = {i, {}} end").unwrap();
+        for _ in 0..100 {
+            l.new_thread().unwrap();
+            l.move_top(1, false);
+        }
+        let peak = l.gc_count().unwrap();
+        assert!(peak > base + 5000 * 64, "{} {}", base, peak);
+        assert_eq!(l.global().unwrap().gc.threads.len(), 100);
+        l.gc_restart().unwrap();
+        assert!(l.gc_is_running().unwrap());
+        l.gc_collect().unwrap();
+        assert!(
+            l.gc_count().unwrap() <= base + 1024,
+            "{} {}",
+            base,
+            l.gc_count().unwrap()
+        );
+        assert!(l.global().unwrap().gc.threads.is_empty());
+    }
+
+    #[test]
+    fn steps_end_a_cycle() {
+        let l = new_state();
+        run(l, "keep = {} for i = 1, 2000 do keep[i] = {i} end").unwrap();
+        // small steps
+        l.gc_incremental(0, 10, 6).unwrap();
+        let mut steps = 0;
+        while !l.gc_step(0).unwrap() {
+            steps += 1;
+            assert!(steps < 100000);
+        }
+        assert!(steps > 10, "{}", steps);
+        assert_eq!(run(l, "return keep[2000][1]").unwrap(), ["2000"]);
+        assert_eq!(l.global().unwrap().gc.state, GcState::Pause);
+    }
+
+    #[test]
+    fn generational_mode() {
+        let l = new_state();
+        set_global_fn(l, b"step", lua_step);
+        assert_eq!(l.gc_generational(0, 0).unwrap(), GcKind::Incremental);
+        assert_eq!(l.gc_generational(10, 50).unwrap(), GcKind::Generational);
+        // an old table gets young values between minor collections
+        let src = "local old = {}
+                   for i = 1, 5 do step() end
+                   for i = 1, 2000 do
+                       old[i % 50 + 1] = {i}
+                       local garbage = {tostring(i)}
+                       if i % 7 == 0 then step() end
+                   end
+                   local s = 0
+                   for i = 1, 50 do s = s + old[i][1] end
+                   return s";
+        assert_eq!(run(l, src).unwrap(), ["98775"]);
+        assert_eq!(l.global().unwrap().gc.kind, GcKind::Generational);
+        l.gc_collect().unwrap();
+        let base = l.gc_count().unwrap();
+        run(l, "local t for i = 1, 5000 do t = {i, {}} end").unwrap();
+        l.gc_collect().unwrap();
+        assert!(l.gc_count().unwrap() <= base + 1024);
+        // and back, in the middle of the work; after a bad major collection
+        // it may already be collecting incrementally for a while
+        run(l, "keep = {} for i = 1, 500 do keep[i] = {i} end").unwrap();
+        l.gc_incremental(0, 0, 0).unwrap();
+        assert_eq!(l.global().unwrap().gc.kind, GcKind::Incremental);
+        l.gc_step(0).unwrap();
+        l.gc_collect().unwrap();
+        assert_eq!(run(l, "return keep[500][1]").unwrap(), ["500"]);
+    }
+
+    /// brief: collect(), a full collection from Lua
+    fn lua_collect(l: &mut LuaState) -> usize {
+        match l.gc_collect() {
+            Ok(_) => 0,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: a new state with `collect` and `setmetatable`
+    fn weak_state() -> &'static mut LuaState {
+        let l = new_state();
+        set_global_fn(l, b"collect", lua_collect);
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        l
+    }
+
+    #[test]
+    fn weak_values_and_keys() {
+        let src = "local keep = {}
+                   local w = setmetatable({}, {__mode = 'v'})
+                   w[1], w[2], w[3], w[4] = {}, 'str', 5, keep
+                   local k = setmetatable({}, {__mode = 'k'})
+                   local probe = setmetatable({}, {__mode = 'v'})
+                   local v = {}
+                   k[{}] = v probe[1] = v v = nil
+                   k[keep] = 'kept'
+                   local kv = setmetatable({}, {__mode = 'kv'})
+                   kv[keep] = {} kv[1] = keep
+                   collect()
+                   return w[1], w[2], w[3], w[4] == keep, probe[1], k[keep], kv[keep], kv[1] == keep";
+        let l = weak_state();
+        assert_eq!(
+            run(l, src).unwrap(),
+            ["nil", "str", "5", "true", "nil", "kept", "nil", "true"]
+        );
+    }
+
+    #[test]
+    fn ephemerons() {
+        // a value is reachable through its key only
+        let src = "local e = setmetatable({}, {__mode = 'k'})
+                   local probe = setmetatable({}, {__mode = 'v'})
+                   local k1, k2, k3 = {}, {}, {}
+                   e[k1] = k2 e[k2] = k3 e[k3] = 'end'
+                   local lone = {}
+                   e[lone] = {lone} probe[1] = e[lone]
+                   k2, k3, lone = nil, nil, nil
+                   collect()
+                   return e[e[e[k1]]], probe[1]";
+        let l = weak_state();
+        assert_eq!(run(l, src).unwrap(), ["end", "nil"]);
+        // the same in generational mode, with the table old by then
+        l.gc_generational(0, 0).unwrap();
+        let src = "e = setmetatable({}, {__mode = 'k'})
+                   probe = setmetatable({}, {__mode = 'v'})
+                   collect()
+                   local k = {}
+                   e[k] = {k} probe[1] = e[k]
+                   keep = {}
+                   e[keep] = {keep}
+                   collect()";
+        run(l, src).unwrap();
+        assert_eq!(
+            run(l, "collect() return probe[1], e[keep][1] == keep").unwrap(),
+            ["nil", "true"]
+        );
+    }
+
+    #[test]
+    fn finalizer_order() {
+        let src = "log = ''
+                   local mt = {__gc = function(o) log = log .. o.n end}
+                   local a, b, c = {n = 'a'}, {n = 'b'}, {n = 'c'}
+                   setmetatable(a, mt) setmetatable(c, mt) setmetatable(b, mt)
+                   -- no `__gc` yet when the metatable was set: never finalized
+                   local lmt = {}
+                   local late = setmetatable({n = 'x'}, lmt)
+                   lmt.__gc = mt.__gc
+                   a, b, c, late = nil, nil, nil, nil
+                   collect()
+                   return log";
+        let l = weak_state();
+        assert_eq!(run(l, src).unwrap(), ["bca"]);
+    }
+
+    #[test]
+    fn resurrection() {
+        let src = "local count, saved = 0, nil
+                   local wv = setmetatable({}, {__mode = 'v'})
+                   local wk = setmetatable({}, {__mode = 'k'})
+                   local seen
+                   local o = setmetatable({inner = {v = 7}}, {__gc = function(o)
+                       count = count + 1
+                       saved = o
+                       seen = tostring(wv[1] == nil) .. tostring(wk[o])
+                   end})
+                   wv[1] = o wk[o] = true
+                   o = nil
+                   collect()
+                   local r1 = saved.inner.v
+                   saved = nil
+                   collect() collect()
+                   return count, r1, saved, seen";
+        let l = weak_state();
+        // gone from weak values before the finalizer, from weak keys only after it
+        assert_eq!(run(l, src).unwrap(), ["1", "7", "nil", "truetrue"]);
+    }
+
+    thread_local! {
+        static WARNINGS: RefCell<String> = const { RefCell::new(String::new()) };
+    }
+
+    fn record_warning(msg: &str, _tocont: bool) {
+        WARNINGS.with(|w| w.borrow_mut().push_str(msg));
+    }
+
+    #[test]
+    fn error_in_finalizer_is_a_warning() {
+        let l = weak_state();
+        l.set_warnf(Some(record_warning)).unwrap();
+        let src = "setmetatable({}, {__gc = function() error('boom') end})
+                   collect()
+                   return 'after'";
+        assert_eq!(run(l, src).unwrap(), ["after"]);
+        let w = WARNINGS.with(|w| w.borrow().clone());
+        assert!(w.contains("error in __gc") && w.contains("boom"), "{}", w);
+    }
+}
@@ -1,5 +1,10 @@
+pub mod closure;
+pub mod gc;
 pub mod objdef;
 pub mod statedef;
+pub mod string;
+pub mod table;
+pub mod userdata;
 
 #[macro_export]
 macro_rules! ptr_get {
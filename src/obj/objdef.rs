@@ -1,12 +1,34 @@
 use crate::{
-    info::lua::{ErrCode, NONE_OBJECT},
-    obj::statedef::LuaState,
+    info::lua::ErrCode,
+    obj::{
+        closure::{LClosure, RClosure},
+        statedef::LuaState,
+        string::LuaString,
+        table::LuaTable,
+        userdata::LuaUserData,
+    },
 };
 
-type Dt = u32;
-pub type INT = i32; // integer
-pub type FLT = f32; // float
+pub type Dt = u32;
+// the width of numbers follows Lua 5.4, the `lua32` feature makes them 32 bits wide
+#[cfg(not(feature = "lua32"))]
+pub type INT = i64; // integer
+#[cfg(not(feature = "lua32"))]
+pub type UINT = u64; // unsigned counterpart of INT
+#[cfg(not(feature = "lua32"))]
+pub type FLT = f64; // float
+#[cfg(feature = "lua32")]
+pub type INT = i32;
+#[cfg(feature = "lua32")]
+pub type UINT = u32;
+#[cfg(feature = "lua32")]
+pub type FLT = f32;
+pub const LUA_MAXINTEGER: INT = INT::MAX; // `math.maxinteger`
+pub const LUA_MININTEGER: INT = INT::MIN; // `math.mininteger`
 pub type FFUNC = fn(&mut LuaState) -> usize;
+pub type KCTX = isize; // context kept for a continuation
+/// brief: continuation of a Rust function, run in its place when the coroutine goes on
+pub type KFUNC = fn(&mut LuaState, ErrCode, KCTX) -> usize;
 
 pub const BASIC_TYPE_BIT: usize = 4;
 
@@ -19,65 +41,111 @@ pub const T_TABLE: Dt = 6;
 pub const T_FUNCTION: Dt = 7;
 pub const T_THREAD: Dt = 8;
 pub const T_NONE: Dt = 9;
+pub const LUA_NUM_TYPES: usize = T_NONE as usize; // room for a slot per basic type
+pub const T_UPVAL: Dt = T_NONE + 1; // upvalues are collectable objects, never values
+pub const T_DEADKEY: Dt = T_NONE + 2; // key of an empty node, its object may be collected
+
+/// brief: the name of a basic type
+pub fn type_name(t: Dt) -> &'static str {
+    match t {
+        T_NUMBER => "number",
+        T_LIGHT_USER_DATA => "userdata",
+        T_BOOLEAN => "boolean",
+        T_STRING => "string",
+        T_NIL => "nil",
+        T_TABLE => "table",
+        T_FUNCTION => "function",
+        T_THREAD => "thread",
+        _ => "no value",
+    }
+}
 
+#[allow(clippy::identity_op)]
 pub const T_NUM_INT: Dt = T_NUMBER | (0 << 4);
 pub const T_NUM_FLT: Dt = T_NUMBER | (1 << 4);
 
+#[allow(clippy::identity_op)]
 pub const T_LCL: Dt = T_FUNCTION | (0 << 4);
 pub const T_LRF: Dt = T_FUNCTION | (1 << 4);
 pub const T_CCL: Dt = T_FUNCTION | (2 << 4);
 
+pub const T_USER_DATA: Dt = T_LIGHT_USER_DATA | (1 << 4); // full userdata, owned by the collector
+
+#[allow(clippy::identity_op)]
 pub const T_LNG_STR: Dt = T_STRING | (0 << 4);
 pub const T_SHR_STR: Dt = T_STRING | (1 << 4);
 
 pub type TObj = LuaTObject;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct ObjectType(Dt);
 
-impl Default for ObjectType {
-    fn default() -> Self {
-        Self(Default::default())
-    }
-}
-
 impl ObjectType {
     #[inline(always)]
     pub fn is_function(&self) -> bool {
-        if self.0 & T_FUNCTION == T_FUNCTION {
-            true
-        } else {
-            false
-        }
+        self.0 & T_FUNCTION == T_FUNCTION
     }
 
     #[inline(always)]
     pub fn into_inner(&self) -> Dt {
         self.0
     }
+
+    /// brief: the type without its variant bits
+    #[inline(always)]
+    pub fn basic_type(&self) -> Dt {
+        self.0 & ((1 << BASIC_TYPE_BIT) - 1)
+    }
 }
 
 #[repr(align(8))]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct LuaTObject {
     pub val: DataType,
     pub val_idx: ObjectType,
 }
 
 impl Default for LuaTObject {
+    /// brief: an empty slot holds nil
     fn default() -> Self {
-        Self {
-            val: Default::default(),
-            val_idx: Default::default(),
+        Some(()).new()
+    }
+}
+
+impl LuaTObject {
+    /// brief: only nil and false are false in conditions
+    #[inline(always)]
+    pub fn is_false(&self) -> bool {
+        match self.val_idx.0 {
+            T_NIL => true,
+            T_BOOLEAN => Option::<bool>::into_inner(self) == Some(false),
+            _ => false,
         }
     }
+
+    #[inline(always)]
+    pub fn is_nil(&self) -> bool {
+        self.val_idx.0 == T_NIL
+    }
+
+    /// brief: turn the key of an empty node into one that no lookup matches
+    #[inline(always)]
+    pub(crate) fn set_dead_key(&mut self) {
+        self.val_idx.0 = T_DEADKEY;
+    }
 }
 
 #[repr(align(8))]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub enum DataType {
     UserData(Option<*mut ()>),
+    FullUserData(Option<*mut LuaUserData>),
     Function(Option<FFUNC>),
+    LClosure(Option<*mut LClosure>),
+    RClosure(Option<*mut RClosure>),
+    String(Option<*mut LuaString>),
+    Table(Option<*mut LuaTable>),
+    Thread(Option<*mut LuaState>),
     Bool(Option<bool>),
     Integer(Option<INT>),
     Number(Option<FLT>),
@@ -91,6 +159,7 @@ impl Default for DataType {
 }
 
 pub trait ObjectTrait {
+    #[allow(clippy::new_ret_no_self, clippy::wrong_self_convention)]
     fn new(self) -> LuaTObject;
 
     fn set_value(self, obj: &mut LuaTObject);
@@ -98,47 +167,52 @@ pub trait ObjectTrait {
     fn into_inner(obj: &LuaTObject) -> Self;
 }
 
-impl ObjectTrait for ErrCode {
+
+impl ObjectTrait for Option<*mut ()> {
     fn new(self) -> LuaTObject {
         LuaTObject {
-            val: DataType::Nil(None),
-            val_idx: ObjectType(self.0),
+            val_idx: ObjectType(T_LIGHT_USER_DATA),
+            val: DataType::UserData(self),
         }
     }
 
     fn set_value(self, obj: &mut LuaTObject) {
-        obj.val = DataType::Nil(None);
-        obj.val_idx = ObjectType(self.0);
+        obj.val = DataType::UserData(self);
+        obj.val_idx.0 = T_LIGHT_USER_DATA;
     }
 
     fn into_inner(obj: &LuaTObject) -> Self {
-        if let DataType::Nil(_id) = obj.val {
-            return ErrCode(NONE_OBJECT);
+        if obj.val_idx.0 != T_LIGHT_USER_DATA {
+            return None;
+        }
+
+        if let DataType::UserData(mut val) = obj.val {
+            val.take()
         } else {
-            ErrCode(obj.val_idx.0)
+            None
         }
     }
 }
 
-impl ObjectTrait for Option<*mut ()> {
+impl ObjectTrait for Option<*mut LuaUserData> {
     fn new(self) -> LuaTObject {
         LuaTObject {
-            val_idx: ObjectType(T_LIGHT_USER_DATA),
-            val: DataType::UserData(self),
+            val_idx: ObjectType(T_USER_DATA),
+            val: DataType::FullUserData(self),
         }
     }
 
     fn set_value(self, obj: &mut LuaTObject) {
-        obj.val = DataType::UserData(self);
-        obj.val_idx.0 = T_LIGHT_USER_DATA;
+        obj.val = DataType::FullUserData(self);
+        obj.val_idx.0 = T_USER_DATA;
     }
 
     fn into_inner(obj: &LuaTObject) -> Self {
-        if obj.val_idx.0 != T_LIGHT_USER_DATA {
+        if obj.val_idx.0 != T_USER_DATA {
             return None;
         }
 
-        if let DataType::UserData(mut val) = obj.val {
+        if let DataType::FullUserData(mut val) = obj.val {
             val.take()
         } else {
             None
@@ -172,6 +246,138 @@ impl ObjectTrait for Option<FFUNC> {
     }
 }
 
+impl ObjectTrait for Option<*mut LClosure> {
+    fn new(self) -> LuaTObject {
+        LuaTObject {
+            val_idx: ObjectType(T_LCL),
+            val: DataType::LClosure(self),
+        }
+    }
+
+    fn set_value(self, obj: &mut LuaTObject) {
+        obj.val = DataType::LClosure(self);
+        obj.val_idx.0 = T_LCL;
+    }
+
+    fn into_inner(obj: &LuaTObject) -> Self {
+        if obj.val_idx.0 != T_LCL {
+            return None;
+        }
+
+        if let DataType::LClosure(mut val) = obj.val {
+            val.take()
+        } else {
+            None
+        }
+    }
+}
+
+impl ObjectTrait for Option<*mut RClosure> {
+    fn new(self) -> LuaTObject {
+        LuaTObject {
+            val_idx: ObjectType(T_CCL),
+            val: DataType::RClosure(self),
+        }
+    }
+
+    fn set_value(self, obj: &mut LuaTObject) {
+        obj.val = DataType::RClosure(self);
+        obj.val_idx.0 = T_CCL;
+    }
+
+    fn into_inner(obj: &LuaTObject) -> Self {
+        if obj.val_idx.0 != T_CCL {
+            return None;
+        }
+
+        if let DataType::RClosure(mut val) = obj.val {
+            val.take()
+        } else {
+            None
+        }
+    }
+}
+
+impl ObjectTrait for Option<*mut LuaString> {
+    fn new(self) -> LuaTObject {
+        // the variant tag comes from the string itself
+        let tt = self.map_or(T_SHR_STR, |ts| unsafe { (*ts).tt() });
+        LuaTObject {
+            val_idx: ObjectType(tt),
+            val: DataType::String(self),
+        }
+    }
+
+    fn set_value(self, obj: &mut LuaTObject) {
+        obj.val_idx.0 = self.map_or(T_SHR_STR, |ts| unsafe { (*ts).tt() });
+        obj.val = DataType::String(self);
+    }
+
+    fn into_inner(obj: &LuaTObject) -> Self {
+        if obj.val_idx.basic_type() != T_STRING {
+            return None;
+        }
+
+        if let DataType::String(mut val) = obj.val {
+            val.take()
+        } else {
+            None
+        }
+    }
+}
+
+impl ObjectTrait for Option<*mut LuaTable> {
+    fn new(self) -> LuaTObject {
+        LuaTObject {
+            val_idx: ObjectType(T_TABLE),
+            val: DataType::Table(self),
+        }
+    }
+
+    fn set_value(self, obj: &mut LuaTObject) {
+        obj.val = DataType::Table(self);
+        obj.val_idx.0 = T_TABLE;
+    }
+
+    fn into_inner(obj: &LuaTObject) -> Self {
+        if obj.val_idx.0 != T_TABLE {
+            return None;
+        }
+
+        if let DataType::Table(mut val) = obj.val {
+            val.take()
+        } else {
+            None
+        }
+    }
+}
+
+impl ObjectTrait for Option<*mut LuaState> {
+    fn new(self) -> LuaTObject {
+        LuaTObject {
+            val_idx: ObjectType(T_THREAD),
+            val: DataType::Thread(self),
+        }
+    }
+
+    fn set_value(self, obj: &mut LuaTObject) {
+        obj.val = DataType::Thread(self);
+        obj.val_idx.0 = T_THREAD;
+    }
+
+    fn into_inner(obj: &LuaTObject) -> Self {
+        if obj.val_idx.0 != T_THREAD {
+            return None;
+        }
+
+        if let DataType::Thread(mut val) = obj.val {
+            val.take()
+        } else {
+            None
+        }
+    }
+}
+
 impl ObjectTrait for Option<bool> {
     fn new(self) -> LuaTObject {
         LuaTObject {
@@ -280,10 +486,16 @@ impl DataType {
     pub fn is_none(&self) -> bool {
         match self {
             DataType::UserData(val) => val.is_none(),
+            DataType::FullUserData(val) => val.is_none(),
             DataType::Bool(val) => val.is_none(),
             DataType::Integer(val) => val.is_none(),
             DataType::Number(val) => val.is_none(),
             DataType::Function(val) => val.is_none(),
+            DataType::LClosure(val) => val.is_none(),
+            DataType::RClosure(val) => val.is_none(),
+            DataType::String(val) => val.is_none(),
+            DataType::Table(val) => val.is_none(),
+            DataType::Thread(val) => val.is_none(),
             DataType::Nil(_) => true,
         }
     }
@@ -291,10 +503,16 @@ impl DataType {
     pub fn is_some(&self) -> bool {
         match self {
             DataType::UserData(val) => val.is_some(),
+            DataType::FullUserData(val) => val.is_some(),
             DataType::Bool(val) => val.is_some(),
             DataType::Integer(val) => val.is_some(),
             DataType::Number(val) => val.is_some(),
             DataType::Function(val) => val.is_some(),
+            DataType::LClosure(val) => val.is_some(),
+            DataType::RClosure(val) => val.is_some(),
+            DataType::String(val) => val.is_some(),
+            DataType::Table(val) => val.is_some(),
+            DataType::Thread(val) => val.is_some(),
             DataType::Nil(_) => false,
         }
     }
@@ -1,23 +1,34 @@
 use core::cell::UnsafeCell;
-use core::mem::swap;
+use core::mem::{size_of, swap};
 use core::ptr::null_mut;
 use core::ptr::NonNull;
 
 use crate::info::lua::MEMORY_ALLOC_FAIL;
+use crate::info::lua::MEMORY_BORROW_FAIL;
 use crate::info::lua::MEMORY_REALLOC_FAIL;
 use crate::info::lua::MEMORY_TYPE_MISMATCH;
 use crate::info::lua::MEMORY_UNREACHABLE;
 use crate::vec_pop;
 use crate::{
     info::lua::{
-        ErrCode, FINE, LUA_CI_LEN, LUA_EXTRASPACE, LUA_EXTRA_STACK, LUA_MAX_CALLS, LUA_MAX_STACK,
-        LUA_MIN_STACK, LUA_STACK_SIZE,
+        ErrCode, FINE, INVOKE_STACK_OVERFLOW, LUA_CI_LEN, LUA_EXTRASPACE, LUA_EXTRA_STACK, LUA_MAX_LCALLS, LUA_MAX_STACK,
+        LUA_MIN_STACK, LUA_REGISTRY_INDEX, LUA_RIDX_GLOBALS, LUA_RIDX_LAST, LUA_STACK_SIZE,
     },
-    obj::objdef::{ObjectTrait, TObj, FFUNC, FLT, INT},
-    ptr_get, vec_alloc, vec_push, DEBUG,
+    obj::{
+        closure::UpVal,
+        gc::{Collector, GcHeader},
+        objdef::{ObjectTrait, TObj, FFUNC, FLT, INT, KCTX, KFUNC, LUA_NUM_TYPES, T_THREAD},
+        string::{make_seed, LuaString, StringTable},
+        table::LuaTable,
+    },
+    ptr_get, vec_alloc, vec_push,
+    vm::tm::TM_N,
+    DEBUG,
 };
 
 pub type StkElem = TObj;
+/// brief: gets the warnings, a message may come in pieces, `tocont` tells that more follow
+pub type WARNF = fn(&str, bool);
 
 #[derive(Debug)]
 pub struct Stack(Vec<UnsafeCell<StkElem>>);
@@ -26,19 +37,19 @@ impl Stack {
     /// brief: alloc a new stack with capacity and length
     /// note that capacity >= length
     #[inline]
+    #[allow(clippy::needless_return)]
     fn new(capacity: usize, length: usize) -> Result<Stack, ErrCode> {
         // return error if length is greater than capacity
         if length > capacity {
             return Err(ErrCode(MEMORY_ALLOC_FAIL));
         }
-        let mut stk = Stack {
-            0: vec_alloc!(capacity),
-        };
+        let mut stk = Stack(vec_alloc!(capacity));
         vec_push!(stk.0, UnsafeCell::from(<StkElem>::default()), length);
         return Ok(stk);
     }
 
     #[inline(always)]
+    #[allow(clippy::mut_from_ref)]
     pub fn get_mut_elem(&self, index: usize) -> Result<&mut StkElem, ErrCode> {
         if let Some(stk) = self.0.get(index) {
             Ok(unsafe { &mut *(stk.get()) })
@@ -75,6 +86,17 @@ impl Stack {
     }
 
     #[inline(always)]
+    pub fn set_elem(&self, index: usize, elem: StkElem) -> Result<ErrCode, ErrCode> {
+        if let Some(stk) = self.0.get(index) {
+            unsafe { *(stk.get()) = elem };
+            Ok(ErrCode(FINE))
+        } else {
+            Err(ErrCode(MEMORY_UNREACHABLE))
+        }
+    }
+
+    #[inline(always)]
+    #[allow(clippy::needless_return)]
     pub fn swap_elem(&self, index: usize, new_stkelem: &mut StkElem) -> Result<ErrCode, ErrCode> {
         if let Some(s) = self.0.get(index) {
             let stk = s.get();
@@ -89,6 +111,7 @@ impl Stack {
         }
     }
 
+    #[allow(clippy::needless_return)]
     fn increase(&mut self, need: usize) -> Result<usize, ErrCode> {
         // the space that has been allocated
         let old_alloc = self.0.len();
@@ -123,9 +146,16 @@ impl Stack {
 
 #[derive(Default, Debug)]
 pub struct Frame {
-    stack_func_index: usize,
-    stack_upper_bound: usize,
+    pub(crate) stack_func_index: usize,
+    pub(crate) stack_upper_bound: usize,
     callstatus: ErrCode,
+    pub(crate) savedpc: usize,    // next instruction of a Lua frame
+    pub(crate) nresults: isize,   // results wanted by the caller
+    pub(crate) nextraargs: usize, // extra arguments of a vararg Lua frame
+    pub(crate) fresh: bool,       // returning from it leaves the interpreter loop
+    pub(crate) k: Option<KFUNC>,  // continuation of a Rust frame, in case it is suspended
+    pub(crate) ctx: KCTX,         // context of the continuation
+    pub(crate) ypcall: Option<(usize, Option<usize>)>, // function and handler slots of a `pcall_k`
 }
 
 impl Frame {
@@ -134,15 +164,12 @@ impl Frame {
             stack_func_index,
             stack_upper_bound: stack_top_index,
             callstatus: status,
+            ..Default::default()
         }
     }
 
     fn fm_check_stkedge(&self, size: usize) -> bool {
-        if self.stack_func_index + size >= self.stack_upper_bound {
-            false
-        } else {
-            true
-        }
+        self.stack_func_index + size < self.stack_upper_bound
     }
 }
 
@@ -156,14 +183,13 @@ impl FrameVec {
             return Err(ErrCode(MEMORY_ALLOC_FAIL));
         }
 
-        let mut frames = FrameVec {
-            0: vec_alloc!(capacity),
-        };
+        let mut frames = FrameVec(vec_alloc!(capacity));
 
         vec_push!(frames.0, UnsafeCell::from(<Frame>::default()), length);
         Ok(frames)
     }
 
+    #[allow(clippy::needless_return)]
     fn swap_elem(&self, index: usize, new_ci: &mut Frame) -> Result<ErrCode, ErrCode> {
         if let Some(c) = self.0.get(index) {
             let ci = c.get();
@@ -178,11 +204,12 @@ impl FrameVec {
         }
     }
 
+    #[allow(clippy::needless_return)]
     fn increase(&mut self, civ_top_index: usize, need: usize) -> Result<usize, ErrCode> {
         // the space that has been allocated
         let old_alloc = self.0.len();
 
-        if old_alloc > LUA_MAX_CALLS as usize {
+        if old_alloc > LUA_MAX_LCALLS {
             return Err(ErrCode(MEMORY_REALLOC_FAIL));
         }
         // will never happen
@@ -196,11 +223,11 @@ impl FrameVec {
                 to_add = to_add2;
             }
 
-            if old_alloc + to_add > LUA_MAX_STACK as usize {
-                if old_alloc + to_add2 > LUA_MAX_STACK as usize {
+            if old_alloc + to_add > LUA_MAX_LCALLS {
+                // grow up to the limit, fail only when the frames really do not fit
+                to_add = LUA_MAX_LCALLS - old_alloc;
+                if civ_top_index + need > LUA_MAX_LCALLS {
                     return Err(ErrCode(MEMORY_REALLOC_FAIL));
-                } else {
-                    to_add = to_add2;
                 }
             }
             // capacity >= length
@@ -213,9 +240,10 @@ impl FrameVec {
         } // it is not necessary to add
     }
 
+    #[allow(clippy::needless_return)]
     fn decrease(&mut self, ncalls: usize) -> Result<usize, ErrCode> {
         let old_alloc = self.0.len();
-        if old_alloc > LUA_MAX_CALLS as usize {
+        if old_alloc > LUA_MAX_LCALLS {
             return Err(ErrCode(MEMORY_REALLOC_FAIL));
         }
         if old_alloc <= LUA_CI_LEN {
@@ -243,6 +271,7 @@ impl FrameVec {
     }
 
     #[inline(always)]
+    #[allow(clippy::mut_from_ref)]
     fn get_mut_elem(&self, index: usize) -> Result<&mut Frame, ErrCode> {
         if let Some(ci) = self.0.get(index) {
             Ok(unsafe { &mut *(ci.get()) })
@@ -267,6 +296,7 @@ struct Meta {
 static mut META: *mut Meta = null_mut();
 
 impl Meta {
+    #[allow(clippy::new_ret_no_self)]
     fn new() {
         unsafe { META = Box::leak(Box::new(Meta::default())) };
         DEBUG!("META is created successfully");
@@ -315,28 +345,48 @@ macro_rules! get_main_state_ptr {
 
 #[derive(Default, Debug)]
 struct Base {
+    #[allow(dead_code)]
     pub extra: [UnsafeCell<u8>; LUA_EXTRASPACE],
     pub state: UnsafeCell<LuaState>,
 }
 
 #[derive(Default, Debug)]
-struct GlobalState {
-    mainthread: Option<NonNull<LuaState>>,
+pub(crate) struct GlobalState {
+    pub(crate) mainthread: Option<NonNull<LuaState>>,
     userdata: Option<NonNull<()>>,
+    pub(crate) strt: StringTable, // interned short strings
+    pub(crate) seed: u32,         // randomizes string hashes
+    pub(crate) registry: *mut LuaTable, // holds the table of globals, and whatever Rust code keeps
+    pub(crate) gc: Collector,
+    pub(crate) mt: [*mut LuaTable; LUA_NUM_TYPES], // metatables of the basic types but tables
+    pub(crate) tmname: [*mut LuaString; TM_N], // names of the events, e.g. `__index`
+    pub(crate) warnf: Option<WARNF>, // warnings are dropped without it
+    pub(crate) nresumes: usize, // coroutines resumed one inside another
+    pub(crate) nrcalls: usize,  // calls nested in the Rust stack, which all the threads share
+    pub(crate) closed: bool,    // all the objects are freed, the state is no longer usable
 }
 
+#[repr(C)]
 #[derive(Debug, Default)]
 pub struct LuaState {
+    header: GcHeader, // threads but the main one are collectable
     pub stack: Option<NonNull<Stack>>, // a pointer to the stack
     pub stack_last_index: usize,
     pub stack_top_index: usize, // first not used
     pub stack_size: usize,
-    next: Option<NonNull<LuaState>>,     // default value: None
+    #[allow(dead_code)]
+    next: Option<NonNull<LuaState>>, // default value: None
+    #[allow(dead_code)]
     previous: Option<NonNull<LuaState>>, // default value: None
     frames: Option<NonNull<FrameVec>>,
     pub ncalls: usize, // [frame]= ncalls -1
     global: Option<NonNull<GlobalState>>,
     status: ErrCode,
+    pub(crate) openupval: *mut UpVal, // open upvalues of this stack, by decreasing level
+    pub(crate) tbclist: Vec<usize>,   // to-be-closed variables, by increasing level
+    pub(crate) nny: usize,            // non-yieldable calls in the stack
+    pub(crate) nyield: usize,         // values yielded, while suspended
+    pub(crate) errobj: TObj,          // the value raised by `error`, until it is caught
 }
 
 impl LuaState {
@@ -352,6 +402,15 @@ impl LuaState {
         self.stack_top_index
     }
 
+    /// brief: the number of values above the running function, its arguments when it has
+    /// just been called; argument i, from 1, is `arg_count() - i` slots below the top
+    pub fn arg_count(&self) -> usize {
+        match self.get_frame(self.ncalls.wrapping_sub(1)) {
+            Ok(frame) => self.stack_top_index - (frame.stack_func_index + 1),
+            Err(_) => self.stack_top_index,
+        }
+    }
+
     pub fn change_ncalls(&mut self, step: usize, direction: bool) {
         self.ncalls = {
             if direction {
@@ -362,6 +421,7 @@ impl LuaState {
         }
     }
 
+    #[allow(clippy::needless_return)]
     pub fn write_frame_status(&self, ci_index: usize, status: ErrCode) -> Result<ErrCode, ErrCode> {
         let cci: &mut Frame = ptr_get!(self, frames)?.get_mut_elem(ci_index)?;
         cci.callstatus = status;
@@ -373,6 +433,42 @@ impl LuaState {
         Ok(cci.callstatus)
     }
 
+    /// the reference must not be kept across `push_frame`, which may move the frames
+    pub fn get_frame(&self, ci_index: usize) -> Result<&Frame, ErrCode> {
+        ptr_get!(self, frames)?.get_ref_elem(ci_index)
+    }
+
+    /// the reference must not be kept across `push_frame`, which may move the frames
+    pub fn get_frame_mut(&mut self, ci_index: usize) -> Result<&mut Frame, ErrCode> {
+        ptr_get!(self, frames)?.get_mut_elem(ci_index)
+    }
+
+    /// brief: the state shared by the threads, none once it is closed
+    pub(crate) fn global(&self) -> Result<&GlobalState, ErrCode> {
+        let g = ptr_get!(self, global)?;
+        if g.closed {
+            return Err(ErrCode(MEMORY_UNREACHABLE));
+        }
+        Ok(g)
+    }
+
+    pub(crate) fn global_mut(&mut self) -> Result<&mut GlobalState, ErrCode> {
+        let g = ptr_get!(self, global)?;
+        if g.closed {
+            return Err(ErrCode(MEMORY_UNREACHABLE));
+        }
+        Ok(g)
+    }
+
+    /// brief: the bytes a thread accounts for, with its initial stack and frames
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<LuaState>()
+            + LUA_STACK_SIZE as usize * size_of::<StkElem>()
+            + LUA_CI_LEN * size_of::<Frame>()
+    }
+
+    #[allow(clippy::mut_from_ref)]
     pub fn get_stack_mut_ref(&self) -> Result<&mut Stack, ErrCode> {
         if let Some(stack) = self.stack {
             let ptr = stack.as_ptr();
@@ -386,6 +482,7 @@ impl LuaState {
         }
     }
 
+    #[allow(clippy::mut_from_ref)]
     pub fn get_civ_mut_ref(&self) -> Result<&mut FrameVec, ErrCode> {
         if let Some(civ) = self.frames {
             let ptr = civ.as_ptr();
@@ -400,9 +497,11 @@ impl LuaState {
     }
 
     /// initialize the stack, drop the memory manually
+    #[allow(clippy::needless_return, clippy::identity_op)]
     fn stack_init(&mut self) -> Result<ErrCode, ErrCode> {
         // None type will return only if length size is greater that capacity
-        let stk = Stack::new(LUA_MAX_STACK as usize, LUA_STACK_SIZE as usize);
+        // it grows on demand, up to LUA_MAX_STACK
+        let stk = Stack::new(LUA_STACK_SIZE as usize, LUA_STACK_SIZE as usize);
 
         // static lifetime
         self.stack = Some(NonNull::from(Box::leak(Box::new(stk?))));
@@ -418,7 +517,9 @@ impl LuaState {
     }
 
     fn stack_increase(&mut self, size: usize) -> Result<ErrCode, ErrCode> {
-        let size_add = ptr_get!(self, stack)?.increase(size)?;
+        let size_add = ptr_get!(self, stack)?
+            .increase(size)
+            .map_err(|_| ErrCode(INVOKE_STACK_OVERFLOW))?;
         self.stack_size += size_add;
         self.stack_last_index = self.stack_size - LUA_EXTRA_STACK as usize;
         Ok(ErrCode(FINE))
@@ -434,7 +535,7 @@ impl LuaState {
     /// true: legal
     /// false: illegal
     pub fn calls_check(&self) -> bool {
-        !(self.ncalls >= LUA_MAX_CALLS)
+        self.ncalls < LUA_MAX_LCALLS
     }
 
     const ILLEGAL_INDEX: usize = usize::MAX;
@@ -449,16 +550,19 @@ impl LuaState {
     }
 
     fn stack_clear(&mut self) {
-        drop(self.stack.take());
-        self.stack = None;
+        if let Some(stk) = self.stack.take() {
+            drop(unsafe { Box::from_raw(stk.as_ptr()) });
+        }
         self.stack_size = 0;
         self.stack_top_index = Self::ILLEGAL_INDEX;
         self.stack_last_index = Self::ILLEGAL_INDEX;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn frames_init(&mut self) -> Result<ErrCode, ErrCode> {
         // None type will return only if length size is greater that capacity
-        let frames = FrameVec::new(LUA_MAX_CALLS, LUA_CI_LEN);
+        // it grows on demand, up to LUA_MAX_LCALLS
+        let frames = FrameVec::new(LUA_CI_LEN, LUA_CI_LEN);
         // static lifetime
         let civ_box = Box::new(frames?);
         self.frames = Some(NonNull::from(Box::leak(civ_box)));
@@ -478,12 +582,7 @@ impl LuaState {
             ErrCode(FINE),
         );
 
-        DEBUG!("func_index: {}", func_index);
-        DEBUG!("self.stack_top_index {}", self.stack_top_index);
-        DEBUG!("ci stack_upper_bound {}", ci.stack_upper_bound);
-
         civ_ptr.swap_elem(self.ncalls, &mut ci)?;
-        DEBUG!("previous call val:{}", self.ncalls);
         self.ncalls += 1;
         Ok(self.ncalls - 1)
     }
@@ -493,8 +592,7 @@ impl LuaState {
         let frames = ptr_get!(self, frames)?;
         // frames.swap_elem(self.ncalls - 1, &mut empty_frame)?;
         self.ncalls -= 1;
-        let len = frames.decrease(self.ncalls)?;
-        DEBUG!("new len of frames is: {}", len);
+        frames.decrease(self.ncalls)?;
         Ok(ErrCode(FINE))
     }
 
@@ -505,7 +603,9 @@ impl LuaState {
     }
 
     fn frames_clear(&mut self) {
-        drop(self.frames.take());
+        if let Some(frames) = self.frames.take() {
+            drop(unsafe { Box::from_raw(frames.as_ptr()) });
+        }
         self.ncalls = 0; // no space
     }
 
@@ -513,18 +613,96 @@ impl LuaState {
         // initialize meta, with default value
         Meta::new();
         // global state accepts userdata
-        get_global_state!()?.userdata = Some(NonNull::from(unsafe { &*ud }));
+        get_global_state!()?.userdata = NonNull::new(ud as *mut ());
+        get_global_state!()?.seed = make_seed(get_meta_mut()? as usize);
         // link the state with global state
         get_main_state!()?.global = Some(NonNull::from(get_global_state!()?));
         // link the global state with the state
         get_global_state!()?.mainthread = Some(NonNull::from(get_main_state!()?));
+        // the main thread is never collected, nor can it yield
+        get_main_state!()?.header = GcHeader::new(T_THREAD);
+        get_main_state!()?.nny = 1;
         // stack initialize
         let _ = get_main_state!()?.stack_init()?;
         // civ initialize
         let _ = get_main_state!()?.frames_init()?;
+        // the registry and the table of globals
+        let _ = get_main_state!()?.init_registry()?;
+        // names of the metamethods
+        let _ = get_main_state!()?.init_tm()?;
         Ok(get_main_state_ptr!()?)
     }
 
+    /// brief: a new thread sharing the global state, with a stack and frames of its own,
+    /// linked to the collector
+    pub(crate) fn thread_new(&mut self) -> Result<*mut LuaState, ErrCode> {
+        let mut th = Box::<LuaState>::default();
+        th.header = GcHeader::new(T_THREAD);
+        th.global = self.global;
+        th.stack_init()?;
+        th.frames_init()?;
+        Ok(self.global_mut()?.link(Box::leak(th)))
+    }
+
+    fn init_registry(&mut self) -> Result<ErrCode, ErrCode> {
+        let g = self.global_mut()?;
+        let registry = g.link(LuaTable::new(LUA_RIDX_LAST, 0));
+        let globals = g.link(LuaTable::new(0, 0));
+        unsafe { (*registry).set_int(LUA_RIDX_GLOBALS as INT, Some(globals).new()) };
+        g.registry = registry;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: close the state, the open upvalues are closed, every pending finalizer
+    /// is called and all the objects are freed; afterwards, what needs the state fails
+    /// with `MEMORY_UNREACHABLE`; it fails, and nothing is freed, while Rust borrows
+    /// the value of a userdata
+    pub fn close(&mut self) -> Result<ErrCode, ErrCode> {
+        if self.global()?.any_borrowed() {
+            return Err(ErrCode(MEMORY_BORROW_FAIL));
+        }
+        self.close_upvals(0)?;
+        self.tbclist.clear();
+        self.free_all_objects()?;
+        let g = self.global_mut()?;
+        g.registry = null_mut();
+        g.mt = [null_mut(); LUA_NUM_TYPES];
+        g.tmname = [null_mut(); TM_N];
+        g.closed = true;
+        let stk = self.get_stack_mut_ref()?;
+        for j in 0..self.stack_size {
+            stk.set_elem(j, StkElem::default())?;
+        }
+        self.stack_top_index = 0;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: set the function that gets the warnings, none turns them off
+    pub fn set_warnf(&mut self, warnf: Option<WARNF>) -> Result<ErrCode, ErrCode> {
+        self.global_mut()?.warnf = warnf;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: emit a warning, or a piece of it when `tocont` is true
+    pub fn warning(&mut self, msg: &str, tocont: bool) {
+        if let Ok(Some(warnf)) = self.global_mut().map(|g| g.warnf) {
+            warnf(msg, tocont);
+        }
+    }
+
+    /// brief: warn about an error raised where no one can catch it, such as in a finalizer
+    pub(crate) fn warn_error(&mut self, place: &str, err: &TObj) {
+        let msg = match Option::<*mut LuaString>::into_inner(err) {
+            Some(ts) => String::from_utf8_lossy(unsafe { (*ts).as_bytes() }).into_owned(),
+            None => String::from("error object is not a string"),
+        };
+        self.warning("error in ", true);
+        self.warning(place, true);
+        self.warning(" (", true);
+        self.warning(&msg, true);
+        self.warning(")", false);
+    }
+
     #[inline(always)]
     pub fn move_top_to(&mut self, index: usize) {
         self.stack_top_index = index;
@@ -545,13 +723,6 @@ impl LuaState {
         self.move_top(1, true);
     }
 
-    pub fn push_errcode(&mut self, code: ErrCode) -> Result<ErrCode, ErrCode> {
-        let mut elem = ErrCode::new(code);
-        ptr_get!(self, stack)?.swap_elem(self.stack_top_index, &mut elem)?;
-        self.increase_top();
-        Ok(ErrCode(FINE))
-    }
-
     pub fn push_integer(&mut self, integer: INT) -> Result<ErrCode, ErrCode> {
         let mut elem = Option::<INT>::new(Some(integer));
         ptr_get!(self, stack)?.swap_elem(self.stack_top_index, &mut elem)?;
@@ -618,7 +789,10 @@ impl LuaState {
     #[inline(always)]
     // start from 0
     pub fn get_stkelem_fromtop(&mut self, step: usize) -> Result<StkElem, ErrCode> {
-        Ok(ptr_get!(self, stack)?.get_elem(self.stack_top_index - 1 - step)?)
+        if step >= LUA_REGISTRY_INDEX {
+            return self.pseudo_get(step);
+        }
+        ptr_get!(self, stack)?.get_elem(self.stack_top_index - 1 - step)
     }
 
     pub fn cstack_clear(&mut self, index: usize) -> Result<ErrCode, ErrCode> {
@@ -633,11 +807,6 @@ impl LuaState {
         Ok(ErrCode(FINE))
     }
 
-    pub fn get_errcode_fromtop(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
-        let elem = self.get_stkelem_fromtop(step)?;
-        Ok(ErrCode::into_inner(&elem))
-    }
-
     pub fn get_rfunc_fromtop(&mut self, step: usize) -> Result<FFUNC, ErrCode> {
         let elem = self.get_stkelem_fromtop(step)?;
         if let Some(val) = Option::<FFUNC>::into_inner(&elem) {
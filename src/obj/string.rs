@@ -0,0 +1,296 @@
+use core::cell::Cell;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::info::lua::{ErrCode, FINE, LUAI_MAXSHORTLEN, MEMORY_TYPE_MISMATCH};
+
+use super::gc::GcHeader;
+use super::objdef::{Dt, ObjectTrait, T_LNG_STR, T_SHR_STR};
+use super::statedef::LuaState;
+
+const MINSTRTABSIZE: usize = 128; // initial size of the string table
+
+/// brief: an immutable byte string;
+/// short strings are interned, so two equal ones are the same object,
+/// long strings are hashed only when a table needs it
+#[repr(C)]
+#[derive(Debug)]
+pub struct LuaString {
+    header: GcHeader,                 // tt is T_SHR_STR or T_LNG_STR
+    hash: Cell<u32>,                  // the seed, until a long string is hashed
+    hashed: Cell<bool>,               // long strings: whether `hash` is computed
+    pub(crate) hnext: *mut LuaString, // chain of a bucket of the string table
+    data: Box<[u8]>,
+}
+
+impl LuaString {
+    fn new(tt: Dt, data: &[u8], hash: u32) -> *mut LuaString {
+        Box::leak(Box::new(LuaString {
+            header: GcHeader::new(tt),
+            hash: Cell::new(hash),
+            hashed: Cell::new(tt == T_SHR_STR),
+            hnext: null_mut(),
+            data: data.into(),
+        }))
+    }
+
+    #[inline(always)]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    #[inline(always)]
+    pub fn is_short(&self) -> bool {
+        self.header.tt == T_SHR_STR
+    }
+
+    #[inline(always)]
+    pub fn tt(&self) -> Dt {
+        self.header.tt
+    }
+
+    /// brief: the bytes the string accounts for
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<LuaString>() + self.data.len()
+    }
+
+    /// brief: the hash of the string, computed on first use for a long one
+    pub fn hash(&self) -> u32 {
+        if !self.hashed.get() {
+            self.hash.set(str_hash(&self.data, self.hash.get()));
+            self.hashed.set(true);
+        }
+        self.hash.get()
+    }
+
+    /// brief: equality of contents, short strings can compare their addresses instead
+    #[inline(always)]
+    pub fn eq_contents(&self, other: &LuaString) -> bool {
+        self.data == other.data
+    }
+}
+
+pub fn str_hash(s: &[u8], seed: u32) -> u32 {
+    let mut h = seed ^ (s.len() as u32);
+    for &c in s.iter().rev() {
+        h ^= (h << 5).wrapping_add(h >> 2).wrapping_add(c as u32);
+    }
+    h
+}
+
+/// brief: a seed for string hashes, different on every run
+pub fn make_seed(addr: usize) -> u32 {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut h = (t ^ (t >> 32)) as u32;
+    h ^= (addr ^ (addr >> 32)) as u32;
+    h ^= (&h as *const u32 as usize) as u32;
+    str_hash(&h.to_ne_bytes(), h)
+}
+
+/// brief: the table of interned short strings, buckets of chained strings
+#[derive(Debug, Default)]
+pub struct StringTable {
+    hash: Vec<*mut LuaString>,
+    nuse: usize, // number of strings
+}
+
+impl StringTable {
+    #[inline(always)]
+    fn bucket(&self, h: u32) -> usize {
+        (h as usize) & (self.hash.len() - 1)
+    }
+
+    fn resize(&mut self, newsize: usize) {
+        let old = std::mem::replace(&mut self.hash, vec![null_mut(); newsize]);
+        for mut p in old {
+            while !p.is_null() {
+                let ts = unsafe { &mut *p };
+                let next = ts.hnext;
+                let b = self.bucket(ts.hash());
+                ts.hnext = self.hash[b];
+                self.hash[b] = p;
+                p = next;
+            }
+        }
+    }
+
+    /// brief: the interned string with these contents and hash
+    fn lookup(&self, s: &[u8], h: u32) -> Option<*mut LuaString> {
+        if self.hash.is_empty() {
+            return None;
+        }
+        let mut p = self.hash[self.bucket(h)];
+        while !p.is_null() {
+            let ts = unsafe { &*p };
+            if ts.as_bytes() == s {
+                return Some(p);
+            }
+            p = ts.hnext;
+        }
+        None
+    }
+
+    fn insert(&mut self, ts: *mut LuaString) {
+        if self.hash.is_empty() {
+            self.resize(MINSTRTABSIZE);
+        } else if self.nuse >= self.hash.len() {
+            self.resize(self.hash.len() * 2);
+        }
+        let b = self.bucket(unsafe { (*ts).hash() });
+        unsafe { (*ts).hnext = self.hash[b] };
+        self.hash[b] = ts;
+        self.nuse += 1;
+    }
+
+    /// brief: unlink a string that is being freed
+    pub(crate) fn remove(&mut self, ts: *mut LuaString) {
+        let b = self.bucket(unsafe { (*ts).hash() });
+        let mut p: *mut *mut LuaString = &mut self.hash[b];
+        unsafe {
+            while !(*p).is_null() {
+                if *p == ts {
+                    *p = (*ts).hnext;
+                    self.nuse -= 1;
+                    return;
+                }
+                p = &mut (**p).hnext;
+            }
+        }
+    }
+
+    /// brief: shrink the table when it is mostly empty
+    pub(crate) fn check_size(&mut self) {
+        let size = self.hash.len();
+        if size > MINSTRTABSIZE && self.nuse < size / 4 {
+            self.resize(size / 2);
+        }
+    }
+}
+
+impl LuaState {
+    /// brief: a string object with these contents,
+    /// short strings come from the string table, long ones are always new
+    pub fn new_string(&mut self, s: &[u8]) -> Result<*mut LuaString, ErrCode> {
+        let g = self.global_mut()?;
+        if s.len() <= LUAI_MAXSHORTLEN {
+            let h = str_hash(s, g.seed);
+            if let Some(ts) = g.strt.lookup(s, h) {
+                // a dead string not yet swept is still in the table
+                if g.is_dead(ts as *mut GcHeader) {
+                    g.resurrect(ts as *mut GcHeader);
+                }
+                return Ok(ts);
+            }
+            let ts = g.link(LuaString::new(T_SHR_STR, s, h));
+            g.strt.insert(ts);
+            return Ok(ts);
+        }
+        Ok(g.link(LuaString::new(T_LNG_STR, s, g.seed)))
+    }
+
+    pub fn push_string(&mut self, s: &[u8]) -> Result<ErrCode, ErrCode> {
+        let ts = self.new_string(s)?;
+        self.push_obj(Some(ts).new())?;
+        self.check_gc()?;
+        Ok(ErrCode(FINE))
+    }
+
+    pub fn get_string_fromtop(&mut self, step: usize) -> Result<&[u8], ErrCode> {
+        let elem = self.get_stkelem_fromtop(step)?;
+        if let Some(ts) = Option::<*mut LuaString>::into_inner(&elem) {
+            Ok(unsafe { (*ts).as_bytes() })
+        } else {
+            Err(ErrCode(MEMORY_TYPE_MISMATCH))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::machine::tests::{eval, new_state};
+
+    #[test]
+    fn short_strings_are_interned() {
+        let l = new_state();
+        let a = l.new_string(b"hello").unwrap();
+        let b = l.new_string(b"hello").unwrap();
+        assert_eq!(a, b);
+        assert!(unsafe { (*a).is_short() });
+        let short = [b'x'; LUAI_MAXSHORTLEN];
+        let long = [b'x'; LUAI_MAXSHORTLEN + 1];
+        let s1 = l.new_string(&short).unwrap();
+        assert_eq!(s1, l.new_string(&short).unwrap());
+        let l1 = l.new_string(&long).unwrap();
+        let l2 = l.new_string(&long).unwrap();
+        assert_ne!(l1, l2);
+        let (l1, l2) = unsafe { (&*l1, &*l2) };
+        assert_eq!(l1.tt(), T_LNG_STR);
+        assert!(l1.eq_contents(l2));
+        // hashed on demand, with the seed of the state
+        assert_eq!(l1.hash(), str_hash(&long, l.global().unwrap().seed));
+        assert_eq!(l1.hash(), l2.hash());
+    }
+
+    #[test]
+    fn bytes_are_kept_as_they_are() {
+        let l = new_state();
+        l.push_string(b"a\0b\xff").unwrap();
+        assert_eq!(l.get_string_fromtop(0).unwrap(), b"a\0b\xff");
+        l.push_string(b"").unwrap();
+        assert_eq!(l.get_string_fromtop(0).unwrap(), b"");
+        l.move_top(2, false);
+        assert_eq!(
+            eval("local s = 'a\\0b' return #s, s == 'a\\0b', s == 'a'"),
+            ["3", "true", "false"]
+        );
+    }
+
+    #[test]
+    fn long_strings_as_keys() {
+        // two long strings built apart find the same entry
+        let src = "local a, b = '', '' for i = 1, 60 do a = a .. 'k' b = b .. 'k' end
+                   local t = {[a] = 1} t[b] = t[b] + 1 return t[a], a == b, #a";
+        assert_eq!(eval(src), ["2", "true", "60"]);
+    }
+
+    #[test]
+    fn string_table_follows_collection() {
+        let l = new_state();
+        let before = l.global().unwrap().strt.nuse;
+        for j in 0..1000 {
+            l.new_string(format!("s{}", j).as_bytes()).unwrap();
+        }
+        let g = l.global().unwrap();
+        assert!(g.strt.nuse >= before + 1000);
+        let peak = g.strt.hash.len();
+        assert!(peak >= g.strt.nuse);
+        l.gc_collect().unwrap();
+        let g = l.global().unwrap();
+        assert!(g.strt.nuse < before + 1000);
+        // mostly empty, it shrinks
+        assert!(g.strt.hash.len() < peak);
+        // one of them made again is a live string
+        let ts = l.new_string(b"s7").unwrap();
+        l.push_obj(Some(ts).new()).unwrap();
+        l.gc_collect().unwrap();
+        assert_eq!(l.get_string_fromtop(0).unwrap(), b"s7");
+        assert_eq!(l.new_string(b"s7").unwrap(), ts);
+        l.move_top(1, false);
+    }
+}
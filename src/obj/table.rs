@@ -0,0 +1,768 @@
+use core::cell::Cell;
+use core::mem::size_of;
+
+use crate::{
+    info::lua::{
+        ErrCode, FINE, LUA_RIDX_GLOBALS, MEMORY_TYPE_MISMATCH, RUNTIME_NAN_INDEX, RUNTIME_NIL_INDEX,
+    },
+    vm::{arith::flt_to_int, machine::raw_equal},
+};
+
+use super::{
+    gc::GcHeader,
+    objdef::{
+        DataType, ObjectTrait, TObj, FLT, INT, T_BOOLEAN, T_LNG_STR, T_NIL, T_NUM_FLT, T_NUM_INT,
+        T_SHR_STR, T_TABLE, UINT,
+    },
+    statedef::LuaState,
+    string::LuaString,
+};
+use crate::vm::tm::TagMethod;
+
+// largest n such that 2^n array slots are addressable by integer keys
+const MAXABITS: usize = INT::BITS as usize - 1;
+const MAXASIZE: usize = 1 << MAXABITS;
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Node {
+    pub(crate) key: TObj, // nil in a node that was never used
+    pub(crate) val: TObj, // nil in an empty node
+    next: isize,          // offset to the next node of the chain, 0 ends it
+}
+
+/// brief: a Lua table, an array part for the keys 1..n and a hash part for the rest;
+/// colliding keys are chained through the free nodes of the hash part
+#[repr(C)]
+#[derive(Default)]
+pub struct LuaTable {
+    header: GcHeader,
+    pub(crate) array: Vec<TObj>,
+    pub(crate) node: Vec<Node>, // size is a power of 2, or 0 without a hash part
+    lastfree: usize,            // all nodes at or above it are in use
+    pub(crate) metatable: *mut LuaTable,
+    flags: Cell<u8>, // 1 << event: the table, as a metatable, lacks that metamethod
+}
+
+/// brief: ceil(log2(x))
+#[inline(always)]
+fn ceil_log2(x: usize) -> usize {
+    if x <= 1 {
+        return 0;
+    }
+    (usize::BITS - (x - 1).leading_zeros()) as usize
+}
+
+/// brief: the address of the object a value refers to, for hashing
+pub(crate) fn obj_addr(v: &TObj) -> usize {
+    match v.val {
+        DataType::UserData(p) => p.map_or(0, |p| p as usize),
+        DataType::FullUserData(p) => p.map_or(0, |p| p as usize),
+        DataType::Function(f) => f.map_or(0, |f| f as usize),
+        DataType::LClosure(p) => p.map_or(0, |p| p as usize),
+        DataType::RClosure(p) => p.map_or(0, |p| p as usize),
+        DataType::String(p) => p.map_or(0, |p| p as usize),
+        DataType::Table(p) => p.map_or(0, |p| p as usize),
+        DataType::Thread(p) => p.map_or(0, |p| p as usize),
+        _ => 0,
+    }
+}
+
+/// brief: a key as stored, a float with an integer value becomes that integer
+pub fn normalize_key(key: &TObj) -> Result<TObj, ErrCode> {
+    match key.val_idx.into_inner() {
+        T_NIL => Err(ErrCode(RUNTIME_NIL_INDEX)),
+        T_NUM_FLT => {
+            let n = Option::<FLT>::into_inner(key).unwrap_or_default();
+            if n.is_nan() {
+                return Err(ErrCode(RUNTIME_NAN_INDEX));
+            }
+            match flt_to_int(n) {
+                Some(i) => Ok(Some(i).new()),
+                None => Ok(*key),
+            }
+        }
+        _ => Ok(*key),
+    }
+}
+
+impl LuaTable {
+    pub fn new(narray: usize, nhash: usize) -> *mut LuaTable {
+        let mut t = LuaTable {
+            header: GcHeader::new(T_TABLE),
+            ..Default::default()
+        };
+        t.resize(narray, nhash);
+        Box::leak(Box::new(t))
+    }
+
+    /// brief: the bytes the table accounts for
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<LuaTable>()
+            + self.array.len() * size_of::<TObj>()
+            + self.node.len() * size_of::<Node>()
+    }
+
+    /// brief: number of slots of the array part
+    #[inline(always)]
+    pub fn array_size(&self) -> usize {
+        self.array.len()
+    }
+
+    /// brief: index in the array part of an integer key
+    #[inline(always)]
+    fn array_index(&self, i: INT) -> Option<usize> {
+        let idx = (i as UINT as usize).wrapping_sub(1);
+        if i > 0 && idx < self.array.len() {
+            return Some(idx);
+        }
+        None
+    }
+
+    #[inline(always)]
+    fn hash_pow2(&self, h: usize) -> usize {
+        h & (self.node.len() - 1)
+    }
+
+    /// brief: modulo by an odd number, for hashes whose low bits are poor
+    #[inline(always)]
+    fn hash_mod(&self, h: usize) -> usize {
+        h % ((self.node.len() - 1) | 1)
+    }
+
+    /// brief: the node a key hashes to
+    fn main_position(&self, key: &TObj) -> usize {
+        match key.val_idx.into_inner() {
+            T_NUM_INT => {
+                self.hash_mod(Option::<INT>::into_inner(key).unwrap_or_default() as UINT as usize)
+            }
+            T_NUM_FLT => {
+                self.hash_mod(Option::<FLT>::into_inner(key).unwrap_or_default().to_bits() as usize)
+            }
+            T_SHR_STR | T_LNG_STR => {
+                let ts = Option::<*mut LuaString>::into_inner(key);
+                self.hash_pow2(ts.map_or(0, |ts| unsafe { (*ts).hash() }) as usize)
+            }
+            T_BOOLEAN => {
+                self.hash_pow2(Option::<bool>::into_inner(key).unwrap_or_default() as usize)
+            }
+            _ => self.hash_mod(obj_addr(key)),
+        }
+    }
+
+    /// brief: the node holding a normalized key
+    fn find_node(&self, key: &TObj) -> Option<usize> {
+        if self.node.is_empty() {
+            return None;
+        }
+        let tt = key.val_idx.into_inner();
+        let mut n = self.main_position(key);
+        loop {
+            let node = &self.node[n];
+            if node.key.val_idx.into_inner() == tt && raw_equal(&node.key, key) {
+                return Some(n);
+            }
+            if node.next == 0 {
+                return None;
+            }
+            n = (n as isize + node.next) as usize;
+        }
+    }
+
+    pub fn get_int(&self, i: INT) -> TObj {
+        if let Some(idx) = self.array_index(i) {
+            return self.array[idx];
+        }
+        match self.find_node(&Some(i).new()) {
+            Some(n) => self.node[n].val,
+            None => TObj::default(),
+        }
+    }
+
+    /// brief: t[key] without metamethods, nil for an absent key
+    pub fn get(&self, key: &TObj) -> TObj {
+        match key.val_idx.into_inner() {
+            T_NIL => TObj::default(),
+            T_NUM_INT => self.get_int(Option::<INT>::into_inner(key).unwrap_or_default()),
+            T_NUM_FLT => match normalize_key(key) {
+                Ok(k) if k.val_idx.into_inner() == T_NUM_INT => {
+                    self.get_int(Option::<INT>::into_inner(&k).unwrap_or_default())
+                }
+                Ok(k) => self
+                    .find_node(&k)
+                    .map_or(TObj::default(), |n| self.node[n].val),
+                Err(_) => TObj::default(),
+            },
+            _ => self
+                .find_node(key)
+                .map_or(TObj::default(), |n| self.node[n].val),
+        }
+    }
+
+    pub fn get_str(&self, ts: *mut LuaString) -> TObj {
+        match self.find_node(&Some(ts).new()) {
+            Some(n) => self.node[n].val,
+            None => TObj::default(),
+        }
+    }
+
+    /// brief: the metamethod of an event in this table used as a metatable;
+    /// the absence of the most frequent ones is remembered until the table changes
+    pub(crate) fn get_tm(&self, event: TagMethod, ename: *mut LuaString) -> TObj {
+        let cached = event as u8 <= TagMethod::Eq as u8;
+        let bit = 1u8.wrapping_shl(event as u32);
+        if cached && self.flags.get() & bit != 0 {
+            return TObj::default();
+        }
+        let tm = self.get_str(ename);
+        if cached && tm.is_nil() {
+            self.flags.set(self.flags.get() | bit);
+        }
+        tm
+    }
+
+    pub fn set_int(&mut self, i: INT, val: TObj) {
+        if let Some(idx) = self.array_index(i) {
+            self.array[idx] = val;
+            return;
+        }
+        let key = Some(i).new();
+        if let Some(n) = self.find_node(&key) {
+            self.node[n].val = val;
+            return;
+        }
+        if !val.is_nil() {
+            self.new_key(key, val);
+        }
+    }
+
+    /// brief: t[key] = val without metamethods, nil and NaN keys are errors
+    pub fn set(&mut self, key: &TObj, val: TObj) -> Result<ErrCode, ErrCode> {
+        let key = normalize_key(key)?;
+        if let Some(i) = Option::<INT>::into_inner(&key) {
+            self.set_int(i, val);
+            return Ok(ErrCode(FINE));
+        }
+        // the key may be the name of a metamethod
+        self.flags.set(0);
+        if let Some(n) = self.find_node(&key) {
+            self.node[n].val = val;
+        } else if !val.is_nil() {
+            self.new_key(key, val);
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    fn get_free_pos(&mut self) -> Option<usize> {
+        while self.lastfree > 0 {
+            self.lastfree -= 1;
+            if self.node[self.lastfree].key.is_nil() {
+                return Some(self.lastfree);
+            }
+        }
+        None
+    }
+
+    /// brief: insert a key that is not in the table;
+    /// a key out of its main position gives way to one that belongs there
+    fn new_key(&mut self, key: TObj, val: TObj) {
+        if self.node.is_empty() {
+            self.rehash(&key);
+            self.insert_after_rehash(key, val);
+            return;
+        }
+        let mut mp = self.main_position(&key);
+        if !self.node[mp].val.is_nil() {
+            let f = match self.get_free_pos() {
+                Some(f) => f,
+                None => {
+                    self.rehash(&key);
+                    self.insert_after_rehash(key, val);
+                    return;
+                }
+            };
+            let mut othern = self.main_position(&self.node[mp].key);
+            if othern != mp {
+                // the colliding node is out of its main position: move it to the free one
+                while (othern as isize + self.node[othern].next) as usize != mp {
+                    othern = (othern as isize + self.node[othern].next) as usize;
+                }
+                self.node[othern].next = f as isize - othern as isize;
+                self.node[f] = self.node[mp];
+                if self.node[mp].next != 0 {
+                    self.node[f].next += mp as isize - f as isize;
+                    self.node[mp].next = 0;
+                }
+                self.node[mp].val = TObj::default();
+            } else {
+                // the colliding node is in its main position: the new key goes to the free one
+                if self.node[mp].next != 0 {
+                    self.node[f].next = mp as isize + self.node[mp].next - f as isize;
+                }
+                self.node[mp].next = f as isize - mp as isize;
+                mp = f;
+            }
+        }
+        self.node[mp].key = key;
+        self.node[mp].val = val;
+    }
+
+    fn insert_after_rehash(&mut self, key: TObj, val: TObj) {
+        match Option::<INT>::into_inner(&key) {
+            Some(i) => self.set_int(i, val),
+            None => self.new_key(key, val),
+        }
+    }
+
+    /// brief: count a key in the slice of nums it falls into, if it can go to the array part
+    fn count_int(key: &TObj, nums: &mut [usize]) -> usize {
+        if let Some(k) = Option::<INT>::into_inner(key) {
+            if k > 0 && (k as usize) <= MAXASIZE {
+                nums[ceil_log2(k as usize)] += 1;
+                return 1;
+            }
+        }
+        0
+    }
+
+    /// brief: count the keys of the array part by slices (2^(lg-1), 2^lg]
+    fn num_use_array(&self, nums: &mut [usize]) -> usize {
+        let mut ause = 0;
+        let mut i = 1;
+        let mut ttlg = 1;
+        for num in nums.iter_mut() {
+            let mut lim = ttlg;
+            if lim > self.array.len() {
+                lim = self.array.len();
+                if i > lim {
+                    break;
+                }
+            }
+            let mut lc = 0;
+            while i <= lim {
+                if !self.array[i - 1].is_nil() {
+                    lc += 1;
+                }
+                i += 1;
+            }
+            *num += lc;
+            ause += lc;
+            ttlg *= 2;
+        }
+        ause
+    }
+
+    /// brief: count the keys of the hash part, and the integer ones among them
+    fn num_use_hash(&self, nums: &mut [usize], na: &mut usize) -> usize {
+        let mut totaluse = 0;
+        for node in self.node.iter() {
+            if !node.val.is_nil() {
+                *na += Self::count_int(&node.key, nums);
+                totaluse += 1;
+            }
+        }
+        totaluse
+    }
+
+    /// brief: the largest n such that more than half of the slots 1..n would be used;
+    /// na becomes the number of keys that go to the array part
+    fn compute_sizes(nums: &[usize], na: &mut usize) -> usize {
+        let mut a = 0;
+        let mut nna = 0;
+        let mut optimal = 0;
+        let mut twotoi: usize = 1;
+        for num in nums.iter() {
+            if twotoi == 0 || *na <= twotoi / 2 {
+                break;
+            }
+            a += num;
+            if a > twotoi / 2 {
+                optimal = twotoi;
+                nna = a;
+            }
+            twotoi = twotoi.wrapping_mul(2);
+        }
+        *na = nna;
+        optimal
+    }
+
+    /// brief: resize both parts for the keys in use plus an extra one
+    fn rehash(&mut self, extra: &TObj) {
+        let mut nums = [0usize; MAXABITS + 1];
+        let mut na = self.num_use_array(&mut nums);
+        let mut totaluse = na;
+        totaluse += self.num_use_hash(&mut nums, &mut na);
+        na += Self::count_int(extra, &mut nums);
+        totaluse += 1;
+        let asize = Self::compute_sizes(&nums, &mut na);
+        self.resize(asize, totaluse - na);
+    }
+
+    /// brief: give the array part nasize slots and the hash part room for nhsize keys
+    pub fn resize(&mut self, nasize: usize, nhsize: usize) {
+        let old_node = if nhsize == 0 {
+            std::mem::take(&mut self.node)
+        } else {
+            let size = 1usize << ceil_log2(nhsize);
+            std::mem::replace(&mut self.node, vec![Node::default(); size])
+        };
+        self.lastfree = self.node.len();
+        // keys of a shrinking array part move to the hash part
+        let vanishing = if nasize < self.array.len() {
+            self.array.split_off(nasize)
+        } else {
+            Vec::new()
+        };
+        self.array.resize(nasize, TObj::default());
+        for (j, v) in vanishing.into_iter().enumerate() {
+            if !v.is_nil() {
+                self.set_int((nasize + j + 1) as INT, v);
+            }
+        }
+        for node in old_node.into_iter() {
+            if !node.val.is_nil() {
+                self.insert_after_rehash(node.key, node.val);
+            }
+        }
+    }
+
+    /// brief: find a border in the hash part, knowing that t[j] is present or j is 0
+    fn hash_search(&self, mut j: usize) -> usize {
+        const MAXINT: usize = INT::MAX as usize;
+        let mut i;
+        if j == 0 {
+            j += 1;
+        }
+        loop {
+            i = j;
+            if j <= MAXINT / 2 {
+                j *= 2;
+            } else {
+                j = MAXINT;
+                if self.get_int(j as INT).is_nil() {
+                    break;
+                }
+                return j;
+            }
+            if self.get_int(j as INT).is_nil() {
+                break;
+            }
+        }
+        // t[i] is present and t[j] absent
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as INT).is_nil() {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+
+    /// brief: a border of the table: t[n] is present and t[n + 1] absent, or 0 when t[1] is absent
+    pub fn length(&self) -> usize {
+        let limit = self.array.len();
+        if limit > 0 && self.array[limit - 1].is_nil() {
+            // there is a border in the array part
+            let (mut i, mut j) = (0, limit);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i;
+        }
+        if self.node.is_empty() || self.get_int((limit + 1) as INT).is_nil() {
+            return limit;
+        }
+        self.hash_search(limit)
+    }
+}
+
+impl LuaState {
+    fn table_fromtop(&mut self, step: usize) -> Result<*mut LuaTable, ErrCode> {
+        let elem = self.get_stkelem_fromtop(step)?;
+        Option::<*mut LuaTable>::into_inner(&elem).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))
+    }
+
+    /// brief: t[key] = val without metamethods, counting the growth of t for the collector
+    pub(crate) fn table_set(
+        &mut self,
+        t: *mut LuaTable,
+        key: &TObj,
+        val: TObj,
+    ) -> Result<ErrCode, ErrCode> {
+        let h = unsafe { &mut *t };
+        let g = self.global_mut()?;
+        let size = h.mem_size();
+        h.set(key, val)?;
+        g.account(h.mem_size() as isize - size as isize);
+        g.barrier_back(t as *mut GcHeader, key);
+        g.barrier_back(t as *mut GcHeader, &val);
+        Ok(ErrCode(FINE))
+    }
+
+    pub(crate) fn table_set_int(
+        &mut self,
+        t: *mut LuaTable,
+        i: INT,
+        val: TObj,
+    ) -> Result<ErrCode, ErrCode> {
+        let h = unsafe { &mut *t };
+        let g = self.global_mut()?;
+        let size = h.mem_size();
+        h.set_int(i, val);
+        g.account(h.mem_size() as isize - size as isize);
+        g.barrier_back(t as *mut GcHeader, &val);
+        Ok(ErrCode(FINE))
+    }
+
+    pub(crate) fn table_resize(
+        &mut self,
+        t: *mut LuaTable,
+        nasize: usize,
+        nhsize: usize,
+    ) -> Result<ErrCode, ErrCode> {
+        let h = unsafe { &mut *t };
+        let size = h.mem_size();
+        h.resize(nasize, nhsize);
+        self.global_mut()?
+            .account(h.mem_size() as isize - size as isize);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: a new table handed over to the collector
+    pub(crate) fn create_table(
+        &mut self,
+        narray: usize,
+        nhash: usize,
+    ) -> Result<*mut LuaTable, ErrCode> {
+        Ok(self.global_mut()?.link(LuaTable::new(narray, nhash)))
+    }
+
+    /// brief: the table of global variables, kept in the registry
+    pub(crate) fn globals(&self) -> Result<TObj, ErrCode> {
+        let registry = self.global()?.registry;
+        Ok(unsafe { (*registry).get_int(LUA_RIDX_GLOBALS as INT) })
+    }
+
+    /// brief: push a new empty table
+    pub fn new_table(&mut self) -> Result<ErrCode, ErrCode> {
+        let t = self.create_table(0, 0)?;
+        self.push_obj(Some(t).new())?;
+        self.check_gc()
+    }
+
+    /// brief: push t[key], t being `step` slots below the top
+    pub fn get_field(&mut self, step: usize, key: &[u8]) -> Result<ErrCode, ErrCode> {
+        let t = self.get_stkelem_fromtop(step)?;
+        let key = Some(self.new_string(key)?).new();
+        let v = self.get_table(&t, &key)?;
+        self.push_obj(v)
+    }
+
+    /// brief: t[key] = v, v being the value on top, which is popped
+    /// and t being `step` slots below the top
+    pub fn set_field(&mut self, step: usize, key: &[u8]) -> Result<ErrCode, ErrCode> {
+        let t = self.get_stkelem_fromtop(step)?;
+        let key = Some(self.new_string(key)?).new();
+        let v = self.get_stkelem_fromtop(0)?;
+        self.set_table(&t, &key, v)?;
+        self.move_top(1, false);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: push t[n], t being `step` slots below the top
+    pub fn get_index(&mut self, step: usize, n: INT) -> Result<ErrCode, ErrCode> {
+        let t = self.get_stkelem_fromtop(step)?;
+        let v = self.get_table(&t, &Some(n).new())?;
+        self.push_obj(v)
+    }
+
+    /// brief: t[n] = v, v being the value on top, which is popped
+    /// and t being `step` slots below the top
+    pub fn set_index(&mut self, step: usize, n: INT) -> Result<ErrCode, ErrCode> {
+        let t = self.get_stkelem_fromtop(step)?;
+        let v = self.get_stkelem_fromtop(0)?;
+        self.set_table(&t, &Some(n).new(), v)?;
+        self.move_top(1, false);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: replace the key on top with t[key], without metamethods,
+    /// t being `step` slots below the top
+    pub fn raw_get(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let t = self.table_fromtop(step)?;
+        let key = self.get_stkelem_fromtop(0)?;
+        let v = unsafe { (*t).get(&key) };
+        self.move_top(1, false);
+        self.push_obj(v)
+    }
+
+    /// brief: t[k] = v without metamethods, k and v being the two values on top,
+    /// which are popped, and t being `step` slots below the top
+    pub fn raw_set(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let t = self.table_fromtop(step)?;
+        let key = self.get_stkelem_fromtop(1)?;
+        let v = self.get_stkelem_fromtop(0)?;
+        self.table_set(t, &key, v)?;
+        self.move_top(2, false);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: push the table of global variables
+    pub fn push_global_table(&mut self) -> Result<ErrCode, ErrCode> {
+        let globals = self.globals()?;
+        self.push_obj(globals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// brief: run f on a table not linked to any state, freed afterwards
+    fn with_table(narray: usize, nhash: usize, f: impl FnOnce(&mut LuaTable)) {
+        let t = LuaTable::new(narray, nhash);
+        f(unsafe { &mut *t });
+        drop(unsafe { Box::from_raw(t) });
+    }
+
+    fn int(i: INT) -> TObj {
+        Some(i).new()
+    }
+
+    /// brief: n is a border of t: t[n] is present, or n is 0, and t[n + 1] is absent
+    fn is_border(t: &LuaTable, n: usize) -> bool {
+        (n == 0 || !t.get_int(n as INT).is_nil()) && t.get_int(n as INT + 1).is_nil()
+    }
+
+    #[test]
+    fn empty_tables() {
+        with_table(0, 0, |t| assert_eq!(t.length(), 0));
+        with_table(4, 4, |t| assert_eq!(t.length(), 0));
+        with_table(0, 0, |t| {
+            t.set_int(2, int(2));
+            assert_eq!(t.length(), 0);
+        });
+    }
+
+    #[test]
+    fn sequences() {
+        with_table(0, 0, |t| {
+            for i in 1..=100 {
+                t.set_int(i, int(i));
+                assert_eq!(t.length(), i as usize);
+            }
+            // the keys went to the array part as the table grew
+            assert!(t.array_size() >= 64);
+            for i in (1..=100).rev() {
+                t.set_int(i, TObj::default());
+                assert_eq!(t.length(), i as usize - 1);
+            }
+        });
+    }
+
+    #[test]
+    fn border_in_array_part() {
+        with_table(8, 0, |t| {
+            for i in 1..=5 {
+                t.set_int(i, int(i));
+            }
+            assert_eq!(t.length(), 5);
+            t.set_int(7, int(7));
+            assert_eq!(t.array_size(), 8);
+            assert!(is_border(t, t.length()));
+            t.set_int(1, TObj::default());
+            assert!(is_border(t, t.length()));
+        });
+    }
+
+    #[test]
+    fn border_in_hash_part() {
+        with_table(2, 4, |t| {
+            for i in 1..=5 {
+                t.set_int(i, int(i));
+            }
+            assert_eq!(t.array_size(), 2);
+            assert_eq!(t.length(), 5);
+        });
+        with_table(0, 8, |t| {
+            for i in [1, 2, 3, 5, 6] {
+                t.set_int(i, int(i));
+            }
+            assert_eq!(t.array_size(), 0);
+            assert!(is_border(t, t.length()));
+        });
+    }
+
+    #[test]
+    fn border_at_maxinteger() {
+        with_table(0, 4, |t| {
+            t.set_int(1, int(1));
+            t.set_int(INT::MAX, int(0));
+            assert!(is_border(t, t.length()));
+        });
+    }
+
+    #[test]
+    fn sparse_tables() {
+        with_table(0, 0, |t| {
+            let mut k: INT = 1;
+            for _ in 0..200 {
+                // a pseudo-random walk of keys, set and cleared
+                k = (k * 37 + 11) % 97 + 1;
+                let v = if k % 3 == 0 { TObj::default() } else { int(k) };
+                t.set_int(k, v);
+                assert!(is_border(t, t.length()), "{}", t.length());
+            }
+        });
+    }
+
+    #[test]
+    fn resize_keeps_keys() {
+        with_table(4, 0, |t| {
+            for i in 1..=4 {
+                t.set_int(i, int(i * 10));
+            }
+            t.resize(2, 2);
+            assert_eq!(t.array_size(), 2);
+            for i in 1..=4 {
+                assert_eq!(Option::<INT>::into_inner(&t.get_int(i)), Some(i * 10));
+            }
+            assert_eq!(t.length(), 4);
+            t.resize(8, 0);
+            assert_eq!(t.length(), 4);
+            assert!(t.get_int(5).is_nil());
+        });
+    }
+
+    #[test]
+    fn keys() {
+        with_table(0, 0, |t| {
+            // a float with an integer value is that integer
+            t.set(&Some(2.0 as FLT).new(), int(2)).unwrap();
+            assert!(!t.get_int(2).is_nil());
+            assert!(!t.get(&int(2)).is_nil());
+            t.set(&Some(1.5 as FLT).new(), int(3)).unwrap();
+            assert!(!t.get(&Some(1.5 as FLT).new()).is_nil());
+            assert!(t.get_int(1).is_nil());
+            t.set(&Some(true).new(), int(4)).unwrap();
+            assert!(!t.get(&Some(true).new()).is_nil());
+            assert!(t.get(&Some(false).new()).is_nil());
+            assert_eq!(
+                t.set(&TObj::default(), int(0)).unwrap_err().0,
+                RUNTIME_NIL_INDEX
+            );
+            assert_eq!(
+                t.set(&Some(FLT::NAN).new(), int(0)).unwrap_err().0,
+                RUNTIME_NAN_INDEX
+            );
+            assert!(t.get(&TObj::default()).is_nil());
+        });
+    }
+}
@@ -0,0 +1,528 @@
+use core::any::TypeId;
+use core::cell::Cell;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{drop_in_place, null_mut, write};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+
+use crate::info::lua::{ErrCode, MEMORY_ALLOC_FAIL, MEMORY_BORROW_FAIL, MEMORY_TYPE_MISMATCH};
+
+use super::gc::GcHeader;
+use super::objdef::{ObjectTrait, TObj, T_USER_DATA};
+use super::statedef::LuaState;
+use super::table::LuaTable;
+
+pub(crate) const LUAI_MAXALIGN: usize = 16; // alignment of the block of a userdata
+
+type DropFn = unsafe fn(*mut u8); // drops the Rust value in a block
+
+/// brief: a full userdata, a block of raw memory owned by the collector,
+/// with a metatable of its own and a fixed number of user values
+#[repr(C)]
+pub struct LuaUserData {
+    header: GcHeader,
+    pub(crate) metatable: *mut LuaTable,
+    pub(crate) uv: Box<[TObj]>, // the user values, nil at first
+    block: *mut u8,
+    layout: Layout,
+    rtype: Option<(TypeId, DropFn)>, // a Rust value in the block: its type and drop
+    borrow: Cell<isize>, // shared borrows of the Rust value, or -1 while borrowed mutably
+}
+
+/// brief: drop the value of type T in a block
+unsafe fn drop_block<T>(p: *mut u8) {
+    drop_in_place(p as *mut T);
+}
+
+impl LuaUserData {
+    /// brief: a userdata with a zeroed block of size bytes aligned to align, and nuvalue user values
+    pub(crate) fn new(
+        size: usize,
+        align: usize,
+        nuvalue: usize,
+    ) -> Result<*mut LuaUserData, ErrCode> {
+        let layout =
+            Layout::from_size_align(size, align).map_err(|_| ErrCode(MEMORY_ALLOC_FAIL))?;
+        let block = if size == 0 {
+            // no memory behind it, but still aligned
+            align as *mut u8
+        } else {
+            let p = unsafe { alloc_zeroed(layout) };
+            if p.is_null() {
+                return Err(ErrCode(MEMORY_ALLOC_FAIL));
+            }
+            p
+        };
+        Ok(Box::leak(Box::new(LuaUserData {
+            header: GcHeader::new(T_USER_DATA),
+            metatable: null_mut(),
+            uv: vec![TObj::default(); nuvalue].into_boxed_slice(),
+            block,
+            layout,
+            rtype: None,
+            borrow: Cell::new(0),
+        })))
+    }
+
+    /// brief: whether the block holds a Rust value of type T
+    #[inline(always)]
+    pub fn is<T: 'static>(&self) -> bool {
+        self.rtype.is_some_and(|(id, _)| id == TypeId::of::<T>())
+    }
+
+    /// brief: whether a `UserRef` or `UserRefMut` to the Rust value is alive
+    #[inline(always)]
+    pub(crate) fn is_borrowed(&self) -> bool {
+        self.borrow.get() != 0
+    }
+
+    /// brief: the address of the block
+    #[inline(always)]
+    pub fn block(&self) -> *mut u8 {
+        self.block
+    }
+
+    /// brief: the size of the block in bytes
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.layout.size() == 0
+    }
+
+    #[inline(always)]
+    pub fn nuvalue(&self) -> usize {
+        self.uv.len()
+    }
+
+    /// brief: the bytes the userdata accounts for
+    #[inline(always)]
+    pub(crate) fn mem_size(&self) -> usize {
+        size_of::<LuaUserData>() + self.layout.size() + self.uv.len() * size_of::<TObj>()
+    }
+}
+
+impl Drop for LuaUserData {
+    fn drop(&mut self) {
+        if let Some((_, drop)) = self.rtype.take() {
+            unsafe { drop(self.block) };
+        }
+        if self.layout.size() != 0 {
+            unsafe { dealloc(self.block, self.layout) };
+        }
+    }
+}
+
+impl LuaState {
+    /// brief: push a new full userdata with a zeroed block of size bytes and nuvalue user values,
+    /// the address of the block is given; it lives as long as the userdata is reachable
+    pub fn new_userdatauv(&mut self, size: usize, nuvalue: usize) -> Result<*mut u8, ErrCode> {
+        let u = self
+            .global_mut()?
+            .link(LuaUserData::new(size, LUAI_MAXALIGN, nuvalue)?);
+        self.push_obj(Some(u).new())?;
+        self.check_gc()?;
+        Ok(unsafe { (*u).block() })
+    }
+
+    /// brief: the full userdata `step` slots below the top
+    fn userdata_at(&mut self, step: usize) -> Result<*mut LuaUserData, ErrCode> {
+        let o = self.get_stkelem_fromtop(step)?;
+        Option::<*mut LuaUserData>::into_inner(&o).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))
+    }
+
+    /// brief: the address of the block of the full userdata `step` slots below the top,
+    /// or the pointer of a light userdata
+    pub fn get_userdata_fromtop(&mut self, step: usize) -> Result<*mut u8, ErrCode> {
+        let o = self.get_stkelem_fromtop(step)?;
+        if let Some(p) = Option::<*mut ()>::into_inner(&o) {
+            return Ok(p as *mut u8);
+        }
+        Ok(unsafe { (*self.userdata_at(step)?).block() })
+    }
+
+    /// brief: push the n-th user value, from 1, of the full userdata `step` slots below the top;
+    /// nil is pushed and false given if it has no such value
+    pub fn get_iuservalue(&mut self, step: usize, n: usize) -> Result<bool, ErrCode> {
+        let u = unsafe { &*self.userdata_at(step)? };
+        match n.checked_sub(1).and_then(|j| u.uv.get(j)) {
+            Some(&v) => {
+                self.push_obj(v)?;
+                Ok(true)
+            }
+            None => {
+                self.push_nil()?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// brief: pop a value into the n-th user value, from 1, of the full userdata `step` slots
+    /// below the top, counted with the value on top; false if it has no such value
+    pub fn set_iuservalue(&mut self, step: usize, n: usize) -> Result<bool, ErrCode> {
+        let v = self.get_stkelem_fromtop(0)?;
+        let u = self.userdata_at(step)?;
+        let slot = n
+            .checked_sub(1)
+            .and_then(|j| unsafe { (&mut *u).uv.get_mut(j) });
+        let done = match slot {
+            Some(slot) => {
+                *slot = v;
+                self.global_mut()?.barrier_back(u as *mut GcHeader, &v);
+                true
+            }
+            None => false,
+        };
+        self.move_top(1, false);
+        Ok(done)
+    }
+
+    /// brief: push a new full userdata holding value, with nuvalue user values;
+    /// it is borrowed back with `borrow_userdata` and dropped when the userdata is collected
+    pub fn push_userdata<T: 'static>(
+        &mut self,
+        value: T,
+        nuvalue: usize,
+    ) -> Result<ErrCode, ErrCode> {
+        let layout = Layout::new::<T>();
+        let u = LuaUserData::new(layout.size(), layout.align(), nuvalue)?;
+        unsafe {
+            write((*u).block as *mut T, value);
+            (*u).rtype = Some((TypeId::of::<T>(), drop_block::<T>));
+        }
+        let u = self.global_mut()?.link(u);
+        self.push_obj(Some(u).new())?;
+        self.check_gc()
+    }
+
+    /// brief: whether the value `step` slots below the top is a userdata holding a T
+    pub fn is_userdata<T: 'static>(&mut self, step: usize) -> bool {
+        self.userdata_at(step)
+            .is_ok_and(|u| unsafe { (*u).is::<T>() })
+    }
+
+    /// brief: the userdata `step` slots below the top, holding a T, borrowed as borrow says;
+    /// while borrowed it is kept alive
+    fn typed_userdata<T: 'static>(
+        &mut self,
+        step: usize,
+        borrow: fn(isize) -> Option<isize>,
+    ) -> Result<*mut LuaUserData, ErrCode> {
+        let u = self.userdata_at(step)?;
+        let ud = unsafe { &*u };
+        if !ud.is::<T>() {
+            return Err(ErrCode(MEMORY_TYPE_MISMATCH));
+        }
+        let old = ud.borrow.get();
+        ud.borrow
+            .set(borrow(old).ok_or(ErrCode(MEMORY_BORROW_FAIL))?);
+        if old == 0 {
+            self.global_mut()?.pin(u);
+        }
+        Ok(u)
+    }
+
+    /// brief: borrow the T held by the userdata `step` slots below the top;
+    /// it fails with another type, or while the value is borrowed mutably
+    pub fn borrow_userdata<T: 'static>(&mut self, step: usize) -> Result<UserRef<T>, ErrCode> {
+        let u = self.typed_userdata::<T>(step, |b| (b >= 0).then_some(b + 1))?;
+        Ok(UserRef { u, t: PhantomData })
+    }
+
+    /// brief: borrow mutably the T held by the userdata `step` slots below the top;
+    /// it fails with another type, or while the value is borrowed at all
+    pub fn borrow_userdata_mut<T: 'static>(
+        &mut self,
+        step: usize,
+    ) -> Result<UserRefMut<T>, ErrCode> {
+        let u = self.typed_userdata::<T>(step, |b| (b == 0).then_some(-1))?;
+        Ok(UserRefMut { u, t: PhantomData })
+    }
+}
+
+/// brief: a shared borrow of the Rust value of a userdata, the userdata stays alive meanwhile
+pub struct UserRef<T: 'static> {
+    u: *mut LuaUserData,
+    t: PhantomData<*const T>,
+}
+
+impl<T: 'static> Deref for UserRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*((*self.u).block as *const T) }
+    }
+}
+
+impl<T: 'static> Drop for UserRef<T> {
+    fn drop(&mut self) {
+        let borrow = unsafe { &(*self.u).borrow };
+        borrow.set(borrow.get() - 1);
+    }
+}
+
+/// brief: a mutable borrow of the Rust value of a userdata, the userdata stays alive meanwhile
+pub struct UserRefMut<T: 'static> {
+    u: *mut LuaUserData,
+    t: PhantomData<*mut T>,
+}
+
+impl<T: 'static> Deref for UserRefMut<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*((*self.u).block as *const T) }
+    }
+}
+
+impl<T: 'static> DerefMut for UserRefMut<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *((*self.u).block as *mut T) }
+    }
+}
+
+impl<T: 'static> Drop for UserRefMut<T> {
+    fn drop(&mut self) {
+        unsafe { (*self.u).borrow.set(0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::obj::objdef::INT;
+    use crate::vm::machine::tests::{lua_setmetatable, new_state, run, set_global_fn};
+
+    /// brief: a Rust value that counts its drops
+    struct Tracked {
+        n: i64,
+        drops: Rc<Cell<usize>>,
+    }
+
+    impl Drop for Tracked {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    fn push_tracked(l: &mut LuaState, n: i64) -> Rc<Cell<usize>> {
+        let drops = Rc::new(Cell::new(0));
+        l.push_userdata(
+            Tracked {
+                n,
+                drops: drops.clone(),
+            },
+            0,
+        )
+        .unwrap();
+        drops
+    }
+
+    #[test]
+    fn borrow_across_collection() {
+        let l = new_state();
+        let drops = push_tracked(l, 7);
+        let r = l.borrow_userdata::<Tracked>(0).unwrap();
+        // no longer reachable from Lua, the borrow keeps it alive
+        l.move_top(1, false);
+        l.gc_collect().unwrap();
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 0);
+        assert_eq!(r.n, 7);
+        drop(r);
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn close_while_borrowed() {
+        let l = new_state();
+        let drops = push_tracked(l, 1);
+        let mut r = l.borrow_userdata_mut::<Tracked>(0).unwrap();
+        assert_eq!(l.close().unwrap_err().0, MEMORY_BORROW_FAIL);
+        // nothing was freed
+        r.n += 1;
+        assert_eq!(r.n, 2);
+        assert_eq!(drops.get(), 0);
+        drop(r);
+        l.close().unwrap();
+        assert_eq!(drops.get(), 1);
+    }
+
+    /// brief: newud(n), a userdata whose block holds the integer n
+    fn lua_newud(l: &mut LuaState) -> usize {
+        let n = l.get_integer_fromtop(0).unwrap();
+        let p = l.new_userdatauv(size_of::<INT>(), 0).unwrap();
+        unsafe { write(p as *mut INT, n) };
+        1
+    }
+
+    /// brief: udval(u), the integer in the block of u
+    fn lua_udval(l: &mut LuaState) -> usize {
+        let p = l.get_userdata_fromtop(0).unwrap();
+        l.push_integer(unsafe { *(p as *const INT) }).unwrap();
+        1
+    }
+
+    #[test]
+    fn userdata_with_metatables() {
+        let l = new_state();
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        set_global_fn(l, b"newud", lua_newud);
+        set_global_fn(l, b"udval", lua_udval);
+        let src = "local mt = {}
+                   function mt.__index(u, k) return k .. udval(u) end
+                   function mt.__len(u) return udval(u) * 2 end
+                   function mt.__eq(a, b) return udval(a) == udval(b) end
+                   local a, b = setmetatable(newud(3), mt), setmetatable(newud(3), mt)
+                   local c = newud(3)
+                   return a.x, #a, a == b, a ~= b, c == newud(3), c == c";
+        assert_eq!(
+            run(l, src).unwrap(),
+            ["x3", "6", "true", "false", "false", "true"]
+        );
+        let e = run(l, "local c = newud(1) return c.x").unwrap_err();
+        assert!(e.contains("attempt to index a userdata value"), "{}", e);
+        // a new block is zeroed and aligned, without a metatable
+        let p = l.new_userdatauv(24, 0).unwrap();
+        assert_eq!(p as usize % LUAI_MAXALIGN, 0);
+        assert!(unsafe { core::slice::from_raw_parts(p, 24) }
+            .iter()
+            .all(|&b| b == 0));
+        assert!(!l.get_metatable(0).unwrap());
+        l.move_top(1, false);
+    }
+
+    #[test]
+    fn user_values() {
+        let l = new_state();
+        l.new_userdatauv(0, 2).unwrap();
+        assert_eq!(unsafe { (*l.userdata_at(0).unwrap()).nuvalue() }, 2);
+        l.push_string(b"kept by the userdata only").unwrap();
+        assert!(l.set_iuservalue(1, 1).unwrap());
+        l.push_integer(5).unwrap();
+        assert!(l.set_iuservalue(1, 2).unwrap());
+        // out of range, the value is popped all the same
+        let top = l.get_stack_top();
+        l.push_integer(6).unwrap();
+        assert!(!l.set_iuservalue(1, 3).unwrap());
+        assert_eq!(l.get_stack_top(), top);
+        l.gc_collect().unwrap();
+        assert!(l.get_iuservalue(0, 1).unwrap());
+        assert_eq!(
+            l.get_string_fromtop(0).unwrap(),
+            b"kept by the userdata only"
+        );
+        assert!(l.get_iuservalue(1, 2).unwrap());
+        assert_eq!(l.get_integer_fromtop(0).unwrap(), 5);
+        for n in [0, 3] {
+            assert!(!l.get_iuservalue(2, n).unwrap());
+            assert!(l.get_stkelem_fromtop(0).unwrap().is_nil());
+            l.move_top(1, false);
+        }
+        l.move_top(2, false);
+        // only full userdata have user values
+        l.push_integer(1).unwrap();
+        assert_eq!(l.get_iuservalue(0, 1).unwrap_err().0, MEMORY_TYPE_MISMATCH);
+        l.move_top(1, false);
+    }
+
+    #[test]
+    fn typed_borrows() {
+        let l = new_state();
+        let drops = push_tracked(l, 3);
+        l.push_userdata(String::from("s"), 0).unwrap();
+        assert!(l.is_userdata::<Tracked>(1));
+        assert!(!l.is_userdata::<Tracked>(0));
+        assert!(l.is_userdata::<String>(0));
+        // the type is checked
+        assert_eq!(
+            l.borrow_userdata::<Tracked>(0).err().unwrap().0,
+            MEMORY_TYPE_MISMATCH
+        );
+        assert_eq!(
+            l.borrow_userdata_mut::<String>(1).err().unwrap().0,
+            MEMORY_TYPE_MISMATCH
+        );
+        // shared borrows go together, but not with a mutable one
+        let (a, b) = (
+            l.borrow_userdata::<Tracked>(1).unwrap(),
+            l.borrow_userdata::<Tracked>(1).unwrap(),
+        );
+        assert_eq!(a.n + b.n, 6);
+        assert_eq!(
+            l.borrow_userdata_mut::<Tracked>(1).err().unwrap().0,
+            MEMORY_BORROW_FAIL
+        );
+        drop(a);
+        assert_eq!(
+            l.borrow_userdata_mut::<Tracked>(1).err().unwrap().0,
+            MEMORY_BORROW_FAIL
+        );
+        drop(b);
+        let mut m = l.borrow_userdata_mut::<Tracked>(1).unwrap();
+        m.n = 4;
+        assert_eq!(
+            l.borrow_userdata::<Tracked>(1).err().unwrap().0,
+            MEMORY_BORROW_FAIL
+        );
+        assert_eq!(
+            l.borrow_userdata_mut::<Tracked>(1).err().unwrap().0,
+            MEMORY_BORROW_FAIL
+        );
+        // another userdata is not affected
+        l.borrow_userdata_mut::<String>(0).unwrap().push('t');
+        drop(m);
+        assert_eq!(l.borrow_userdata::<Tracked>(1).unwrap().n, 4);
+        assert_eq!(*l.borrow_userdata::<String>(0).unwrap(), "st");
+        // the value is dropped with the userdata, once
+        l.move_top(2, false);
+        l.gc_collect().unwrap();
+        l.gc_collect().unwrap();
+        assert_eq!(drops.get(), 1);
+    }
+
+    /// brief: bump(u, f), adds one to the Tracked in u and calls f while holding it
+    fn lua_bump(l: &mut LuaState) -> usize {
+        let mut r = match l.borrow_userdata_mut::<Tracked>(1) {
+            Ok(r) => r,
+            Err(e) => return l.raise(e),
+        };
+        r.n += 1;
+        match l.call(0, 0) {
+            Ok(_) => 0,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    #[test]
+    fn callback_cannot_alias() {
+        let l = new_state();
+        set_global_fn(l, b"bump", lua_bump);
+        l.push_global_table().unwrap();
+        push_tracked(l, 0);
+        l.set_field(1, b"u").unwrap();
+        l.move_top(1, false);
+        let res = run(
+            l,
+            "bump(u, function() end) bump(u, function() end) return 1",
+        )
+        .unwrap();
+        assert_eq!(res, ["1"]);
+        let e = run(l, "bump(u, function() bump(u, function() end) end)").unwrap_err();
+        assert_eq!(e, ErrCode(MEMORY_BORROW_FAIL).msg());
+        // the failed call let go of its borrow
+        run(l, "bump(u, function() end)").unwrap();
+        l.push_global_table().unwrap();
+        l.get_field(0, b"u").unwrap();
+        assert_eq!(l.borrow_userdata::<Tracked>(0).unwrap().n, 4);
+        l.move_top(2, false);
+    }
+}
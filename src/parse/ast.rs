@@ -0,0 +1,224 @@
+use crate::obj::objdef::{FLT, INT};
+
+pub type Name = String;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+}
+
+/// brief: `return e1, e2, ...`, always the last statement of a block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub exprs: Vec<Expr>,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stat {
+    /// `;`
+    Empty,
+    /// `v1, v2 = e1, e2`, every target is a `Name` or an `Index`
+    Assign {
+        targets: Vec<Expr>,
+        exprs: Vec<Expr>,
+        line: usize,
+    },
+    /// a function or method call used as a statement
+    Call(Expr),
+    /// `local n1 <attrib>, n2 = e1, e2`
+    Local {
+        names: Vec<LocalName>,
+        exprs: Vec<Expr>,
+        line: usize,
+    },
+    /// `local function name body`
+    LocalFunction { name: Name, body: FuncBody },
+    /// `function a.b.c:m body`
+    Function { name: FuncName, body: FuncBody },
+    /// `do block end`
+    Do(Block),
+    /// `while cond do block end`
+    While { cond: Expr, body: Block, line: usize },
+    /// `repeat block until cond`, cond sees the locals of the block
+    Repeat { body: Block, cond: Expr, line: usize },
+    /// `if c1 then b1 elseif c2 then b2 else b3 end`
+    If {
+        branches: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+        line: usize,
+    },
+    /// `for var = start, limit, step do block end`
+    NumericFor {
+        var: Name,
+        start: Expr,
+        limit: Expr,
+        step: Option<Expr>,
+        body: Block,
+        line: usize,
+    },
+    /// `for n1, n2 in e1, e2 do block end`
+    GenericFor {
+        names: Vec<Name>,
+        exprs: Vec<Expr>,
+        body: Block,
+        line: usize,
+    },
+    Break { line: usize },
+    Goto { label: Name, line: usize },
+    /// `::name::`
+    Label { name: Name, line: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    Const,
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: Name,
+    pub attrib: Option<Attrib>,
+}
+
+/// brief: the name of a function statement, `path[0].path[1]...:method`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    pub path: Vec<Name>,
+    pub method: Option<Name>,
+}
+
+/// brief: parameters and body of a function, `line` is where `function` is
+/// and `lastline` where its `end` is
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub block: Block,
+    pub line: usize,
+    pub lastline: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Nil,
+    True,
+    False,
+    /// `...`
+    Vararg,
+    Int(INT),
+    Flt(FLT),
+    Str(Vec<u8>),
+    Function(Box<FuncBody>),
+    Table { fields: Vec<Field>, line: usize },
+    Name(Name),
+    /// `obj[key]`, `obj.key` is an index with a string key
+    Index {
+        obj: Box<Expr>,
+        key: Box<Expr>,
+        line: usize,
+    },
+    /// `func(args)`
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+        line: usize,
+    },
+    /// `obj:name(args)`
+    Method {
+        obj: Box<Expr>,
+        name: Name,
+        args: Vec<Expr>,
+        line: usize,
+    },
+    /// `(expr)`, truncates multiple results to one
+    Paren(Box<Expr>),
+    BinOp {
+        op: BinOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        line: usize,
+    },
+    UnOp {
+        op: UnOp,
+        expr: Box<Expr>,
+        line: usize,
+    },
+}
+
+impl Expr {
+    /// brief: calls and `...` may produce any number of values
+    pub fn is_multi(&self) -> bool {
+        matches!(self, Expr::Call { .. } | Expr::Method { .. } | Expr::Vararg)
+    }
+}
+
+/// brief: an entry of a table constructor
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    /// `expr`
+    Positional(Expr),
+    /// `name = expr`
+    Named(Name, Expr),
+    /// `[key] = expr`
+    Keyed(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Lt,
+    Le,
+    Ne,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    /// brief: left and right priority, a higher right one is right associative
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13),
+            BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Ne | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    BNot,
+    Not,
+    Len,
+}
+
+impl UnOp {
+    pub const PRIORITY: u8 = 12;
+}
@@ -0,0 +1,815 @@
+use crate::{
+    info::lua::{ErrCode, COMPILE_SYNTAX, LUAI_MAXCCALLS},
+    lex::{
+        lexer::{Lexer, SyntaxError},
+        token::{Lexeme, Token},
+    },
+    parse::ast::{
+        Attrib, BinOp, Block, Expr, Field, FuncBody, FuncName, LocalName, Name, Return, Stat,
+        UnOp,
+    },
+};
+
+/// brief: parse a whole chunk into the block of its main function
+pub fn parse(src: &[u8], chunkname: &str) -> Result<Block, SyntaxError> {
+    let mut parser = Parser::new(src, chunkname)?;
+    parser.chunk()
+}
+
+/// brief: recursive-descent parser following the grammar of the reference manual
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: Lexeme,
+    ahead: Option<Lexeme>,
+    // line of the last token consumed
+    last_line: usize,
+    // whether each enclosing function is a vararg one
+    vararg: Vec<bool>,
+    // nesting of syntactical structures
+    level: usize,
+}
+
+type PResult<T> = Result<T, SyntaxError>;
+
+impl<'a> Parser<'a> {
+    pub fn new(src: &'a [u8], chunkname: &str) -> PResult<Self> {
+        let mut lexer = Lexer::new(src, chunkname);
+        let current = lexer.next_token()?;
+        Ok(Self {
+            lexer,
+            current,
+            ahead: None,
+            last_line: 1,
+            vararg: Vec::new(),
+            level: 0,
+        })
+    }
+
+    /// brief: the main function is always vararg
+    pub fn chunk(&mut self) -> PResult<Block> {
+        self.vararg.push(true);
+        let block = self.block()?;
+        self.check(Token::Eos)?;
+        self.vararg.pop();
+        Ok(block)
+    }
+
+    /* ------ token handling ------ */
+
+    fn next(&mut self) -> PResult<()> {
+        self.last_line = self.current.line;
+        self.current = match self.ahead.take() {
+            Some(lexeme) => lexeme,
+            None => self.lexer.next_token()?,
+        };
+        Ok(())
+    }
+
+    fn lookahead(&mut self) -> PResult<&Token> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lexer.next_token()?);
+        }
+        Ok(&self.ahead.as_ref().unwrap().token)
+    }
+
+    #[inline(always)]
+    fn is(&self, token: &Token) -> bool {
+        self.current.token == *token
+    }
+
+    fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            code: ErrCode(COMPILE_SYNTAX),
+            chunkname: self.lexer.chunkname().to_string(),
+            line: self.current.line,
+            msg: format!("{} near {}", msg, self.current.token),
+        }
+    }
+
+    fn error_expected(&self, token: &Token) -> SyntaxError {
+        self.error(&format!("{} expected", token))
+    }
+
+    fn test_next(&mut self, token: &Token) -> PResult<bool> {
+        if self.is(token) {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check(&self, token: Token) -> PResult<()> {
+        if self.is(&token) {
+            Ok(())
+        } else {
+            Err(self.error_expected(&token))
+        }
+    }
+
+    fn check_next(&mut self, token: Token) -> PResult<()> {
+        self.check(token)?;
+        self.next()
+    }
+
+    /// brief: check that the closing `what` of `who` (opened at `line`) follows
+    fn check_match(&mut self, what: Token, who: Token, line: usize) -> PResult<()> {
+        if self.test_next(&what)? {
+            return Ok(());
+        }
+        if line == self.current.line {
+            Err(self.error_expected(&what))
+        } else {
+            Err(self.error(&format!(
+                "{} expected (to close {} at line {})",
+                what, who, line
+            )))
+        }
+    }
+
+    fn check_name(&mut self) -> PResult<Name> {
+        if let Token::Name(name) = &self.current.token {
+            let name = name.clone();
+            self.next()?;
+            Ok(name)
+        } else {
+            Err(self.error("<name> expected"))
+        }
+    }
+
+    fn enter_level(&mut self) -> PResult<()> {
+        self.level += 1;
+        if self.level > LUAI_MAXCCALLS {
+            return Err(self.error("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.level -= 1;
+    }
+
+    /* ------ rules for statements ------ */
+
+    /// brief: is the current token one that closes a block?
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.current.token {
+            Token::Else | Token::Elseif | Token::End | Token::Eos => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    fn block(&mut self) -> PResult<Block> {
+        self.enter_level()?;
+        let mut stats = Vec::new();
+        let mut ret = None;
+        while !self.block_follow(true) {
+            if self.is(&Token::Return) {
+                ret = Some(self.ret_stat()?);
+                // 'return' must be the last statement
+                break;
+            }
+            stats.push(self.statement()?);
+        }
+        self.leave_level();
+        Ok(Block { stats, ret })
+    }
+
+    fn statement(&mut self) -> PResult<Stat> {
+        let line = self.current.line;
+        self.enter_level()?;
+        let stat = match self.current.token {
+            Token::Semi => {
+                self.next()?;
+                Stat::Empty
+            }
+            Token::If => self.if_stat(line)?,
+            Token::While => {
+                self.next()?;
+                let cond = self.expr()?;
+                self.check_next(Token::Do)?;
+                let body = self.block()?;
+                self.check_match(Token::End, Token::While, line)?;
+                Stat::While { cond, body, line }
+            }
+            Token::Do => {
+                self.next()?;
+                let body = self.block()?;
+                self.check_match(Token::End, Token::Do, line)?;
+                Stat::Do(body)
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => {
+                self.next()?;
+                let body = self.block()?;
+                self.check_match(Token::Until, Token::Repeat, line)?;
+                let cond = self.expr()?;
+                Stat::Repeat { body, cond, line }
+            }
+            Token::Function => self.func_stat(line)?,
+            Token::Local => {
+                self.next()?;
+                if self.test_next(&Token::Function)? {
+                    let name = self.check_name()?;
+                    let body = self.body(false, line)?;
+                    Stat::LocalFunction { name, body }
+                } else {
+                    self.local_stat(line)?
+                }
+            }
+            Token::DbColon => {
+                self.next()?;
+                let name = self.check_name()?;
+                self.check_next(Token::DbColon)?;
+                Stat::Label { name, line }
+            }
+            Token::Break => {
+                self.next()?;
+                Stat::Break { line }
+            }
+            Token::Goto => {
+                self.next()?;
+                let label = self.check_name()?;
+                Stat::Goto { label, line }
+            }
+            _ => self.expr_stat(line)?,
+        };
+        self.leave_level();
+        Ok(stat)
+    }
+
+    fn if_stat(&mut self, line: usize) -> PResult<Stat> {
+        let mut branches = Vec::new();
+        let mut else_block = None;
+        // skip 'if' or 'elseif'
+        loop {
+            self.next()?;
+            let cond = self.expr()?;
+            self.check_next(Token::Then)?;
+            let block = self.block()?;
+            branches.push((cond, block));
+            if !self.is(&Token::Elseif) {
+                break;
+            }
+        }
+        if self.test_next(&Token::Else)? {
+            else_block = Some(self.block()?);
+        }
+        self.check_match(Token::End, Token::If, line)?;
+        Ok(Stat::If {
+            branches,
+            else_block,
+            line,
+        })
+    }
+
+    fn for_stat(&mut self, line: usize) -> PResult<Stat> {
+        // skip 'for'
+        self.next()?;
+        let first = self.check_name()?;
+        let stat = match self.current.token {
+            Token::Assign => {
+                self.next()?;
+                let start = self.expr()?;
+                self.check_next(Token::Comma)?;
+                let limit = self.expr()?;
+                let step = if self.test_next(&Token::Comma)? {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
+                self.check_next(Token::Do)?;
+                let body = self.block()?;
+                Stat::NumericFor {
+                    var: first,
+                    start,
+                    limit,
+                    step,
+                    body,
+                    line,
+                }
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![first];
+                while self.test_next(&Token::Comma)? {
+                    names.push(self.check_name()?);
+                }
+                self.check_next(Token::In)?;
+                let exprs = self.expr_list()?;
+                self.check_next(Token::Do)?;
+                let body = self.block()?;
+                Stat::GenericFor {
+                    names,
+                    exprs,
+                    body,
+                    line,
+                }
+            }
+            _ => return Err(self.error("'=' or 'in' expected")),
+        };
+        self.check_match(Token::End, Token::For, line)?;
+        Ok(stat)
+    }
+
+    fn func_stat(&mut self, line: usize) -> PResult<Stat> {
+        // skip 'function'
+        self.next()?;
+        let mut path = vec![self.check_name()?];
+        let mut method = None;
+        while self.test_next(&Token::Dot)? {
+            path.push(self.check_name()?);
+        }
+        if self.test_next(&Token::Colon)? {
+            method = Some(self.check_name()?);
+        }
+        let body = self.body(method.is_some(), line)?;
+        Ok(Stat::Function {
+            name: FuncName { path, method },
+            body,
+        })
+    }
+
+    fn attrib(&mut self) -> PResult<Option<Attrib>> {
+        if !self.test_next(&Token::Lt)? {
+            return Ok(None);
+        }
+        let attr = self.check_name()?;
+        self.check_next(Token::Gt)?;
+        match attr.as_str() {
+            "const" => Ok(Some(Attrib::Const)),
+            "close" => Ok(Some(Attrib::Close)),
+            _ => Err(self.error(&format!("unknown attribute '{}'", attr))),
+        }
+    }
+
+    fn local_stat(&mut self, line: usize) -> PResult<Stat> {
+        let mut names = Vec::new();
+        let mut has_close = false;
+        loop {
+            let name = self.check_name()?;
+            let attrib = self.attrib()?;
+            if attrib == Some(Attrib::Close) {
+                if has_close {
+                    return Err(self.error("multiple to-be-closed variables in local list"));
+                }
+                has_close = true;
+            }
+            names.push(LocalName { name, attrib });
+            if !self.test_next(&Token::Comma)? {
+                break;
+            }
+        }
+        let exprs = if self.test_next(&Token::Assign)? {
+            self.expr_list()?
+        } else {
+            Vec::new()
+        };
+        Ok(Stat::Local { names, exprs, line })
+    }
+
+    fn expr_stat(&mut self, line: usize) -> PResult<Stat> {
+        let first = self.suffixed_expr()?;
+        if self.is(&Token::Assign) || self.is(&Token::Comma) {
+            let mut targets = vec![first];
+            while self.test_next(&Token::Comma)? {
+                targets.push(self.suffixed_expr()?);
+            }
+            for target in &targets {
+                if !matches!(target, Expr::Name(_) | Expr::Index { .. }) {
+                    return Err(self.error("syntax error"));
+                }
+            }
+            self.check_next(Token::Assign)?;
+            let exprs = self.expr_list()?;
+            return Ok(Stat::Assign {
+                targets,
+                exprs,
+                line,
+            });
+        }
+        match first {
+            Expr::Call { .. } | Expr::Method { .. } => Ok(Stat::Call(first)),
+            _ => Err(self.error("syntax error")),
+        }
+    }
+
+    fn ret_stat(&mut self) -> PResult<Return> {
+        let line = self.current.line;
+        // skip 'return'
+        self.next()?;
+        let exprs = if self.block_follow(true) || self.is(&Token::Semi) {
+            Vec::new()
+        } else {
+            self.expr_list()?
+        };
+        // skip optional semicolon
+        self.test_next(&Token::Semi)?;
+        Ok(Return { exprs, line })
+    }
+
+    /* ------ rules for expressions ------ */
+
+    /// brief: `(params) block end`, methods get an implicit `self`
+    fn body(&mut self, is_method: bool, line: usize) -> PResult<FuncBody> {
+        self.check_next(Token::LParen)?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if is_method {
+            params.push("self".to_string());
+        }
+        if !self.is(&Token::RParen) {
+            loop {
+                match self.current.token {
+                    Token::Name(_) => params.push(self.check_name()?),
+                    Token::Dots => {
+                        self.next()?;
+                        is_vararg = true;
+                    }
+                    _ => return Err(self.error("<name> or '...' expected")),
+                }
+                if is_vararg || !self.test_next(&Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.check_next(Token::RParen)?;
+        self.vararg.push(is_vararg);
+        let block = self.block()?;
+        self.vararg.pop();
+        let lastline = self.current.line;
+        self.check_match(Token::End, Token::Function, line)?;
+        Ok(FuncBody {
+            params,
+            is_vararg,
+            block,
+            line,
+            lastline,
+        })
+    }
+
+    fn expr_list(&mut self) -> PResult<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(&Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    pub fn expr(&mut self) -> PResult<Expr> {
+        self.sub_expr(0)
+    }
+
+    fn unary_op(&self) -> Option<UnOp> {
+        match self.current.token {
+            Token::Not => Some(UnOp::Not),
+            Token::Sub => Some(UnOp::Neg),
+            Token::Tilde => Some(UnOp::BNot),
+            Token::Len => Some(UnOp::Len),
+            _ => None,
+        }
+    }
+
+    fn binary_op(&self) -> Option<BinOp> {
+        let op = match self.current.token {
+            Token::Add => BinOp::Add,
+            Token::Sub => BinOp::Sub,
+            Token::Mul => BinOp::Mul,
+            Token::Mod => BinOp::Mod,
+            Token::Pow => BinOp::Pow,
+            Token::Div => BinOp::Div,
+            Token::IDiv => BinOp::IDiv,
+            Token::BAnd => BinOp::BAnd,
+            Token::BOr => BinOp::BOr,
+            Token::Tilde => BinOp::BXor,
+            Token::Shl => BinOp::Shl,
+            Token::Shr => BinOp::Shr,
+            Token::Concat => BinOp::Concat,
+            Token::Ne => BinOp::Ne,
+            Token::Eq => BinOp::Eq,
+            Token::Lt => BinOp::Lt,
+            Token::Le => BinOp::Le,
+            Token::Gt => BinOp::Gt,
+            Token::Ge => BinOp::Ge,
+            Token::And => BinOp::And,
+            Token::Or => BinOp::Or,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// brief: `(simpleexp | unop subexpr) { binop subexpr }`
+    /// where binop is any binary operator with a priority higher than `limit`
+    fn sub_expr(&mut self, limit: u8) -> PResult<Expr> {
+        self.enter_level()?;
+        let mut lhs = if let Some(op) = self.unary_op() {
+            let line = self.current.line;
+            self.next()?;
+            let expr = self.sub_expr(UnOp::PRIORITY)?;
+            Expr::UnOp {
+                op,
+                expr: Box::new(expr),
+                line,
+            }
+        } else {
+            self.simple_expr()?
+        };
+        while let Some(op) = self.binary_op() {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            let line = self.current.line;
+            self.next()?;
+            let rhs = self.sub_expr(right)?;
+            lhs = Expr::BinOp {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                line,
+            };
+        }
+        self.leave_level();
+        Ok(lhs)
+    }
+
+    fn simple_expr(&mut self) -> PResult<Expr> {
+        let expr = match &self.current.token {
+            Token::Flt(n) => Expr::Flt(*n),
+            Token::Int(i) => Expr::Int(*i),
+            Token::Str(s) => Expr::Str(s.clone()),
+            Token::Nil => Expr::Nil,
+            Token::True => Expr::True,
+            Token::False => Expr::False,
+            Token::Dots => {
+                if !self.vararg.last().copied().unwrap_or(false) {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                Expr::Vararg
+            }
+            Token::LBrace => return self.constructor(),
+            Token::Function => {
+                let line = self.current.line;
+                self.next()?;
+                return Ok(Expr::Function(Box::new(self.body(false, line)?)));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.next()?;
+        Ok(expr)
+    }
+
+    fn primary_expr(&mut self) -> PResult<Expr> {
+        match self.current.token {
+            Token::Name(_) => Ok(Expr::Name(self.check_name()?)),
+            Token::LParen => {
+                let line = self.current.line;
+                self.next()?;
+                let expr = self.expr()?;
+                self.check_match(Token::RParen, Token::LParen, line)?;
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    /// brief: `primaryexp { '.' NAME | '[' exp ']' | ':' NAME funcargs | funcargs }`
+    fn suffixed_expr(&mut self) -> PResult<Expr> {
+        let line = self.current.line;
+        let mut expr = self.primary_expr()?;
+        loop {
+            match self.current.token {
+                Token::Dot => {
+                    self.next()?;
+                    let key = Expr::Str(self.check_name()?.into_bytes());
+                    expr = Expr::Index {
+                        obj: Box::new(expr),
+                        key: Box::new(key),
+                        line: self.last_line,
+                    };
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.expr()?;
+                    self.check_next(Token::RBracket)?;
+                    expr = Expr::Index {
+                        obj: Box::new(expr),
+                        key: Box::new(key),
+                        line: self.last_line,
+                    };
+                }
+                Token::Colon => {
+                    self.next()?;
+                    let name = self.check_name()?;
+                    let args = self.func_args(line)?;
+                    expr = Expr::Method {
+                        obj: Box::new(expr),
+                        name,
+                        args,
+                        line,
+                    };
+                }
+                Token::LParen | Token::Str(_) | Token::LBrace => {
+                    let args = self.func_args(line)?;
+                    expr = Expr::Call {
+                        func: Box::new(expr),
+                        args,
+                        line,
+                    };
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn func_args(&mut self, line: usize) -> PResult<Vec<Expr>> {
+        match &self.current.token {
+            Token::LParen => {
+                self.next()?;
+                let args = if self.is(&Token::RParen) {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                self.check_match(Token::RParen, Token::LParen, line)?;
+                Ok(args)
+            }
+            Token::LBrace => Ok(vec![self.constructor()?]),
+            Token::Str(s) => {
+                let arg = Expr::Str(s.clone());
+                self.next()?;
+                Ok(vec![arg])
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    /// brief: `'{' [ field { sep field } [sep] ] '}'` where sep is ',' or ';'
+    fn constructor(&mut self) -> PResult<Expr> {
+        let line = self.current.line;
+        self.check_next(Token::LBrace)?;
+        let mut fields = Vec::new();
+        loop {
+            if self.is(&Token::RBrace) {
+                break;
+            }
+            let field = match self.current.token {
+                Token::Name(_) => {
+                    if *self.lookahead()? == Token::Assign {
+                        let name = self.check_name()?;
+                        self.next()?;
+                        Field::Named(name, self.expr()?)
+                    } else {
+                        Field::Positional(self.expr()?)
+                    }
+                }
+                Token::LBracket => {
+                    self.next()?;
+                    let key = self.expr()?;
+                    self.check_next(Token::RBracket)?;
+                    self.check_next(Token::Assign)?;
+                    Field::Keyed(key, self.expr()?)
+                }
+                _ => Field::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.test_next(&Token::Comma)? && !self.test_next(&Token::Semi)? {
+                break;
+            }
+        }
+        self.check_match(Token::RBrace, Token::LBrace, line)?;
+        Ok(Expr::Table { fields, line })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// brief: an expression with every operation in parentheses
+    fn show(e: &Expr) -> String {
+        match e {
+            Expr::Int(i) => i.to_string(),
+            Expr::Name(n) => n.clone(),
+            Expr::Paren(e) => format!("[{}]", show(e)),
+            Expr::Call { func, args, .. } => {
+                let args: Vec<_> = args.iter().map(show).collect();
+                format!("{}({})", show(func), args.join(", "))
+            }
+            Expr::Index { obj, key, .. } => format!("{}[{}]", show(obj), show(key)),
+            Expr::Str(s) => format!("'{}'", String::from_utf8_lossy(s)),
+            Expr::UnOp { op, expr, .. } => {
+                let op = match op {
+                    UnOp::Neg => "-",
+                    UnOp::BNot => "~",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                };
+                format!("({}{})", op, show(expr))
+            }
+            Expr::BinOp { op, lhs, rhs, .. } => {
+                let op = match op {
+                    BinOp::Add => "+",
+                    BinOp::Sub => "-",
+                    BinOp::Mul => "*",
+                    BinOp::Mod => "%",
+                    BinOp::Pow => "^",
+                    BinOp::Div => "/",
+                    BinOp::IDiv => "//",
+                    BinOp::BAnd => "&",
+                    BinOp::BOr => "|",
+                    BinOp::BXor => "~",
+                    BinOp::Shl => "<<",
+                    BinOp::Shr => ">>",
+                    BinOp::Concat => "..",
+                    BinOp::Eq => "==",
+                    BinOp::Lt => "<",
+                    BinOp::Le => "<=",
+                    BinOp::Ne => "~=",
+                    BinOp::Gt => ">",
+                    BinOp::Ge => ">=",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                };
+                format!("({} {} {})", show(lhs), op, show(rhs))
+            }
+            e => format!("{:?}", e),
+        }
+    }
+
+    fn expr(src: &str) -> String {
+        let block = parse(format!("return {}", src).as_bytes(), "=test").unwrap();
+        let exprs = block.ret.unwrap().exprs;
+        assert_eq!(exprs.len(), 1);
+        show(&exprs[0])
+    }
+
+    fn error(src: &str) -> String {
+        parse(src.as_bytes(), "=test").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(expr("1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(expr("1 * 2 + 3"), "((1 * 2) + 3)");
+        assert_eq!(expr("a or b and c"), "(a or (b and c))");
+        assert_eq!(expr("a and b or c"), "((a and b) or c)");
+        assert_eq!(expr("1 + 2 < 3 and x"), "(((1 + 2) < 3) and x)");
+        assert_eq!(expr("a .. b == c"), "((a .. b) == c)");
+        assert_eq!(expr("a < b .. c"), "(a < (b .. c))");
+        assert_eq!(expr("1 << 2 + 3"), "(1 << (2 + 3))");
+        assert_eq!(expr("a | b ~ c & d"), "(a | (b ~ (c & d)))");
+        assert_eq!(expr("a & b << c"), "(a & (b << c))");
+        assert_eq!(expr("a .. b << c"), "((a .. b) << c)");
+        assert_eq!(expr("a // b % c * d / e"), "((((a // b) % c) * d) / e)");
+    }
+
+    #[test]
+    fn unary_operators() {
+        assert_eq!(expr("-2 ^ 2"), "(-(2 ^ 2))");
+        assert_eq!(expr("2 ^ -3"), "(2 ^ (-3))");
+        assert_eq!(expr("not a == b"), "((not a) == b)");
+        assert_eq!(expr("- - x"), "(-(-x))");
+        assert_eq!(expr("#t + 1"), "((#t) + 1)");
+        assert_eq!(expr("~a ~ b"), "((~a) ~ b)");
+        assert_eq!(expr("-x * y"), "((-x) * y)");
+    }
+
+    #[test]
+    fn associativity() {
+        assert_eq!(expr("1 - 2 - 3"), "((1 - 2) - 3)");
+        assert_eq!(expr("a / b / c"), "((a / b) / c)");
+        assert_eq!(expr("a < b < c"), "((a < b) < c)");
+        assert_eq!(expr("a or b or c"), "((a or b) or c)");
+        // power and concatenation are right associative
+        assert_eq!(expr("2 ^ 3 ^ 2"), "(2 ^ (3 ^ 2))");
+        assert_eq!(expr("a .. b .. c"), "(a .. (b .. c))");
+        assert_eq!(expr("a .. b .. c .. d"), "(a .. (b .. (c .. d)))");
+    }
+
+    #[test]
+    fn suffixes_and_parentheses() {
+        assert_eq!(expr("(1 + 2) * 3"), "([(1 + 2)] * 3)");
+        assert_eq!(expr("f(a)[1] + t.x"), "(f(a)[1] + t['x'])");
+        assert_eq!(expr("-f(x) ^ 2"), "(-(f(x) ^ 2))");
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(error("x = 1 +"), "test:1: unexpected symbol near <eof>");
+        assert_eq!(error("x ="), "test:1: unexpected symbol near <eof>");
+        assert_eq!(
+            error("if x then\n\ny = 1"),
+            "test:3: 'end' expected (to close 'if' at line 1) near <eof>"
+        );
+        assert_eq!(
+            error("function f() return ... end"),
+            "test:1: cannot use '...' outside a vararg function near '...'"
+        );
+        assert_eq!(error("x"), "test:1: syntax error near <eof>");
+        assert_eq!(
+            error(&format!("return {}1{}", "(".repeat(300), ")".repeat(300))),
+            "test:1: chunk has too many syntax levels near '('"
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use crate::obj::objdef::{ObjectTrait, TObj, FLT, INT, T_NUM_FLT, T_NUM_INT, UINT};
+
+/// brief: arithmetic and bitwise operators, in the order of the reference `LUA_OP*`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+}
+
+const ARITH_OPS: [ArithOp; 14] = [
+    ArithOp::Add,
+    ArithOp::Sub,
+    ArithOp::Mul,
+    ArithOp::Mod,
+    ArithOp::Pow,
+    ArithOp::Div,
+    ArithOp::IDiv,
+    ArithOp::BAnd,
+    ArithOp::BOr,
+    ArithOp::BXor,
+    ArithOp::Shl,
+    ArithOp::Shr,
+    ArithOp::Unm,
+    ArithOp::BNot,
+];
+
+impl ArithOp {
+    #[inline(always)]
+    pub fn from_u8(op: u8) -> Option<ArithOp> {
+        ARITH_OPS.get(op as usize).copied()
+    }
+}
+
+/// brief: comparison operators, in the order of the reference `LUA_OPEQ`, `LUA_OPLT`, `LUA_OPLE`
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Lt,
+    Le,
+}
+
+/// brief: how a float without an exact integer value is rounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum F2I {
+    Eq,
+    Floor,
+    Ceil,
+}
+
+const NBITS: INT = INT::BITS as INT;
+
+// integers in [-MAXINTFITSF, MAXINTFITSF] have an exact float representation
+const MAXINTFITSF: UINT = 1 << FLT::MANTISSA_DIGITS;
+
+/// brief: convert a float to an integer only if it has an exact representation
+#[inline(always)]
+pub fn flt_to_int(f: FLT) -> Option<INT> {
+    // -(INT::MIN as FLT) is exactly representable, INT::MAX may not be
+    if f.floor() == f && f >= INT::MIN as FLT && f < -(INT::MIN as FLT) {
+        return Some(f as INT);
+    }
+    None
+}
+
+/// brief: convert a float to an integer, rounding it as `mode` says
+#[inline(always)]
+pub fn flt_to_int_mode(f: FLT, mode: F2I) -> Option<INT> {
+    match mode {
+        F2I::Eq => flt_to_int(f),
+        F2I::Floor => flt_to_int(f.floor()),
+        F2I::Ceil => flt_to_int(f.ceil()),
+    }
+}
+
+/// brief: integer value of a number, floats rounded as `mode` says
+#[inline(always)]
+pub fn to_integer_mode(obj: &TObj, mode: F2I) -> Option<INT> {
+    match obj.val_idx.into_inner() {
+        T_NUM_INT => Option::<INT>::into_inner(obj),
+        T_NUM_FLT => Option::<FLT>::into_inner(obj).and_then(|f| flt_to_int_mode(f, mode)),
+        _ => None,
+    }
+}
+
+/// brief: integer value of a number without string coercion
+#[inline(always)]
+pub fn to_integer_ns(obj: &TObj) -> Option<INT> {
+    match obj.val_idx.into_inner() {
+        T_NUM_INT => Option::<INT>::into_inner(obj),
+        T_NUM_FLT => Option::<FLT>::into_inner(obj).and_then(flt_to_int),
+        _ => None,
+    }
+}
+
+/// brief: float value of a number without string coercion
+#[inline(always)]
+pub fn to_number_ns(obj: &TObj) -> Option<FLT> {
+    match obj.val_idx.into_inner() {
+        T_NUM_INT => Option::<INT>::into_inner(obj).map(|i| i as FLT),
+        T_NUM_FLT => Option::<FLT>::into_inner(obj),
+        _ => None,
+    }
+}
+
+/// brief: floor modulo, `None` when dividing by zero
+#[inline(always)]
+pub fn int_mod(m: INT, n: INT) -> Option<INT> {
+    if n == 0 {
+        return None;
+    }
+    if n == -1 {
+        // avoid overflow with INT::MIN % -1
+        return Some(0);
+    }
+    let r = m % n;
+    if r != 0 && (r ^ n) < 0 {
+        return Some(r + n);
+    }
+    Some(r)
+}
+
+/// brief: floor division, `None` when dividing by zero
+#[inline(always)]
+pub fn int_idiv(m: INT, n: INT) -> Option<INT> {
+    if n == 0 {
+        return None;
+    }
+    if n == -1 {
+        // avoid overflow with INT::MIN // -1
+        return Some(m.wrapping_neg());
+    }
+    let q = m / n;
+    if (m ^ n) < 0 && m % n != 0 {
+        return Some(q - 1);
+    }
+    Some(q)
+}
+
+/// brief: logical shift left, a negative shift goes right
+#[inline(always)]
+pub fn shift_left(x: INT, y: INT) -> INT {
+    if y < 0 {
+        if y <= -NBITS {
+            return 0;
+        }
+        return ((x as UINT) >> (-y)) as INT;
+    }
+    if y >= NBITS {
+        return 0;
+    }
+    x.wrapping_shl(y as u32)
+}
+
+/// brief: float modulo with the sign of the divisor
+#[inline(always)]
+pub fn flt_mod(a: FLT, b: FLT) -> FLT {
+    let m = a % b;
+    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+        return m + b;
+    }
+    m
+}
+
+pub fn int_arith(op: ArithOp, a: INT, b: INT) -> Option<INT> {
+    let res = match op {
+        ArithOp::Add => a.wrapping_add(b),
+        ArithOp::Sub => a.wrapping_sub(b),
+        ArithOp::Mul => a.wrapping_mul(b),
+        ArithOp::Mod => return int_mod(a, b),
+        ArithOp::IDiv => return int_idiv(a, b),
+        ArithOp::BAnd => a & b,
+        ArithOp::BOr => a | b,
+        ArithOp::BXor => a ^ b,
+        ArithOp::Shl => shift_left(a, b),
+        ArithOp::Shr => shift_left(a, b.wrapping_neg()),
+        ArithOp::Unm => a.wrapping_neg(),
+        ArithOp::BNot => !a,
+        ArithOp::Pow | ArithOp::Div => return None,
+    };
+    Some(res)
+}
+
+pub fn flt_arith(op: ArithOp, a: FLT, b: FLT) -> FLT {
+    match op {
+        ArithOp::Add => a + b,
+        ArithOp::Sub => a - b,
+        ArithOp::Mul => a * b,
+        ArithOp::Div => a / b,
+        ArithOp::Pow => {
+            if b == 2.0 {
+                a * a
+            } else {
+                a.powf(b)
+            }
+        }
+        ArithOp::IDiv => (a / b).floor(),
+        ArithOp::Unm => -a,
+        ArithOp::Mod => flt_mod(a, b),
+        // bitwise operators never get here
+        ArithOp::BAnd
+        | ArithOp::BOr
+        | ArithOp::BXor
+        | ArithOp::Shl
+        | ArithOp::Shr
+        | ArithOp::BNot => 0.0,
+    }
+}
+
+/// brief: operate on two numbers without metamethods or string coercion
+/// `None` when an operand does not fit the operator or an integer is divided by zero
+pub fn raw_arith(op: ArithOp, a: &TObj, b: &TObj) -> Option<TObj> {
+    match op {
+        ArithOp::BAnd
+        | ArithOp::BOr
+        | ArithOp::BXor
+        | ArithOp::Shl
+        | ArithOp::Shr
+        | ArithOp::BNot => {
+            let i1 = to_integer_ns(a)?;
+            let i2 = to_integer_ns(b)?;
+            int_arith(op, i1, i2).map(|i| Some(i).new())
+        }
+        ArithOp::Div | ArithOp::Pow => {
+            let n1 = to_number_ns(a)?;
+            let n2 = to_number_ns(b)?;
+            Some(Some(flt_arith(op, n1, n2)).new())
+        }
+        _ => {
+            if a.val_idx.into_inner() == T_NUM_INT && b.val_idx.into_inner() == T_NUM_INT {
+                let i1 = Option::<INT>::into_inner(a)?;
+                let i2 = Option::<INT>::into_inner(b)?;
+                return int_arith(op, i1, i2).map(|i| Some(i).new());
+            }
+            let n1 = to_number_ns(a)?;
+            let n2 = to_number_ns(b)?;
+            Some(Some(flt_arith(op, n1, n2)).new())
+        }
+    }
+}
+
+#[inline(always)]
+fn int_fits_flt(i: INT) -> bool {
+    MAXINTFITSF.wrapping_add(i as UINT) <= 2 * MAXINTFITSF
+}
+
+/// brief: i < f, exact even when i has no float representation
+fn lt_int_flt(i: INT, f: FLT) -> bool {
+    if int_fits_flt(i) {
+        return (i as FLT) < f;
+    }
+    // i < f <=> i < ceil(f)
+    match flt_to_int_mode(f, F2I::Ceil) {
+        Some(fi) => i < fi,
+        None => f > 0.0,
+    }
+}
+
+fn le_int_flt(i: INT, f: FLT) -> bool {
+    if int_fits_flt(i) {
+        return (i as FLT) <= f;
+    }
+    // i <= f <=> i <= floor(f)
+    match flt_to_int_mode(f, F2I::Floor) {
+        Some(fi) => i <= fi,
+        None => f > 0.0,
+    }
+}
+
+fn lt_flt_int(f: FLT, i: INT) -> bool {
+    if int_fits_flt(i) {
+        return f < (i as FLT);
+    }
+    // f < i <=> floor(f) < i
+    match flt_to_int_mode(f, F2I::Floor) {
+        Some(fi) => fi < i,
+        None => f < 0.0,
+    }
+}
+
+fn le_flt_int(f: FLT, i: INT) -> bool {
+    if int_fits_flt(i) {
+        return f <= (i as FLT);
+    }
+    // f <= i <=> ceil(f) <= i
+    match flt_to_int_mode(f, F2I::Ceil) {
+        Some(fi) => fi <= i,
+        None => f < 0.0,
+    }
+}
+
+/// brief: a < b for two numbers, `None` when some of them is not a number
+pub fn lt_num(a: &TObj, b: &TObj) -> Option<bool> {
+    match (a.val_idx.into_inner(), b.val_idx.into_inner()) {
+        (T_NUM_INT, T_NUM_INT) => {
+            Some(Option::<INT>::into_inner(a)? < Option::<INT>::into_inner(b)?)
+        }
+        (T_NUM_INT, T_NUM_FLT) => Some(lt_int_flt(
+            Option::<INT>::into_inner(a)?,
+            Option::<FLT>::into_inner(b)?,
+        )),
+        (T_NUM_FLT, T_NUM_INT) => Some(lt_flt_int(
+            Option::<FLT>::into_inner(a)?,
+            Option::<INT>::into_inner(b)?,
+        )),
+        (T_NUM_FLT, T_NUM_FLT) => {
+            Some(Option::<FLT>::into_inner(a)? < Option::<FLT>::into_inner(b)?)
+        }
+        _ => None,
+    }
+}
+
+/// brief: a <= b for two numbers, `None` when some of them is not a number
+pub fn le_num(a: &TObj, b: &TObj) -> Option<bool> {
+    match (a.val_idx.into_inner(), b.val_idx.into_inner()) {
+        (T_NUM_INT, T_NUM_INT) => {
+            Some(Option::<INT>::into_inner(a)? <= Option::<INT>::into_inner(b)?)
+        }
+        (T_NUM_INT, T_NUM_FLT) => Some(le_int_flt(
+            Option::<INT>::into_inner(a)?,
+            Option::<FLT>::into_inner(b)?,
+        )),
+        (T_NUM_FLT, T_NUM_INT) => Some(le_flt_int(
+            Option::<FLT>::into_inner(a)?,
+            Option::<INT>::into_inner(b)?,
+        )),
+        (T_NUM_FLT, T_NUM_FLT) => {
+            Some(Option::<FLT>::into_inner(a)? <= Option::<FLT>::into_inner(b)?)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,460 @@
+use core::mem::size_of;
+
+use crate::info::lua::{ErrCode, MEMORY_TYPE_MISMATCH};
+use crate::obj::objdef::{ObjectTrait, TObj, FLT, INT, T_NUMBER, UINT};
+use crate::obj::statedef::LuaState;
+use crate::obj::string::LuaString;
+use crate::vm::arith::{to_integer_ns, to_number_ns};
+
+const LUAI_NUMDIGITS: usize = if size_of::<FLT>() == 4 { 7 } else { 14 }; // digits of a float as text
+
+#[inline(always)]
+pub(crate) fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c)
+}
+
+#[inline(always)]
+pub(crate) fn hex_value(c: u8) -> u32 {
+    match c {
+        b'0'..=b'9' => (c - b'0') as u32,
+        _ => ((c | 0x20) - b'a' + 10) as u32,
+    }
+}
+
+/// brief: read an integer numeral, decimal or hexadecimal, negated when neg is true;
+/// decimal integers that overflow are not integers, hexadecimal ones wrap around
+pub(crate) fn str2int(buff: &[u8], neg: bool) -> Option<INT> {
+    const MAXBY10: UINT = (INT::MAX / 10) as UINT;
+    const MAXLASTD: UINT = (INT::MAX % 10) as UINT;
+
+    let mut acc: UINT = 0;
+    if buff.len() > 2 && buff[0] == b'0' && (buff[1] | 0x20) == b'x' {
+        for &c in &buff[2..] {
+            if !c.is_ascii_hexdigit() {
+                return None;
+            }
+            acc = acc.wrapping_mul(16).wrapping_add(hex_value(c) as UINT);
+        }
+    } else {
+        if buff.is_empty() {
+            return None;
+        }
+        for &c in buff {
+            if !c.is_ascii_digit() {
+                return None;
+            }
+            let d = (c - b'0') as UINT;
+            // the minimum integer has one more unit than the maximum
+            if acc >= MAXBY10 && (acc > MAXBY10 || d > MAXLASTD + neg as UINT) {
+                // overflow, not accepted as an integer
+                return None;
+            }
+            acc = acc * 10 + d;
+        }
+    }
+    let i = acc as INT;
+    Some(if neg { i.wrapping_neg() } else { i })
+}
+
+/// brief: read a float numeral, decimal or hexadecimal, without sign
+pub(crate) fn str2flt(buff: &[u8]) -> Option<FLT> {
+    if buff.iter().any(|&c| matches!(c, b'n' | b'N' | b'i' | b'I')) {
+        // reject 'inf' and 'nan'
+        return None;
+    }
+    if buff.len() > 1 && buff[0] == b'0' && (buff[1] | 0x20) == b'x' {
+        return hexstr2flt(&buff[2..]);
+    }
+    if matches!(buff.first(), Some(b'+' | b'-')) {
+        return None;
+    }
+    core::str::from_utf8(buff).ok()?.parse::<FLT>().ok()
+}
+
+/// brief: read the part of a hexadecimal float after `0x`
+fn hexstr2flt(buff: &[u8]) -> Option<FLT> {
+    const MAXSIGDIG: i32 = 30;
+
+    let mut r: f64 = 0.0;
+    let mut sigdig = 0;
+    let mut nosigdig = 0;
+    let mut e: i32 = 0;
+    let mut hasdot = false;
+    let mut pos = 0;
+
+    while pos < buff.len() {
+        let c = buff[pos];
+        if c == b'.' {
+            if hasdot {
+                return None;
+            }
+            hasdot = true;
+        } else if c.is_ascii_hexdigit() {
+            if sigdig == 0 && c == b'0' {
+                nosigdig += 1;
+            } else {
+                sigdig += 1;
+                if sigdig <= MAXSIGDIG {
+                    r = r * 16.0 + hex_value(c) as f64;
+                } else {
+                    // too many digits; ignore, but still count for exponent
+                    e += 1;
+                }
+            }
+            if hasdot {
+                e -= 1;
+            }
+        } else {
+            break;
+        }
+        pos += 1;
+    }
+    if nosigdig + sigdig == 0 {
+        return None;
+    }
+    e *= 4;
+    if pos < buff.len() {
+        if (buff[pos] | 0x20) != b'p' {
+            return None;
+        }
+        pos += 1;
+        let mut neg = false;
+        if pos < buff.len() && (buff[pos] == b'+' || buff[pos] == b'-') {
+            neg = buff[pos] == b'-';
+            pos += 1;
+        }
+        if pos >= buff.len() {
+            return None;
+        }
+        let mut exp: i32 = 0;
+        for &c in &buff[pos..] {
+            if !c.is_ascii_digit() {
+                return None;
+            }
+            exp = exp.saturating_mul(10).saturating_add((c - b'0') as i32);
+        }
+        e = e.saturating_add(if neg { -exp } else { exp });
+    }
+    Some((r * 2f64.powi(e)) as FLT)
+}
+
+/// brief: the text without the spaces around it
+fn trim_spaces(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&c| !is_space(c)).unwrap_or(s.len());
+    let end = s.iter().rposition(|&c| !is_space(c)).map_or(start, |e| e + 1);
+    &s[start..end]
+}
+
+/// brief: split an optional sign from the text, true when it is a minus
+fn split_sign(s: &[u8]) -> (bool, &[u8]) {
+    match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    }
+}
+
+/// brief: the number a string stands for, read as Lua does: an integer or a float numeral,
+/// decimal or hexadecimal, with an optional sign and spaces around it
+pub fn str2number(s: &[u8]) -> Option<TObj> {
+    let (neg, body) = split_sign(trim_spaces(s));
+    if let Some(i) = str2int(body, neg) {
+        return Some(Some(i).new());
+    }
+    let f = str2flt(body)?;
+    Some(Some(if neg { -f } else { f }).new())
+}
+
+/// brief: the integer a string writes in base, 2 to 36, as `tonumber(s, base)` reads it:
+/// letters are digits from 10 on, an optional minus sign and spaces around it are allowed,
+/// the value wraps around
+pub fn str2int_base(s: &[u8], base: u32) -> Option<INT> {
+    let (neg, digits) = split_sign(trim_spaces(s));
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: UINT = 0;
+    for &c in digits {
+        let d = if c.is_ascii_digit() {
+            (c - b'0') as u32
+        } else if c.is_ascii_alphabetic() {
+            ((c | 0x20) - b'a' + 10) as u32
+        } else {
+            return None;
+        };
+        if d >= base {
+            return None;
+        }
+        n = n.wrapping_mul(base as UINT).wrapping_add(d as UINT);
+    }
+    let i = n as INT;
+    Some(if neg { i.wrapping_neg() } else { i })
+}
+
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// brief: a float in the `%.<prec>g` format of C
+fn fmt_g(x: FLT, prec: usize) -> String {
+    if x.is_nan() {
+        return if x.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if x.is_infinite() {
+        return if x < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    if x == 0.0 {
+        return if x.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+    // the exponent after rounding to prec digits decides between the two styles
+    let sci = format!("{:.*e}", prec - 1, x);
+    let (mant, exp) = sci.split_once('e').unwrap_or((&sci, "0"));
+    let exp: i32 = exp.parse().unwrap_or(0);
+    if exp < -4 || exp >= prec as i32 {
+        let sign = if exp < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", strip_zeros(mant), sign, exp.abs());
+    }
+    let fixed = format!("{:.*}", (prec as i32 - 1 - exp) as usize, x);
+    strip_zeros(&fixed).to_string()
+}
+
+/// brief: the text of a number, a float that looks like an integer gets a ".0"
+pub fn number_to_str(v: &TObj) -> Option<String> {
+    if let Some(i) = Option::<INT>::into_inner(v) {
+        return Some(i.to_string());
+    }
+    let n = Option::<FLT>::into_inner(v)?;
+    let mut s = fmt_g(n, LUAI_NUMDIGITS);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    Some(s)
+}
+
+/// brief: a number as it is, or the number a string converts to
+pub fn to_numeric(obj: &TObj) -> Option<TObj> {
+    if obj.val_idx.basic_type() == T_NUMBER {
+        return Some(*obj);
+    }
+    let ts = Option::<*mut LuaString>::into_inner(obj)?;
+    str2number(unsafe { (*ts).as_bytes() })
+}
+
+/// brief: float value of a number, or of a string that converts to one
+#[inline(always)]
+pub fn to_number(obj: &TObj) -> Option<FLT> {
+    to_numeric(obj).as_ref().and_then(to_number_ns)
+}
+
+/// brief: integer value of a number, or of a string that converts to one,
+/// a float only when it has an exact integer value
+#[inline(always)]
+pub fn to_integer(obj: &TObj) -> Option<INT> {
+    to_numeric(obj).as_ref().and_then(to_integer_ns)
+}
+
+impl LuaState {
+    /// brief: the float value of the value `step` slots below the top,
+    /// a string is converted as in arithmetic
+    pub fn to_number(&mut self, step: usize) -> Result<FLT, ErrCode> {
+        let v = self.get_stkelem_fromtop(step)?;
+        to_number(&v).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))
+    }
+
+    /// brief: the integer value of the value `step` slots below the top,
+    /// a string is converted and a float must have an exact integer value
+    pub fn to_integer(&mut self, step: usize) -> Result<INT, ErrCode> {
+        let v = self.get_stkelem_fromtop(step)?;
+        to_integer(&v).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))
+    }
+
+    /// brief: the text of the string or number `step` slots below the top,
+    /// a number is replaced by its text in the stack
+    pub fn to_string(&mut self, step: usize) -> Result<&[u8], ErrCode> {
+        let idx = self
+            .stack_top_index
+            .checked_sub(step + 1)
+            .ok_or(ErrCode(MEMORY_TYPE_MISMATCH))?;
+        if !self.tostring_at(idx)? {
+            return Err(ErrCode(MEMORY_TYPE_MISMATCH));
+        }
+        self.get_string_fromtop(step)
+    }
+
+    /// brief: make the value at the stack index idx a string if it is a number,
+    /// false when it is neither
+    pub(crate) fn tostring_at(&mut self, idx: usize) -> Result<bool, ErrCode> {
+        let stk = self.get_stack_mut_ref()?;
+        let v = stk.get_elem(idx)?;
+        if Option::<*mut LuaString>::into_inner(&v).is_some() {
+            return Ok(true);
+        }
+        let text = match number_to_str(&v) {
+            Some(text) => text,
+            None => return Ok(false),
+        };
+        let ts = self.new_string(text.as_bytes())?;
+        self.get_stack_mut_ref()?.set_elem(idx, Some(ts).new())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(s: &str) -> Option<INT> {
+        str2number(s.as_bytes()).and_then(|v| Option::<INT>::into_inner(&v))
+    }
+
+    fn flt(s: &str) -> Option<FLT> {
+        str2number(s.as_bytes()).and_then(|v| Option::<FLT>::into_inner(&v))
+    }
+
+    fn text_of_int(i: INT) -> String {
+        number_to_str(&Some(i).new()).unwrap()
+    }
+
+    fn text_of_flt(n: FLT) -> String {
+        number_to_str(&Some(n).new()).unwrap()
+    }
+
+    /// brief: the hexadecimal digit d repeated as many times as an INT has digits
+    fn hex_digits(d: &str) -> String {
+        d.repeat(2 * size_of::<INT>())
+    }
+
+    #[test]
+    fn str2number_integers() {
+        assert_eq!(int("10"), Some(10));
+        assert_eq!(int("  -7\t\n"), Some(-7));
+        assert_eq!(int("+7"), Some(7));
+        assert_eq!(int("0x10"), Some(16));
+        assert_eq!(int(" -0XfF "), Some(-255));
+        assert_eq!(int(&INT::MAX.to_string()), Some(INT::MAX));
+        assert_eq!(int(&INT::MIN.to_string()), Some(INT::MIN));
+        // hexadecimal integers wrap around
+        assert_eq!(int(&format!("0x{}", hex_digits("f"))), Some(-1));
+        assert_eq!(int(&format!("0x1{}", hex_digits("0"))), Some(0));
+        assert_eq!(
+            int(&format!("-0x8{}", &hex_digits("0")[1..])),
+            Some(INT::MIN)
+        );
+    }
+
+    #[test]
+    fn str2number_floats() {
+        assert_eq!(flt("1.5"), Some(1.5));
+        assert_eq!(flt("1e15"), Some(1e15));
+        assert_eq!(flt(" .5 "), Some(0.5));
+        assert_eq!(flt("5."), Some(5.0));
+        assert_eq!(flt("-2E-1"), Some(-0.2));
+        assert_eq!(flt("0x1p4"), Some(16.0));
+        assert_eq!(flt("0x.8"), Some(0.5));
+        assert_eq!(flt("0xA.8p0"), Some(10.5));
+        let neg_zero = flt("-0.0").unwrap();
+        assert!(neg_zero == 0.0 && neg_zero.is_sign_negative());
+        // a decimal integer that overflows is a float
+        let over = (INT::MAX as UINT + 1).to_string();
+        assert_eq!(int(&over), None);
+        assert_eq!(flt(&over), Some(-(INT::MIN as FLT)));
+        let under = format!("-{}", over.replace("8", "9"));
+        assert!(flt(&under).is_some());
+    }
+
+    #[test]
+    fn str2number_rejects() {
+        for s in [
+            "", " ", "abc", "1a", "1 2", "0x", "0xg", "1e", "--1", "+-1", "- 1", "inf", "nan",
+            "-inf", "1e+", ".", "0x.p1", "1..2",
+        ] {
+            assert!(str2number(s.as_bytes()).is_none(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn str2int_base_digits() {
+        assert_eq!(str2int_base(b"ff", 16), Some(255));
+        assert_eq!(str2int_base(b"FF", 16), Some(255));
+        assert_eq!(str2int_base(b" -101 ", 2), Some(-5));
+        assert_eq!(str2int_base(b"zz", 36), Some(1295));
+        assert_eq!(str2int_base(b"777", 8), Some(511));
+        assert_eq!(str2int_base(b"8", 8), None);
+        assert_eq!(str2int_base(b"1.0", 10), None);
+        assert_eq!(str2int_base(b"0x10", 16), None);
+        assert_eq!(str2int_base(b"", 10), None);
+        assert_eq!(str2int_base(b"-", 10), None);
+        // the value wraps around
+        assert_eq!(str2int_base(hex_digits("f").as_bytes(), 16), Some(-1));
+        assert_eq!(
+            str2int_base(format!("1{}", hex_digits("0")).as_bytes(), 16),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn number_text() {
+        assert_eq!(text_of_int(0), "0");
+        assert_eq!(text_of_int(-42), "-42");
+        assert_eq!(text_of_int(INT::MIN), INT::MIN.to_string());
+        assert_eq!(text_of_flt(1.0), "1.0");
+        assert_eq!(text_of_flt(-0.0), "-0.0");
+        assert_eq!(text_of_flt(0.1), "0.1");
+        assert_eq!(text_of_flt(1.5), "1.5");
+        assert_eq!(text_of_flt(100.0), "100.0");
+        assert_eq!(text_of_flt(1e30), "1e+30");
+        assert_eq!(text_of_flt(-2.5e-7), "-2.5e-07");
+        assert_eq!(text_of_flt(FLT::INFINITY), "inf");
+        assert_eq!(text_of_flt(FLT::NEG_INFINITY), "-inf");
+        assert!(text_of_flt(FLT::NAN).ends_with("nan"));
+        assert_eq!(number_to_str(&Some(true).new()), None);
+    }
+
+    #[test]
+    #[cfg(not(feature = "lua32"))]
+    fn number_text_of_doubles() {
+        // 14 significant digits, as `%.14g`
+        assert_eq!(text_of_flt(1e15), "1e+15");
+        assert_eq!(text_of_flt(1e14), "1e+14");
+        assert_eq!(text_of_flt(123456789012345.0), "1.2345678901234e+14");
+        assert_eq!(text_of_flt(12345678901234.0), "12345678901234.0");
+        assert_eq!(text_of_flt(2f64.powi(53)), "9.007199254741e+15");
+        assert_eq!(text_of_flt(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(text_of_flt(0.0001), "0.0001");
+        assert_eq!(text_of_flt(0.00001), "1e-05");
+        assert_eq!(text_of_flt(99999999999999.9), "1e+14");
+        assert_eq!(text_of_flt(-(INT::MIN as FLT)), "9.2233720368548e+18");
+    }
+
+    #[test]
+    #[cfg(feature = "lua32")]
+    fn number_text_of_floats() {
+        // 7 significant digits, as `%.7g`
+        assert_eq!(text_of_flt(1e15), "1e+15");
+        assert_eq!(text_of_flt(1234567.0), "1234567.0");
+        assert_eq!(text_of_flt(12345678.0), "1.234568e+07");
+        assert_eq!(text_of_flt(1.0 / 3.0), "0.3333333");
+    }
+
+    #[test]
+    fn fmt_g_rounding() {
+        assert_eq!(fmt_g(0.5, 1), "0.5");
+        assert_eq!(fmt_g(9.5, 1), "1e+01");
+        assert_eq!(fmt_g(0.000123456, 3), "0.000123");
+        assert_eq!(fmt_g(123456.0, 3), "1.23e+05");
+        assert_eq!(fmt_g(100.0, 3), "100");
+        assert_eq!(fmt_g(-0.0, 6), "-0");
+    }
+
+    #[test]
+    fn coercions() {
+        assert_eq!(to_integer(&Some(3.0 as FLT).new()), Some(3));
+        assert_eq!(to_integer(&Some(3.5 as FLT).new()), None);
+        assert_eq!(to_number(&Some(3 as INT).new()), Some(3.0));
+        assert!(to_numeric(&Some(true).new()).is_none());
+    }
+}
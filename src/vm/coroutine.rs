@@ -0,0 +1,729 @@
+use crate::info::lua::{
+    ErrCode, FINE, INVOKE_RESUME_ACTIVE, INVOKE_RESUME_DEAD, INVOKE_RESUME_OVERFLOW,
+    INVOKE_YIELD_BOUNDARY, INVOKE_YIELD_OUTSIDE, LUAI_MAXCCALLS, LUA_MUL_RET,
+    MEMORY_TYPE_MISMATCH, MEMORY_UNREACHABLE, STATE_OK, STATE_YIELD,
+};
+use crate::obj::objdef::{ObjectTrait, KCTX, KFUNC, T_LCL};
+use crate::obj::statedef::LuaState;
+use crate::vm::error::error_status;
+
+/// brief: the status of a coroutine, as seen from the running thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoStatus {
+    Suspended, // yielded, or not started yet
+    Running,   // the thread asking
+    Normal,    // active, but it resumed another coroutine
+    Dead,      // its body returned
+    Errored,   // stopped by an error
+}
+
+impl LuaState {
+    /// brief: create a thread that shares the global state and push it;
+    /// it is collected as any other value, so it must stay reachable while it is used
+    pub fn new_thread(&mut self) -> Result<*mut LuaState, ErrCode> {
+        let th = self.thread_new()?;
+        self.push_obj(Some(th).new())?;
+        self.check_gc()?;
+        Ok(th)
+    }
+
+    /// brief: push the running thread, true when it is the main one
+    pub fn push_thread(&mut self) -> Result<bool, ErrCode> {
+        let th: *mut LuaState = self;
+        self.push_obj(Some(th).new())?;
+        self.is_main_thread()
+    }
+
+    pub fn get_thread_fromtop(&mut self, step: usize) -> Result<*mut LuaState, ErrCode> {
+        let elem = self.get_stkelem_fromtop(step)?;
+        if let Some(val) = Option::<*mut LuaState>::into_inner(&elem) {
+            Ok(val)
+        } else {
+            Err(ErrCode(MEMORY_TYPE_MISMATCH))
+        }
+    }
+
+    fn is_main_thread(&self) -> Result<bool, ErrCode> {
+        let main = self.global()?.mainthread.map(|m| m.as_ptr());
+        Ok(main == Some(self as *const LuaState as *mut LuaState))
+    }
+
+    /// brief: pop n values from this thread and push them onto `to`
+    pub fn xmove(&mut self, to: &mut LuaState, n: usize) -> Result<ErrCode, ErrCode> {
+        if n > self.stack_top_index {
+            return Err(ErrCode(MEMORY_UNREACHABLE));
+        }
+        to.stack_check(n)?;
+        let first = self.stack_top_index - n;
+        let stk = self.get_stack_mut_ref()?;
+        for j in 0..n {
+            to.push_obj(stk.get_elem(first + j)?)?;
+        }
+        self.stack_top_index = first;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: whether the running function can yield
+    pub fn is_yieldable(&self) -> bool {
+        self.nny == 0
+    }
+
+    /// brief: the status of the coroutine co, seen from this thread
+    pub fn thread_status(&self, co: &LuaState) -> CoStatus {
+        if core::ptr::eq(co, self) {
+            return CoStatus::Running;
+        }
+        match co.get_status().0 {
+            STATE_YIELD => CoStatus::Suspended,
+            STATE_OK if co.ncalls > 0 => CoStatus::Normal,
+            STATE_OK if co.stack_top_index == 0 => CoStatus::Dead,
+            STATE_OK => CoStatus::Suspended,
+            _ => CoStatus::Errored,
+        }
+    }
+
+    /// brief: start or go on with this coroutine; to start it the function and its nargs
+    /// arguments are on top, to go on the nargs values become the results of `yield_`;
+    /// the number of values yielded, or returned by the body, is given and they are left on top,
+    /// an error leaves the coroutine dead with the errored status and the error object on top;
+    /// a coroutine that cannot be resumed gets the message in place of the arguments
+    pub fn resume(&mut self, nargs: usize) -> Result<usize, ErrCode> {
+        match self.get_status().0 {
+            STATE_OK => {
+                if self.ncalls > 0 {
+                    return self.resume_error(ErrCode(INVOKE_RESUME_ACTIVE), nargs);
+                }
+                if self.stack_top_index < nargs + 1 {
+                    // no function to run: it has already returned
+                    return self.resume_error(ErrCode(INVOKE_RESUME_DEAD), nargs);
+                }
+            }
+            STATE_YIELD => {}
+            _ => return self.resume_error(ErrCode(INVOKE_RESUME_DEAD), nargs),
+        }
+        if self.global()?.nresumes >= LUAI_MAXCCALLS {
+            return self.resume_error(ErrCode(INVOKE_RESUME_OVERFLOW), nargs);
+        }
+        self.global_mut()?.nresumes += 1;
+        let res = self.resume_body(nargs);
+        self.global_mut()?.nresumes -= 1;
+        match res {
+            Ok(_) => Ok(self.stack_top_index),
+            Err(_) if self.get_status().0 == STATE_YIELD => Ok(self.nyield),
+            Err(e) => {
+                let err = self.error_object(e)?;
+                let status = error_status(e);
+                self.stack_check(1)?;
+                self.push_obj(err)?;
+                self.set_status(status);
+                Err(status)
+            }
+        }
+    }
+
+    /// brief: refuse a resume: the nargs arguments are replaced by the message of e,
+    /// the coroutine is left as it was
+    fn resume_error(&mut self, e: ErrCode, nargs: usize) -> Result<usize, ErrCode> {
+        self.move_top(nargs.min(self.stack_top_index), false);
+        self.push_string(e.msg().as_bytes())?;
+        Err(e)
+    }
+
+    fn resume_body(&mut self, nargs: usize) -> Result<ErrCode, ErrCode> {
+        let mut res = if self.get_status().0 == STATE_OK {
+            self.call_fresh(nargs, LUA_MUL_RET)
+        } else {
+            self.set_status(ErrCode(STATE_OK));
+            // the Rust function that yielded returns the values passed in
+            self.finish_rcall(nargs, ErrCode(STATE_YIELD))
+                .and_then(|_| self.unroll())
+        };
+        // an error is caught by the innermost `pcall_k` still running, if any
+        while let Err(e) = res {
+            if self.get_status().0 == STATE_YIELD {
+                break;
+            }
+            match self.find_pcall()? {
+                Some(ci) => res = self.recover_at(ci, e),
+                None => break,
+            }
+        }
+        res
+    }
+
+    /// brief: the topmost frame inside a `pcall_k` that called a yieldable function
+    fn find_pcall(&mut self) -> Result<Option<usize>, ErrCode> {
+        for ci in (0..self.ncalls).rev() {
+            if self.get_frame_mut(ci)?.ypcall.is_some() {
+                return Ok(Some(ci));
+            }
+        }
+        Ok(None)
+    }
+
+    /// brief: end the protected call of frame ci with the error e
+    /// and go on with its continuation
+    fn recover_at(&mut self, ci: usize, e: ErrCode) -> Result<ErrCode, ErrCode> {
+        let (level, msgh) = self
+            .get_frame_mut(ci)?
+            .ypcall
+            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+        let status = self.recover(e, level, ci + 1, msgh)?;
+        // the frame was running yieldable code
+        self.nny = 0;
+        self.finish_rcall(0, status)?;
+        self.unroll()
+    }
+
+    /// brief: go on with the suspended frames, the ones of Lua in the interpreter loop,
+    /// once the instruction they were left in is finished, and the ones of Rust
+    /// through their continuations, until the body returns
+    fn unroll(&mut self) -> Result<ErrCode, ErrCode> {
+        while self.ncalls > 0 {
+            let ci = self.ncalls - 1;
+            let func = self.get_frame_mut(ci)?.stack_func_index;
+            let f = self.get_stack_mut_ref()?.get_elem(func)?;
+            if f.val_idx.into_inner() == T_LCL {
+                self.finish_op(ci)?;
+                // it stops at the first frame called by a Rust function
+                self.execute(ci)?;
+            } else {
+                self.finish_rcall(0, ErrCode(STATE_YIELD))?;
+            }
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: finish the Rust frame on top, its results are given by its continuation,
+    /// called with status, or, without one, are the n values on top
+    fn finish_rcall(&mut self, n: usize, status: ErrCode) -> Result<ErrCode, ErrCode> {
+        let ci = self.ncalls - 1;
+        let frame = self.get_frame_mut(ci)?;
+        frame.ypcall = None;
+        let n = match frame.k {
+            Some(k) => {
+                let ctx = frame.ctx;
+                let n = k(self, status, ctx);
+                if self.get_status().0 == STATE_YIELD {
+                    // the continuation yields in turn
+                    return Err(ErrCode(STATE_YIELD));
+                }
+                n
+            }
+            None => n,
+        };
+        let frame = self.get_frame_mut(ci)?;
+        let (res, wanted) = (frame.stack_func_index, frame.nresults);
+        self.move_results(res, n, wanted)?;
+        self.pop_frame()?;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: call the function below the nargs arguments on top from a Rust function;
+    /// if the callee yields the call gives `STATE_YIELD` as error, the Rust function
+    /// must then return at once and k runs in its place, with ctx, when the coroutine goes on;
+    /// without k, or where yields are not allowed, nothing called may yield
+    pub fn call_k(
+        &mut self,
+        nargs: usize,
+        nresults: isize,
+        ctx: KCTX,
+        k: Option<KFUNC>,
+    ) -> Result<ErrCode, ErrCode> {
+        if k.is_none() || !self.is_yieldable() {
+            return self.do_call(nargs, nresults);
+        }
+        let frame = self.get_frame_mut(self.ncalls.wrapping_sub(1))?;
+        frame.k = k;
+        frame.ctx = ctx;
+        self.call_fresh(nargs, nresults)
+    }
+
+    /// brief: `pcall` from a Rust function, the callee may yield as with `call_k`;
+    /// an error raised after a yield is caught when the coroutine goes on
+    /// and k then runs with the status of the error, the error object on top
+    pub fn pcall_k(
+        &mut self,
+        nargs: usize,
+        nresults: isize,
+        msgh: Option<usize>,
+        ctx: KCTX,
+        k: Option<KFUNC>,
+    ) -> Result<ErrCode, ErrCode> {
+        if k.is_none() || !self.is_yieldable() {
+            return self.pcall(nargs, nresults, msgh);
+        }
+        let level = self.func_slot(nargs)?;
+        let msgh = self.handler_slot(msgh)?;
+        let ncalls = self.ncalls;
+        let frame = self.get_frame_mut(ncalls.wrapping_sub(1))?;
+        frame.k = k;
+        frame.ctx = ctx;
+        frame.ypcall = Some((level, msgh));
+        let res = self.call_fresh(nargs, nresults);
+        if res.is_err() && self.get_status().0 == STATE_YIELD {
+            return res;
+        }
+        self.get_frame_mut(ncalls - 1)?.ypcall = None;
+        match res {
+            Ok(_) => Ok(ErrCode(STATE_OK)),
+            Err(e) => self.recover(e, level, ncalls, msgh),
+        }
+    }
+
+    /// brief: suspend the running coroutine, the nresults values on top go to `resume`;
+    /// a Rust function ends with it and returns the count given, when the coroutine
+    /// is resumed the values passed to `resume` are the results of the function
+    pub fn yield_(&mut self, nresults: usize) -> Result<usize, ErrCode> {
+        self.yield_k(nresults, 0, None)
+    }
+
+    /// brief: `yield_` with a continuation, when the coroutine is resumed k runs
+    /// with ctx and the values passed to `resume` on top, and its results are the ones
+    /// of the Rust function; it may run inside a metamethod called by Lua code,
+    /// but not inside a finalizer, a `__close` or a message handler
+    pub fn yield_k(
+        &mut self,
+        nresults: usize,
+        ctx: KCTX,
+        k: Option<KFUNC>,
+    ) -> Result<usize, ErrCode> {
+        if self.nny > 0 {
+            if self.is_main_thread()? {
+                return Err(ErrCode(INVOKE_YIELD_OUTSIDE));
+            }
+            return Err(ErrCode(INVOKE_YIELD_BOUNDARY));
+        }
+        if nresults > self.stack_top_index {
+            return Err(ErrCode(MEMORY_UNREACHABLE));
+        }
+        let frame = self.get_frame_mut(self.ncalls.wrapping_sub(1))?;
+        frame.k = k;
+        frame.ctx = ctx;
+        self.nyield = nresults;
+        self.set_status(ErrCode(STATE_YIELD));
+        Ok(nresults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::lua::STATE_ERR_RUN;
+    use crate::obj::objdef::INT;
+    use crate::vm::machine::tests::{lua_setmetatable, new_state, run, set_global_fn};
+
+    /// brief: yield(...), from Lua
+    fn lua_yield(l: &mut LuaState) -> usize {
+        let n = l.arg_count();
+        match l.yield_(n) {
+            Ok(n) => n,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: create(f), a coroutine running f
+    fn lua_create(l: &mut LuaState) -> usize {
+        let th = match l.new_thread() {
+            Ok(th) => th,
+            Err(e) => return l.raise(e),
+        };
+        let f = l.get_stkelem_fromtop(1).unwrap();
+        l.push_obj(f).unwrap();
+        match l.xmove(unsafe { &mut *th }, 1) {
+            Ok(_) => 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: resume(co, ...), true and what it yielded or returned, or false and the error
+    fn lua_resume(l: &mut LuaState) -> usize {
+        let nargs = l.arg_count() - 1;
+        let co = match l.get_thread_fromtop(nargs) {
+            Ok(co) => unsafe { &mut *co },
+            Err(e) => return l.raise(e),
+        };
+        if let Err(e) = l.xmove(co, nargs) {
+            return l.raise(e);
+        }
+        let (ok, n) = match co.resume(nargs) {
+            Ok(n) => (true, n),
+            Err(_) => (false, 1),
+        };
+        l.push_bool(ok).unwrap();
+        match co.xmove(l, n) {
+            Ok(_) => n + 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: status(co), the status of co in lower case
+    fn lua_status(l: &mut LuaState) -> usize {
+        let co = match l.get_thread_fromtop(0) {
+            Ok(co) => unsafe { &*co },
+            Err(e) => return l.raise(e),
+        };
+        let st = format!("{:?}", l.thread_status(co)).to_lowercase();
+        match l.push_string(st.as_bytes()) {
+            Ok(_) => 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: running(), the running thread and whether it is the main one
+    fn lua_running(l: &mut LuaState) -> usize {
+        match l.push_thread() {
+            Ok(main) => {
+                l.push_bool(main).unwrap();
+                2
+            }
+            Err(e) => l.raise(e),
+        }
+    }
+
+    fn open_test_funcs(l: &mut LuaState) {
+        set_global_fn(l, b"yield", lua_yield);
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        set_global_fn(l, b"create", lua_create);
+        set_global_fn(l, b"resume", lua_resume);
+        set_global_fn(l, b"status", lua_status);
+        set_global_fn(l, b"running", lua_running);
+    }
+
+    /// brief: the text of the value `step` slots below the top
+    fn text(l: &mut LuaState, step: usize) -> String {
+        l.tostring(step).unwrap();
+        let s = String::from_utf8_lossy(l.get_string_fromtop(0).unwrap()).into_owned();
+        l.move_top(1, false);
+        s
+    }
+
+    /// brief: run a chunk in a coroutine whose yields are `yield(..., name)`: the names are
+    /// gathered and the other values sent back; the results of the body are given as text
+    fn drive(l: &mut LuaState, src: &str) -> (Vec<String>, Vec<String>) {
+        let co = unsafe { &mut *l.new_thread().unwrap() };
+        assert_eq!(co.load(src.as_bytes(), "=co").unwrap().0, STATE_OK);
+        let mut events = Vec::new();
+        let mut n = co.resume(0).unwrap();
+        while co.get_status().0 == STATE_YIELD {
+            events.push(text(co, 0));
+            co.move_top(1, false);
+            n = co.resume(n - 1).unwrap();
+        }
+        let res = (0..n).rev().map(|j| text(co, j)).collect();
+        l.move_top(1, false);
+        (events, res)
+    }
+
+    #[test]
+    fn yield_in_metamethods() {
+        let l = new_state();
+        open_test_funcs(l);
+        let src = "
+            local mt = {}
+            function mt.__index(t, k) return yield(k .. '!', 'index') end
+            function mt.__newindex(t, k, v) yield('newindex') end
+            function mt.__add(x, y) return yield(10, 'add') end
+            function mt.__unm(x) return yield(11, 'unm') end
+            function mt.__len(x) return yield(12, 'len') end
+            function mt.__concat(x, y) return yield('C', 'concat') end
+            function mt.__lt(x, y) return yield(true, 'lt') end
+            function mt.__le(x, y) return yield(false, 'le') end
+            function mt.__eq(x, y) return yield(true, 'eq') end
+            local a, b = setmetatable({}, mt), setmetatable({}, mt)
+            a.y = 1
+            local function get(o) return o.z end
+            local i, j, z = a.x, a[1], get(a)
+            local s, u, n = a + 1, -a, #a
+            local c = 'p' .. a .. 'q' .. 'r'
+            local lt, le, eq = a < b, a <= b, a == b
+            local lti = 0
+            if a < 1 then lti = lti + 1 end
+            if a >= 2 then lti = lti + 2 end
+            return i, j, z, s, u, n, c, lt, le, eq, lti";
+        let (events, res) = drive(l, src);
+        assert_eq!(
+            events,
+            [
+                "newindex", "index", "index", "index", "add", "unm", "len", "concat", "lt", "le",
+                "eq", "lt", "le"
+            ]
+        );
+        assert_eq!(
+            res,
+            ["x!", "1!", "z!", "10", "11", "12", "pC", "true", "false", "true", "1"]
+        );
+    }
+
+    #[test]
+    fn yield_outside_metamethod_boundaries() {
+        let l = new_state();
+        open_test_funcs(l);
+        // not in a coroutine
+        let e = run(
+            l,
+            "local t = setmetatable({}, {__index = function() return yield(1) end}) return t.x",
+        )
+        .unwrap_err();
+        assert!(e.contains("outside a coroutine"), "{}", e);
+        // a `__close` cannot yield
+        let co = unsafe { &mut *l.new_thread().unwrap() };
+        let src = "do local x <close> = setmetatable({}, {__close = function() yield(1) end}) end";
+        co.load(src.as_bytes(), "=co").unwrap();
+        assert!(co.resume(0).is_err());
+        assert!(text(co, 0).contains("yield across"));
+        assert_eq!(l.thread_status(co), CoStatus::Errored);
+        l.move_top(1, false);
+    }
+
+    #[test]
+    fn resume_and_yield() {
+        let l = new_state();
+        open_test_funcs(l);
+        let src = "local co
+                   co = create(function(a, b)
+                       local s = status(co)
+                       local c = yield(a + b)
+                       local d, e = yield(c * 2)
+                       return d + e, s
+                   end)
+                   local s0 = status(co)
+                   local ok1, v1 = resume(co, 1, 2)
+                   local s1 = status(co)
+                   local ok2, v2 = resume(co, 10)
+                   local ok3, v3, v4 = resume(co, 4, 5)
+                   local ok4, m4 = resume(co)
+                   return s0, ok1, v1, s1, ok2, v2, ok3, v3, v4, status(co), ok4, m4";
+        assert_eq!(
+            run(l, src).unwrap(),
+            [
+                "suspended",
+                "true",
+                "3",
+                "suspended",
+                "true",
+                "20",
+                "true",
+                "9",
+                "running",
+                "dead",
+                "false",
+                "cannot resume dead coroutine"
+            ]
+        );
+    }
+
+    #[test]
+    fn status_of_threads() {
+        let l = new_state();
+        open_test_funcs(l);
+        // a resumes b, which sees a as normal; neither can resume a
+        let src = "local a, b
+                   a = create(function() return status(a), resume(b) end)
+                   b = create(function() return status(a), resume(a) end)
+                   local _, main = running()
+                   return main, resume(a)";
+        assert_eq!(
+            run(l, src).unwrap(),
+            [
+                "true",
+                "true",
+                "running",
+                "true",
+                "normal",
+                "false",
+                "cannot resume non-suspended coroutine"
+            ]
+        );
+        let src =
+            "local co = create(function() local _, main = running() yield(main) error('x') end)
+                   local r1, r2 = resume(co)
+                   local ok, e = resume(co)
+                   return r1, r2, ok, e, status(co), resume(co)";
+        assert_eq!(
+            run(l, src).unwrap(),
+            [
+                "true",
+                "false",
+                "false",
+                "test:1: x",
+                "errored",
+                "false",
+                "cannot resume dead coroutine"
+            ]
+        );
+        let e = run(l, "yield(1)").unwrap_err();
+        assert!(e.contains("outside a coroutine"), "{}", e);
+        assert!(!l.is_yieldable());
+    }
+
+    #[test]
+    fn values_through_the_api() {
+        let l = new_state();
+        open_test_funcs(l);
+        let co = unsafe { &mut *l.new_thread().unwrap() };
+        assert_eq!(l.thread_status(co), CoStatus::Dead);
+        let src = "local n = 0 while true do n = n + yield(n) end";
+        co.load(src.as_bytes(), "=co").unwrap();
+        assert_eq!(l.thread_status(co), CoStatus::Suspended);
+        assert_eq!(co.resume(0).unwrap(), 1);
+        for step in 1..=3 {
+            let n = co.get_integer_fromtop(0).unwrap();
+            co.move_top(1, false);
+            co.push_integer(step).unwrap();
+            assert_eq!(co.resume(1).unwrap(), 1);
+            assert_eq!(co.get_integer_fromtop(0).unwrap(), n + step);
+        }
+        co.move_top(1, false);
+        // a bad value errors the coroutine
+        co.push_bool(true).unwrap();
+        assert!(co.resume(1).is_err());
+        assert_eq!(l.thread_status(co), CoStatus::Errored);
+        assert!(text(co, 0).contains("perform arithmetic"));
+        l.move_top(1, false);
+    }
+
+    /// brief: sum(f, n), f(1) + ... + f(n) where f may yield
+    fn lua_sum(l: &mut LuaState) -> usize {
+        // f, n and the partial sum
+        l.push_integer(0).unwrap();
+        sum_from(l, 1)
+    }
+
+    /// brief: add f(i) for i from `from` on to the partial sum on top
+    fn sum_from(l: &mut LuaState, from: KCTX) -> usize {
+        let n = l.get_integer_fromtop(1).unwrap() as KCTX;
+        for i in from..=n {
+            let f = l.get_stkelem_fromtop(2).unwrap();
+            l.push_obj(f).unwrap();
+            l.push_integer(i as INT).unwrap();
+            match l.call_k(1, 1, i, Some(sum_k)) {
+                Ok(_) => add_result(l),
+                Err(_) if l.get_status().0 == STATE_YIELD => return 0,
+                Err(e) => return l.raise(e),
+            }
+        }
+        1
+    }
+
+    fn add_result(l: &mut LuaState) {
+        let r = l.get_integer_fromtop(0).unwrap();
+        let acc = l.get_integer_fromtop(1).unwrap();
+        l.move_top(2, false);
+        l.push_integer(acc + r).unwrap();
+    }
+
+    fn sum_k(l: &mut LuaState, _: ErrCode, ctx: KCTX) -> usize {
+        add_result(l);
+        sum_from(l, ctx + 1)
+    }
+
+    /// brief: protect(f), the status of f run protected, its result or error, and the context
+    fn lua_protect(l: &mut LuaState) -> usize {
+        match l.pcall_k(0, 1, None, 7, Some(protect_k)) {
+            Ok(status) => protect_k(l, status, 7),
+            Err(_) if l.get_status().0 == STATE_YIELD => 0,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    fn protect_k(l: &mut LuaState, status: ErrCode, ctx: KCTX) -> usize {
+        let v = l.get_stkelem_fromtop(0).unwrap();
+        l.move_top(1, false);
+        l.push_integer(status.0 as INT).unwrap();
+        l.push_obj(v).unwrap();
+        l.push_integer(ctx as INT).unwrap();
+        3
+    }
+
+    /// brief: ask(x), yields x * 2 as `ask` and gives what comes back and x
+    fn lua_ask(l: &mut LuaState) -> usize {
+        let x = l.get_integer_fromtop(0).unwrap();
+        l.push_integer(x * 2).unwrap();
+        l.push_string(b"ask").unwrap();
+        match l.yield_k(2, x as KCTX, Some(ask_k)) {
+            Ok(n) => n,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    fn ask_k(l: &mut LuaState, status: ErrCode, ctx: KCTX) -> usize {
+        assert_eq!(status.0, STATE_YIELD);
+        l.push_integer(ctx as INT).unwrap();
+        2
+    }
+
+    /// brief: callnk(f), calls f without a continuation
+    fn lua_callnk(l: &mut LuaState) -> usize {
+        match l.call_k(0, 0, 0, None) {
+            Ok(_) => 0,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    fn open_k_funcs(l: &mut LuaState) {
+        open_test_funcs(l);
+        set_global_fn(l, b"sum", lua_sum);
+        set_global_fn(l, b"protect", lua_protect);
+        set_global_fn(l, b"ask", lua_ask);
+        set_global_fn(l, b"callnk", lua_callnk);
+    }
+
+    #[test]
+    fn call_k_goes_on_after_yields() {
+        let l = new_state();
+        open_k_funcs(l);
+        // every other call yields, its value coming back from resume
+        let src = "return sum(function(i)
+                       if i % 2 == 0 then return i end
+                       return yield(i * 10, 'step')
+                   end, 5)";
+        let (events, res) = drive(l, src);
+        assert_eq!(events, ["step", "step", "step"]);
+        assert_eq!(res, ["96"]);
+        // no yield, no continuation
+        assert_eq!(
+            run(l, "return sum(function(i) return i end, 4)").unwrap(),
+            ["10"]
+        );
+    }
+
+    #[test]
+    fn pcall_k_catches_errors_after_a_yield() {
+        let l = new_state();
+        open_k_funcs(l);
+        let (events, res) = drive(l, "return protect(function() yield('y') error('boom') end)");
+        assert_eq!(events, ["y"]);
+        assert_eq!(
+            res,
+            [STATE_ERR_RUN.to_string(), "co:1: boom".into(), "7".into()]
+        );
+        let (events, res) = drive(l, "return protect(function() return yield(5, 'y') + 1 end)");
+        assert_eq!(events, ["y"]);
+        assert_eq!(res, [STATE_YIELD.to_string(), "6".into(), "7".into()]);
+        // without a yield the continuation is called in place
+        let res = run(l, "return protect(function() error('e', 0) end)").unwrap();
+        assert_eq!(res, [STATE_ERR_RUN.to_string(), "e".into(), "7".into()]);
+    }
+
+    #[test]
+    fn yield_k_runs_the_continuation() {
+        let l = new_state();
+        open_k_funcs(l);
+        let (events, res) = drive(
+            l,
+            "local a, b = ask(5) local c, d = ask(a) return a, b, c, d",
+        );
+        assert_eq!(events, ["ask", "ask"]);
+        assert_eq!(res, ["10", "5", "20", "10"]);
+    }
+
+    #[test]
+    fn call_without_k_refuses_yields() {
+        let l = new_state();
+        open_k_funcs(l);
+        let (_, res) = drive(l, "return pcall(callnk, function() yield(1, 'x') end)");
+        assert_eq!(res[0], "false");
+        assert!(res[1].contains("yield across"), "{}", res[1]);
+    }
+}
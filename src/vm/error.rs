@@ -0,0 +1,248 @@
+use core::mem::take;
+
+use crate::compile::opcode::{
+    get_a, get_ax, get_b, get_bx, get_c, get_k, get_op, get_sj, Instruction, OpCode,
+};
+use crate::compile::proto::{Constant, Proto};
+use crate::info::lua::{
+    ErrCode, LUA_ENV, LUA_ERR_MEM, MEMORY_ALLOC_FAIL, MEMORY_REALLOC_FAIL, STATE_ERR_ERR,
+    STATE_ERR_RUN,
+};
+use crate::lex::lexer::chunkid;
+use crate::obj::closure::LClosure;
+use crate::obj::objdef::{type_name, ObjectTrait, TObj};
+use crate::obj::statedef::LuaState;
+
+/// brief: the status an error ends a protected call with
+pub(crate) fn error_status(e: ErrCode) -> ErrCode {
+    match e.0 {
+        MEMORY_ALLOC_FAIL | MEMORY_REALLOC_FAIL | LUA_ERR_MEM => ErrCode(LUA_ERR_MEM),
+        STATE_ERR_ERR => ErrCode(STATE_ERR_ERR),
+        _ => ErrCode(STATE_ERR_RUN),
+    }
+}
+
+/// brief: the name of the upvalue idx of a function, `?` when it is not known
+fn upval_name(p: &Proto, idx: usize) -> &str {
+    p.upvalues
+        .get(idx)
+        .and_then(|uv| uv.name.as_deref())
+        .unwrap_or("?")
+}
+
+/// brief: the string constant k of a function, `?` for another constant
+fn k_name(p: &Proto, k: usize) -> String {
+    match p.k.get(k) {
+        Some(Constant::Str(s)) => String::from_utf8_lossy(s).into_owned(),
+        _ => String::from("?"),
+    }
+}
+
+/// brief: the last instruction before lastpc that sets register reg;
+/// none when a jump may go past it, the value then depends on the path taken
+fn find_set_reg(p: &Proto, mut lastpc: usize, reg: usize) -> Option<usize> {
+    // a metamethod instruction stands for the operation before it
+    if lastpc > 0
+        && matches!(
+            get_op(p.code[lastpc]),
+            OpCode::MmBin | OpCode::MmBinI | OpCode::MmBinK
+        )
+    {
+        lastpc -= 1;
+    }
+    let mut setreg = None;
+    let mut jmptarget = 0;
+    for (pc, &i) in p.code[..lastpc].iter().enumerate() {
+        let a = get_a(i) as usize;
+        let change = match get_op(i) {
+            OpCode::LoadNil => a <= reg && reg <= a + get_b(i) as usize,
+            OpCode::TForCall => reg >= a + 2,
+            OpCode::Call | OpCode::TailCall => reg >= a,
+            OpCode::Jmp => {
+                // the furthest forward jump that lands before lastpc
+                let dest = (pc as isize + 1 + get_sj(i) as isize) as usize;
+                if dest <= lastpc && dest > jmptarget {
+                    jmptarget = dest;
+                }
+                false
+            }
+            op => op.sets_a() && reg == a,
+        };
+        if change {
+            setreg = if pc < jmptarget { None } else { Some(pc) };
+        }
+    }
+    setreg
+}
+
+/// brief: the kind and the name of what register reg holds at lastpc:
+/// a local, a global, a field, an upvalue, a constant or a method
+fn obj_name(p: &Proto, lastpc: usize, reg: usize) -> Option<(&'static str, String)> {
+    if let Some(name) = p.get_local_name(reg + 1, lastpc) {
+        return Some(("local", name.to_string()));
+    }
+    let pc = find_set_reg(p, lastpc, reg)?;
+    let i = p.code[pc];
+    let (b, c) = (get_b(i) as usize, get_c(i) as usize);
+    // the fields of _ENV are the globals
+    let gxf = |isenv: bool| if isenv { "global" } else { "field" };
+    let is_env = |t: usize| obj_name(p, pc, t).is_some_and(|(_, name)| name == LUA_ENV);
+    // the name of a register that holds a string constant
+    let r_name = |r: usize| match obj_name(p, pc, r) {
+        Some(("constant", name)) => name,
+        _ => String::from("?"),
+    };
+    match get_op(i) {
+        OpCode::Move if b < get_a(i) as usize => obj_name(p, pc, b),
+        OpCode::GetTabUp => Some((gxf(upval_name(p, b) == LUA_ENV), k_name(p, c))),
+        OpCode::GetTable => Some((gxf(is_env(b)), r_name(c))),
+        OpCode::GetI => Some(("field", String::from("integer index"))),
+        OpCode::GetField => Some((gxf(is_env(b)), k_name(p, c))),
+        OpCode::GetUpval => Some(("upvalue", upval_name(p, b).to_string())),
+        OpCode::LoadK | OpCode::LoadKX => {
+            let k = if get_op(i) == OpCode::LoadK {
+                get_bx(i)
+            } else {
+                get_ax(p.code[pc + 1])
+            };
+            match p.k.get(k as usize) {
+                Some(Constant::Str(s)) => {
+                    Some(("constant", String::from_utf8_lossy(s).into_owned()))
+                }
+                _ => None,
+            }
+        }
+        OpCode::Self_ => Some(("method", if get_k(i) { k_name(p, c) } else { r_name(c) })),
+        _ => None,
+    }
+}
+
+impl LuaState {
+    /// brief: the function running, its instruction and its base, when it is a Lua function
+    fn running_lua(&self) -> Option<(&Proto, usize, usize)> {
+        let frame = self.get_frame(self.ncalls.checked_sub(1)?).ok()?;
+        let f = self
+            .get_stack_mut_ref()
+            .ok()?
+            .get_elem(frame.stack_func_index)
+            .ok()?;
+        let cl = Option::<*mut LClosure>::into_inner(&f)?;
+        // savedpc is past the instruction running
+        let pc = frame.savedpc.checked_sub(1)?;
+        Some((unsafe { &(*cl).p }, pc, frame.stack_func_index + 1))
+    }
+
+    /// brief: the instruction of the running function, when it is a Lua function
+    pub(crate) fn running_instr(&self) -> Option<Instruction> {
+        self.running_lua().map(|(p, pc, _)| p.code[pc])
+    }
+
+    /// brief: ` (local 'x')` and the like, what register reg of the running Lua function
+    /// holds; empty when it is not known
+    pub(crate) fn reg_info(&self, reg: usize) -> String {
+        match self
+            .running_lua()
+            .and_then(|(p, pc, _)| obj_name(p, pc, reg))
+        {
+            Some((kind, name)) => format!(" ({} '{}')", kind, name),
+            None => String::new(),
+        }
+    }
+
+    /// brief: ` (local 'x')` and the like, for the stack slot of a register
+    pub(crate) fn slot_info(&self, slot: usize) -> String {
+        match self.running_lua() {
+            Some((_, _, base)) if slot >= base => self.reg_info(slot - base),
+            _ => String::new(),
+        }
+    }
+
+    /// brief: ` (upvalue 'x')`, the upvalue idx of the running Lua function
+    pub(crate) fn upval_info(&self, idx: usize) -> String {
+        match self.running_lua() {
+            Some((p, _, _)) => format!(" (upvalue '{}')", upval_name(p, idx)),
+            None => String::new(),
+        }
+    }
+
+    /// brief: raise a runtime error with msg as message, after the position of the running
+    /// Lua function; the error is given back for the caller to return
+    pub(crate) fn runtime_error(&mut self, msg: &str) -> ErrCode {
+        let text = format!("{}{}", self.where_(0).unwrap_or_default(), msg);
+        match self.new_string(text.as_bytes()) {
+            Ok(ts) => {
+                self.errobj = Some(ts).new();
+                ErrCode(STATE_ERR_RUN)
+            }
+            Err(e) => e,
+        }
+    }
+
+    /// brief: raise `attempt to <op> a <type> value<info>`
+    pub(crate) fn type_error(&mut self, o: &TObj, op: &str, info: &str) -> ErrCode {
+        let tname = type_name(o.val_idx.basic_type());
+        self.runtime_error(&format!("attempt to {} a {} value{}", op, tname, info))
+    }
+
+    /// brief: raise the value on top of the stack as an error object, any value will do;
+    /// a Rust function ends with it and returns what is given,
+    /// the error then goes on from its caller until a protected call catches it
+    pub fn error(&mut self) -> usize {
+        self.errobj = match self.get_stkelem_fromtop(0) {
+            Ok(v) => {
+                self.move_top(1, false);
+                v
+            }
+            Err(_) => TObj::default(),
+        };
+        self.set_status(ErrCode(STATE_ERR_RUN));
+        0
+    }
+
+    /// brief: raise the error e of the VM from a Rust function, as `error` does
+    pub(crate) fn raise(&mut self, e: ErrCode) -> usize {
+        if let Ok(v) = self.error_object(e) {
+            self.errobj = v;
+        }
+        self.set_status(ErrCode(STATE_ERR_RUN));
+        0
+    }
+
+    /// brief: `chunkname:currentline: ` of the function level calls below the running one,
+    /// empty when it is not a Lua function
+    pub fn where_(&mut self, level: usize) -> Result<String, ErrCode> {
+        let ci = match self.ncalls.checked_sub(level + 1) {
+            Some(ci) => ci,
+            None => return Ok(String::new()),
+        };
+        let frame = self.get_frame(ci)?;
+        let savedpc = frame.savedpc;
+        let f = self.get_stack_mut_ref()?.get_elem(frame.stack_func_index)?;
+        let cl = match Option::<*mut LClosure>::into_inner(&f) {
+            Some(cl) => cl,
+            None => return Ok(String::new()),
+        };
+        let p = unsafe { &(*cl).p };
+        // savedpc is past the instruction running
+        let line = match p.getline(savedpc.saturating_sub(1)) {
+            Some(line) => line.to_string(),
+            None => String::from("?"),
+        };
+        let source = chunkid(p.source.as_deref().unwrap_or("=?"));
+        Ok(format!("{}:{}: ", source, line))
+    }
+
+    /// brief: the error object of the error e: the value raised by `error`, or for an error
+    /// found by the VM its message, after the position of the running Lua function;
+    /// the frames of the error must still be there
+    pub(crate) fn error_object(&mut self, e: ErrCode) -> Result<TObj, ErrCode> {
+        if e.0 == STATE_ERR_RUN {
+            return Ok(take(&mut self.errobj));
+        }
+        let msg = match error_status(e).0 {
+            STATE_ERR_RUN => format!("{}{}", self.where_(0)?, e.msg()),
+            _ => String::from(e.msg()),
+        };
+        Ok(Some(self.new_string(msg.as_bytes())?).new())
+    }
+}
@@ -1,12 +1,40 @@
+use crate::compile::codegen::compile_source;
+use crate::compile::dump::dump;
+use crate::compile::opcode::{
+    get_a, get_ax, get_b, get_bx, get_c, get_k, get_op, get_sb, get_sbx, get_sc, get_sj,
+    Instruction, OpCode, MAXARG_C,
+};
+use crate::compile::proto::{Constant, Proto};
+use crate::compile::undump::{is_binary, undump};
 use crate::info::lua::{
-    ErrCode, FINE, INVOKE_RET_MISMATCH, INVOKE_STACK_OVERFLOW, LUA_MIN_STACK, LUA_MUL_RET,
-    MEMORY_TYPE_MISMATCH, MEMORY_UNREACHABLE,
+    ErrCode, FINE, INVOKE_FRAME_OVERFLOW, INVOKE_RCALL_OVERFLOW, INVOKE_RET_MISMATCH,
+    INVOKE_STACK_OVERFLOW, LUA_MAX_CALLS, LUA_MIN_STACK, LUA_MUL_RET, MEMORY_TYPE_MISMATCH,
+    MEMORY_UNREACHABLE, RUNTIME_DIV_BY_ZERO, RUNTIME_FOR, RUNTIME_FOR_ZERO, RUNTIME_TM_LOOP,
+    STATE_ERR_ERR, STATE_ERR_RUN, STATE_ERR_SYNTAX, STATE_OK, STATE_YIELD,
+};
+use crate::obj::closure::{LClosure, RClosure, UpVal};
+use crate::obj::gc::GcHeader;
+use crate::obj::objdef::{
+    type_name, ObjectTrait, TObj, FFUNC, FLT, INT, T_BOOLEAN, T_CCL, T_LCL, T_LIGHT_USER_DATA,
+    T_LNG_STR, T_LRF, T_NIL, T_NUMBER, T_NUM_FLT, T_NUM_INT, T_SHR_STR, T_TABLE, T_THREAD,
+    T_USER_DATA, UINT,
+};
+use crate::obj::statedef::{LuaState, Stack};
+use crate::obj::string::LuaString;
+use crate::obj::table::LuaTable;
+use crate::obj::userdata::LuaUserData;
+use crate::vm::arith::{
+    le_num, lt_num, raw_arith, to_integer_mode, to_integer_ns, to_number_ns, ArithOp, CompareOp,
+    F2I,
 };
-use crate::obj::objdef::{ObjectTrait, FFUNC, T_LRF};
-use crate::obj::statedef::LuaState;
-use crate::DEBUG;
+use crate::vm::convert::{to_number, to_numeric};
+use crate::vm::error::error_status;
+use crate::vm::tm::TagMethod;
+use crate::ptr_get;
 use core::ptr::null_mut;
 
+const MAXTAGLOOP: usize = 2000; // limit of `__index` and `__newindex` chains
+
 static mut MAINTHREAD: *mut LuaState = null_mut();
 
 fn start() -> Result<&'static mut LuaState, ErrCode> {
@@ -16,57 +44,400 @@ fn start() -> Result<&'static mut LuaState, ErrCode> {
 }
 
 pub fn get_mainthread() -> Result<&'static mut LuaState, ErrCode> {
-    if unsafe { MAINTHREAD == null_mut() } {
+    if unsafe { MAINTHREAD.is_null() } {
         start()
     } else {
         unsafe { Ok(&mut *MAINTHREAD) }
     }
 }
 
+/// brief: primitive equality, no metamethods;
+/// an integer and a float are equal when they have the same mathematical value
+pub fn raw_equal(a: &TObj, b: &TObj) -> bool {
+    let (ta, tb) = (a.val_idx.into_inner(), b.val_idx.into_inner());
+    if ta != tb {
+        if a.val_idx.basic_type() == T_NUMBER && b.val_idx.basic_type() == T_NUMBER {
+            return match (to_integer_ns(a), to_integer_ns(b)) {
+                (Some(i1), Some(i2)) => i1 == i2,
+                _ => false,
+            };
+        }
+        return false;
+    }
+    match ta {
+        T_NIL => true,
+        T_BOOLEAN => Option::<bool>::into_inner(a) == Option::<bool>::into_inner(b),
+        T_NUM_INT => Option::<INT>::into_inner(a) == Option::<INT>::into_inner(b),
+        T_NUM_FLT => Option::<FLT>::into_inner(a) == Option::<FLT>::into_inner(b),
+        T_LIGHT_USER_DATA => Option::<*mut ()>::into_inner(a) == Option::<*mut ()>::into_inner(b),
+        T_LRF => {
+            Option::<FFUNC>::into_inner(a).map(|f| f as usize)
+                == Option::<FFUNC>::into_inner(b).map(|f| f as usize)
+        }
+        T_SHR_STR => {
+            Option::<*mut LuaString>::into_inner(a) == Option::<*mut LuaString>::into_inner(b)
+        }
+        T_LNG_STR => match (
+            Option::<*mut LuaString>::into_inner(a),
+            Option::<*mut LuaString>::into_inner(b),
+        ) {
+            (Some(s1), Some(s2)) => s1 == s2 || unsafe { (*s1).eq_contents(&*s2) },
+            _ => false,
+        },
+        T_LCL => Option::<*mut LClosure>::into_inner(a) == Option::<*mut LClosure>::into_inner(b),
+        T_CCL => Option::<*mut RClosure>::into_inner(a) == Option::<*mut RClosure>::into_inner(b),
+        T_TABLE => Option::<*mut LuaTable>::into_inner(a) == Option::<*mut LuaTable>::into_inner(b),
+        T_USER_DATA => {
+            Option::<*mut LuaUserData>::into_inner(a) == Option::<*mut LuaUserData>::into_inner(b)
+        }
+        T_THREAD => Option::<*mut LuaState>::into_inner(a) == Option::<*mut LuaState>::into_inner(b),
+        _ => false,
+    }
+}
+
+/// brief: the contents of a string value
+#[inline(always)]
+fn str_bytes<'a>(v: &TObj) -> Option<&'a [u8]> {
+    Option::<*mut LuaString>::into_inner(v).map(|ts| unsafe { (*ts).as_bytes() })
+}
+
+/// brief: arithmetic over two numbers, `Ok(None)` leaves the operation to a metamethod
+fn arith_fast(op: ArithOp, a: &TObj, b: &TObj) -> Result<Option<TObj>, ErrCode> {
+    if matches!(op, ArithOp::Mod | ArithOp::IDiv)
+        && a.val_idx.into_inner() == T_NUM_INT
+        && Option::<INT>::into_inner(b) == Some(0)
+    {
+        return Err(ErrCode(RUNTIME_DIV_BY_ZERO));
+    }
+    Ok(raw_arith(op, a, b))
+}
+
+/// brief: pc after a conditional jump, the jump is the instruction at pc
+#[inline(always)]
+fn cond_jump(code: &[Instruction], pc: usize, cond: bool, k: bool) -> usize {
+    if cond != k {
+        return pc + 1;
+    }
+    jump(pc + 1, get_sj(code[pc]))
+}
+
+#[inline(always)]
+fn jump(pc: usize, offset: i32) -> usize {
+    (pc as isize + offset as isize) as usize
+}
+
+/// brief: the integer limit of a numeric for loop, `None` when the loop must not run
+fn for_limit(init: INT, lim: &TObj, step: INT) -> Result<Option<INT>, ErrCode> {
+    let lim = &to_numeric(lim).ok_or(ErrCode(RUNTIME_FOR))?;
+    let mode = if step < 0 { F2I::Ceil } else { F2I::Floor };
+    let limit = match to_integer_mode(lim, mode) {
+        Some(limit) => limit,
+        None => {
+            // not coercible to an integer: the limit is out of range or not a number
+            let flim = to_number_ns(lim).ok_or(ErrCode(RUNTIME_FOR))?;
+            if 0.0 < flim {
+                if step < 0 {
+                    return Ok(None);
+                }
+                INT::MAX
+            } else {
+                if step > 0 {
+                    return Ok(None);
+                }
+                INT::MIN
+            }
+        }
+    };
+    if (step > 0 && init > limit) || (step < 0 && init < limit) {
+        return Ok(None);
+    }
+    Ok(Some(limit))
+}
+
+/// brief: prepare a numeric for loop at ra, true when the loop must be skipped
+/// an integer loop keeps the iteration count in place of the limit
+fn for_prep(stk: &Stack, ra: usize) -> Result<bool, ErrCode> {
+    let init = stk.get_elem(ra)?;
+    let plimit = stk.get_elem(ra + 1)?;
+    let pstep = stk.get_elem(ra + 2)?;
+    if let (Some(init), Some(step)) = (
+        Option::<INT>::into_inner(&init),
+        Option::<INT>::into_inner(&pstep),
+    ) {
+        if step == 0 {
+            return Err(ErrCode(RUNTIME_FOR_ZERO));
+        }
+        stk.set_elem(ra + 3, Some(init).new())?;
+        let limit = match for_limit(init, &plimit, step)? {
+            Some(limit) => limit,
+            None => return Ok(true),
+        };
+        let count = if step > 0 {
+            let count = (limit as UINT).wrapping_sub(init as UINT);
+            if step != 1 {
+                count / step as UINT
+            } else {
+                count
+            }
+        } else {
+            // step + 1 avoids negating INT::MIN
+            (init as UINT).wrapping_sub(limit as UINT) / ((-(step + 1)) as UINT + 1)
+        };
+        stk.set_elem(ra + 1, Some(count as INT).new())?;
+        return Ok(false);
+    }
+    // strings are coerced, into a float loop
+    let flimit = to_number(&plimit).ok_or(ErrCode(RUNTIME_FOR))?;
+    let fstep = to_number(&pstep).ok_or(ErrCode(RUNTIME_FOR))?;
+    let finit = to_number(&init).ok_or(ErrCode(RUNTIME_FOR))?;
+    if fstep == 0.0 {
+        return Err(ErrCode(RUNTIME_FOR_ZERO));
+    }
+    if (0.0 < fstep && flimit < finit) || (fstep < 0.0 && finit < flimit) {
+        return Ok(true);
+    }
+    stk.set_elem(ra, Some(finit).new())?;
+    stk.set_elem(ra + 1, Some(flimit).new())?;
+    stk.set_elem(ra + 2, Some(fstep).new())?;
+    stk.set_elem(ra + 3, Some(finit).new())?;
+    Ok(false)
+}
+
+/// brief: next step of a float for loop, true when the loop goes on
+fn float_for_loop(stk: &Stack, ra: usize) -> Result<bool, ErrCode> {
+    let step = Option::<FLT>::into_inner(&stk.get_elem(ra + 2)?).ok_or(ErrCode(RUNTIME_FOR))?;
+    let limit = Option::<FLT>::into_inner(&stk.get_elem(ra + 1)?).ok_or(ErrCode(RUNTIME_FOR))?;
+    let idx = Option::<FLT>::into_inner(&stk.get_elem(ra)?).ok_or(ErrCode(RUNTIME_FOR))? + step;
+    if (0.0 < step && idx <= limit) || (step < 0.0 && limit <= idx) {
+        stk.set_elem(ra, Some(idx).new())?;
+        stk.set_elem(ra + 3, Some(idx).new())?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
 impl LuaState {
-    pub fn call(&mut self, nargs: usize, sresults: isize) {
-        DEBUG!("func_index is: {}", self.get_stack_top() - (nargs + 1));
-        self.call_unprotected(nargs, sresults);
+    /// brief: call the function below the nargs arguments on top, an error goes to the caller
+    pub fn call(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        self.call_unprotected(nargs, sresults)
+    }
+
+    fn call_unprotected(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        self.run(nargs, sresults)
     }
 
-    fn call_unprotected(&mut self, nargs: usize, sresults: isize) {
-        let res = self.run(nargs, sresults);
+    /// brief: call the function below the nargs arguments on top in protected mode;
+    /// on an error the frames of the call are dropped, its upvalues and to-be-closed variables
+    /// are closed and the error object takes the place of the function,
+    /// after going through the message handler `msgh` slots below the top, if any;
+    /// the status is given, `STATE_OK` when no error happened
+    pub fn pcall(
+        &mut self,
+        nargs: usize,
+        sresults: isize,
+        msgh: Option<usize>,
+    ) -> Result<ErrCode, ErrCode> {
+        let level = self.func_slot(nargs)?;
+        let msgh = self.handler_slot(msgh)?;
+        let (ncalls, nny, status) = (self.ncalls, self.nny, self.get_status());
+        match self.run(nargs, sresults) {
+            Ok(_) => Ok(ErrCode(STATE_OK)),
+            Err(e) => {
+                let st = self.recover(e, level, ncalls, msgh)?;
+                self.nny = nny;
+                self.set_status(status);
+                Ok(st)
+            }
+        }
+    }
+
+    /// brief: the slot of the function below the nargs arguments on top
+    pub(crate) fn func_slot(&self, nargs: usize) -> Result<usize, ErrCode> {
+        self.stack_top_index
+            .checked_sub(nargs + 1)
+            .ok_or(ErrCode(MEMORY_UNREACHABLE))
+    }
+
+    /// brief: the slot of a message handler given as a step below the top
+    pub(crate) fn handler_slot(&self, msgh: Option<usize>) -> Result<Option<usize>, ErrCode> {
+        match msgh {
+            Some(step) => Ok(Some(
+                self.stack_top_index
+                    .checked_sub(step + 1)
+                    .ok_or(ErrCode(MEMORY_UNREACHABLE))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// brief: catch the error e raised by a call whose function was at `level`:
+    /// the message handler at `msgh` gets the error object while the frames are still there,
+    /// then the frames above `ncalls` go, the upvalues and to-be-closed variables from level on
+    /// are closed and the error object is left at level; the status of the error is given
+    pub(crate) fn recover(
+        &mut self,
+        e: ErrCode,
+        level: usize,
+        ncalls: usize,
+        msgh: Option<usize>,
+    ) -> Result<ErrCode, ErrCode> {
+        let mut errobj = self.error_object(e)?;
+        let mut status = error_status(e);
+        // nothing above the point of the error may be overwritten yet
+        if self.ncalls > 0 {
+            let ci_top = self.get_frame_mut(self.ncalls - 1)?.stack_upper_bound;
+            self.stack_top_index = self.stack_top_index.max(ci_top);
+        }
+        if let (Some(h), STATE_ERR_RUN) = (msgh, status.0) {
+            let h = self.get_stack_mut_ref()?.get_elem(h)?;
+            match self.call_tm(h, &[errobj], 1) {
+                Ok(v) => errobj = v,
+                Err(_) => {
+                    // whatever the handler raised is dropped
+                    self.errobj = TObj::default();
+                    status = ErrCode(STATE_ERR_ERR);
+                    errobj = self.error_object(status)?;
+                }
+            }
+        }
+        while self.ncalls > ncalls {
+            self.pop_frame()?;
+        }
+        self.close_upvals(level)?;
+        // an error in a `__close` replaces the one being handled
+        while let Err(e) = self.close_tbc(level, errobj) {
+            while self.ncalls > ncalls {
+                self.pop_frame()?;
+            }
+            status = error_status(e);
+            errobj = self.error_object(e)?;
+        }
+        self.get_stack_mut_ref()?.set_elem(level, errobj)?;
+        self.stack_top_index = level + 1;
+        Ok(status)
+    }
+
+    /// brief: load a chunk, source text or precompiled, and push it as a Lua function,
+    /// its first upvalue, `_ENV`, is the table of globals;
+    /// a chunk that does not load pushes its message, `chunkname:line: message`, instead
+    /// and gives `STATE_ERR_SYNTAX`, otherwise `STATE_OK` is given
+    pub fn load(&mut self, src: &[u8], chunkname: &str) -> Result<ErrCode, ErrCode> {
+        let res = if is_binary(src) {
+            undump(src, chunkname)
+        } else {
+            compile_source(src, chunkname)
+        };
+        let p = match res {
+            Ok(p) => p,
+            Err(e) => {
+                self.push_string(e.to_string().as_bytes())?;
+                return Ok(ErrCode(STATE_ERR_SYNTAX));
+            }
+        };
+        let globals = self.globals()?;
+        let g = self.global_mut()?;
+        let upvals = p
+            .upvalues
+            .iter()
+            .enumerate()
+            .map(|(j, _)| g.link(UpVal::new(if j == 0 { globals } else { TObj::default() })))
+            .collect();
+        let k = self.k_values(&p)?;
+        let cl = self.global_mut()?.link(LClosure::new(p.into(), upvals, k));
+        self.push_obj(Some(cl).new())?;
+        self.check_gc()?;
+        Ok(ErrCode(STATE_OK))
+    }
+
+    /// brief: serialize the Lua function on top of the stack into a binary chunk,
+    /// `strip` leaves out the debug information
+    pub fn dump(&mut self, strip: bool) -> Result<Vec<u8>, ErrCode> {
+        let obj = self.get_stkelem_fromtop(0)?;
+        let cl = Option::<*mut LClosure>::into_inner(&obj).ok_or(ErrCode(MEMORY_TYPE_MISMATCH))?;
+        Ok(dump(unsafe { &(*cl).p }, strip))
     }
 
     fn run(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
         if !self.calls_check() {
-            todo!()
+            return Err(ErrCode(INVOKE_FRAME_OVERFLOW));
         }
-        self.pre_call(nargs, sresults)?;
+        self.do_call(nargs, sresults)
+    }
 
-        Ok(ErrCode(FINE))
+    /// brief: call a function and run it to completion,
+    /// a coroutine cannot yield from inside it
+    pub(crate) fn do_call(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        self.nny += 1;
+        let res = self.call_fresh(nargs, sresults);
+        self.nny -= 1;
+        res
+    }
+
+    /// brief: call a function, a Lua one is executed until it returns;
+    /// the calls made this way nest in the Rust stack, so there may be `LUA_MAX_CALLS` of them
+    pub(crate) fn call_fresh(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+        if self.global()?.nrcalls >= LUA_MAX_CALLS {
+            return Err(ErrCode(INVOKE_RCALL_OVERFLOW));
+        }
+        self.global_mut()?.nrcalls += 1;
+        let res = self.pre_call(nargs, sresults).and_then(|ci| match ci {
+            Some(frame_index) => {
+                self.get_frame_mut(frame_index)?.fresh = true;
+                self.execute(frame_index)
+            }
+            None => Ok(ErrCode(FINE)),
+        });
+        self.global_mut()?.nrcalls -= 1;
+        res
     }
 
-    fn pre_call(&mut self, nargs: usize, sresults: isize) -> Result<ErrCode, ErrCode> {
+    /// brief: start a call of the function below the nargs arguments on top of the stack;
+    /// a Rust function runs to completion and gives `None`,
+    /// a Lua function gives its new frame, ready to be executed;
+    /// a Rust function that yields leaves its frame and gives `STATE_YIELD` as error
+    pub(crate) fn pre_call(&mut self, nargs: usize, sresults: isize) -> Result<Option<usize>, ErrCode> {
         let func_index = self.get_stack_top() - (nargs + 1);
-        DEBUG!("func_index: {}", func_index);
-        DEBUG!("sresults: {}", sresults);
         // acquire the stack
 
         // acquire the object at the index func_index
-        let obj = self.get_stkelem_fromtop(nargs)?;
-        if !obj.val_idx.is_function() {
-            DEBUG!("error: is not function");
-            return Err(ErrCode(MEMORY_TYPE_MISMATCH));
+        let mut obj = self.get_stkelem_fromtop(nargs)?;
+        let mut nargs = nargs;
+        while !obj.val_idx.is_function() {
+            // a callable value: its `__call` gets it as first argument
+            obj = self.try_func_tm(func_index)?;
+            nargs += 1;
+        }
+        if !self.calls_check() {
+            return Err(ErrCode(INVOKE_FRAME_OVERFLOW));
         }
         match obj.val_idx.into_inner() {
-            T_LRF => {
+            T_LRF | T_CCL => {
                 let f = Option::<FFUNC>::into_inner(&obj);
-                DEBUG!("{}", f.is_some());
-                if let Some(function) = f {
+                let rcl = Option::<*mut RClosure>::into_inner(&obj);
+                if f.is_some() || rcl.is_some() {
                     self.stack_check(LUA_MIN_STACK as usize)?;
                     let frame_index = self.push_frame(func_index)?;
-                    DEBUG!("frame_index: {}", frame_index);
-                    let rresults = function(self);
-                    DEBUG!("rresults:{}", rresults);
+                    self.get_frame_mut(frame_index)?.nresults = sresults;
+                    let rresults = match (f, rcl) {
+                        (Some(function), _) => function(self),
+                        (_, Some(rcl)) => self.call_rclosure(rcl)?,
+                        _ => 0,
+                    };
+                    match self.get_status().0 {
+                        STATE_YIELD => {
+                            // the frame is finished by the next resume
+                            return Err(ErrCode(STATE_YIELD));
+                        }
+                        STATE_ERR_RUN => {
+                            // raised by `error`, the error object is waiting in errobj
+                            self.set_status(ErrCode(STATE_OK));
+                            return Err(ErrCode(STATE_ERR_RUN));
+                        }
+                        _ => {}
+                    }
 
                     // check if the top edge exceeds the boundary
-                    if !self.cframe_check_stkedge(frame_index, rresults as usize)? {
+                    if !self.cframe_check_stkedge(frame_index, rresults)? {
                         self.write_frame_status(frame_index, ErrCode(INVOKE_STACK_OVERFLOW))?;
                         self.set_status(ErrCode(INVOKE_STACK_OVERFLOW));
                         return Err(ErrCode(INVOKE_STACK_OVERFLOW));
@@ -74,42 +445,1603 @@ impl LuaState {
 
                     // deal with the generated frame
                     self.post_call(func_index, rresults, sresults)?;
-                    DEBUG!("self.ncalls: {}", self.ncalls);
                     let _old_frame = self.pop_frame()?;
-                    DEBUG!("self.ncalls: {}", self.ncalls);
-                    return Ok(ErrCode(FINE));
+                    Ok(None)
                 } else {
-                    return Err(ErrCode(MEMORY_UNREACHABLE));
+                    Err(ErrCode(MEMORY_UNREACHABLE))
                 }
             }
-            _ => {
-                return Err(ErrCode(MEMORY_TYPE_MISMATCH));
+            T_LCL => {
+                let cl =
+                    Option::<*mut LClosure>::into_inner(&obj).ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+                let p = unsafe { &(*cl).p };
+                let fsize = p.maxstacksize as usize;
+                self.stack_check(fsize)?;
+                let frame_index = self.push_frame(func_index)?;
+                // missing parameters are nil
+                for _ in nargs..p.numparams as usize {
+                    self.push_nil()?;
+                }
+                let frame = self.get_frame_mut(frame_index)?;
+                frame.stack_upper_bound = func_index + 1 + fsize;
+                frame.nresults = sresults;
+                Ok(Some(frame_index))
             }
+            _ => Err(ErrCode(MEMORY_TYPE_MISMATCH)),
+        }
+    }
+
+    /// brief: put the `__call` metamethod of the value at func_index in its place,
+    /// the value becomes the first argument
+    fn try_func_tm(&mut self, func_index: usize) -> Result<TObj, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        let f = stk.get_elem(func_index)?;
+        let tm = self.get_tm_by_obj(&f, TagMethod::Call)?;
+        if tm.is_nil() {
+            let info = match self.running_instr().map(get_op) {
+                Some(OpCode::Call | OpCode::TailCall) => self.slot_info(func_index),
+                Some(OpCode::TForCall) => String::from(" (for iterator 'for iterator')"),
+                _ => String::new(),
+            };
+            return Err(self.type_error(&f, "call", &info));
+        }
+        self.stack_check(1)?;
+        for p in (func_index + 1..=self.stack_top_index).rev() {
+            stk.set_elem(p, stk.get_elem(p - 1)?)?;
         }
+        self.stack_top_index += 1;
+        stk.set_elem(func_index, tm)?;
+        Ok(tm)
     }
 
+    /// brief: finish the call of the Rust function at func_index, its rresults results
+    /// are on top; sresults of them, padded with nil, or all for `LUA_MUL_RET`,
+    /// are moved to func_index
     fn post_call(
         &mut self,
         func_index: usize,
         rresults: usize,
         sresults: isize,
     ) -> Result<ErrCode, ErrCode> {
-        const ZERO_RETURN: isize = 0;
-        const ONE_RETURN: isize = 1;
-
-        match sresults {
-            ZERO_RETURN => {
-                self.move_top_to(func_index);
-                // not really necessary, just in case
-            }
-            ONE_RETURN => {
-                self.move_top_to(func_index + 1);
-            }
-            LUA_MUL_RET => {
-                todo!()
-            } //
-            _ => return Err(ErrCode(INVOKE_RET_MISMATCH)),
-        } // inside match, deal with stack
+        // a function cannot return more values than it has on its stack
+        if sresults < LUA_MUL_RET || rresults > self.stack_top_index - (func_index + 1) {
+            return Err(ErrCode(INVOKE_RET_MISMATCH));
+        }
+        self.move_results(func_index, rresults, sresults)
+    }
+
+    /// brief: move the nres values on top of the stack to res, adjusted to wanted,
+    /// and leave the top after the last one
+    pub(crate) fn move_results(&mut self, res: usize, nres: usize, wanted: isize) -> Result<ErrCode, ErrCode> {
+        let wanted = if wanted == LUA_MUL_RET {
+            nres
+        } else {
+            wanted as usize
+        };
+        if wanted > nres {
+            // room for the nils
+            self.stack_check(wanted - nres)?;
+        }
+        let stk = ptr_get!(self, stack)?;
+        let first = self.stack_top_index - nres;
+        for i in 0..wanted {
+            let v = if i < nres {
+                stk.get_elem(first + i)?
+            } else {
+                TObj::default()
+            };
+            stk.set_elem(res + i, v)?;
+        }
+        self.stack_top_index = res + wanted;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: finish the Lua frame ci, whose nres results are on top of the stack,
+    /// true when the frame was the entry of `execute`
+    fn pos_call(&mut self, ci: usize, nres: usize) -> Result<bool, ErrCode> {
+        let frame = self.get_frame_mut(ci)?;
+        let (res, wanted, fresh) = (frame.stack_func_index, frame.nresults, frame.fresh);
+        self.move_results(res, nres, wanted)?;
+        self.pop_frame()?;
+        Ok(fresh)
+    }
+
+    /// brief: move the fixed parameters of a vararg function above its extra arguments,
+    /// the frame then starts after them
+    fn adjust_varargs(
+        &mut self,
+        ci: usize,
+        nfixparams: usize,
+        fsize: usize,
+    ) -> Result<ErrCode, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        let func = self.get_frame_mut(ci)?.stack_func_index;
+        let actual = self.stack_top_index - func - 1;
+        let nextra = actual.saturating_sub(nfixparams);
+        self.stack_check(fsize + 1)?;
+        // copy the function and the fixed parameters to the top
+        stk.set_elem(self.stack_top_index, stk.get_elem(func)?)?;
+        self.stack_top_index += 1;
+        for i in 1..=nfixparams {
+            stk.set_elem(self.stack_top_index, stk.get_elem(func + i)?)?;
+            stk.set_elem(func + i, TObj::default())?;
+            self.stack_top_index += 1;
+        }
+        let frame = self.get_frame_mut(ci)?;
+        frame.nextraargs = nextra;
+        frame.stack_func_index += actual + 1;
+        frame.stack_upper_bound += actual + 1;
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: copy the extra arguments of frame ci to ra, all of them when wanted < 0
+    fn get_varargs(&mut self, ci: usize, ra: usize, wanted: isize) -> Result<ErrCode, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        let frame = self.get_frame_mut(ci)?;
+        let (func, nextra) = (frame.stack_func_index, frame.nextraargs);
+        let wanted = if wanted < 0 {
+            self.stack_top_index = ra;
+            self.stack_check(nextra)?;
+            self.stack_top_index = ra + nextra;
+            nextra
+        } else {
+            wanted as usize
+        };
+        for i in 0..wanted {
+            let v = if i < nextra {
+                stk.get_elem(func - nextra + i)?
+            } else {
+                TObj::default()
+            };
+            stk.set_elem(ra + i, v)?;
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: t[key], following `__index` when the key is absent or t is not a table
+    pub(crate) fn get_table(&mut self, t: &TObj, key: &TObj) -> Result<TObj, ErrCode> {
+        let mut t = *t;
+        for n in 0..MAXTAGLOOP {
+            let tm = if let Some(h) = Option::<*mut LuaTable>::into_inner(&t) {
+                let h = unsafe { &*h };
+                let v = h.get(key);
+                if !v.is_nil() {
+                    return Ok(v);
+                }
+                let tm = self.fast_tm(h.metatable, TagMethod::Index)?;
+                if tm.is_nil() {
+                    return Ok(v);
+                }
+                tm
+            } else {
+                let tm = self.get_tm_by_obj(&t, TagMethod::Index)?;
+                if tm.is_nil() {
+                    return Err(self.index_error(&t, n == 0));
+                }
+                tm
+            };
+            if tm.val_idx.is_function() {
+                return self.call_op_tm(tm, &[t, *key], 1);
+            }
+            // repeat the access on the metamethod
+            t = tm;
+        }
+        Err(ErrCode(RUNTIME_TM_LOOP))
+    }
+
+    /// brief: t[key] = val, following `__newindex` when the key is absent or t is not a table
+    pub(crate) fn set_table(
+        &mut self,
+        t: &TObj,
+        key: &TObj,
+        val: TObj,
+    ) -> Result<ErrCode, ErrCode> {
+        let mut t = *t;
+        for n in 0..MAXTAGLOOP {
+            let tm = if let Some(h) = Option::<*mut LuaTable>::into_inner(&t) {
+                let (raw, mt) = unsafe { ((*h).get(key), (*h).metatable) };
+                if !raw.is_nil() {
+                    return self.table_set(h, key, val);
+                }
+                let tm = self.fast_tm(mt, TagMethod::NewIndex)?;
+                if tm.is_nil() {
+                    return self.table_set(h, key, val);
+                }
+                tm
+            } else {
+                let tm = self.get_tm_by_obj(&t, TagMethod::NewIndex)?;
+                if tm.is_nil() {
+                    return Err(self.index_error(&t, n == 0));
+                }
+                tm
+            };
+            if tm.val_idx.is_function() {
+                self.call_op_tm(tm, &[t, *key, val], 0)?;
+                return Ok(ErrCode(FINE));
+            }
+            // repeat the assignment over the metamethod
+            t = tm;
+        }
+        Err(ErrCode(RUNTIME_TM_LOOP))
+    }
+
+    /// brief: raise the error of indexing t, named after what the running instruction indexes
+    /// when t is its operand rather than a value met along an `__index` chain
+    fn index_error(&mut self, t: &TObj, operand: bool) -> ErrCode {
+        let info = match self.running_instr() {
+            Some(i) if operand => match get_op(i) {
+                OpCode::GetTabUp => self.upval_info(get_b(i) as usize),
+                OpCode::GetTable | OpCode::GetI | OpCode::GetField | OpCode::Self_ => {
+                    self.reg_info(get_b(i) as usize)
+                }
+                OpCode::SetTabUp => self.upval_info(get_a(i) as usize),
+                OpCode::SetTable | OpCode::SetI | OpCode::SetField => {
+                    self.reg_info(get_a(i) as usize)
+                }
+                _ => String::new(),
+            },
+            _ => String::new(),
+        };
+        self.type_error(t, "index", &info)
+    }
+
+    /// brief: what the first or the second operand of the running operator is
+    fn operand_info(&self, second: bool) -> String {
+        match self.running_instr() {
+            Some(i) => match get_op(i) {
+                OpCode::MmBin => self.reg_info(if second { get_b(i) } else { get_a(i) } as usize),
+                // k: the immediate operand was the first one
+                OpCode::MmBinI | OpCode::MmBinK if second == get_k(i) => {
+                    self.reg_info(get_a(i) as usize)
+                }
+                OpCode::Unm | OpCode::BNot | OpCode::Len => self.reg_info(get_b(i) as usize),
+                _ => String::new(),
+            },
+            None => String::new(),
+        }
+    }
+
+    /// brief: raise the error of a binary event without a metamethod,
+    /// about the first operand that is not fit for it
+    fn bin_error(&mut self, a: &TObj, b: &TObj, event: TagMethod) -> ErrCode {
+        match event {
+            TagMethod::Concat => {
+                let second = a.val_idx.basic_type() == T_NUMBER || str_bytes(a).is_some();
+                // the operands are the two values on top
+                let info = match self.running_instr().map(get_op) {
+                    Some(OpCode::Concat) => {
+                        self.slot_info(self.stack_top_index - 2 + second as usize)
+                    }
+                    _ => String::new(),
+                };
+                self.type_error(if second { b } else { a }, "concatenate", &info)
+            }
+            TagMethod::BAnd
+            | TagMethod::BOr
+            | TagMethod::BXor
+            | TagMethod::Shl
+            | TagMethod::Shr
+            | TagMethod::BNot
+                if to_number(a).is_some() && to_number(b).is_some() =>
+            {
+                let info = self.operand_info(to_integer_ns(a).is_some());
+                self.runtime_error(&format!("number{} has no integer representation", info))
+            }
+            _ => {
+                let second = to_number(a).is_some();
+                let info = self.operand_info(second);
+                let op = match event {
+                    TagMethod::BAnd
+                    | TagMethod::BOr
+                    | TagMethod::BXor
+                    | TagMethod::Shl
+                    | TagMethod::Shr
+                    | TagMethod::BNot => "perform bitwise operation on",
+                    _ => "perform arithmetic on",
+                };
+                self.type_error(if second { b } else { a }, op, &info)
+            }
+        }
+    }
+
+    /// brief: raise the error of a numeric for loop whose values are not all numbers
+    fn for_error(&mut self, stk: &Stack, ra: usize) -> ErrCode {
+        // in the order the reference checks them
+        for (slot, what) in [(ra + 1, "limit"), (ra + 2, "step"), (ra, "initial value")] {
+            if stk.get_elem(slot).map_or(true, |v| to_number(&v).is_none()) {
+                return self.runtime_error(&format!("'for' {} must be a number", what));
+            }
+        }
+        ErrCode(RUNTIME_FOR)
+    }
+
+    /// brief: the result of a binary event whose operands are not fit for the primitive operation:
+    /// arithmetic over strings that convert to numbers, or else the metamethod
+    /// of the first operand, or else of the second one
+    fn try_bin_tm(&mut self, a: &TObj, b: &TObj, event: TagMethod) -> Result<TObj, ErrCode> {
+        let op = ArithOp::from_u8((event as u8).wrapping_sub(TagMethod::Add as u8));
+        if let (Some(op), Some(n1), Some(n2)) = (op, to_numeric(a), to_numeric(b)) {
+            if let Some(v) = arith_fast(op, &n1, &n2)? {
+                return Ok(v);
+            }
+        }
+        let tm = self.bin_tm(a, b, event)?;
+        self.call_op_tm(tm, &[*a, *b], 1)
+    }
+
+    /// brief: the metamethod of a binary event, from the first operand or else the second one;
+    /// the error of the event is raised when neither has it
+    fn bin_tm(&mut self, a: &TObj, b: &TObj, event: TagMethod) -> Result<TObj, ErrCode> {
+        let mut tm = self.get_tm_by_obj(a, event)?;
+        if tm.is_nil() {
+            tm = self.get_tm_by_obj(b, event)?;
+        }
+        if tm.is_nil() {
+            return Err(self.bin_error(a, b, event));
+        }
+        Ok(tm)
+    }
+
+    /// brief: a == b, two different tables, or full userdata, are compared by the `__eq` of either
+    fn equal_obj(&mut self, a: &TObj, b: &TObj) -> Result<bool, ErrCode> {
+        if raw_equal(a, b) {
+            return Ok(true);
+        }
+        let tt = a.val_idx.into_inner();
+        if tt == b.val_idx.into_inner() && matches!(tt, T_TABLE | T_USER_DATA) {
+            let mut tm = self.get_tm_by_obj(a, TagMethod::Eq)?;
+            if tm.is_nil() {
+                tm = self.get_tm_by_obj(b, TagMethod::Eq)?;
+            }
+            if tm.is_nil() {
+                return Ok(false);
+            }
+            return Ok(!self.call_op_tm(tm, &[*a, *b], 1)?.is_false());
+        }
+        Ok(false)
+    }
+
+    /// brief: the result of an order event, from the metamethod of either operand
+    fn call_order_tm(&mut self, a: &TObj, b: &TObj, event: TagMethod) -> Result<bool, ErrCode> {
+        let mut tm = self.get_tm_by_obj(a, event)?;
+        if tm.is_nil() {
+            tm = self.get_tm_by_obj(b, event)?;
+        }
+        if tm.is_nil() {
+            let t1 = type_name(a.val_idx.basic_type());
+            let t2 = type_name(b.val_idx.basic_type());
+            let msg = if t1 == t2 {
+                format!("attempt to compare two {} values", t1)
+            } else {
+                format!("attempt to compare {} with {}", t1, t2)
+            };
+            return Err(self.runtime_error(&msg));
+        }
+        Ok(!self.call_op_tm(tm, &[*a, *b], 1)?.is_false())
+    }
+
+    /// brief: a < b
+    fn less_than(&mut self, a: &TObj, b: &TObj) -> Result<bool, ErrCode> {
+        if let (Some(s1), Some(s2)) = (str_bytes(a), str_bytes(b)) {
+            return Ok(s1 < s2);
+        }
+        match lt_num(a, b) {
+            Some(res) => Ok(res),
+            None => self.call_order_tm(a, b, TagMethod::Lt),
+        }
+    }
+
+    /// brief: a <= b
+    fn less_equal(&mut self, a: &TObj, b: &TObj) -> Result<bool, ErrCode> {
+        if let (Some(s1), Some(s2)) = (str_bytes(a), str_bytes(b)) {
+            return Ok(s1 <= s2);
+        }
+        match le_num(a, b) {
+            Some(res) => Ok(res),
+            None => self.call_order_tm(a, b, TagMethod::Le),
+        }
+    }
+
+    /// brief: a op b for an arithmetic or bitwise operator, through the metamethod of its event
+    /// when the operands are not fit for it; a unary operator takes a as b too
+    pub(crate) fn arith_obj(&mut self, op: ArithOp, a: &TObj, b: &TObj) -> Result<TObj, ErrCode> {
+        if let Some(v) = arith_fast(op, a, b)? {
+            return Ok(v);
+        }
+        let event = TagMethod::from_u8(TagMethod::Add as u8 + op as u8)
+            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+        self.try_bin_tm(a, b, event)
+    }
+
+    /// brief: a op b for a comparison operator
+    pub(crate) fn compare_obj(&mut self, a: &TObj, b: &TObj, op: CompareOp) -> Result<bool, ErrCode> {
+        match op {
+            CompareOp::Eq => self.equal_obj(a, b),
+            CompareOp::Lt => self.less_than(a, b),
+            CompareOp::Le => self.less_equal(a, b),
+        }
+    }
+
+    /// brief: #v, the border of a table without `__len`
+    pub(crate) fn obj_len(&mut self, v: &TObj) -> Result<TObj, ErrCode> {
+        if let Some(s) = str_bytes(v) {
+            return Ok(Some(s.len() as INT).new());
+        }
+        let tm = if let Some(h) = Option::<*mut LuaTable>::into_inner(v) {
+            let h = unsafe { &*h };
+            let tm = self.fast_tm(h.metatable, TagMethod::Len)?;
+            if tm.is_nil() {
+                return Ok(Some(h.length() as INT).new());
+            }
+            tm
+        } else {
+            self.get_tm_by_obj(v, TagMethod::Len)?
+        };
+        if tm.is_nil() {
+            let info = self.operand_info(false);
+            return Err(self.type_error(v, "get length of", &info));
+        }
+        self.call_op_tm(tm, &[*v, *v], 1)
+    }
+
+    /// brief: concatenate the total values on top of the stack,
+    /// the result takes the place of the first one
+    pub(crate) fn concat_top(&mut self, mut total: usize) -> Result<ErrCode, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        while total > 1 {
+            let top = self.stack_top_index;
+            let v1 = stk.get_elem(top - 2)?;
+            let v2 = stk.get_elem(top - 1)?;
+            // at least two values are concatenated at each step
+            let mut n = 2;
+            // numbers are turned into strings in place
+            let strnum = |v: &TObj| v.val_idx.basic_type() == T_NUMBER || str_bytes(v).is_some();
+            if !strnum(&v1) || !self.tostring_at(top - 1)? {
+                // it runs at the top, where `finish_op` finds it after a yield
+                let tm = self.bin_tm(&v1, &v2, TagMethod::Concat)?;
+                let v = self.call_tm_at(top, tm, &[v1, v2], 1, true)?;
+                stk.set_elem(top - 2, v)?;
+            } else {
+                self.tostring_at(top - 2)?;
+                let v1 = stk.get_elem(top - 2)?;
+                let v2 = stk.get_elem(top - 1)?;
+                match (str_bytes(&v1), str_bytes(&v2)) {
+                    (_, Some([])) => {}
+                    (Some([]), _) => {
+                        stk.set_elem(top - 2, v2)?;
+                    }
+                    (_, Some(s2)) => {
+                        // take as many strings and numbers as there are in a row
+                        let mut len = s2.len();
+                        n = 1;
+                        while n < total && self.tostring_at(top - n - 1)? {
+                            len += str_bytes(&stk.get_elem(top - n - 1)?).map_or(0, |s| s.len());
+                            n += 1;
+                        }
+                        let mut buf = Vec::with_capacity(len);
+                        for j in (1..=n).rev() {
+                            let v = stk.get_elem(top - j)?;
+                            buf.extend_from_slice(str_bytes(&v).unwrap_or_default());
+                        }
+                        let ts = self.new_string(&buf)?;
+                        stk.set_elem(top - n, Some(ts).new())?;
+                    }
+                    _ => {}
+                }
+            }
+            total -= n - 1;
+            self.stack_top_index -= n - 1;
+        }
         Ok(ErrCode(FINE))
     }
+
+    /// brief: the values of the constants of a prototype, strings are made into string objects;
+    /// a closure keeps them, so that the instructions index them as they run
+    fn k_values(&mut self, p: &Proto) -> Result<Vec<TObj>, ErrCode> {
+        p.k.iter()
+            .map(|k| match k {
+                Constant::Nil => Ok(Some(()).new()),
+                Constant::Bool(b) => Ok(Some(*b).new()),
+                Constant::Int(i) => Ok(Some(*i).new()),
+                Constant::Flt(n) => Ok(Some(*n).new()),
+                Constant::Str(s) => Ok(Some(self.new_string(s)?).new()),
+            })
+            .collect()
+    }
+
+    /// brief: finish the instruction of the Lua frame ci that a yield in a metamethod interrupted,
+    /// the result of the metamethod is on top; the calls and the assignments
+    /// have nothing left to do
+    pub(crate) fn finish_op(&mut self, ci: usize) -> Result<ErrCode, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        let frame = self.get_frame_mut(ci)?;
+        let func_obj = stk.get_elem(frame.stack_func_index)?;
+        let cl = Option::<*mut LClosure>::into_inner(&func_obj)
+            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+        let p = unsafe { &(*cl).p };
+        let code = &p.code[..];
+        let base = frame.stack_func_index + 1;
+        let pc = frame.savedpc;
+        let i = match pc.checked_sub(1) {
+            Some(j) => code[j],
+            None => return Ok(ErrCode(FINE)),
+        };
+        match get_op(i) {
+            OpCode::MmBin | OpCode::MmBinI | OpCode::MmBinK => {
+                // the failed arithmetic instruction tells where the result goes
+                self.stack_top_index -= 1;
+                let v = stk.get_elem(self.stack_top_index)?;
+                stk.set_elem(base + get_a(code[pc - 2]) as usize, v)?;
+            }
+            OpCode::Unm
+            | OpCode::BNot
+            | OpCode::Len
+            | OpCode::GetTabUp
+            | OpCode::GetTable
+            | OpCode::GetI
+            | OpCode::GetField
+            | OpCode::Self_ => {
+                self.stack_top_index -= 1;
+                let v = stk.get_elem(self.stack_top_index)?;
+                stk.set_elem(base + get_a(i) as usize, v)?;
+            }
+            OpCode::Eq
+            | OpCode::Lt
+            | OpCode::Le
+            | OpCode::LtI
+            | OpCode::LeI
+            | OpCode::GtI
+            | OpCode::GeI => {
+                self.stack_top_index -= 1;
+                let cond = !stk.get_elem(self.stack_top_index)?.is_false();
+                self.get_frame_mut(ci)?.savedpc = cond_jump(code, pc, cond, get_k(i));
+            }
+            OpCode::Concat => {
+                // the metamethod ran at the top of the values still to concatenate
+                let top = self.stack_top_index - 1;
+                stk.set_elem(top - 2, stk.get_elem(top)?)?;
+                self.stack_top_index = top - 1;
+                self.concat_top(top - 1 - (base + get_a(i) as usize))?;
+            }
+            _ => {}
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: run the Lua function of frame ci, together with the Lua functions it calls,
+    /// until the fresh frame that entered here returns
+    pub(crate) fn execute(&mut self, mut ci: usize) -> Result<ErrCode, ErrCode> {
+        let stk = ptr_get!(self, stack)?;
+        'newframe: loop {
+            let frame = self.get_frame_mut(ci)?;
+            let func_obj = stk.get_elem(frame.stack_func_index)?;
+            let cl = Option::<*mut LClosure>::into_inner(&func_obj)
+                .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+            let cl: &LClosure = unsafe { &*cl };
+            let p = &*cl.p;
+            let code = &p.code[..];
+            let k = &cl.k[..];
+            let mut base = frame.stack_func_index + 1;
+            let mut pc = frame.savedpc;
+            loop {
+                let i = code[pc];
+                pc += 1;
+                // kept in the frame, an error reports the line it was raised at
+                self.get_frame_mut(ci)?.savedpc = pc;
+                let ra = base + get_a(i) as usize;
+                let op = get_op(i);
+                match op {
+                    OpCode::Move => {
+                        stk.set_elem(ra, stk.get_elem(base + get_b(i) as usize)?)?;
+                    }
+                    OpCode::LoadI => {
+                        stk.set_elem(ra, Some(get_sbx(i) as INT).new())?;
+                    }
+                    OpCode::LoadF => {
+                        stk.set_elem(ra, Some(get_sbx(i) as FLT).new())?;
+                    }
+                    OpCode::LoadK => {
+                        let v = k[get_bx(i) as usize];
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::LoadKX => {
+                        let v = k[get_ax(code[pc]) as usize];
+                        pc += 1;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::LoadFalse => {
+                        stk.set_elem(ra, Some(false).new())?;
+                    }
+                    OpCode::LFalseSkip => {
+                        stk.set_elem(ra, Some(false).new())?;
+                        pc += 1;
+                    }
+                    OpCode::LoadTrue => {
+                        stk.set_elem(ra, Some(true).new())?;
+                    }
+                    OpCode::LoadNil => {
+                        for j in 0..=get_b(i) as usize {
+                            stk.set_elem(ra + j, TObj::default())?;
+                        }
+                    }
+                    OpCode::GetUpval => {
+                        let uv = unsafe { &*cl.upvals[get_b(i) as usize] };
+                        stk.set_elem(ra, uv.get())?;
+                    }
+                    OpCode::SetUpval => {
+                        let uv = cl.upvals[get_b(i) as usize];
+                        let v = stk.get_elem(ra)?;
+                        unsafe { (*uv).set(v) };
+                        self.global_mut()?.barrier(uv as *mut GcHeader, &v);
+                    }
+                    OpCode::GetTabUp => {
+                        let t = unsafe { (*cl.upvals[get_b(i) as usize]).get() };
+                        let key = k[get_c(i) as usize];
+                        let v = self.get_table(&t, &key)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::GetTable => {
+                        let t = stk.get_elem(base + get_b(i) as usize)?;
+                        let key = stk.get_elem(base + get_c(i) as usize)?;
+                        let v = self.get_table(&t, &key)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::GetI => {
+                        let t = stk.get_elem(base + get_b(i) as usize)?;
+                        let v = self.get_table(&t, &Some(get_c(i) as INT).new())?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::GetField => {
+                        let t = stk.get_elem(base + get_b(i) as usize)?;
+                        let key = k[get_c(i) as usize];
+                        let v = self.get_table(&t, &key)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::SetTabUp => {
+                        let t = unsafe { (*cl.upvals[get_a(i) as usize]).get() };
+                        let key = k[get_b(i) as usize];
+                        let v = self.rk(stk, k, base, i)?;
+                        self.set_table(&t, &key, v)?;
+                    }
+                    OpCode::SetTable => {
+                        let t = stk.get_elem(ra)?;
+                        let key = stk.get_elem(base + get_b(i) as usize)?;
+                        let v = self.rk(stk, k, base, i)?;
+                        self.set_table(&t, &key, v)?;
+                    }
+                    OpCode::SetI => {
+                        let t = stk.get_elem(ra)?;
+                        let v = self.rk(stk, k, base, i)?;
+                        self.set_table(&t, &Some(get_b(i) as INT).new(), v)?;
+                    }
+                    OpCode::SetField => {
+                        let t = stk.get_elem(ra)?;
+                        let key = k[get_b(i) as usize];
+                        let v = self.rk(stk, k, base, i)?;
+                        self.set_table(&t, &key, v)?;
+                    }
+                    OpCode::NewTable => {
+                        // B is log2 of the hash size plus one, C the array size
+                        let mut b = get_b(i) as usize;
+                        let mut c = get_c(i) as usize;
+                        if b > 0 {
+                            b = 1 << (b - 1);
+                        }
+                        if get_k(i) {
+                            c += get_ax(code[pc]) as usize * (MAXARG_C as usize + 1);
+                        }
+                        pc += 1; // skip the extra argument
+                        let t = self.create_table(c, b)?;
+                        stk.set_elem(ra, Some(t).new())?;
+                        self.stack_top_index = ra + 1;
+                        self.check_gc()?;
+                    }
+                    OpCode::Self_ => {
+                        let rb = stk.get_elem(base + get_b(i) as usize)?;
+                        let key = self.rk(stk, k, base, i)?;
+                        stk.set_elem(ra + 1, rb)?;
+                        let v = self.get_table(&rb, &key)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::AddI => {
+                        let v1 = stk.get_elem(base + get_b(i) as usize)?;
+                        let v2 = Some(get_sc(i) as INT).new();
+                        if let Some(v) = arith_fast(ArithOp::Add, &v1, &v2)? {
+                            stk.set_elem(ra, v)?;
+                            pc += 1;
+                        }
+                    }
+                    OpCode::AddK
+                    | OpCode::SubK
+                    | OpCode::MulK
+                    | OpCode::ModK
+                    | OpCode::PowK
+                    | OpCode::DivK
+                    | OpCode::IDivK
+                    | OpCode::BAndK
+                    | OpCode::BOrK
+                    | OpCode::BXorK => {
+                        let aop = ArithOp::from_u8(op as u8 - OpCode::AddK as u8)
+                            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+                        let v1 = stk.get_elem(base + get_b(i) as usize)?;
+                        let v2 = k[get_c(i) as usize];
+                        if let Some(v) = arith_fast(aop, &v1, &v2)? {
+                            stk.set_elem(ra, v)?;
+                            pc += 1;
+                        }
+                    }
+                    OpCode::ShrI => {
+                        let v1 = stk.get_elem(base + get_b(i) as usize)?;
+                        let v2 = Some(get_sc(i) as INT).new();
+                        if let Some(v) = arith_fast(ArithOp::Shr, &v1, &v2)? {
+                            stk.set_elem(ra, v)?;
+                            pc += 1;
+                        }
+                    }
+                    OpCode::ShlI => {
+                        let v1 = Some(get_sc(i) as INT).new();
+                        let v2 = stk.get_elem(base + get_b(i) as usize)?;
+                        if let Some(v) = arith_fast(ArithOp::Shl, &v1, &v2)? {
+                            stk.set_elem(ra, v)?;
+                            pc += 1;
+                        }
+                    }
+                    OpCode::Add
+                    | OpCode::Sub
+                    | OpCode::Mul
+                    | OpCode::Mod
+                    | OpCode::Pow
+                    | OpCode::Div
+                    | OpCode::IDiv
+                    | OpCode::BAnd
+                    | OpCode::BOr
+                    | OpCode::BXor
+                    | OpCode::Shl
+                    | OpCode::Shr => {
+                        let aop = ArithOp::from_u8(op as u8 - OpCode::Add as u8)
+                            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+                        let v1 = stk.get_elem(base + get_b(i) as usize)?;
+                        let v2 = stk.get_elem(base + get_c(i) as usize)?;
+                        if let Some(v) = arith_fast(aop, &v1, &v2)? {
+                            stk.set_elem(ra, v)?;
+                            pc += 1;
+                        }
+                    }
+                    OpCode::MmBin => {
+                        // the failed arithmetic instruction tells where the result goes
+                        let result = base + get_a(code[pc - 2]) as usize;
+                        let event = TagMethod::from_u8(get_c(i) as u8)
+                            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+                        let v1 = stk.get_elem(ra)?;
+                        let v2 = stk.get_elem(base + get_b(i) as usize)?;
+                        let v = self.try_bin_tm(&v1, &v2, event)?;
+                        stk.set_elem(result, v)?;
+                    }
+                    OpCode::MmBinI | OpCode::MmBinK => {
+                        let result = base + get_a(code[pc - 2]) as usize;
+                        let event = TagMethod::from_u8(get_c(i) as u8)
+                            .ok_or(ErrCode(MEMORY_UNREACHABLE))?;
+                        let v1 = stk.get_elem(ra)?;
+                        let imm = if op == OpCode::MmBinI {
+                            Some(get_sb(i) as INT).new()
+                        } else {
+                            k[get_b(i) as usize]
+                        };
+                        // k: the immediate operand was the first one
+                        let v = if get_k(i) {
+                            self.try_bin_tm(&imm, &v1, event)?
+                        } else {
+                            self.try_bin_tm(&v1, &imm, event)?
+                        };
+                        stk.set_elem(result, v)?;
+                    }
+                    OpCode::Unm | OpCode::BNot => {
+                        let aop = if op == OpCode::Unm {
+                            ArithOp::Unm
+                        } else {
+                            ArithOp::BNot
+                        };
+                        let rb = stk.get_elem(base + get_b(i) as usize)?;
+                        let v = self.arith_obj(aop, &rb, &rb)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::Not => {
+                        let rb = stk.get_elem(base + get_b(i) as usize)?;
+                        stk.set_elem(ra, Some(rb.is_false()).new())?;
+                    }
+                    OpCode::Len => {
+                        let rb = stk.get_elem(base + get_b(i) as usize)?;
+                        let v = self.obj_len(&rb)?;
+                        stk.set_elem(ra, v)?;
+                    }
+                    OpCode::Concat => {
+                        let n = get_b(i) as usize;
+                        self.stack_top_index = ra + n;
+                        self.concat_top(n)?;
+                        self.check_gc()?;
+                    }
+                    OpCode::Close => {
+                        self.close_upvals(ra)?;
+                        self.close_tbc(ra, TObj::default())?;
+                    }
+                    OpCode::Tbc => {
+                        self.new_tbc(ra)?;
+                    }
+                    OpCode::Jmp => {
+                        pc = jump(pc, get_sj(i));
+                    }
+                    OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                        let v1 = stk.get_elem(ra)?;
+                        let v2 = stk.get_elem(base + get_b(i) as usize)?;
+                        let cop = match op {
+                            OpCode::Eq => CompareOp::Eq,
+                            OpCode::Lt => CompareOp::Lt,
+                            _ => CompareOp::Le,
+                        };
+                        let cond = self.compare_obj(&v1, &v2, cop)?;
+                        pc = cond_jump(code, pc, cond, get_k(i));
+                    }
+                    OpCode::EqK => {
+                        let v1 = stk.get_elem(ra)?;
+                        let v2 = k[get_b(i) as usize];
+                        let cond = raw_equal(&v1, &v2);
+                        pc = cond_jump(code, pc, cond, get_k(i));
+                    }
+                    OpCode::EqI => {
+                        let v1 = stk.get_elem(ra)?;
+                        let im = get_sb(i);
+                        let cond = match v1.val_idx.into_inner() {
+                            T_NUM_INT => Option::<INT>::into_inner(&v1) == Some(im as INT),
+                            T_NUM_FLT => Option::<FLT>::into_inner(&v1) == Some(im as FLT),
+                            _ => false,
+                        };
+                        pc = cond_jump(code, pc, cond, get_k(i));
+                    }
+                    OpCode::LtI | OpCode::LeI | OpCode::GtI | OpCode::GeI => {
+                        let v1 = stk.get_elem(ra)?;
+                        let im = Some(get_sb(i) as INT).new();
+                        let cond = match op {
+                            OpCode::LtI => self.less_than(&v1, &im)?,
+                            OpCode::LeI => self.less_equal(&v1, &im)?,
+                            OpCode::GtI => self.less_than(&im, &v1)?,
+                            _ => self.less_equal(&im, &v1)?,
+                        };
+                        pc = cond_jump(code, pc, cond, get_k(i));
+                    }
+                    OpCode::Test => {
+                        let cond = !stk.get_elem(ra)?.is_false();
+                        pc = cond_jump(code, pc, cond, get_k(i));
+                    }
+                    OpCode::TestSet => {
+                        let rb = stk.get_elem(base + get_b(i) as usize)?;
+                        if rb.is_false() == get_k(i) {
+                            pc += 1;
+                        } else {
+                            stk.set_elem(ra, rb)?;
+                            pc = jump(pc + 1, get_sj(code[pc]));
+                        }
+                    }
+                    OpCode::Call => {
+                        let b = get_b(i) as usize;
+                        let nresults = get_c(i) as isize - 1;
+                        if b != 0 {
+                            self.stack_top_index = ra + b;
+                        }
+                        self.get_frame_mut(ci)?.savedpc = pc;
+                        let nargs = self.stack_top_index - ra - 1;
+                        if let Some(new_ci) = self.pre_call(nargs, nresults)? {
+                            ci = new_ci;
+                            continue 'newframe;
+                        }
+                    }
+                    OpCode::TailCall => {
+                        let mut b = get_b(i) as usize;
+                        let nparams1 = get_c(i) as usize;
+                        if b != 0 {
+                            self.stack_top_index = ra + b;
+                        } else {
+                            b = self.stack_top_index - ra;
+                        }
+                        if get_k(i) {
+                            // the frame goes away, its variables leave scope
+                            self.close_upvals(base)?;
+                        }
+                        let frame = self.get_frame_mut(ci)?;
+                        // a vararg function gives back the room of its extra arguments
+                        let delta = if nparams1 != 0 {
+                            frame.nextraargs + nparams1
+                        } else {
+                            0
+                        };
+                        let func = frame.stack_func_index - delta;
+                        let mut f = stk.get_elem(ra)?;
+                        while !f.val_idx.is_function() {
+                            f = self.try_func_tm(ra)?;
+                            b += 1;
+                        }
+                        if let Some(ncl) = Option::<*mut LClosure>::into_inner(&f) {
+                            // reuse the frame for the called Lua function
+                            let np = unsafe { &(*ncl).p };
+                            let fsize = np.maxstacksize as usize;
+                            for j in 0..b {
+                                stk.set_elem(func + j, stk.get_elem(ra + j)?)?;
+                            }
+                            self.stack_top_index = func + b;
+                            self.stack_check(fsize)?;
+                            for _ in b - 1..np.numparams as usize {
+                                self.push_nil()?;
+                            }
+                            let frame = self.get_frame_mut(ci)?;
+                            frame.stack_func_index = func;
+                            frame.stack_upper_bound = func + 1 + fsize;
+                            frame.savedpc = 0;
+                            frame.nextraargs = 0;
+                            continue 'newframe;
+                        }
+                        self.get_frame_mut(ci)?.savedpc = pc;
+                        self.pre_call(b - 1, LUA_MUL_RET)?;
+                        self.get_frame_mut(ci)?.stack_func_index = func;
+                        let n = self.stack_top_index - ra;
+                        if self.pos_call(ci, n)? {
+                            return Ok(ErrCode(FINE));
+                        }
+                        ci -= 1;
+                        continue 'newframe;
+                    }
+                    OpCode::Return | OpCode::Return0 | OpCode::Return1 => {
+                        let n = match op {
+                            OpCode::Return0 => 0,
+                            OpCode::Return1 => 1,
+                            _ => match get_b(i) as usize {
+                                0 => self.stack_top_index - ra,
+                                b => b - 1,
+                            },
+                        };
+                        if op == OpCode::Return && get_k(i) {
+                            // close the upvalues and variables of the frame before its slots are reused
+                            self.stack_top_index = ra + n;
+                            self.close_upvals(base)?;
+                            self.close_tbc(base, TObj::default())?;
+                        }
+                        if op == OpCode::Return && get_c(i) != 0 {
+                            let frame = self.get_frame_mut(ci)?;
+                            frame.stack_func_index -= frame.nextraargs + get_c(i) as usize;
+                        }
+                        self.stack_top_index = ra + n;
+                        if self.pos_call(ci, n)? {
+                            return Ok(ErrCode(FINE));
+                        }
+                        ci -= 1;
+                        continue 'newframe;
+                    }
+                    OpCode::ForLoop => {
+                        let step = stk.get_elem(ra + 2)?;
+                        if let Some(step) = Option::<INT>::into_inner(&step) {
+                            let count = Option::<INT>::into_inner(&stk.get_elem(ra + 1)?)
+                                .ok_or(ErrCode(RUNTIME_FOR))?
+                                as UINT;
+                            if count > 0 {
+                                let idx = Option::<INT>::into_inner(&stk.get_elem(ra)?)
+                                    .ok_or(ErrCode(RUNTIME_FOR))?
+                                    .wrapping_add(step);
+                                stk.set_elem(ra + 1, Some((count - 1) as INT).new())?;
+                                stk.set_elem(ra, Some(idx).new())?;
+                                stk.set_elem(ra + 3, Some(idx).new())?;
+                                pc -= get_bx(i) as usize;
+                            }
+                        } else if float_for_loop(stk, ra)? {
+                            pc -= get_bx(i) as usize;
+                        }
+                    }
+                    OpCode::ForPrep => {
+                        let skip = match for_prep(stk, ra) {
+                            Err(e) if e.0 == RUNTIME_FOR => return Err(self.for_error(stk, ra)),
+                            skip => skip?,
+                        };
+                        if skip {
+                            pc += get_bx(i) as usize + 1;
+                        }
+                    }
+                    OpCode::TForPrep => {
+                        // the closing value of the loop
+                        self.new_tbc(ra + 3)?;
+                        pc += get_bx(i) as usize;
+                    }
+                    OpCode::TForCall => {
+                        // the iterator is called with the state and the control variable
+                        for j in 0..3 {
+                            stk.set_elem(ra + 4 + j, stk.get_elem(ra + j)?)?;
+                        }
+                        self.stack_top_index = ra + 4 + 3;
+                        self.get_frame_mut(ci)?.savedpc = pc;
+                        // a Lua iterator runs in this loop, so it may yield
+                        if let Some(new_ci) = self.pre_call(2, get_c(i) as isize)? {
+                            ci = new_ci;
+                            continue 'newframe;
+                        }
+                    }
+                    OpCode::TForLoop => {
+                        let control = stk.get_elem(ra + 4)?;
+                        if !control.is_nil() {
+                            stk.set_elem(ra + 2, control)?;
+                            pc -= get_bx(i) as usize;
+                        }
+                    }
+                    OpCode::SetList => {
+                        let mut n = get_b(i) as usize;
+                        let mut last = get_c(i) as usize;
+                        if n == 0 {
+                            n = self.stack_top_index - ra - 1;
+                        }
+                        if get_k(i) {
+                            last += get_ax(code[pc]) as usize * (MAXARG_C as usize + 1);
+                            pc += 1;
+                        }
+                        last += n;
+                        let t = stk.get_elem(ra)?;
+                        let h = Option::<*mut LuaTable>::into_inner(&t)
+                            .ok_or(ErrCode(MEMORY_TYPE_MISMATCH))?;
+                        if last > unsafe { (*h).array_size() } {
+                            // room for all the items in the array part
+                            self.table_resize(h, last, 0)?;
+                        }
+                        for j in (1..=n).rev() {
+                            self.table_set_int(h, last as INT, stk.get_elem(ra + j)?)?;
+                            last -= 1;
+                        }
+                    }
+                    OpCode::Closure => {
+                        let np = p.p[get_bx(i) as usize].clone();
+                        let mut upvals = Vec::with_capacity(np.upvalues.len());
+                        for uv in np.upvalues.iter() {
+                            if uv.instack {
+                                upvals.push(self.find_upval(base + uv.idx as usize)?);
+                            } else {
+                                upvals.push(cl.upvals[uv.idx as usize]);
+                            }
+                        }
+                        let nk = self.k_values(&np)?;
+                        let ncl = self.global_mut()?.link(LClosure::new(np, upvals, nk));
+                        stk.set_elem(ra, Some(ncl).new())?;
+                        self.stack_top_index = ra + 1;
+                        self.check_gc()?;
+                    }
+                    OpCode::VarArg => {
+                        self.get_varargs(ci, ra, get_c(i) as isize - 1)?;
+                    }
+                    OpCode::VarArgPrep => {
+                        self.adjust_varargs(ci, get_a(i) as usize, p.maxstacksize as usize)?;
+                        base = self.get_frame_mut(ci)?.stack_func_index + 1;
+                    }
+                    OpCode::ExtraArg => {
+                        return Err(ErrCode(MEMORY_UNREACHABLE));
+                    }
+                }
+            }
+        }
+    }
+
+    /// brief: the RK(C) operand of an instruction
+    #[inline(always)]
+    fn rk(
+        &mut self,
+        stk: &Stack,
+        k: &[TObj],
+        base: usize,
+        i: Instruction,
+    ) -> Result<TObj, ErrCode> {
+        if get_k(i) {
+            return Ok(k[get_c(i) as usize]);
+        }
+        stk.get_elem(base + get_c(i) as usize)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::info::lua::STATE_OK;
+    use crate::obj::gc::GcHeader;
+    use crate::obj::objdef::{LUA_MAXINTEGER, LUA_MININTEGER};
+    use crate::obj::string::LuaString;
+
+    // the global state of a new main thread goes through a static, one at a time
+    static NEW_STATE: Mutex<()> = Mutex::new(());
+
+    /// brief: a new main thread with the base library
+    pub(crate) fn new_state() -> &'static mut LuaState {
+        let _guard = NEW_STATE.lock().unwrap_or_else(|e| e.into_inner());
+        let l = unsafe { &mut *LuaState::mainthread_new(null_mut()).unwrap() };
+        l.open_base().unwrap();
+        l
+    }
+
+    /// brief: run a chunk in protected mode, its results as text,
+    /// or the text of the error object
+    pub(crate) fn run(l: &mut LuaState, src: &str) -> Result<Vec<String>, String> {
+        let top = l.get_stack_top();
+        let mut status = l.load(src.as_bytes(), "=test").unwrap();
+        if status.0 == STATE_OK {
+            status = l.pcall(0, LUA_MUL_RET, None).unwrap();
+        }
+        let mut res = Vec::new();
+        for j in (0..l.get_stack_top() - top).rev() {
+            l.tostring(j).unwrap();
+            res.push(String::from_utf8_lossy(l.get_string_fromtop(0).unwrap()).into_owned());
+            l.move_top(1, false);
+        }
+        l.move_top_to(top);
+        if status.0 == STATE_OK {
+            Ok(res)
+        } else {
+            Err(res.concat())
+        }
+    }
+
+    /// brief: the results of a chunk run in a new state
+    pub(crate) fn eval(src: &str) -> Vec<String> {
+        run(new_state(), src).unwrap()
+    }
+
+    /// brief: set a Rust function as a global
+    pub(crate) fn set_global_fn(l: &mut LuaState, name: &[u8], f: FFUNC) {
+        l.push_global_table().unwrap();
+        l.push_rfunc(f).unwrap();
+        l.set_field(1, name).unwrap();
+        l.move_top(1, false);
+    }
+
+    /// brief: setmetatable(t, mt), which the base library lacks
+    pub(crate) fn lua_setmetatable(l: &mut LuaState) -> usize {
+        match l.set_metatable(1) {
+            Ok(_) => 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    /// brief: the results of a chunk run in a new state that has `setmetatable`
+    pub(crate) fn eval_mt(src: &str) -> Vec<String> {
+        let l = new_state();
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        run(l, src).unwrap()
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let src = "local function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end
+                   return f(10000)";
+        assert_eq!(eval(src), ["10000"]);
+        // the frames and the stack grown by a call serve the next ones
+        let l = new_state();
+        assert_eq!(run(l, src).unwrap(), ["10000"]);
+        assert_eq!(run(l, src).unwrap(), ["10000"]);
+        assert_eq!(run(l, "return 1").unwrap(), ["1"]);
+    }
+
+    #[test]
+    fn endless_recursion() {
+        let l = new_state();
+        let e = run(l, "local function f() return 1 + f() end return f()").unwrap_err();
+        assert!(e.contains("stack overflow"), "{}", e);
+        // the thread is still usable
+        assert_eq!(run(l, "return 2").unwrap(), ["2"]);
+    }
+
+    /// brief: call the global `again` with the arguments, from Rust
+    fn again(l: &mut LuaState) -> usize {
+        let nargs = l.arg_count();
+        let res = l
+            .push_global_table()
+            .and_then(|_| l.get_field(0, b"again"))
+            .and_then(|_| {
+                // the function goes below the arguments
+                let f = l.get_stkelem_fromtop(0)?;
+                l.move_top(2, false);
+                let base = l.get_stack_top() - nargs;
+                let stk = l.get_stack_mut_ref()?;
+                for j in (base..base + nargs).rev() {
+                    stk.set_elem(j + 1, stk.get_elem(j)?)?;
+                }
+                stk.set_elem(base, f)?;
+                l.move_top(1, true);
+                l.call(nargs, 1)
+            });
+        match res {
+            Ok(_) => 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    #[test]
+    fn rust_call_limit() {
+        let l = new_state();
+        l.push_global_table().unwrap();
+        l.push_rfunc(again).unwrap();
+        l.set_field(1, b"again").unwrap();
+        l.move_top(1, false);
+        // every call of `again` nests one more interpreter in the Rust stack
+        let e = run(l, "return again()").unwrap_err();
+        assert_eq!(e, "C stack overflow");
+        assert_eq!(l.global().unwrap().nrcalls, 0);
+        assert_eq!(
+            run(l, "local ok, e = pcall(again) return ok, e").unwrap(),
+            ["false", "C stack overflow"]
+        );
+        assert_eq!(run(l, "return 3").unwrap(), ["3"]);
+    }
+
+    #[test]
+    fn constants_made_once() {
+        let l = new_state();
+        let src =
+            "local s = '' for i = 1, 3 do s = s .. 'a constant string longer than a short one' end
+                   return #s, 'k'";
+        l.load(src.as_bytes(), "=test").unwrap();
+        let f = l.get_stkelem_fromtop(0).unwrap();
+        let cl = unsafe { &*Option::<*mut LClosure>::into_inner(&f).unwrap() };
+        let k = cl.k.clone();
+        assert_eq!(k.len(), cl.p.k.len());
+        // the closure keeps its strings alive
+        l.gc_collect().unwrap();
+        l.gc_collect().unwrap();
+        for v in k.iter() {
+            if let Some(ts) = Option::<*mut LuaString>::into_inner(v) {
+                assert!(!l.global().unwrap().is_dead(ts as *mut GcHeader));
+            }
+        }
+        assert_eq!(l.pcall(0, 2, None).unwrap().0, STATE_OK);
+        assert_eq!(l.to_integer(1).unwrap(), 3 * 41);
+        assert_eq!(l.get_string_fromtop(0).unwrap(), b"k");
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(
+            eval("return 7 // 2, -7 // 2, 7 % -3, -7 % 3, 7.5 % 2, 2^10, 7 / 2, 1 // 0.0, -(-3)"),
+            ["3", "-4", "-2", "2", "1.5", "1024.0", "3.5", "inf", "3"]
+        );
+        assert_eq!(
+            eval("return '10' + 1, 10 .. 20, 0/0 ~= 0/0"),
+            ["11", "1020", "true"]
+        );
+        let l = new_state();
+        let e = run(l, "local z = 0 return 1 // z").unwrap_err();
+        assert!(e.contains("attempt to perform 'n//0'"), "{}", e);
+        let e = run(l, "local z = 0 return 1 % z").unwrap_err();
+        assert!(e.contains("'n%0'"), "{}", e);
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(
+            eval("return 3 | 5, 3 & 5, 3 ~ 5, ~0, 1 << 4, 1 << 64, 0xF0 >> 4, 2.0 | 1"),
+            ["7", "1", "6", "-1", "16", "0", "15", "3"]
+        );
+    }
+
+    #[test]
+    fn integer_semantics() {
+        let l = new_state();
+        l.push_global_table().unwrap();
+        l.push_integer(LUA_MAXINTEGER).unwrap();
+        l.set_field(1, b"maxinteger").unwrap();
+        l.push_integer(LUA_MININTEGER).unwrap();
+        l.set_field(1, b"mininteger").unwrap();
+        l.move_top(1, false);
+        // integers wrap around
+        let src = "return maxinteger + 1 == mininteger, mininteger - 1 == maxinteger,
+                          maxinteger * 2, -mininteger == mininteger,
+                          mininteger // -1 == mininteger, mininteger % -1";
+        assert_eq!(
+            run(l, src).unwrap(),
+            ["true", "true", "-2", "true", "true", "0"]
+        );
+        // floor division and modulo keep the subtype
+        let src = "return 7 // -2, 7.0 // -2, -7 % 2.0, 5 % (1/0), -5 % (1/0), 10 / 2, 2^2";
+        assert_eq!(
+            run(l, src).unwrap(),
+            ["-4", "-4.0", "1.0", "5.0", "inf", "5.0", "4.0"]
+        );
+        // integers and floats compare exactly, across the precision of floats
+        let src = "local f = maxinteger + 0.0
+                   return f == maxinteger, maxinteger < f, mininteger == mininteger + 0.0";
+        assert_eq!(run(l, src).unwrap(), ["false", "true", "true"]);
+        // floats with an integer value are integer keys and bitwise operands
+        let src = "local t = {} t[1.0], t[2] = 'a', 'b'
+                   return t[1], t[2.0], #t, 3.0 | 0, 2^3 >> 1";
+        assert_eq!(run(l, src).unwrap(), ["a", "b", "2", "3", "4"]);
+        let e = run(l, "local x = 3.5 return x | 0").unwrap_err();
+        assert!(e.contains("no integer representation"), "{}", e);
+        // a loop up to the largest integer ends
+        let src = "local n = 0 for i = maxinteger - 2, maxinteger do n = n + 1 end return n";
+        assert_eq!(run(l, src).unwrap(), ["3"]);
+        assert_eq!(
+            run(l, "return maxinteger").unwrap(),
+            [LUA_MAXINTEGER.to_string()]
+        );
+        #[cfg(not(feature = "lua32"))]
+        assert_eq!(LUA_MAXINTEGER, i64::MAX);
+        #[cfg(feature = "lua32")]
+        assert_eq!(LUA_MAXINTEGER, i32::MAX);
+    }
+
+    #[test]
+    fn comparison() {
+        assert_eq!(
+            eval("return 1 == 1.0, 1 < 2.5, 'a' < 'b', 'b' <= 'a', {} == {}, not nil, #'abc'"),
+            ["true", "true", "true", "false", "false", "true", "3"]
+        );
+    }
+
+    #[test]
+    fn tables_and_varargs() {
+        assert_eq!(
+            eval("local t = {1, 2, 3, x = 4, [10] = 5} return #t, t.x, t[10], t[4]"),
+            ["3", "4", "5", "nil"]
+        );
+        assert_eq!(
+            eval("local function f(...) local a, b = ... return b, a, ... end return f(1, 2, 3)"),
+            ["2", "1", "1", "2", "3"]
+        );
+    }
+
+    #[test]
+    fn numeric_for() {
+        assert_eq!(
+            eval("local t = {} for i = 10, 1, -3 do t[#t + 1] = i end return #t, t[1], t[4]"),
+            ["4", "10", "1"]
+        );
+        assert_eq!(
+            eval("local n = 0 for i = 1.0, 2.0, 0.5 do n = n + i end return n"),
+            ["4.5"]
+        );
+        assert_eq!(
+            eval("local n = 0 for i = 1, 0 do n = n + 1 end for i = 3.5, 1 do n = n + 1 end return n"),
+            ["0"]
+        );
+        // the loop ends at the limit without overflowing
+        assert_eq!(
+            eval("local n = 0 for i = math.maxinteger - 1, math.maxinteger do n = n + 1 end return n"
+                .replace("math.maxinteger", &INT::MAX.to_string())
+                .as_str()),
+            ["2"]
+        );
+        let l = new_state();
+        let e = run(l, "for i = 1, 10, 0 do end").unwrap_err();
+        assert!(e.ends_with("'for' step is zero"), "{}", e);
+        assert!(run(l, "for i = {}, 1 do end").is_err());
+    }
+
+    #[test]
+    fn generic_for_and_goto() {
+        let src = "local function it(s, c) if c < s then return c + 1, c * 2 end end
+                   local r = 0 for i, d in it, 3, 0 do r = r + i + d end return r";
+        assert_eq!(eval(src), ["12"]);
+        assert_eq!(
+            eval("local i = 0 ::top:: i = i + 1 if i < 5 then goto top end return i"),
+            ["5"]
+        );
+    }
+
+    #[test]
+    fn error_messages() {
+        let l = new_state();
+        let cases = [
+            ("return x.y", "attempt to index a nil value (global 'x')"),
+            (
+                "local t = {} return t.a.b",
+                "attempt to index a nil value (field 'a')",
+            ),
+            (
+                "local t t.x = 1",
+                "attempt to index a nil value (local 't')",
+            ),
+            (
+                "local u local function g() return u[1] end return g()",
+                "attempt to index a nil value (upvalue 'u')",
+            ),
+            ("f()", "attempt to call a nil value (global 'f')"),
+            (
+                "local t = {} t:m()",
+                "attempt to call a nil value (method 'm')",
+            ),
+            (
+                "return ('x')()",
+                "attempt to call a string value (constant 'x')",
+            ),
+            (
+                "local a return a + 1",
+                "attempt to perform arithmetic on a nil value (local 'a')",
+            ),
+            (
+                "local u = {} return u .. 'x'",
+                "attempt to concatenate a table value (local 'u')",
+            ),
+            (
+                "local f = 2.5 return f & 1",
+                "number (local 'f') has no integer representation",
+            ),
+            (
+                "local n return #n",
+                "attempt to get length of a nil value (local 'n')",
+            ),
+            ("return 1 < 'x'", "attempt to compare number with string"),
+            ("return {} < {}", "attempt to compare two table values"),
+            (
+                "for i = 'a', 2 do end",
+                "'for' initial value must be a number",
+            ),
+            ("for i = 1, 'x' do end", "'for' limit must be a number"),
+            ("for i = 1, 2, {} do end", "'for' step must be a number"),
+        ];
+        for (src, msg) in cases {
+            let e = run(l, src).unwrap_err();
+            assert_eq!(e, format!("test:1: {}", msg), "{}", src);
+        }
+    }
+
+    #[test]
+    fn table_error_values() {
+        // a table raised as the error comes back as it is, with no position
+        assert_eq!(
+            eval("local e = {code = 7} local ok, v = pcall(error, e) return ok, v == e, v.code"),
+            ["false", "true", "7"]
+        );
+        let l = new_state();
+        assert!(run(l, "error({})").unwrap_err().starts_with("table: "));
+        // a message handler sees the same table
+        assert_eq!(
+            run(
+                l,
+                "return xpcall(function() error({1, 2}) end, function(e) return #e end)"
+            )
+            .unwrap(),
+            ["false", "2"]
+        );
+    }
+
+    #[test]
+    fn pcall_closes_on_unwinding() {
+        // the to-be-closed variables of every frame left are closed, innermost first
+        let src = "local log = ''
+                   local mt = {__close = function(o, e) log = log .. o.n .. ':' .. tostring(e) .. ' ' end}
+                   local function f()
+                       local a <close> = setmetatable({n = 'a'}, mt)
+                       local function g()
+                           local b <close> = setmetatable({n = 'b'}, mt)
+                           error('x', 0)
+                       end
+                       g()
+                   end
+                   local ok, e = pcall(f)
+                   return ok, e, log";
+        assert_eq!(eval_mt(src), ["false", "x", "b:x a:x "]);
+        // upvalues of the frames left keep their last values
+        let src = "local get
+                   local ok = pcall(function()
+                       local v = 1
+                       get = function() return v end
+                       v = 2
+                       error('e')
+                   end)
+                   return ok, get()";
+        assert_eq!(eval(src), ["false", "2"]);
+        // an error in a `__close` replaces the one being handled
+        let src = "local ok, e = pcall(function()
+                       local a <close> = setmetatable({}, {__close = function() error('c', 0) end})
+                       error('x', 0)
+                   end)
+                   return ok, e";
+        assert_eq!(eval_mt(src), ["false", "c"]);
+    }
+
+    /// brief: depth(e), the error and the number of frames
+    fn lua_depth(l: &mut LuaState) -> usize {
+        let e = String::from_utf8_lossy(l.get_string_fromtop(0).unwrap()).into_owned();
+        let s = format!("{} {}", e, l.ncalls);
+        match l.push_string(s.as_bytes()) {
+            Ok(_) => 1,
+            Err(e) => l.raise(e),
+        }
+    }
+
+    #[test]
+    fn message_handler_sees_the_frames() {
+        let l = new_state();
+        set_global_fn(l, b"depth", lua_depth);
+        let src = "local _, e1 = xpcall(function() error('x', 0) end, depth)
+                   local _, e2 = xpcall(function()
+                       local function g() error('y', 0) end
+                       g()
+                   end, depth)
+                   return e1, e2";
+        let res = run(l, src).unwrap();
+        let depth = |s: &str| s.split(' ').nth(1).unwrap().parse::<usize>().unwrap();
+        assert!(
+            res[0].starts_with("x ") && res[1].starts_with("y "),
+            "{:?}",
+            res
+        );
+        // one more frame, the one of g, is still there
+        assert_eq!(depth(&res[1]), depth(&res[0]) + 1);
+    }
+
+    #[test]
+    fn pcall_from_rust() {
+        let l = new_state();
+        let (top, ncalls) = (l.get_stack_top(), l.ncalls);
+        // the error object takes the place of the function
+        l.load(b"local a, b = ... error(a .. b, 0)", "=test")
+            .unwrap();
+        l.push_string(b"o").unwrap();
+        l.push_string(b"k").unwrap();
+        assert_eq!(l.pcall(2, 0, None).unwrap().0, STATE_ERR_RUN);
+        assert_eq!((l.get_stack_top(), l.ncalls), (top + 1, ncalls));
+        assert_eq!(l.get_string_fromtop(0).unwrap(), b"ok");
+        l.move_top(1, false);
+        // an error in the message handler gives `STATE_ERR_ERR`
+        l.load(b"error('h')", "=handler").unwrap();
+        l.load(b"error('x')", "=test").unwrap();
+        assert_eq!(l.pcall(0, 0, Some(1)).unwrap().0, STATE_ERR_ERR);
+        assert_eq!((l.get_stack_top(), l.ncalls), (top + 2, ncalls));
+        assert_eq!(
+            l.get_string_fromtop(0).unwrap(),
+            ErrCode(STATE_ERR_ERR).msg().as_bytes()
+        );
+        l.move_top(2, false);
+        assert_eq!(run(l, "return 1").unwrap(), ["1"]);
+    }
+
+    /// brief: three(), the results 1, 2 and 3
+    fn lua_three(l: &mut LuaState) -> usize {
+        for i in 1..=3 {
+            if let Err(e) = l.push_integer(i) {
+                return l.raise(e);
+            }
+        }
+        3
+    }
+
+    /// brief: liar(), claims more results than it has
+    fn lua_liar(l: &mut LuaState) -> usize {
+        match l.push_integer(1) {
+            Ok(_) => l.get_stack_top(),
+            Err(e) => l.raise(e),
+        }
+    }
+
+    #[test]
+    fn results_adjusted_to_the_call() {
+        let l = new_state();
+        set_global_fn(l, b"three", lua_three);
+        set_global_fn(l, b"liar", lua_liar);
+        let src = "local a, b, c, d, e = three()
+                   local t = {three()}
+                   local u = {three(), 9}
+                   local function pass(...) return ... end
+                   return a, b, c, d, e, #t, #u, u[2], pass(three())";
+        assert_eq!(
+            run(l, src).unwrap(),
+            ["1", "2", "3", "nil", "nil", "3", "2", "9", "1", "2", "3"]
+        );
+        let e = run(l, "return liar()").unwrap_err();
+        assert!(e.contains("wrong number of results"), "{}", e);
+        // from Rust: all the results, or a fixed count padded with nil or cut
+        let top = l.get_stack_top();
+        for (wanted, n) in [(LUA_MUL_RET, 3), (5, 5), (1, 1), (0, 0)] {
+            l.load(b"return 'x', 'y', 'z'", "=test").unwrap();
+            assert_eq!(l.pcall(0, wanted, None).unwrap().0, STATE_OK);
+            assert_eq!(l.get_stack_top(), top + n);
+            if n == 5 {
+                assert!(l.get_stkelem_fromtop(0).unwrap().is_nil());
+                assert!(l.get_stkelem_fromtop(1).unwrap().is_nil());
+                assert_eq!(l.get_string_fromtop(2).unwrap(), b"z");
+            } else if n > 0 {
+                assert_eq!(l.get_string_fromtop(n - 1).unwrap(), b"x");
+            }
+            l.move_top_to(top);
+        }
+    }
 }
@@ -1 +1,7 @@
-pub mod machine;
\ No newline at end of file
+pub mod arith;
+pub mod convert;
+pub mod coroutine;
+pub mod error;
+pub mod machine;
+pub mod operator;
+pub mod tm;
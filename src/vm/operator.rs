@@ -0,0 +1,196 @@
+use crate::info::lua::ErrCode;
+use crate::obj::statedef::LuaState;
+use crate::vm::arith::{ArithOp, CompareOp};
+use crate::vm::machine::raw_equal;
+
+impl LuaState {
+    /// brief: pop the two operands on top, the second one on top, and push the result of op
+    /// over them; a unary operator pops a single operand; metamethods and string coercion
+    /// apply as in Lua code
+    pub fn arith(&mut self, op: ArithOp) -> Result<ErrCode, ErrCode> {
+        let unary = matches!(op, ArithOp::Unm | ArithOp::BNot);
+        let b = self.get_stkelem_fromtop(0)?;
+        let a = if unary {
+            b
+        } else {
+            self.get_stkelem_fromtop(1)?
+        };
+        let v = self.arith_obj(op, &a, &b)?;
+        self.move_top(if unary { 1 } else { 2 }, false);
+        self.push_obj(v)
+    }
+
+    /// brief: whether the value `a` slots below the top compares with op to the one
+    /// `b` slots below the top, metamethods apply as in Lua code
+    pub fn compare(&mut self, a: usize, b: usize, op: CompareOp) -> Result<bool, ErrCode> {
+        let v1 = self.get_stkelem_fromtop(a)?;
+        let v2 = self.get_stkelem_fromtop(b)?;
+        self.compare_obj(&v1, &v2, op)
+    }
+
+    /// brief: whether the values `a` and `b` slots below the top are primitively equal,
+    /// without `__eq`
+    pub fn raw_equal(&mut self, a: usize, b: usize) -> Result<bool, ErrCode> {
+        let v1 = self.get_stkelem_fromtop(a)?;
+        let v2 = self.get_stkelem_fromtop(b)?;
+        Ok(raw_equal(&v1, &v2))
+    }
+
+    /// brief: pop the n values on top and push their concatenation, as `..` does;
+    /// a single value stays as it is, none gives the empty string
+    pub fn concat(&mut self, n: usize) -> Result<ErrCode, ErrCode> {
+        if n == 0 {
+            return self.push_string(b"");
+        }
+        if n > 1 {
+            self.concat_top(n)?;
+        }
+        self.check_gc()
+    }
+
+    /// brief: push the length of the value `step` slots below the top, as `#` does
+    pub fn len(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let v = self.get_stkelem_fromtop(step)?;
+        let n = self.obj_len(&v)?;
+        self.push_obj(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info::lua::{LUA_MUL_RET, STATE_OK};
+    use crate::vm::machine::tests::{lua_setmetatable, new_state, run, set_global_fn};
+
+    /// brief: pop the value on top, as text
+    fn pop_text(l: &mut LuaState) -> String {
+        l.tostring(0).unwrap();
+        let s = String::from_utf8_lossy(l.get_string_fromtop(0).unwrap()).into_owned();
+        l.move_top(2, false);
+        s
+    }
+
+    /// brief: push the results of a chunk
+    fn push_chunk(l: &mut LuaState, src: &str) {
+        l.load(src.as_bytes(), "=test").unwrap();
+        assert_eq!(l.pcall(0, LUA_MUL_RET, None).unwrap().0, STATE_OK);
+    }
+
+    /// brief: the text of the error object of e
+    fn error_text(l: &mut LuaState, e: ErrCode) -> String {
+        let v = l.error_object(e).unwrap();
+        l.push_obj(v).unwrap();
+        pop_text(l)
+    }
+
+    #[test]
+    fn arith_agrees_with_lua() {
+        let l = new_state();
+        let ops = [
+            (ArithOp::Add, "+"),
+            (ArithOp::Sub, "-"),
+            (ArithOp::Mul, "*"),
+            (ArithOp::Mod, "%"),
+            (ArithOp::Pow, "^"),
+            (ArithOp::Div, "/"),
+            (ArithOp::IDiv, "//"),
+            (ArithOp::BAnd, "&"),
+            (ArithOp::BOr, "|"),
+            (ArithOp::BXor, "~"),
+            (ArithOp::Shl, "<<"),
+            (ArithOp::Shr, ">>"),
+        ];
+        for (a, b) in [("7", "3"), ("-7", "2.0"), ("'10'", "4"), ("6.0", "-4")] {
+            for (op, sym) in ops {
+                let src = format!("return ({}) {} ({})", a, sym, b);
+                let expected = run(l, &src).unwrap();
+                push_chunk(l, &format!("return {}, {}", a, b));
+                l.arith(op).unwrap();
+                assert_eq!([pop_text(l)], *expected, "{}", src);
+            }
+        }
+        push_chunk(l, "return 5");
+        l.arith(ArithOp::Unm).unwrap();
+        assert_eq!(pop_text(l), "-5");
+        push_chunk(l, "return '0'");
+        l.arith(ArithOp::BNot).unwrap();
+        assert_eq!(pop_text(l), "-1");
+        // the same errors as in Lua code
+        push_chunk(l, "return {}, 1");
+        let e = l.arith(ArithOp::Add).unwrap_err();
+        assert_eq!(
+            error_text(l, e),
+            "attempt to perform arithmetic on a table value"
+        );
+        l.move_top(2, false);
+        push_chunk(l, "return 1.5, 1");
+        let e = l.arith(ArithOp::BOr).unwrap_err();
+        assert!(error_text(l, e).contains("no integer representation"));
+        l.move_top(2, false);
+    }
+
+    #[test]
+    fn metamethods_apply() {
+        let l = new_state();
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        let top = l.get_stack_top();
+        push_chunk(
+            l,
+            "local mt = {}
+             function mt.__add(a, b) return 'add' end
+             function mt.__unm(a) return 'unm' end
+             function mt.__lt(a, b) return true end
+             function mt.__le(a, b) return false end
+             function mt.__eq(a, b) return true end
+             function mt.__concat(a, b) return 'cat' end
+             function mt.__len(a) return 42 end
+             return setmetatable({}, mt), setmetatable({}, mt)",
+        );
+        // a, b
+        assert!(l.compare(1, 0, CompareOp::Lt).unwrap());
+        assert!(!l.compare(1, 0, CompareOp::Le).unwrap());
+        assert!(l.compare(1, 0, CompareOp::Eq).unwrap());
+        assert!(!l.raw_equal(1, 0).unwrap());
+        assert!(l.raw_equal(1, 1).unwrap());
+        l.len(0).unwrap();
+        assert_eq!(pop_text(l), "42");
+        l.push_integer(1).unwrap();
+        l.concat(2).unwrap();
+        assert_eq!(pop_text(l), "cat");
+        l.arith(ArithOp::Unm).unwrap();
+        assert_eq!(pop_text(l), "unm");
+        assert_eq!(l.get_stack_top(), top);
+    }
+
+    #[test]
+    fn compare_concat_and_len() {
+        let l = new_state();
+        push_chunk(l, "return 1, 2.5, 'a', 'b', 2.5");
+        assert!(l.compare(4, 3, CompareOp::Lt).unwrap());
+        assert!(l.compare(3, 0, CompareOp::Le).unwrap());
+        assert!(l.compare(3, 0, CompareOp::Eq).unwrap());
+        assert!(l.raw_equal(3, 0).unwrap());
+        assert!(!l.compare(0, 3, CompareOp::Lt).unwrap());
+        assert!(l.compare(2, 1, CompareOp::Lt).unwrap());
+        let e = l.compare(4, 2, CompareOp::Lt).unwrap_err();
+        assert_eq!(error_text(l, e), "attempt to compare number with string");
+        // the five values, numbers as text
+        l.concat(5).unwrap();
+        assert_eq!(pop_text(l), "12.5ab2.5");
+        l.concat(0).unwrap();
+        assert_eq!(pop_text(l), "");
+        l.push_integer(3).unwrap();
+        l.concat(1).unwrap();
+        assert_eq!(pop_text(l), "3");
+        push_chunk(l, "return 'abc', {1, 2, 3, nil}");
+        l.len(0).unwrap();
+        l.len(2).unwrap();
+        assert_eq!(pop_text(l), "3");
+        assert_eq!(pop_text(l), "3");
+        l.move_top(2, false);
+        l.push_bool(true).unwrap();
+        let e = l.len(0).unwrap_err();
+        assert_eq!(error_text(l, e), "attempt to get length of a boolean value");
+        l.move_top(1, false);
+    }
+}
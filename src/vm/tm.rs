@@ -0,0 +1,402 @@
+use core::ptr::null_mut;
+
+use crate::info::lua::{ErrCode, FINE, MEMORY_TYPE_MISMATCH, RUNTIME_TOSTRING};
+use crate::obj::gc::GcHeader;
+use crate::obj::objdef::{type_name, ObjectTrait, TObj, T_BOOLEAN, T_NIL, T_NUMBER};
+use crate::obj::statedef::LuaState;
+use crate::obj::string::LuaString;
+use crate::obj::table::{obj_addr, LuaTable};
+use crate::obj::userdata::LuaUserData;
+use crate::vm::convert::number_to_str;
+
+/// brief: the events a metatable can handle
+/// the order is part of the bytecode: MMBIN* instructions carry the event number
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagMethod {
+    Index,
+    NewIndex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+    Lt,
+    Le,
+    Concat,
+    Call,
+    Close,
+}
+
+pub const TM_N: usize = TagMethod::Close as usize + 1;
+
+const TAG_METHODS: [TagMethod; TM_N] = [
+    TagMethod::Index,
+    TagMethod::NewIndex,
+    TagMethod::Gc,
+    TagMethod::Mode,
+    TagMethod::Len,
+    TagMethod::Eq,
+    TagMethod::Add,
+    TagMethod::Sub,
+    TagMethod::Mul,
+    TagMethod::Mod,
+    TagMethod::Pow,
+    TagMethod::Div,
+    TagMethod::IDiv,
+    TagMethod::BAnd,
+    TagMethod::BOr,
+    TagMethod::BXor,
+    TagMethod::Shl,
+    TagMethod::Shr,
+    TagMethod::Unm,
+    TagMethod::BNot,
+    TagMethod::Lt,
+    TagMethod::Le,
+    TagMethod::Concat,
+    TagMethod::Call,
+    TagMethod::Close,
+];
+
+const TM_NAMES: [&str; TM_N] = [
+    "__index",
+    "__newindex",
+    "__gc",
+    "__mode",
+    "__len",
+    "__eq",
+    "__add",
+    "__sub",
+    "__mul",
+    "__mod",
+    "__pow",
+    "__div",
+    "__idiv",
+    "__band",
+    "__bor",
+    "__bxor",
+    "__shl",
+    "__shr",
+    "__unm",
+    "__bnot",
+    "__lt",
+    "__le",
+    "__concat",
+    "__call",
+    "__close",
+];
+
+impl TagMethod {
+    #[inline(always)]
+    pub fn from_u8(event: u8) -> Option<TagMethod> {
+        TAG_METHODS.get(event as usize).copied()
+    }
+
+    /// brief: the metatable key of the event, e.g. `__add`
+    #[inline(always)]
+    pub fn name(self) -> &'static str {
+        TM_NAMES[self as usize]
+    }
+}
+
+impl LuaState {
+    /// brief: intern the names of the events, the keys of metamethods in a metatable
+    pub(crate) fn init_tm(&mut self) -> Result<ErrCode, ErrCode> {
+        for (i, name) in TM_NAMES.iter().enumerate() {
+            let ts = self.new_string(name.as_bytes())?;
+            self.global_mut()?.tmname[i] = ts;
+        }
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: the metatable of a value, null if it has none;
+    /// a table or a full userdata has its own, other values share the one of their type
+    pub fn metatable_of(&self, o: &TObj) -> Result<*mut LuaTable, ErrCode> {
+        if let Some(h) = Option::<*mut LuaTable>::into_inner(o) {
+            return Ok(unsafe { (*h).metatable });
+        }
+        if let Some(u) = Option::<*mut LuaUserData>::into_inner(o) {
+            return Ok(unsafe { (*u).metatable });
+        }
+        Ok(self.global()?.mt[o.val_idx.basic_type() as usize])
+    }
+
+    /// brief: the metamethod of an event in a metatable, nil if absent
+    pub(crate) fn fast_tm(&self, mt: *mut LuaTable, event: TagMethod) -> Result<TObj, ErrCode> {
+        if mt.is_null() {
+            return Ok(TObj::default());
+        }
+        let ename = self.global()?.tmname[event as usize];
+        Ok(unsafe { (*mt).get_tm(event, ename) })
+    }
+
+    /// brief: the metamethod of a value for an event, nil if absent
+    pub(crate) fn get_tm_by_obj(&self, o: &TObj, event: TagMethod) -> Result<TObj, ErrCode> {
+        let mt = self.metatable_of(o)?;
+        self.fast_tm(mt, event)
+    }
+
+    /// brief: call a metamethod and give its first result, nil when nres is 0;
+    /// it runs above the registers of the current frame and leaves the top as it was,
+    /// nothing it calls may yield
+    pub(crate) fn call_tm(&mut self, f: TObj, args: &[TObj], nres: usize) -> Result<TObj, ErrCode> {
+        let at = self.tm_slot()?;
+        self.call_tm_at(at, f, args, nres, false)
+    }
+
+    /// brief: `call_tm` for the metamethod of an operator or of an access; run from Lua code
+    /// it may yield, the call then gives `STATE_YIELD` as error and leaves its frames,
+    /// and `finish_op` puts its result in place when the coroutine goes on
+    pub(crate) fn call_op_tm(&mut self, f: TObj, args: &[TObj], nres: usize) -> Result<TObj, ErrCode> {
+        let at = self.tm_slot()?;
+        self.call_tm_at(at, f, args, nres, true)
+    }
+
+    /// brief: the first slot above the registers of the current frame and the values on top
+    fn tm_slot(&mut self) -> Result<usize, ErrCode> {
+        let mut at = self.stack_top_index;
+        if self.ncalls > 0 {
+            at = at.max(self.get_frame_mut(self.ncalls - 1)?.stack_upper_bound);
+        }
+        Ok(at)
+    }
+
+    /// brief: call a metamethod with its function at the slot `at`, the slots from there on
+    /// being free; with yy it may yield if the running function is a Lua one
+    pub(crate) fn call_tm_at(
+        &mut self,
+        at: usize,
+        f: TObj,
+        args: &[TObj],
+        nres: usize,
+        yy: bool,
+    ) -> Result<TObj, ErrCode> {
+        let saved = self.stack_top_index;
+        self.stack_top_index = at;
+        self.stack_check(args.len() + 1)?;
+        self.push_obj(f)?;
+        for a in args.iter() {
+            self.push_obj(*a)?;
+        }
+        if yy && self.running_instr().is_some() {
+            self.call_fresh(args.len(), nres as isize)?;
+        } else {
+            self.do_call(args.len(), nres as isize)?;
+        }
+        let v = if nres > 0 {
+            self.get_stkelem_fromtop(nres - 1)?
+        } else {
+            TObj::default()
+        };
+        self.stack_top_index = saved;
+        Ok(v)
+    }
+
+    /// brief: push the metatable of the value `step` slots below the top,
+    /// nothing is pushed and false given if it has none
+    pub fn get_metatable(&mut self, step: usize) -> Result<bool, ErrCode> {
+        let o = self.get_stkelem_fromtop(step)?;
+        let mt = self.metatable_of(&o)?;
+        if mt.is_null() {
+            return Ok(false);
+        }
+        self.push_obj(Some(mt).new())?;
+        Ok(true)
+    }
+
+    /// brief: pop a table, or nil, into the metatable of the value `step` slots below the top,
+    /// counted with the metatable on top; for values but tables and full userdata it is
+    /// the one of their type; a table or a userdata gets finalized if the metatable has `__gc` by then
+    pub fn set_metatable(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let top = self.get_stkelem_fromtop(0)?;
+        let mt = match Option::<*mut LuaTable>::into_inner(&top) {
+            Some(mt) => mt,
+            None if top.is_nil() => null_mut(),
+            None => return Err(ErrCode(MEMORY_TYPE_MISMATCH)),
+        };
+        let o = self.get_stkelem_fromtop(step)?;
+        if let Some(h) = Option::<*mut LuaTable>::into_inner(&o) {
+            unsafe { (*h).metatable = mt };
+            let g = self.global_mut()?;
+            g.barrier(h as *mut GcHeader, &top);
+            g.check_finalizer(h as *mut GcHeader, mt);
+        } else if let Some(u) = Option::<*mut LuaUserData>::into_inner(&o) {
+            unsafe { (*u).metatable = mt };
+            let g = self.global_mut()?;
+            g.barrier(u as *mut GcHeader, &top);
+            g.check_finalizer(u as *mut GcHeader, mt);
+        } else {
+            self.global_mut()?.mt[o.val_idx.basic_type() as usize] = mt;
+        }
+        self.move_top(1, false);
+        Ok(ErrCode(FINE))
+    }
+
+    /// brief: push the text of the value `step` slots below the top, through its `__tostring`
+    /// if it has one; without it, values but numbers, strings and booleans show their
+    /// `__name`, or their type, and their address
+    pub fn tostring(&mut self, step: usize) -> Result<ErrCode, ErrCode> {
+        let o = self.get_stkelem_fromtop(step)?;
+        let mt = self.metatable_of(&o)?;
+        if !mt.is_null() {
+            let ename = self.new_string(b"__tostring")?;
+            let tm = unsafe { (*mt).get_str(ename) };
+            if !tm.is_nil() {
+                let s = self.call_tm(tm, &[o], 1)?;
+                if Option::<*mut LuaString>::into_inner(&s).is_none() {
+                    return Err(ErrCode(RUNTIME_TOSTRING));
+                }
+                return self.push_obj(s);
+            }
+        }
+        if Option::<*mut LuaString>::into_inner(&o).is_some() {
+            return self.push_obj(o);
+        }
+        let text = match o.val_idx.basic_type() {
+            T_NUMBER => number_to_str(&o).unwrap_or_default(),
+            T_NIL => "nil".to_string(),
+            T_BOOLEAN => Option::<bool>::into_inner(&o)
+                .unwrap_or_default()
+                .to_string(),
+            t => {
+                let mut kind = type_name(t).to_string();
+                if !mt.is_null() {
+                    let ename = self.new_string(b"__name")?;
+                    let name = unsafe { (*mt).get_str(ename) };
+                    if let Some(ts) = Option::<*mut LuaString>::into_inner(&name) {
+                        kind = String::from_utf8_lossy(unsafe { (*ts).as_bytes() }).into_owned();
+                    }
+                }
+                format!("{}: {:#x}", kind, obj_addr(&o))
+            }
+        };
+        self.push_string(text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::machine::tests::{eval_mt, lua_setmetatable, new_state, run, set_global_fn};
+
+    #[test]
+    fn index_and_newindex() {
+        let src = "local base = {a = 1}
+                   local t = setmetatable({b = 2}, {__index = base})
+                   local f = setmetatable({}, {__index = function(t, k) return k .. '?' end})
+                   return t.a, t.b, t.c, f.x, f[1]";
+        assert_eq!(eval_mt(src), ["1", "2", "nil", "x?", "1?"]);
+        let src = "local store, log = {}, {}
+                   local t = setmetatable({old = 0}, {__newindex = store})
+                   t.x = 1 t.old = 2
+                   local u = setmetatable({}, {__newindex = function(u, k, v) log[#log + 1] = k .. v end})
+                   u.a = 1 u.b = 2
+                   return store.x, t.x, t.old, store.old, log[1], log[2], u.a";
+        assert_eq!(eval_mt(src), ["1", "nil", "2", "nil", "a1", "b2", "nil"]);
+    }
+
+    #[test]
+    fn arithmetic_events() {
+        let src = "local names = {'add', 'sub', 'mul', 'mod', 'pow', 'div', 'idiv',
+                                  'band', 'bor', 'bxor', 'shl', 'shr', 'unm', 'bnot'}
+                   local mt = {}
+                   for i = 1, #names do
+                       local n = names[i]
+                       mt['__' .. n] = function(x, y) return n end
+                   end
+                   local a = setmetatable({}, mt)
+                   return a + 1, a - 1, a * 2, a % 2, a ^ 2, a / 2, a // 2,
+                          a & 1, a | 1, a ~ 1, a << 1, a >> 1, -a, ~a";
+        assert_eq!(
+            eval_mt(src),
+            [
+                "add", "sub", "mul", "mod", "pow", "div", "idiv", "band", "bor", "bxor", "shl",
+                "shr", "unm", "bnot"
+            ]
+        );
+        // the operands keep their order, with a constant or an immediate on either side
+        let src = "local a
+                   a = setmetatable({}, {__sub = function(x, y)
+                       return (x == a and 'a' or tostring(x)) .. '-' .. (y == a and 'a' or tostring(y))
+                   end})
+                   local k = 2.5
+                   return 1 - a, a - 1, k - a, a - k, a - a, '3' - 1";
+        assert_eq!(eval_mt(src), ["1-a", "a-1", "2.5-a", "a-2.5", "a-a", "2"]);
+    }
+
+    #[test]
+    fn comparison_events() {
+        let src = "local log = {}
+                   local mt = {__eq = function(x, y) log[#log + 1] = 'eq' return 1 end,
+                               __lt = function(x, y) return x.v < y.v end,
+                               __le = function(x, y) return x.v <= y.v end}
+                   local a = setmetatable({v = 1}, mt)
+                   local b = setmetatable({v = 2}, mt)
+                   return a == b, a ~= b, a == a, a == 1, #log, a < b, a > b, a <= b, b >= a, b <= a";
+        assert_eq!(
+            eval_mt(src),
+            ["true", "false", "true", "false", "2", "true", "false", "true", "true", "false"]
+        );
+        // `__le` no longer falls back on `__lt`
+        let l = new_state();
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        let src = "local mt = {__lt = function() return true end}
+                   local a, b = setmetatable({}, mt), setmetatable({}, mt)
+                   return a <= b";
+        let e = run(l, src).unwrap_err();
+        assert!(e.contains("attempt to compare two table values"), "{}", e);
+    }
+
+    #[test]
+    fn concat_len_and_call() {
+        let src =
+            "local mt = {__concat = function(x, y) return tostring(x == 1) .. tostring(y == 1) end,
+                               __len = function() return 42 end,
+                               __call = function(self, x, y) return x + y, self end}
+                   local a = setmetatable({}, mt)
+                   local s1, s2 = 1 .. a, a .. 1
+                   local r, me = a(2, 3)
+                   return s1, s2, 'x' .. 'y' .. 1 .. 2, #a, r, me == a";
+        assert_eq!(
+            eval_mt(src),
+            ["truefalse", "falsetrue", "xy12", "42", "5", "true"]
+        );
+    }
+
+    #[test]
+    fn close_tostring_and_name() {
+        let src = "local log = ''
+                   local mt = {__close = function(x, e) log = log .. x.n .. tostring(e) end}
+                   do
+                       local a <close> = setmetatable({n = 'a'}, mt)
+                       local b <close> = setmetatable({n = 'b'}, mt)
+                   end
+                   local s = setmetatable({}, {__tostring = function() return 'S' end})
+                   local n = tostring(setmetatable({}, {__name = 'Point'}))
+                   return log, tostring(s), n";
+        let res = eval_mt(src);
+        assert_eq!(res[..2], ["bnilanil", "S"]);
+        assert!(res[2].starts_with("Point: "), "{}", res[2]);
+    }
+
+    #[test]
+    fn index_chain_loop() {
+        let l = new_state();
+        set_global_fn(l, b"setmetatable", lua_setmetatable);
+        let src = "local t = {} setmetatable(t, {__index = t}) return t.x";
+        let e = run(l, src).unwrap_err();
+        assert!(e.contains("'__index' chain too long"), "{}", e);
+    }
+}